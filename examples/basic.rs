@@ -6,8 +6,10 @@ rust_db::schema! {
     table_name: "User",
     #[derive(Debug, Serialize, Deserialize, Clone)]
     struct User {
+        #[primary_key]
         id: u64,
         name: String,
+        #[unique]
         email: String,
         age: u32,
     }
@@ -56,6 +58,17 @@ async fn main() -> Result<(), DbError> {
     db.insert(&user).await?;
     println!("✅ Inserted valid user: {:?}", user);
 
+    // Records are keyed by their primary key, so a second user doesn't overwrite the first
+    let second_user = User {
+        id: 5,
+        name: "Eve".to_string(),
+        email: "eve@example.com".to_string(),
+        age: 41,
+    };
+    db.insert(&second_user).await?;
+    println!("🔑 User 1: {:?}", db.get::<User>("1").await?);
+    println!("🔑 User 5: {:?}", db.get::<User>("5").await?);
+
     // Test compile-time schema validation
     use rust_db::CompileTimeSchema;
     println!("📊 Schema info:");
@@ -109,5 +122,18 @@ async fn main() -> Result<(), DbError> {
         Ok(_) => println!("❌ Should have failed for unrealistic age"),
     }
 
+    // Test unique email
+    let duplicate_email = User {
+        id: 6,
+        name: "Mallory".to_string(),
+        email: "alice@example.com".to_string(),
+        age: 35,
+    };
+
+    match db.insert(&duplicate_email).await {
+        Err(e) => println!("❌ Expected error for duplicate email: {}", e),
+        Ok(_) => println!("❌ Should have failed for duplicate email"),
+    }
+
     Ok(())
 }
\ No newline at end of file
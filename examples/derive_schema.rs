@@ -0,0 +1,53 @@
+use rust_db::{Database, DbError, CompileTimeSchema};
+use serde::{Serialize, Deserialize};
+
+/// An ordinary struct: doc comments, private fields and IDE completion all work.
+#[derive(rust_db::Schema, Debug, Serialize, Deserialize, Clone)]
+#[table_name = "Order"]
+struct Order {
+    #[primary_key]
+    id: u64,
+    #[validate(non_empty)]
+    customer: String,
+    #[validate(email)]
+    contact: String,
+    #[validate(min = 1, max = 1_000_000)]
+    total_cents: u64,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), DbError> {
+    pretty_env_logger::init();
+    let db = Database::open("./data").await?;
+
+    println!("🧾 Derived Schema Demo");
+    println!("Table name: {}", Order::TABLE_NAME);
+    println!("Field count: {}", Order::FIELD_COUNT);
+
+    let order = Order {
+        id: 1,
+        customer: "Alice".to_string(),
+        contact: "alice@example.com".to_string(),
+        total_cents: 4_250,
+    };
+
+    db.insert(&order).await?;
+    println!("✅ Inserted order: {:?}", order);
+
+    let stored = db.get_by_key::<Order, _>(order.id).await?;
+    println!("🔑 Order 1: {:?}", stored);
+
+    // Field constraints come from the #[validate(...)] attributes
+    let empty_order = Order {
+        id: 2,
+        customer: "Bob".to_string(),
+        contact: "bob@example.com".to_string(),
+        total_cents: 0,
+    };
+    match db.insert(&empty_order).await {
+        Err(e) => println!("❌ Expected error for zero total: {}", e),
+        Ok(_) => println!("❌ Should have failed for zero total"),
+    }
+
+    Ok(())
+}
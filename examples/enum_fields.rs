@@ -0,0 +1,64 @@
+use rust_db::{Database, DbError, IndexOp, Schema};
+use serde::{Deserialize, Serialize};
+
+/// Stored as its variant name, e.g. `"Shipped"`, by serde
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum Status {
+    Pending,
+    Shipped,
+    Delivered,
+    Cancelled,
+}
+
+/// A variant carrying data, which serde stores along with its name
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum Delivery {
+    Pickup,
+    Courier { tracking: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Schema, Serialize, Deserialize)]
+#[table_name = "Shipment"]
+struct Shipment {
+    #[primary_key]
+    id: u64,
+    // Cancelled shipments live in another table
+    #[index]
+    #[validate(one_of(Status::Pending, Status::Shipped, Status::Delivered))]
+    status: Status,
+    delivery: Delivery,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), DbError> {
+    pretty_env_logger::init();
+    let dir = "./data_enum_fields";
+    let _ = std::fs::remove_dir_all(dir);
+    let db = Database::builder(dir).register::<Shipment>().open().await?;
+
+    let shipments = [
+        Shipment { id: 1, status: Status::Pending, delivery: Delivery::Pickup },
+        Shipment { id: 2, status: Status::Shipped, delivery: Delivery::Courier { tracking: "ZX-81".into() } },
+        Shipment { id: 3, status: Status::Shipped, delivery: Delivery::Pickup },
+    ];
+    db.insert_many(&shipments).await?;
+
+    let stored = db.get::<Shipment>("2").await?;
+    assert_eq!(stored.as_ref(), Some(&shipments[1]));
+    println!("📦 Shipment 2: {:?}", stored);
+
+    // A unit variant compares by name, so the index on `status` answers this
+    let shipped = db.query::<Shipment>().filter_field(Shipment::STATUS, IndexOp::Eq, Status::Shipped).execute().await?;
+    assert_eq!(shipped.len(), 2);
+    println!("🚚 Shipped: {:?}", shipped.iter().map(|s| s.id).collect::<Vec<_>>());
+
+    let cancelled = Shipment { id: 4, status: Status::Cancelled, delivery: Delivery::Pickup };
+    match db.insert(&cancelled).await {
+        Err(e) => println!("❌ Expected error for a cancelled shipment: {}", e),
+        Ok(_) => println!("❌ Should have failed for a cancelled shipment"),
+    }
+
+    drop(db);
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
@@ -6,6 +6,7 @@ rust_db::schema! {
     table_name: "Product",
     #[derive(Debug, Serialize, Deserialize, Clone)]
     struct Product {
+        #[primary_key]
         id: u64,
         name: String,
         price: f64,
@@ -0,0 +1,40 @@
+use rust_db::{DbError, Schema, SyncDatabase};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Schema, Serialize, Deserialize)]
+#[table_name = "Task"]
+struct Task {
+    #[primary_key]
+    id: u64,
+    title: String,
+    done: bool,
+}
+
+// An ordinary `fn main`: no Tokio runtime anywhere
+fn main() -> Result<(), DbError> {
+    pretty_env_logger::init();
+
+    let dir = "./data_sync";
+    let _ = std::fs::remove_dir_all(dir);
+    let db = SyncDatabase::open(dir)?;
+
+    db.insert(&Task { id: 1, title: "Write the report".to_string(), done: false })?;
+    db.insert(&Task { id: 2, title: "Send the report".to_string(), done: false })?;
+    db.update::<Task, _>("1", |task| task.done = true)?;
+
+    let first = db.get::<Task>("1")?.expect("task 1 was inserted");
+    assert!(first.done);
+    println!("Task 1: {:?}", first);
+
+    let open = db.query::<Task>().filter(|task| !task.done).execute()?;
+    assert_eq!(open.len(), 1);
+    println!("Still open: {:?}", open);
+
+    // Flushing compacts inline, since there's no runtime to run it on
+    db.flush()?;
+    assert_eq!(db.get_by_key::<Task, _>(2u64)?.map(|task| task.id), Some(2));
+
+    db.close()?;
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
@@ -0,0 +1,413 @@
+//! Derive macros for `rust_db`. Use them through the re-exports in `rust_db`.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::ext::IdentExt;
+use syn::punctuated::Punctuated;
+use syn::{
+    parse_macro_input, Attribute, Data, DeriveInput, Expr, ExprLit, Field, Fields, Ident, Lit, Meta, Pat, Token, Type,
+};
+
+/// Derive `Schema`, `CompileTimeSchema` and, when a field is marked
+/// `#[primary_key]`, `PrimaryKey` for an ordinary struct.
+///
+/// ```ignore
+/// #[derive(Schema, Serialize, Deserialize)]
+/// #[table_name = "User"]
+/// struct User {
+///     #[primary_key]
+///     id: u64,
+///     name: String,
+/// }
+/// ```
+///
+/// The table name goes through the same compile-time check as `schema!`.
+/// Fields marked `#[unique]` must not repeat across records of the table,
+/// and neither may the combined values of the fields a `#[unique(a, b)]`
+/// on the struct lists. A field marked `#[index]`, or `#[index(hash)]`
+/// for a hash index, is listed in `Schema::indexes`, and so is a composite
+/// index `#[index(a, b)]` on the struct. `Database::create_indexes` builds
+/// them, as does opening with `DatabaseBuilder::register`.
+///
+/// Each field also gets a `rust_db::Field` constant named after it in upper
+/// case, with the field's visibility, for `QueryBuilder::filter_field`:
+/// `User::AGE` for a field `age`.
+/// A `u64` field marked `#[auto_increment]` is assigned the table's next id
+/// by `Database::insert` whenever it's zero.
+/// Ids carry on from the last one across reopens. One whose record was
+/// never written, as in a rolled-back transaction, is skipped rather than
+/// reused.
+///
+/// Fields can carry `#[validate(...)]` constraints, which the generated
+/// `schema_validate` checks in field order, reporting the first failure:
+///
+/// - `non_empty`: `is_empty()` must be false (strings, collections)
+/// - `min = N` / `max = N`: numeric bounds, inclusive
+/// - `email`: a plausible `local@domain.tld` address
+/// - `required`: an `Option` field must be `Some`
+/// - `one_of(A, B, ..)`: the value must match one of the patterns, e.g.
+///   `one_of(Status::Open, Status::Held(_))` for an enum field
+///
+/// On an `Option` field the other checks apply to the value inside, and
+/// `None` passes them, so an absent value is distinct from an empty one.
+///
+/// `#[schema_version = N]` sets `CompileTimeSchema::SCHEMA_VERSION`, and
+/// `#[migrate = "path::to_fn"]` names the `fn(u32, &[u8]) -> Result<Self, DbError>`
+/// that upgrades records stored under older versions.
+#[proc_macro_derive(
+    Schema,
+    attributes(table_name, primary_key, unique, index, auto_increment, validate, schema_version, migrate)
+)]
+pub fn derive_schema(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_schema(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand_schema(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let table = table_name(input)?;
+    let schema_version = match struct_attr(input, "schema_version")? {
+        Some(Lit::Int(version)) => Some(quote! { const SCHEMA_VERSION: u32 = #version; }),
+        Some(other) => return Err(syn::Error::new_spanned(other, "expected #[schema_version = <integer>]")),
+        None => None,
+    };
+    let migrate = match struct_attr(input, "migrate")? {
+        Some(Lit::Str(path)) => {
+            let path: syn::Path = path.parse()?;
+            Some(quote! {
+                fn migrate(old_version: u32, bytes: &[u8]) -> Result<Self, ::rust_db::DbError> {
+                    #path(old_version, bytes)
+                }
+            })
+        }
+        Some(other) => return Err(syn::Error::new_spanned(other, "expected #[migrate = \"path::to_fn\"]")),
+        None => None,
+    };
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    name,
+                    "#[derive(Schema)] requires a struct with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                name,
+                "#[derive(Schema)] can only be used on structs",
+            ))
+        }
+    };
+    if fields.is_empty() {
+        return Err(syn::Error::new_spanned(name, "#[derive(Schema)] requires at least one field"));
+    }
+    let field_count = fields.len();
+
+    let mut primary_key = None;
+    let mut auto_increment = None;
+    let mut unique = Vec::new();
+    let mut indexes = Vec::new();
+    let mut checks = Vec::new();
+    for field in fields {
+        checks.extend(field_checks(field)?);
+
+        for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("index")) {
+            let name = field.ident.as_ref().expect("named field").unraw().to_string();
+            indexes.push((name, index_kind(attr)?));
+        }
+
+        if field.attrs.iter().any(|attr| attr.path().is_ident("unique")) {
+            unique.push(field.ident.clone().expect("named field"));
+        }
+
+        if field.attrs.iter().any(|attr| attr.path().is_ident("primary_key")) {
+            if primary_key.is_some() {
+                return Err(syn::Error::new_spanned(
+                    field,
+                    "Only one #[primary_key] field is allowed",
+                ));
+            }
+            primary_key = field.ident.clone();
+        }
+
+        if field.attrs.iter().any(|attr| attr.path().is_ident("auto_increment")) {
+            if auto_increment.is_some() {
+                return Err(syn::Error::new_spanned(
+                    field,
+                    "Only one #[auto_increment] field is allowed",
+                ));
+            }
+            auto_increment = field.ident.clone();
+        }
+    }
+
+    let mut unique_names: Vec<String> = unique.iter().map(ToString::to_string).collect();
+    let mut unique_values: Vec<TokenStream2> = unique.iter().map(|field| quote!(&self.#field)).collect();
+    for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("unique")) {
+        let group = field_list(attr, fields)?;
+        if !(2..=4).contains(&group.len()) {
+            return Err(syn::Error::new_spanned(
+                attr,
+                "#[unique(..)] takes 2 to 4 fields; mark a single field #[unique] instead",
+            ));
+        }
+        unique_names.push(group.iter().map(ToString::to_string).collect::<Vec<_>>().join(","));
+        unique_values.push(quote!(&(#(&self.#group,)*)));
+    }
+    for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("index")) {
+        let group = field_list(attr, fields)?;
+        let name = group.iter().map(ToString::to_string).collect::<Vec<_>>().join(",");
+        indexes.push((name, quote!(::rust_db::IndexKind::BTree)));
+    }
+    let (index_names, index_kinds): (Vec<_>, Vec<_>) = indexes.into_iter().unzip();
+
+    let field_consts = fields.iter().map(|field| {
+        let ident = field.ident.as_ref().expect("named field");
+        let field_name = ident.unraw().to_string();
+        let const_name = Ident::new(&field_name.to_uppercase(), ident.span());
+        let (vis, ty) = (&field.vis, &field.ty);
+        let doc = format!("The `{}` field, for `QueryBuilder::filter_field`.", field_name);
+        quote! {
+            #[doc = #doc]
+            #vis const #const_name: ::rust_db::Field<Self, #ty> =
+                ::rust_db::Field::new(#field_name, |record: &Self| &record.#ident);
+        }
+    });
+
+    let primary_key_impl = primary_key.map(|field| {
+        quote! {
+            impl #impl_generics ::rust_db::PrimaryKey for #name #ty_generics #where_clause {
+                fn primary_key(&self) -> Vec<u8> {
+                    ::rust_db::AsKeyBytes::as_key_bytes(&self.#field)
+                }
+
+                fn parse_key(key: &str) -> Option<Vec<u8>> {
+                    ::rust_db::schema::parse_key_field(key, |record: &Self| &record.#field)
+                }
+            }
+        }
+    });
+
+    let auto_increment_impl = auto_increment.map(|field| {
+        quote! {
+            fn auto_increment(&self) -> Option<u64> {
+                Some(self.#field)
+            }
+
+            fn set_auto_increment(&mut self, id: u64) {
+                self.#field = id;
+            }
+        }
+    });
+
+    Ok(quote! {
+        impl #impl_generics ::rust_db::Schema for #name #ty_generics #where_clause {
+            fn schema_validate(&self) -> Result<(), ::rust_db::SchemaError> {
+                #(#checks)*
+                Ok(())
+            }
+
+            fn table_name() -> &'static str {
+                #table
+            }
+
+            fn indexes() -> &'static [::rust_db::IndexDef] {
+                &[#(::rust_db::IndexDef { name: #index_names, kind: #index_kinds }),*]
+            }
+
+            #migrate
+        }
+
+        impl #impl_generics ::rust_db::CompileTimeSchema for #name #ty_generics #where_clause {
+            const TABLE_NAME: &'static str = #table;
+            const FIELD_COUNT: usize = #field_count;
+            #schema_version
+            const UNIQUE_FIELDS: &'static [&'static str] = &[#(#unique_names),*];
+            const INDEXES: &'static [&'static str] = &[#(#index_names),*];
+
+            fn unique_values(&self) -> Vec<(&'static str, Vec<u8>)> {
+                vec![#((#unique_names, ::rust_db::AsKeyBytes::as_key_bytes(#unique_values))),*]
+            }
+
+            #auto_increment_impl
+        }
+
+        #primary_key_impl
+
+        impl #impl_generics #name #ty_generics #where_clause {
+            #(#field_consts)*
+        }
+
+        // Compile-time validation
+        const _: () = ::rust_db::schema::validate_table_name(#table);
+    })
+}
+
+/// Turn a field's `#[validate(...)]` attributes into checks for `schema_validate`.
+fn field_checks(field: &Field) -> syn::Result<Vec<TokenStream2>> {
+    let ident = field.ident.as_ref().expect("named field");
+    let field_name = ident.to_string();
+    let optional = is_option(&field.ty);
+    // An `Option` field's checks run on the `value` it holds
+    let value = if optional { quote!((*value)) } else { quote!((self.#ident)) };
+    let mut required = None;
+    let mut checks = Vec::new();
+
+    for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("validate")) {
+        attr.parse_nested_meta(|meta| {
+            let check = if meta.path.is_ident("required") {
+                if !optional {
+                    return Err(meta.error("`required` only applies to `Option` fields"));
+                }
+                let message = format!("{} is required", field_name);
+                required = Some(quote! {
+                    if self.#ident.is_none() {
+                        return Err(::rust_db::SchemaError::ValidationError(#message.to_string()));
+                    }
+                });
+                return Ok(());
+            } else if meta.path.is_ident("non_empty") {
+                let message = format!("{} cannot be empty", field_name);
+                quote! {
+                    if #value.is_empty() {
+                        return Err(::rust_db::SchemaError::ValidationError(#message.to_string()));
+                    }
+                }
+            } else if meta.path.is_ident("email") {
+                let message = format!("{} is not a valid email address", field_name);
+                quote! {
+                    if !::rust_db::schema::is_valid_email(::core::convert::AsRef::<str>::as_ref(&#value)) {
+                        return Err(::rust_db::SchemaError::ValidationError(#message.to_string()));
+                    }
+                }
+            } else if meta.path.is_ident("one_of") {
+                let content;
+                syn::parenthesized!(content in meta.input);
+                let patterns = Punctuated::<Pat, Token![,]>::parse_terminated_with(&content, Pat::parse_single)?;
+                if patterns.is_empty() {
+                    return Err(meta.error("expected at least one pattern in `one_of(..)`"));
+                }
+                // `Status::Open` rather than the `Status :: Open` tokens print as
+                let listed: Vec<String> =
+                    patterns.iter().map(|pattern| quote!(#pattern).to_string().split_whitespace().collect()).collect();
+                let message = format!("{} must be one of {}", field_name, listed.join(", "));
+                let patterns = patterns.iter();
+                quote! {
+                    if !matches!(#value, #(#patterns)|*) {
+                        return Err(::rust_db::SchemaError::ValidationError(#message.to_string()));
+                    }
+                }
+            } else if meta.path.is_ident("min") || meta.path.is_ident("max") {
+                let bound: Lit = meta.value()?.parse()?;
+                let is_min = meta.path.is_ident("min");
+                let literal = quote!(#bound).to_string();
+                let (message, failed) = if is_min {
+                    (format!("{} must be at least {}", field_name, literal), quote!(#value < #bound))
+                } else {
+                    (format!("{} must be at most {}", field_name, literal), quote!(#value > #bound))
+                };
+                quote! {
+                    if #failed {
+                        return Err(::rust_db::SchemaError::ValidationError(#message.to_string()));
+                    }
+                }
+            } else {
+                return Err(meta.error(
+                    "unknown validation, expected `required`, `non_empty`, `email`, `one_of(..)`, `min = ..` or `max = ..`",
+                ));
+            };
+            checks.push(check);
+            Ok(())
+        })?;
+    }
+
+    if optional && !checks.is_empty() {
+        checks = vec![quote! {
+            if let Some(value) = &self.#ident {
+                #(#checks)*
+            }
+        }];
+    }
+    checks.splice(0..0, required);
+    Ok(checks)
+}
+
+/// Whether a field is declared as an `Option`, which is as far as a derive
+/// can tell without type information.
+fn is_option(ty: &Type) -> bool {
+    match ty {
+        Type::Path(path) if path.qself.is_none() => {
+            path.path.segments.last().is_some_and(|segment| segment.ident == "Option")
+        }
+        _ => false,
+    }
+}
+
+/// The kind a field's `#[index]` or `#[index(hash)]` asks for.
+fn index_kind(attr: &Attribute) -> syn::Result<TokenStream2> {
+    if let Meta::Path(_) = attr.meta {
+        return Ok(quote!(::rust_db::IndexKind::BTree));
+    }
+    let kind: Ident = attr.parse_args()?;
+    if kind == "hash" {
+        Ok(quote!(::rust_db::IndexKind::Hash))
+    } else if kind == "btree" {
+        Ok(quote!(::rust_db::IndexKind::BTree))
+    } else {
+        Err(syn::Error::new_spanned(kind, "expected #[index], #[index(hash)] or #[index(btree)]"))
+    }
+}
+
+/// The fields a struct-level `#[unique(a, b)]` or `#[index(a, b)]` lists,
+/// each checked to be one of the struct's.
+fn field_list(attr: &Attribute, fields: &Punctuated<Field, Token![,]>) -> syn::Result<Vec<Ident>> {
+    let listed = attr.parse_args_with(Punctuated::<Ident, Token![,]>::parse_terminated)?;
+    for ident in &listed {
+        if !fields.iter().any(|field| field.ident.as_ref() == Some(ident)) {
+            return Err(syn::Error::new_spanned(ident, format!("no field named `{}`", ident)));
+        }
+    }
+    if listed.is_empty() {
+        return Err(syn::Error::new_spanned(attr, "expected at least one field"));
+    }
+    Ok(listed.into_iter().collect())
+}
+
+/// Read `#[table_name = "..."]` off the struct.
+fn table_name(input: &DeriveInput) -> syn::Result<String> {
+    match struct_attr(input, "table_name")? {
+        Some(Lit::Str(table)) => Ok(table.value()),
+        Some(other) => Err(syn::Error::new_spanned(other, "expected #[table_name = \"...\"]")),
+        None => Err(syn::Error::new_spanned(
+            &input.ident,
+            "#[derive(Schema)] requires a #[table_name = \"...\"] attribute",
+        )),
+    }
+}
+
+/// Literal value of a `#[name = <literal>]` attribute on the struct, if present.
+fn struct_attr(input: &DeriveInput, name: &str) -> syn::Result<Option<Lit>> {
+    for attr in &input.attrs {
+        if !attr.path().is_ident(name) {
+            continue;
+        }
+        if let Meta::NameValue(meta) = &attr.meta {
+            if let Expr::Lit(ExprLit { lit, .. }) = &meta.value {
+                return Ok(Some(lit.clone()));
+            }
+        }
+        return Err(syn::Error::new_spanned(
+            attr,
+            format!("expected #[{} = ...]", name),
+        ));
+    }
+    Ok(None)
+}
@@ -0,0 +1,51 @@
+//! Copying a running database to another directory.
+
+use std::path::Path;
+
+use crate::error::DbError;
+use crate::Database;
+
+impl Database {
+    /// Copy the database as it is now into `dest`, so that
+    /// `Database::open(dest)` finds the same records, while reads and
+    /// writes carry on here. `dest` is created if it's missing, and has to
+    /// be empty otherwise.
+    ///
+    /// The memtable is flushed first, and the SSTables and blob files are
+    /// then hard-linked into `dest` (or copied, if it's on another
+    /// filesystem) along with a manifest. The database's metadata is in the
+    /// SSTables with the records. Writes wait out only the flush: once it's
+    /// done, they go ahead and don't show up in the backup. Indexes live in
+    /// memory and aren't part of it; open the backup with
+    /// [`DatabaseBuilder::register`](crate::DatabaseBuilder::register) or
+    /// call `create_index` to build them. A backup that fails part way
+    /// leaves what it had written in `dest`.
+    ///
+    /// A read-only handle can't flush, so backing one up fails with
+    /// [`DbError::ReadOnly`].
+    pub async fn backup(&self, dest: impl AsRef<Path>) -> Result<(), DbError> {
+        let dest = dest.as_ref();
+        prepare(dest)?;
+        let snapshot = {
+            let storage = self.write_storage().await?;
+            storage.flush()?;
+            storage.snapshot()?
+        };
+        // Holding the snapshot keeps compaction from deleting its files
+        // before they're linked
+        snapshot.link_sstables(dest)
+    }
+}
+
+// Create `dest`, or make sure an existing one is empty, so a backup can't
+// mix with another database's files
+fn prepare(dest: &Path) -> Result<(), DbError> {
+    std::fs::create_dir_all(dest)?;
+    if std::fs::read_dir(dest)?.next().is_some() {
+        return Err(DbError::StorageError(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("backup directory {} is not empty", dest.display()),
+        )));
+    }
+    Ok(())
+}
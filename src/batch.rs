@@ -0,0 +1,57 @@
+use crate::key::raw_key;
+use crate::storage::StorageOp;
+
+/// Raw writes applied together by
+/// [`Database::apply_batch`](crate::Database::apply_batch).
+///
+/// Keys are raw keys, as [`Database::put_raw`](crate::Database::put_raw)
+/// takes them, kept apart from every table's records: nothing is
+/// validated or encoded, and indexes, `#[unique]` claims and change
+/// subscribers don't see the writes. Later writes to a key in the same
+/// batch win over earlier ones.
+///
+/// ```no_run
+/// # async fn run(db: &rust_db::Database) -> Result<(), rust_db::DbError> {
+/// let batch = rust_db::WriteBatch::new()
+///     .put("theme", "dark")
+///     .delete("legacy_theme");
+/// db.apply_batch(batch).await?;
+/// assert_eq!(db.get_raw(b"theme").await?, Some(b"dark".to_vec()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct WriteBatch {
+    ops: Vec<StorageOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    /// Set `key` to `value`.
+    pub fn put(mut self, key: impl AsRef<[u8]>, value: impl Into<Vec<u8>>) -> Self {
+        self.ops.push(StorageOp::Insert(raw_key(key.as_ref()), value.into()));
+        self
+    }
+
+    /// Delete `key`, whether or not it's set.
+    pub fn delete(mut self, key: impl AsRef<[u8]>) -> Self {
+        self.ops.push(StorageOp::Delete(raw_key(key.as_ref())));
+        self
+    }
+
+    /// Number of puts and deletes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub(crate) fn into_ops(self) -> Vec<StorageOp> {
+        self.ops
+    }
+}
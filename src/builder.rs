@@ -0,0 +1,264 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU64;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::RwLock;
+
+use crate::changes::Changes;
+use crate::codec::Codec;
+use crate::error::DbError;
+use crate::index::Indexes;
+use crate::key_locks::KeyLocks;
+use crate::meta;
+use crate::schema::{CompileTimeSchema, Schema};
+use crate::storage::{new_table, CompactionPolicy, Compression, Durability, LsmStorage, MemTableImpl, MergeFn, StorageOptions};
+use crate::Database;
+
+/// `Database::create_indexes` for one registered type
+type BuildIndexes = for<'a> fn(&'a Database) -> BoxFuture<'a, Result<(), DbError>>;
+
+/// Options for opening a [`Database`], created by [`Database::builder`].
+///
+/// ```no_run
+/// # async fn run() -> Result<(), rust_db::DbError> {
+/// let db = rust_db::Database::builder("./data")
+///     .flush_threshold(64 * 1024)
+///     .create_if_missing(false)
+///     .open()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct DatabaseBuilder {
+    path: PathBuf,
+    options: StorageOptions,
+    codec: Option<Codec>,
+    registered: Vec<BuildIndexes>,
+    lock_timeout: Option<Duration>,
+}
+
+impl DatabaseBuilder {
+    pub(crate) fn new(path: &Path) -> Self {
+        DatabaseBuilder {
+            path: path.to_path_buf(),
+            options: StorageOptions::default(),
+            codec: None,
+            registered: Vec::new(),
+            lock_timeout: None,
+        }
+    }
+
+    /// Memtable size in bytes that triggers a flush to an SSTable (default 1MB).
+    pub fn flush_threshold(mut self, bytes: usize) -> Self {
+        self.options.flush_threshold = bytes;
+        self
+    }
+
+    /// Flush between a `low` and a `high` memtable size in bytes, rather
+    /// than at the one [`DatabaseBuilder::flush_threshold`], which is the
+    /// same as both at once.
+    ///
+    /// A memtable past `low` is flushed in the background if no flush is
+    /// running, so flushes start before writes reach `high`. One that gets
+    /// there while the last flush is still going is left to grow, and
+    /// flushed once that's done, so it's never flushed small. At `high` a
+    /// write flushes it regardless, waiting on the running flush first.
+    /// `low` above `high` counts as `high`.
+    pub fn flush_watermarks(mut self, low: usize, high: usize) -> Self {
+        self.options.flush_low_watermark = Some(low);
+        self.options.flush_threshold = high;
+        self
+    }
+
+    /// Create the database directory if it doesn't exist (default `true`).
+    pub fn create_if_missing(mut self, create: bool) -> Self {
+        self.options.create_if_missing = create;
+        self
+    }
+
+    /// Target false-positive rate of the per-SSTable bloom filters (default 1%).
+    pub fn bloom_false_positive_rate(mut self, rate: f64) -> Self {
+        self.options.bloom_false_positive_rate = rate;
+        self
+    }
+
+    /// Compress SSTable data blocks with `compression`. Defaults to
+    /// [`Compression::None`].
+    ///
+    /// Only newly written SSTables use it; existing ones keep the codec
+    /// they were written with, so this can change between opens.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.options.compression = compression;
+        self
+    }
+
+    /// Bytes of recently read SSTable blocks kept in memory, so repeated
+    /// lookups of the same keys skip the disk (default 8MB). `0` turns the
+    /// cache off.
+    pub fn block_cache_size(mut self, bytes: usize) -> Self {
+        self.options.block_cache_size = bytes;
+        self
+    }
+
+    /// When and how SSTables are merged in the background. Defaults to
+    /// [`CompactionPolicy::default`].
+    pub fn compaction_policy(mut self, policy: CompactionPolicy) -> Self {
+        self.options.compaction = policy;
+        self
+    }
+
+    /// How hard WAL writes are pushed to disk before they return. Defaults
+    /// to [`Durability::NoSync`].
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.options.durability = durability;
+        self
+    }
+
+    /// Bytes a WAL segment grows to before writes move on to the next one
+    /// (default 4MB). A segment also ends whenever the memtable is flushed,
+    /// and segments holding only flushed writes are obsolete.
+    pub fn wal_segment_size(mut self, bytes: u64) -> Self {
+        self.options.wal_segment_size = bytes;
+        self
+    }
+
+    /// How many obsolete WAL segments to keep, newest first, e.g. to archive
+    /// or to recover from by hand (default 2). Older ones are deleted.
+    /// Recovery never replays them.
+    pub fn wal_retained_segments(mut self, count: usize) -> Self {
+        self.options.wal_retained_segments = count;
+        self
+    }
+
+    /// The most bytes one record, key and value encoded together, may take
+    /// in the WAL (default 64MB, at most 4GB). A larger write fails with
+    /// [`DbError::ValueTooLarge`] and leaves nothing behind.
+    ///
+    /// Recovery holds the WAL to the same limit, so a damaged length can't
+    /// make it allocate more: a framed record claiming more fails the open
+    /// with `ValueTooLarge`, and ends the replay of a log from before
+    /// framing. Lowering the limit below records already logged makes the
+    /// database fail to open until it's raised again.
+    pub fn max_record_size(mut self, bytes: u64) -> Self {
+        self.options.max_record_size = bytes;
+        self
+    }
+
+    /// Keep values longer than `bytes` in blob files of their own rather
+    /// than in SSTable blocks, so the blocks stay small and compaction
+    /// doesn't rewrite the values each time it merges them. Off by default.
+    ///
+    /// Values move to a blob file when they're flushed, and stay in the
+    /// memtable and WAL as they are until then. Reading one costs a read of
+    /// the blob file on top of the block, which the block cache doesn't
+    /// save. A blob file is deleted once no SSTable refers to it; until
+    /// then, values in it that were overwritten or deleted still take up
+    /// space.
+    pub fn blob_threshold(mut self, bytes: usize) -> Self {
+        self.options.blob_threshold = Some(bytes);
+        self
+    }
+
+    /// Keep memtable entries in an `M`, starting each memtable from
+    /// `M::default()`. Defaults to [`BTreeMemTable`](crate::BTreeMemTable).
+    pub fn memtable<M: MemTableImpl + Default + 'static>(mut self) -> Self {
+        self.options.memtable = new_table::<M>;
+        self
+    }
+
+    /// Split the memtable into `count` shards by a hash of the key, each
+    /// locked on its own, so concurrent writes to keys in different shards
+    /// don't wait for each other (default 1, and 0 counts as 1). The WAL
+    /// still takes writes one at a time, and the shards are flushed
+    /// together, so reads, flushes and recovery work as with one.
+    ///
+    /// Raw writes and typed inserts, upserts and updates run side by side.
+    /// Deletes and transactions still go one at a time.
+    pub fn memtable_shards(mut self, count: usize) -> Self {
+        self.options.memtable_shards = count.max(1);
+        self
+    }
+
+    /// Combine the operands written by [`Database::merge`] with the values
+    /// under them using `merge`. None is set by default, and merging fails
+    /// with [`DbError::NoMergeOperator`].
+    ///
+    /// Operands already on disk are combined by whatever operator the
+    /// database is opened with next, so keep it the same from one open to
+    /// the next.
+    pub fn merge_operator(mut self, merge: MergeFn) -> Self {
+        self.options.merge = Some(merge);
+        self
+    }
+
+    /// Serialize records with `codec`. Defaults to [`Codec::Bincode`].
+    ///
+    /// The codec is recorded when the database is created and can't change
+    /// after that: opening an existing database with a different one fails.
+    /// Without this setting, an existing database opens with its own.
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = Some(codec);
+        self
+    }
+
+    /// Fail an operation with [`DbError::Timeout`] if it can't take the
+    /// storage lock within `timeout`, e.g. behind a long flush or
+    /// `delete_where`, instead of waiting as long as it takes (the
+    /// default). A write that times out has written nothing.
+    pub fn lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = Some(timeout);
+        self
+    }
+
+    /// Open without write access (default `false`), as
+    /// [`Database::open_read_only`] does.
+    ///
+    /// Nothing in the directory is created, repaired or changed: the WAL is
+    /// replayed into memory but not opened for appending, and no flush or
+    /// compaction runs. Every write, flush and compaction fails with
+    /// [`DbError::ReadOnly`]. The directory has to exist already, whatever
+    /// [`DatabaseBuilder::create_if_missing`] says.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.options.read_only = read_only;
+        self
+    }
+
+    /// Build the indexes [`Schema::indexes`] declares for `T` as the database
+    /// opens, so they're in place before the first query, every time.
+    pub fn register<T>(mut self) -> Self
+    where
+        T: Schema + CompileTimeSchema + Serialize + DeserializeOwned + Send + 'static,
+    {
+        self.registered.push(|db| Box::pin(db.create_indexes::<T>()));
+        self
+    }
+
+    /// Open the database, holding its `LOCK` file until it's dropped.
+    ///
+    /// Only one writable handle can have a directory open at a time, across
+    /// processes too; opening another fails with [`DbError::AlreadyOpen`].
+    /// Read-only handles don't take the lock.
+    pub async fn open(self) -> Result<Database, DbError> {
+        let storage = LsmStorage::new(&self.path, self.options)?;
+        let (codec, meta) = meta::open(&storage, self.codec)?;
+        let db = Database {
+            storage: RwLock::new(storage),
+            indexes: RwLock::new(Indexes::default()),
+            record_locks: KeyLocks::new(),
+            unique_locks: KeyLocks::new(),
+            codec,
+            meta: Mutex::new(meta),
+            table_scans: AtomicU64::new(0),
+            changes: Changes::new(),
+            lock_timeout: self.lock_timeout,
+        };
+        for build_indexes in &self.registered {
+            build_indexes(&db).await?;
+        }
+        Ok(db)
+    }
+}
@@ -0,0 +1,45 @@
+//! Loading many records at once with write settings suited to it.
+
+use crate::error::DbError;
+use crate::Database;
+
+impl Database {
+    /// Run `load` with the database set up for a large load: the memtable
+    /// may grow to 16 times the flush threshold before it's flushed, and
+    /// the WAL buffers up to 1MB of each write instead of 8KB, so big
+    /// [`Database::insert_many`] batches and imports flush and reallocate
+    /// less. Writes made from elsewhere while `load` runs get the same
+    /// settings.
+    ///
+    /// The settings go back when `load` finishes, fails or is dropped, and
+    /// a memtable grown past the normal threshold is flushed then in the
+    /// background. Loads may overlap; the settings go back once the last
+    /// one ends. Read-only handles fail with [`DbError::ReadOnly`].
+    ///
+    /// ```no_run
+    /// # #[derive(rust_db::Schema, serde::Serialize, serde::Deserialize)]
+    /// # #[table_name = "User"]
+    /// # struct User { #[primary_key] id: u64, name: String }
+    /// # async fn run(db: &rust_db::Database, users: Vec<User>) -> Result<(), rust_db::DbError> {
+    /// db.bulk_load(async |db| {
+    ///     for chunk in users.chunks(10_000) {
+    ///         db.insert_many(chunk).await?;
+    ///     }
+    ///     Ok(())
+    /// })
+    /// .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn bulk_load<F, R>(&self, load: F) -> Result<R, DbError>
+    where
+        F: AsyncFnOnce(&Database) -> Result<R, DbError>,
+    {
+        let bulk = self.read_storage().await?.begin_bulk()?;
+        let loaded = load(self).await;
+        let ended = self.read_storage().await.and_then(|storage| storage.end_bulk(bulk));
+        let value = loaded?;
+        ended?;
+        Ok(value)
+    }
+}
@@ -0,0 +1,51 @@
+//! The names of the tables in a database, kept in its metadata so they can
+//! be listed without knowing their types.
+
+use std::sync::PoisonError;
+
+use crate::error::DbError;
+use crate::Database;
+
+impl Database {
+    /// The name of every table a record has been written to, sorted, even
+    /// if its records have all been deleted since. Writes through
+    /// [`Database::apply_batch`] and the raw methods don't add tables.
+    ///
+    /// ```no_run
+    /// # async fn run(db: &rust_db::Database) -> Result<(), rust_db::DbError> {
+    /// for table in db.table_names().await {
+    ///     println!("{}", table);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn table_names(&self) -> Vec<String> {
+        let meta = self.meta.lock().unwrap_or_else(PoisonError::into_inner);
+        meta.get().tables.iter().cloned().collect()
+    }
+
+    /// The newest [`CompileTimeSchema::SCHEMA_VERSION`](crate::CompileTimeSchema::SCHEMA_VERSION)
+    /// a record of `table` was written with, or `None` if none was since
+    /// the database kept track. Records written with older versions may
+    /// still be stored, for [`Schema::migrate`](crate::Schema::migrate) to
+    /// read.
+    pub async fn schema_version(&self, table: &str) -> Option<u32> {
+        let meta = self.meta.lock().unwrap_or_else(PoisonError::into_inner);
+        meta.get().schema_versions.get(table).copied()
+    }
+
+    // Add `table` to the catalog, if it's new, and raise its schema version
+    // to `version`, before a record is written to it
+    pub(crate) fn note_table(&self, table: &str, version: u32) -> Result<(), DbError> {
+        let mut meta = self.meta.lock().map_err(|_| DbError::LockPoisoned("metadata".to_string()))?;
+        let current = meta.get().schema_versions.get(table).is_some_and(|&newest| newest >= version);
+        if meta.get().tables.contains(table) && current {
+            return Ok(());
+        }
+        meta.update(|meta| {
+            meta.tables.insert(table.to_string());
+            let newest = meta.schema_versions.entry(table.to_string()).or_insert(version);
+            *newest = (*newest).max(version);
+        })
+    }
+}
@@ -0,0 +1,54 @@
+//! Change events for [`Database::subscribe`](crate::Database::subscribe).
+//!
+//! Every write is published once it's in the WAL, while the record is
+//! still locked, so subscribers see a record's writes in the order they
+//! were applied and never one that failed. Events go out over a Tokio broadcast
+//! channel holding the last [`CAPACITY`] of them; a subscriber that falls
+//! further behind gets `RecvError::Lagged` instead of holding writers up.
+
+use tokio::sync::broadcast;
+
+/// Events a subscriber can fall behind by before it starts missing them
+pub(crate) const CAPACITY: usize = 1024;
+
+/// A record written or deleted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent {
+    pub table: String,
+    /// The record's primary key, encoded as
+    /// [`AsKeyBytes`](crate::AsKeyBytes) encodes it.
+    pub key: Vec<u8>,
+    pub kind: ChangeKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// The record was inserted, or replaced by an insert, upsert or update.
+    Insert,
+    /// The record was deleted.
+    Delete,
+}
+
+#[derive(Debug)]
+pub(crate) struct Changes {
+    sender: broadcast::Sender<ChangeEvent>,
+}
+
+impl Changes {
+    pub(crate) fn new() -> Self {
+        Changes { sender: broadcast::channel(CAPACITY).0 }
+    }
+
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<ChangeEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Publish a change to `table`'s record under `primary_key`. Call it
+    /// with the record locked, after the write.
+    pub(crate) fn publish(&self, table: &str, primary_key: &[u8], kind: ChangeKind) {
+        // Without subscribers there's no one to build the event for
+        if self.sender.receiver_count() > 0 {
+            let _ = self.sender.send(ChangeEvent { table: table.to_string(), key: primary_key.to_vec(), kind });
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! Serialization formats for record payloads.
+//!
+//! A database's codec is fixed when it's created and recorded in its
+//! metadata file, since every stored record depends on it. The WAL and
+//! SSTable framing around the records don't change with it.
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::error::DbError;
+
+/// How records are serialized, set through `DatabaseBuilder::codec`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    /// Compact binary, with no field names. The fastest and smallest.
+    #[default]
+    Bincode,
+    /// JSON text, readable with any tool and tolerant of added fields
+    /// marked `#[serde(default)]`.
+    Json,
+    /// MessagePack, with field names. Requires the `msgpack` feature.
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+}
+
+const ID_BINCODE: u32 = 0;
+const ID_JSON: u32 = 1;
+const ID_MSGPACK: u32 = 2;
+
+impl Codec {
+    pub(crate) fn id(self) -> u32 {
+        match self {
+            Codec::Bincode => ID_BINCODE,
+            Codec::Json => ID_JSON,
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => ID_MSGPACK,
+        }
+    }
+
+    /// The codec for a metadata id, or why this build can't use it.
+    pub(crate) fn from_id(id: u32) -> Result<Self, &'static str> {
+        match id {
+            ID_BINCODE => Ok(Codec::Bincode),
+            ID_JSON => Ok(Codec::Json),
+            #[cfg(feature = "msgpack")]
+            ID_MSGPACK => Ok(Codec::MessagePack),
+            #[cfg(not(feature = "msgpack"))]
+            ID_MSGPACK => Err("records are MessagePack-encoded, but the `msgpack` feature is disabled"),
+            _ => Err("unknown record codec"),
+        }
+    }
+
+    pub fn serialize<T: Serialize>(self, value: &T) -> Result<Vec<u8>, DbError> {
+        let result = match self {
+            Codec::Bincode => bincode::serialize(value).map_err(|e| e.to_string()),
+            Codec::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => rmp_serde::to_vec_named(value).map_err(|e| e.to_string()),
+        };
+        result.map_err(DbError::SerializationError)
+    }
+
+    /// Decode bytes from [`Codec::serialize`]. A [`Schema::migrate`](crate::Schema::migrate)
+    /// hook can use this on the payload of an old record.
+    pub fn deserialize<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, DbError> {
+        let result = match self {
+            Codec::Bincode => bincode::deserialize(bytes).map_err(|e| e.to_string()),
+            Codec::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+        };
+        result.map_err(DbError::SerializationError)
+    }
+}
+
+impl std::fmt::Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Codec::Bincode => "bincode",
+            Codec::Json => "JSON",
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => "MessagePack",
+        })
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::VecDeque;
+use std::ops::Bound;
+
+use crate::error::DbError;
+use crate::storage::{KeyValue, StorageSnapshot};
+
+/// Entries a cursor keeps on each side of its position once it has stepped
+/// past them, so going back and forth doesn't reread them
+const KEPT: usize = 256;
+
+/// Steps over the raw entries of a database, in key order, from
+/// [`Database::cursor`](crate::Database::cursor).
+///
+/// The cursor sees the merged keyspace as of when it was created: the
+/// newest value of each key across the memtables and SSTables, with
+/// deleted and expired keys left out. That includes every table's records,
+/// `<table>:<primary key>` mapped to the stored record, the entries behind
+/// `#[unique]` fields and the database's own metadata, under `~`. Like a
+/// [`Snapshot`](crate::Snapshot), it holds on to the SSTables it reads, so
+/// compaction can't pull them out from under it.
+///
+/// The cursor sits between two entries. [`Iterator::next`] returns the one
+/// after it and moves past it; [`Cursor::prev`] returns the one before it
+/// and moves back, so a `prev` straight after a `next` returns the same
+/// entry again. Entries are read a block or so at a time as the cursor
+/// moves.
+#[derive(Debug)]
+pub struct Cursor {
+    storage: StorageSnapshot,
+    // Entries just after the position, and where reading on past them starts
+    ahead: VecDeque<KeyValue>,
+    ahead_from: Option<Bound<Vec<u8>>>,
+    // Entries just before the position, and where reading back past them ends
+    behind: VecDeque<KeyValue>,
+    behind_to: Option<Bound<Vec<u8>>>,
+}
+
+impl Cursor {
+    pub(crate) fn new(storage: StorageSnapshot) -> Self {
+        let mut cursor = Cursor {
+            storage,
+            ahead: VecDeque::new(),
+            ahead_from: None,
+            behind: VecDeque::new(),
+            behind_to: None,
+        };
+        cursor.seek(&[]);
+        cursor
+    }
+
+    /// Move to just before `key`, or the first key after it if it has no
+    /// entry: `next` then returns the first entry `>= key`, and `prev` the
+    /// last one `< key`.
+    pub fn seek(&mut self, key: &[u8]) {
+        self.ahead.clear();
+        self.behind.clear();
+        self.ahead_from = Some(Bound::Included(key.to_vec()));
+        self.behind_to = Some(Bound::Excluded(key.to_vec()));
+    }
+
+    /// Move past the last entry, so `prev` steps backward from the end.
+    pub fn seek_to_end(&mut self) {
+        self.ahead.clear();
+        self.behind.clear();
+        self.ahead_from = None;
+        self.behind_to = Some(Bound::Unbounded);
+    }
+
+    /// The entry before the cursor, moving the cursor back over it, or
+    /// `None` at the start.
+    pub fn prev(&mut self) -> Option<<Self as Iterator>::Item> {
+        while self.behind.is_empty() {
+            let end = self.behind_to.take()?;
+            let (entries, limit) = match self.storage.window_before(bound_ref(&end)) {
+                Ok(window) => window,
+                Err(e) => {
+                    self.behind_to = Some(end);
+                    return Some(Err(e));
+                }
+            };
+            self.behind.extend(entries);
+            self.behind_to = limit.map(Bound::Excluded);
+        }
+
+        let entry = self.behind.pop_back()?;
+        self.ahead.push_front(entry.clone());
+        if self.ahead.len() > KEPT {
+            if let Some((key, _)) = self.ahead.pop_back() {
+                self.ahead_from = Some(Bound::Included(key));
+            }
+        }
+        Some(Ok(entry))
+    }
+}
+
+impl Iterator for Cursor {
+    type Item = Result<(Vec<u8>, Vec<u8>), DbError>;
+
+    /// The entry after the cursor, moving the cursor past it, or `None` at
+    /// the end.
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.ahead.is_empty() {
+            let start = self.ahead_from.take()?;
+            let (entries, limit) = match self.storage.window_after(bound_ref(&start)) {
+                Ok(window) => window,
+                Err(e) => {
+                    self.ahead_from = Some(start);
+                    return Some(Err(e));
+                }
+            };
+            self.ahead.extend(entries);
+            self.ahead_from = limit.map(Bound::Excluded);
+        }
+
+        let entry = self.ahead.pop_front()?;
+        self.behind.push_back(entry.clone());
+        if self.behind.len() > KEPT {
+            if let Some((key, _)) = self.behind.pop_front() {
+                self.behind_to = Some(Bound::Included(key));
+            }
+        }
+        Some(Ok(entry))
+    }
+}
+
+fn bound_ref(bound: &Bound<Vec<u8>>) -> Bound<&[u8]> {
+    bound.as_ref().map(Vec::as_slice)
+}
@@ -0,0 +1,29 @@
+//! A printout of the storage for troubleshooting, behind the `debug-dump`
+//! feature.
+
+use std::io::Write;
+
+use crate::error::DbError;
+use crate::Database;
+
+impl Database {
+    /// Write what the storage holds to `writer` as plain text: each
+    /// memtable entry with its value's length, each SSTable by level with
+    /// its key range and entry count, and the WAL size. Keys are shown as
+    /// text, with any byte that isn't printable ASCII in hex, so no record
+    /// types are needed. Needs the `debug-dump` feature.
+    ///
+    /// This is for looking into a database by hand, not for regular use:
+    /// it reads every SSTable through, and writes wait until it's done.
+    /// The format may change from one release to the next.
+    ///
+    /// ```no_run
+    /// # async fn run(db: &rust_db::Database) -> Result<(), rust_db::DbError> {
+    /// db.debug_dump(std::io::stderr()).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn debug_dump(&self, mut writer: impl Write) -> Result<(), DbError> {
+        self.read_storage().await?.debug_dump(&mut writer)
+    }
+}
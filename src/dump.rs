@@ -0,0 +1,74 @@
+//! Moving whole tables in and out of the database as text.
+
+use std::io::{Read, Write};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::error::DbError;
+use crate::schema::{CompileTimeSchema, PrimaryKey, Schema};
+use crate::Database;
+
+impl Database {
+    /// Write every record of `T` to `writer` as a JSON array, in primary
+    /// key order, and return how many there were.
+    pub async fn export_json<T>(&self, writer: impl Write) -> Result<usize, DbError>
+    where
+        T: Schema + CompileTimeSchema + Serialize + DeserializeOwned,
+    {
+        let records = self.all::<T>().await?;
+        serde_json::to_writer(writer, &records).map_err(|e| DbError::SerializationError(e.to_string()))?;
+        Ok(records.len())
+    }
+
+    /// Insert the records of a JSON array, as written by
+    /// [`Database::export_json`], and return how many there were.
+    ///
+    /// They go in as one [`Database::insert_many`] batch, so one invalid
+    /// record means none of them are inserted.
+    pub async fn import_json<T>(&self, reader: impl Read) -> Result<usize, DbError>
+    where
+        T: Schema + CompileTimeSchema + PrimaryKey + Serialize + DeserializeOwned,
+    {
+        let records: Vec<T> =
+            serde_json::from_reader(reader).map_err(|e| DbError::SerializationError(e.to_string()))?;
+        self.insert_many(&records).await?;
+        Ok(records.len())
+    }
+
+    /// Insert one record of `T` per row of a CSV file, and return how many
+    /// there were.
+    ///
+    /// The first row is the header; columns map to fields by name, so
+    /// `#[serde(rename)]` applies, and their order doesn't matter. Every row
+    /// is parsed and validated before anything is written, and the first
+    /// bad one fails the import with an error naming its line.
+    pub async fn import_csv<T>(&self, reader: impl Read) -> Result<usize, DbError>
+    where
+        T: Schema + CompileTimeSchema + PrimaryKey + Serialize + DeserializeOwned,
+    {
+        let mut csv = csv::Reader::from_reader(reader);
+        let headers = csv
+            .headers()
+            .map_err(|e| DbError::SerializationError(format!("CSV header: {}", e)))?
+            .clone();
+
+        let mut records = Vec::new();
+        for row in csv.records() {
+            let row = row.map_err(|e| {
+                let line = e.position().map_or(0, |position| position.line());
+                DbError::SerializationError(format!("CSV line {}: {}", line, e))
+            })?;
+            let line = row.position().map_or(0, |position| position.line());
+            let record: T = row
+                .deserialize(Some(&headers))
+                .map_err(|e| DbError::SerializationError(format!("CSV line {}: {}", line, e)))?;
+            record
+                .schema_validate()
+                .map_err(|e| DbError::SchemaError(format!("CSV line {}: {}", line, e)))?;
+            records.push(record);
+        }
+
+        self.insert_many(&records).await?;
+        Ok(records.len())
+    }
+}
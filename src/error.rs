@@ -10,6 +10,61 @@ pub enum DbError{
 
     #[error("Serialization error :{0}")]
     SerializationError(String),
+
+    #[error("Lock poisoned: {0}")]
+    LockPoisoned(String),
+
+    #[error("Unique constraint violated: {0} = {1}")]
+    UniqueViolation(String, String),
+
+    #[error("Corrupt data in {} at offset {offset}", file.display())]
+    Corruption { file: std::path::PathBuf, offset: u64 },
+
+    /// `key` is empty when it's a query that matched nothing
+    #[error("No {table} record{}", key_clause(.key))]
+    NotFound { table: String, key: String },
+
+    #[error("More than one {table} record matches")]
+    MultipleResults { table: String },
+
+    /// The memtable stays readable and in the WAL, and the flush is retried
+    /// on the next one
+    #[error("Memtable flush failed: {source}")]
+    FlushFailed { source: Box<DbError> },
+
+    #[error("Record belongs to table {found}, but was read as {expected}")]
+    SchemaMismatch { expected: String, found: String },
+
+    /// Another writable handle, in this process or another, holds the
+    /// directory's `LOCK` file
+    #[error("Database {} is already open", path.display())]
+    AlreadyOpen { path: std::path::PathBuf },
+
+    /// Returned by every write to a database opened read-only
+    #[error("Database is open read-only")]
+    ReadOnly,
+
+    /// The storage lock wasn't free within `DatabaseBuilder::lock_timeout`
+    #[error("Timed out after {0:?} waiting for the database lock")]
+    Timeout(std::time::Duration),
+
+    /// A merge was written, or merge operands were found, without a
+    /// `DatabaseBuilder::merge_operator` to combine them
+    #[error("No merge operator is set")]
+    NoMergeOperator,
+
+    /// A write, or a WAL record read back, encodes to more than
+    /// `DatabaseBuilder::max_record_size`
+    #[error("Record of {size} bytes is over the {limit}-byte limit")]
+    ValueTooLarge { size: u64, limit: u64 },
+}
+
+fn key_clause(key: &str) -> String {
+    if key.is_empty() {
+        " matches".to_string()
+    } else {
+        format!(" with key {}", key)
+    }
 }
 
 #[derive(Error,Debug)]
@@ -0,0 +1,366 @@
+//! In-memory secondary indexes over named record fields.
+//!
+//! Field values are read through `serde_json`, so any `Serialize` record can
+//! be indexed by field name without generated code. Only scalar values
+//! (null, bools, numbers, strings) are indexed; records whose field holds
+//! anything else are left out and never match an indexed filter.
+//!
+//! A composite index is named by its fields joined with commas, e.g.
+//! `"tenant_id,email"`, and keys each record by the tuple of their values.
+//! Tuples order by their first value, then their second and so on, and a
+//! shorter tuple orders before the longer ones it begins, so `(5,)` with
+//! `Ge` and `(6,)` with `Lt` pick out the records whose first field is 5.
+//!
+//! An index is a [`IndexKind::BTree`], which answers every [`IndexOp`], or
+//! a [`IndexKind::Hash`], which only answers `Eq` but does so without
+//! walking a tree, for fields like emails that are looked up by exact
+//! value. A field can have one of each; `Eq` goes to the hash index.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+use std::ops::Bound;
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::DbError;
+
+/// Comparison used by `QueryBuilder::filter_indexed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexOp {
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl IndexOp {
+    fn matches(self, ordering: Ordering) -> bool {
+        match self {
+            IndexOp::Eq => ordering == Ordering::Equal,
+            IndexOp::Lt => ordering == Ordering::Less,
+            IndexOp::Le => ordering != Ordering::Greater,
+            IndexOp::Gt => ordering == Ordering::Greater,
+            IndexOp::Ge => ordering != Ordering::Less,
+        }
+    }
+}
+
+/// How `Database::create_index_with` stores an index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum IndexKind {
+    /// Ordered by value, for ranges as well as equality.
+    #[default]
+    BTree,
+    /// Hashed by value, for equality lookups only.
+    Hash,
+}
+
+/// An index a type declares through [`Schema::indexes`](crate::Schema::indexes).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexDef {
+    /// The field, or fields joined with commas, as `Database::create_index`
+    /// takes them.
+    pub name: &'static str,
+    pub kind: IndexKind,
+}
+
+/// Ordered form of a scalar field value, or of a tuple of them for a
+/// composite index. Values of different kinds order
+/// null < bool < number < string < tuple; integers and floats compare
+/// numerically.
+#[derive(Debug, Clone)]
+pub(crate) enum IndexKey {
+    Null,
+    Bool(bool),
+    Int(i128),
+    Float(f64),
+    Str(String),
+    Tuple(Vec<IndexKey>),
+}
+
+impl IndexKey {
+    /// A value to compare against, where a tuple or array of scalars is
+    /// one for a composite index.
+    pub(crate) fn from_value<V: Serialize + ?Sized>(value: &V) -> Option<Self> {
+        match serde_json::to_value(value).ok()? {
+            Value::Array(values) => values.iter().map(Self::from_json).collect::<Option<_>>().map(IndexKey::Tuple),
+            value => Self::from_json(&value),
+        }
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        match value {
+            Value::Null => Some(IndexKey::Null),
+            Value::Bool(b) => Some(IndexKey::Bool(*b)),
+            Value::Number(n) => n
+                .as_i64()
+                .map(|i| IndexKey::Int(i as i128))
+                .or_else(|| n.as_u64().map(|u| IndexKey::Int(u as i128)))
+                .or_else(|| n.as_f64().map(IndexKey::Float)),
+            Value::String(s) => Some(IndexKey::Str(s.clone())),
+            Value::Array(_) | Value::Object(_) => None,
+        }
+    }
+
+    /// Read `field` off a record, or `None` if it isn't an indexable scalar.
+    /// A comma-separated list of fields reads as the tuple of them, if
+    /// every one is.
+    pub(crate) fn from_field<T: Serialize>(record: &T, field: &str) -> Option<Self> {
+        match serde_json::to_value(record).ok()? {
+            Value::Object(values) => Self::from_fields(&values, field),
+            _ => None,
+        }
+    }
+
+    fn from_fields(values: &Map<String, Value>, field: &str) -> Option<Self> {
+        if field.contains(',') {
+            let parts = field.split(',').map(|part| values.get(part.trim()).and_then(Self::from_json));
+            parts.collect::<Option<_>>().map(IndexKey::Tuple)
+        } else {
+            Self::from_json(values.get(field)?)
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            IndexKey::Null => 0,
+            IndexKey::Bool(_) => 1,
+            IndexKey::Int(_) | IndexKey::Float(_) => 2,
+            IndexKey::Str(_) => 3,
+            IndexKey::Tuple(_) => 4,
+        }
+    }
+
+    /// Whether `self <op> target` holds.
+    pub(crate) fn satisfies(&self, op: IndexOp, target: &IndexKey) -> bool {
+        // Mixed kinds never match, even though they have a total order
+        self.same_kind(target) && op.matches(self.cmp(target))
+    }
+
+    // Tuples are the same kind if the values they both have are
+    fn same_kind(&self, other: &IndexKey) -> bool {
+        match (self, other) {
+            (IndexKey::Tuple(a), IndexKey::Tuple(b)) => a.iter().zip(b).all(|(a, b)| a.same_kind(b)),
+            _ => self.rank() == other.rank(),
+        }
+    }
+}
+
+impl Ord for IndexKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (IndexKey::Bool(a), IndexKey::Bool(b)) => a.cmp(b),
+            (IndexKey::Int(a), IndexKey::Int(b)) => a.cmp(b),
+            (IndexKey::Int(a), IndexKey::Float(b)) => (*a as f64).total_cmp(b),
+            (IndexKey::Float(a), IndexKey::Int(b)) => a.total_cmp(&(*b as f64)),
+            (IndexKey::Float(a), IndexKey::Float(b)) => a.total_cmp(b),
+            (IndexKey::Str(a), IndexKey::Str(b)) => a.cmp(b),
+            (IndexKey::Tuple(a), IndexKey::Tuple(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+impl PartialOrd for IndexKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for IndexKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for IndexKey {}
+
+impl Hash for IndexKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.rank().hash(state);
+        match self {
+            IndexKey::Null => {}
+            IndexKey::Bool(b) => b.hash(state),
+            // Integers equal floats they convert to, so both hash as one
+            IndexKey::Int(i) => (*i as f64).to_bits().hash(state),
+            IndexKey::Float(f) => f.to_bits().hash(state),
+            IndexKey::Str(s) => s.hash(state),
+            IndexKey::Tuple(values) => values.hash(state),
+        }
+    }
+}
+
+/// Value -> primary keys, in the structure an [`IndexKind`] calls for
+#[derive(Debug)]
+enum ByValue {
+    BTree(BTreeMap<IndexKey, BTreeSet<Vec<u8>>>),
+    Hash(HashMap<IndexKey, BTreeSet<Vec<u8>>>),
+}
+
+impl ByValue {
+    fn add(&mut self, value: IndexKey, primary_key: &[u8]) {
+        let keys = match self {
+            ByValue::BTree(values) => values.entry(value).or_default(),
+            ByValue::Hash(values) => values.entry(value).or_default(),
+        };
+        keys.insert(primary_key.to_vec());
+    }
+
+    fn discard(&mut self, value: &IndexKey, primary_key: &[u8]) {
+        let keys = match self {
+            ByValue::BTree(values) => values.get_mut(value),
+            ByValue::Hash(values) => values.get_mut(value),
+        };
+        let Some(keys) = keys else { return };
+        keys.remove(primary_key);
+        if keys.is_empty() {
+            match self {
+                ByValue::BTree(values) => values.remove(value),
+                ByValue::Hash(values) => values.remove(value),
+            };
+        }
+    }
+}
+
+/// One field's index: value -> primary keys, plus the reverse mapping so a
+/// record can be unindexed without reading it back.
+#[derive(Debug)]
+struct FieldIndex {
+    by_value: ByValue,
+    by_key: HashMap<Vec<u8>, IndexKey>,
+}
+
+impl FieldIndex {
+    fn new(kind: IndexKind) -> Self {
+        let by_value = match kind {
+            IndexKind::BTree => ByValue::BTree(BTreeMap::new()),
+            IndexKind::Hash => ByValue::Hash(HashMap::new()),
+        };
+        FieldIndex { by_value, by_key: HashMap::new() }
+    }
+
+    fn kind(&self) -> IndexKind {
+        match self.by_value {
+            ByValue::BTree(_) => IndexKind::BTree,
+            ByValue::Hash(_) => IndexKind::Hash,
+        }
+    }
+
+    fn insert(&mut self, primary_key: &[u8], value: Option<IndexKey>) {
+        self.remove(primary_key);
+        if let Some(value) = value {
+            self.by_value.add(value.clone(), primary_key);
+            self.by_key.insert(primary_key.to_vec(), value);
+        }
+    }
+
+    fn remove(&mut self, primary_key: &[u8]) {
+        if let Some(old) = self.by_key.remove(primary_key) {
+            self.by_value.discard(&old, primary_key);
+        }
+    }
+
+    /// Matching primary keys, or `None` for a range on a hash index.
+    fn lookup(&self, op: IndexOp, target: &IndexKey) -> Option<BTreeSet<Vec<u8>>> {
+        let values = match &self.by_value {
+            ByValue::BTree(values) => values,
+            // Only values of the target's kind are equal to it, as `satisfies` wants
+            ByValue::Hash(values) if op == IndexOp::Eq => return Some(values.get(target).cloned().unwrap_or_default()),
+            ByValue::Hash(_) => return None,
+        };
+        let bounds = match op {
+            IndexOp::Eq => (Bound::Included(target), Bound::Included(target)),
+            IndexOp::Lt => (Bound::Unbounded, Bound::Excluded(target)),
+            IndexOp::Le => (Bound::Unbounded, Bound::Included(target)),
+            IndexOp::Gt => (Bound::Excluded(target), Bound::Unbounded),
+            IndexOp::Ge => (Bound::Included(target), Bound::Unbounded),
+        };
+        let keys = values
+            .range::<IndexKey, _>(bounds)
+            .filter(|(value, _)| value.satisfies(op, target))
+            .flat_map(|(_, keys)| keys.iter().cloned())
+            .collect();
+        Some(keys)
+    }
+}
+
+/// Every secondary index, by table then field name and kind.
+#[derive(Debug, Default)]
+pub(crate) struct Indexes {
+    tables: HashMap<String, HashMap<(String, IndexKind), FieldIndex>>,
+}
+
+impl Indexes {
+    /// Replace `table.field`'s index of `kind` with one built from
+    /// `records`.
+    pub(crate) fn build<'r, T: Serialize + 'r>(
+        &mut self,
+        table: &str,
+        field: &str,
+        kind: IndexKind,
+        records: impl IntoIterator<Item = (Vec<u8>, &'r T)>,
+    ) {
+        let mut index = FieldIndex::new(kind);
+        for (primary_key, record) in records {
+            index.insert(&primary_key, IndexKey::from_field(record, field));
+        }
+        self.tables
+            .entry(table.to_string())
+            .or_default()
+            .insert((field.to_string(), kind), index);
+    }
+
+    pub(crate) fn is_indexed(&self, table: &str) -> bool {
+        self.tables.get(table).is_some_and(|fields| !fields.is_empty())
+    }
+
+    /// Index a newly written record in each of its table's indexes.
+    pub(crate) fn insert<T: Serialize>(&mut self, table: &str, primary_key: &[u8], record: &T) -> Result<(), DbError> {
+        if !self.is_indexed(table) {
+            return Ok(());
+        }
+        let record = serde_json::to_value(record).map_err(|e| DbError::SerializationError(e.to_string()))?;
+        self.insert_json(table, primary_key, &record);
+        Ok(())
+    }
+
+    /// [`Indexes::insert`] for a record already converted to JSON.
+    pub(crate) fn insert_json(&mut self, table: &str, primary_key: &[u8], record: &Value) {
+        let (Some(fields), Value::Object(values)) = (self.tables.get_mut(table), record) else {
+            return;
+        };
+        for ((field, _), index) in fields.iter_mut() {
+            index.insert(primary_key, IndexKey::from_fields(values, field));
+        }
+    }
+
+    pub(crate) fn remove(&mut self, table: &str, primary_key: &[u8]) {
+        if let Some(fields) = self.tables.get_mut(table) {
+            for index in fields.values_mut() {
+                index.remove(primary_key);
+            }
+        }
+    }
+
+    /// Unindex every record of `table`, keeping its indexes defined.
+    pub(crate) fn clear(&mut self, table: &str) {
+        if let Some(fields) = self.tables.get_mut(table) {
+            for index in fields.values_mut() {
+                *index = FieldIndex::new(index.kind());
+            }
+        }
+    }
+
+    /// Primary keys whose `field` satisfies `op target`, in key order, or
+    /// `None` if no index on the field can answer `op`.
+    pub(crate) fn lookup(&self, table: &str, field: &str, op: IndexOp, target: &IndexKey) -> Option<BTreeSet<Vec<u8>>> {
+        let fields = self.tables.get(table)?;
+        [IndexKind::Hash, IndexKind::BTree]
+            .into_iter()
+            .filter_map(|kind| fields.get(&(field.to_string(), kind)))
+            .find_map(|index| index.lookup(op, target))
+    }
+}
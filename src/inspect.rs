@@ -0,0 +1,42 @@
+//! Reading a single SSTable file on its own, for tooling and for checking
+//! what a flush or compaction wrote.
+
+use std::path::Path;
+
+use crate::error::DbError;
+use crate::storage::SsTableScan;
+use crate::Database;
+
+impl Database {
+    /// Every entry of one of the database's SSTables, in key order, exactly
+    /// as that file holds it: storage keys (`<table>:<primary key>` for a
+    /// record), tombstones and merge operands included, with no newer or
+    /// older layer merged in. Blocks are read and checksummed as for any
+    /// read.
+    ///
+    /// `file` is the table's file name, such as `sst-1791964336-0.bin`, or a
+    /// path ending in one; only the name is looked at. It must be an SSTable
+    /// the database is using now, or the scan fails with
+    /// [`DbError::StorageError`]: a table compaction has replaced is gone,
+    /// with its blob files possibly deleted too.
+    ///
+    /// ```no_run
+    /// # async fn run(db: &rust_db::Database) -> Result<(), rust_db::DbError> {
+    /// db.flush().await?;
+    /// for entry in std::fs::read_dir("./data")? {
+    ///     let name = entry?.file_name().to_string_lossy().into_owned();
+    ///     if name.starts_with("sst-") {
+    ///         for entry in db.scan_sstable(&name).await? {
+    ///             let (key, slot) = entry?;
+    ///             println!("{}: {:?}", key.escape_ascii(), slot);
+    ///         }
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn scan_sstable(&self, file: impl AsRef<Path>) -> Result<SsTableScan, DbError> {
+        let name = file.as_ref().file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        self.read_storage().await?.scan_sstable(&name)
+    }
+}
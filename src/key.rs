@@ -0,0 +1,196 @@
+use std::ops::{Bound, RangeBounds};
+
+/// Byte encoding of a primary key value.
+///
+/// `schema!`'s `#[primary_key]` encodes the field with this trait, so any
+/// value of the same type passed to `Database::get_by_key` lands on the same
+/// storage key.
+///
+/// Storage keys compare bytewise, so encodings preserve order: integers are
+/// fixed-width big-endian, with the sign bit flipped for signed types so
+/// negatives sort first, and `usize`/`isize` widened to 64 bits.
+///
+/// Tuples encode their elements in turn, each with its `0x00` bytes
+/// escaped as `0x00 0xff` and ended by `0x00 0x01`. That orders them by
+/// their first element, then their second and so on, keeps `("a", "bc")`
+/// and `("ab", "c")` apart, and makes `(a,)` a prefix of every `(a, ..)`.
+pub trait AsKeyBytes {
+    fn as_key_bytes(&self) -> Vec<u8>;
+}
+
+impl AsKeyBytes for str {
+    fn as_key_bytes(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+}
+
+/// Bytes already in key encoding, as from `PrimaryKey::primary_key`
+impl AsKeyBytes for [u8] {
+    fn as_key_bytes(&self) -> Vec<u8> {
+        self.to_vec()
+    }
+}
+
+impl AsKeyBytes for String {
+    fn as_key_bytes(&self) -> Vec<u8> {
+        self.as_str().as_key_bytes()
+    }
+}
+
+impl<K: AsKeyBytes + ?Sized> AsKeyBytes for &K {
+    fn as_key_bytes(&self) -> Vec<u8> {
+        (**self).as_key_bytes()
+    }
+}
+
+macro_rules! impl_unsigned_key {
+    ($($int:ty => $wide:ty),*) => {
+        $(
+            impl AsKeyBytes for $int {
+                fn as_key_bytes(&self) -> Vec<u8> {
+                    (*self as $wide).to_be_bytes().to_vec()
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! impl_signed_key {
+    ($($int:ty => $wide:ty as $unsigned:ty),*) => {
+        $(
+            impl AsKeyBytes for $int {
+                fn as_key_bytes(&self) -> Vec<u8> {
+                    let biased = (*self as $wide as $unsigned) ^ (1 << (<$unsigned>::BITS - 1));
+                    biased.to_be_bytes().to_vec()
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! impl_tuple_key {
+    ($(($($element:ident),+)),*) => {
+        $(
+            impl<$($element: AsKeyBytes),+> AsKeyBytes for ($($element,)+) {
+                #[allow(non_snake_case)]
+                fn as_key_bytes(&self) -> Vec<u8> {
+                    let ($($element,)+) = self;
+                    let mut key = Vec::new();
+                    $(push_tuple_element(&mut key, &$element.as_key_bytes());)+
+                    key
+                }
+            }
+        )*
+    };
+}
+
+// Append one escaped, terminated tuple element, as described on `AsKeyBytes`
+fn push_tuple_element(key: &mut Vec<u8>, element: &[u8]) {
+    for &byte in element {
+        key.push(byte);
+        if byte == 0x00 {
+            key.push(0xff);
+        }
+    }
+    key.extend_from_slice(&[0x00, 0x01]);
+}
+
+// The elements of a tuple key, unescaped
+pub(crate) fn tuple_elements(key: &[u8]) -> Vec<Vec<u8>> {
+    let mut elements = Vec::new();
+    let mut element = Vec::new();
+    let mut at = 0;
+    while at < key.len() {
+        match (key[at], key.get(at + 1)) {
+            (0x00, Some(0x01)) => elements.push(std::mem::take(&mut element)),
+            (0x00, _) => element.push(0x00),
+            (byte, _) => {
+                element.push(byte);
+                at += 1;
+                continue;
+            }
+        }
+        at += 2;
+    }
+    elements
+}
+
+impl_tuple_key!((A), (A, B), (A, B, C), (A, B, C, D));
+impl_unsigned_key!(u8 => u8, u16 => u16, u32 => u32, u64 => u64, u128 => u128, usize => u64);
+impl_signed_key!(
+    i8 => i8 as u8,
+    i16 => i16 as u16,
+    i32 => i32 as u32,
+    i64 => i64 as u64,
+    i128 => i128 as u128,
+    isize => i64 as u64
+);
+
+// Storage key for a record: `<table>:<primary key>`, so tables stay namespaced
+pub(crate) fn record_key(table: &str, primary_key: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(table.len() + 1 + primary_key.len());
+    key.extend_from_slice(table.as_bytes());
+    key.push(b':');
+    key.extend_from_slice(primary_key);
+    key
+}
+
+// Storage key bounds for a range of primary keys in `table`. Unbounded ends
+// stop at the edges of the table's `<table>:` key range; `;` is the byte
+// after `:`.
+pub(crate) fn record_range<K: AsKeyBytes>(table: &str, range: &impl RangeBounds<K>) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let key_of = |key: &K| record_key(table, &key.as_key_bytes());
+    let start = match range.start_bound().map(key_of) {
+        Bound::Unbounded => Bound::Included(record_key(table, &[])),
+        start => start,
+    };
+    let end = match range.end_bound().map(key_of) {
+        Bound::Unbounded => {
+            let mut end = table.as_bytes().to_vec();
+            end.push(b':' + 1);
+            Bound::Excluded(end)
+        }
+        end => end,
+    };
+    (start, end)
+}
+
+// Prefix of every `#[unique]` entry of `table`
+pub(crate) fn unique_prefix(table: &str) -> Vec<u8> {
+    let mut prefix = table.as_bytes().to_vec();
+    prefix.push(b'#');
+    prefix
+}
+
+// Storage key for a `#[unique]` value: `<table>#<field>:<value>`, holding the
+// owning record's primary key. `#` can't appear in a table name, so these
+// never show up in a table's `<table>:` scan.
+pub(crate) fn unique_key(table: &str, field: &str, value: &[u8]) -> Vec<u8> {
+    let mut key = unique_prefix(table);
+    key.reserve(field.len() + 1 + value.len());
+    key.extend_from_slice(field.as_bytes());
+    key.push(b':');
+    key.extend_from_slice(value);
+    key
+}
+
+// Storage key for a raw entry: `!<key>`. `!` can't start a table name, so
+// raw entries stay clear of every table's records and `#[unique]` entries.
+pub(crate) fn raw_key(key: &[u8]) -> Vec<u8> {
+    let mut raw = Vec::with_capacity(1 + key.len());
+    raw.push(b'!');
+    raw.extend_from_slice(key);
+    raw
+}
+
+// Prefix of the database's own metadata, kept alongside the records
+pub(crate) const META_PREFIX: &[u8] = b"~";
+
+// Storage key for a metadata entry: `~<name>`. `~` can't start a table name
+// and isn't a raw entry's `!`, so metadata stays out of every other
+// keyspace.
+pub(crate) fn meta_key(name: &str) -> Vec<u8> {
+    let mut key = META_PREFIX.to_vec();
+    key.extend_from_slice(name.as_bytes());
+    key
+}
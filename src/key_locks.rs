@@ -0,0 +1,71 @@
+//! Locks on storage keys, for typed writes that share the storage lock.
+//!
+//! A typed write takes the storage lock shared, as a read does, so writes
+//! of different records go ahead side by side. It locks the record it
+//! writes here first, and then the `#[unique]` values it claims, so two
+//! writes never read the same record or claim the same value at once.
+//! Record keys are always locked before unique ones, which keeps two
+//! writers from waiting on each other.
+//!
+//! Keys are hashed onto a fixed number of stripes, so two keys can share
+//! one and wait for each other needlessly, but never the other way round.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use tokio::sync::{Mutex, MutexGuard};
+
+const STRIPES: usize = 64;
+
+#[derive(Debug)]
+pub(crate) struct KeyLocks {
+    stripes: Vec<Mutex<()>>,
+}
+
+impl KeyLocks {
+    pub(crate) fn new() -> Self {
+        KeyLocks { stripes: (0..STRIPES).map(|_| Mutex::new(())).collect() }
+    }
+
+    /// Lock every one of `keys`, waiting for whoever holds any of them.
+    ///
+    /// Stripes are taken in order, so callers locking some of the same keys
+    /// can't deadlock, as long as none comes back for more keys of the same
+    /// `KeyLocks` while it holds some.
+    pub(crate) async fn lock<K: AsRef<[u8]>>(&self, keys: &[K]) -> KeyGuard<'_> {
+        let mut guards = Vec::new();
+        for stripe in stripes(keys) {
+            guards.push(self.stripes[stripe].lock().await);
+        }
+        KeyGuard { _guards: guards }
+    }
+
+    /// Like [`KeyLocks::lock`], but `None` instead of waiting.
+    pub(crate) fn try_lock<K: AsRef<[u8]>>(&self, keys: &[K]) -> Option<KeyGuard<'_>> {
+        let guards = stripes(keys)
+            .into_iter()
+            .map(|stripe| self.stripes[stripe].try_lock().ok())
+            .collect::<Option<_>>()?;
+        Some(KeyGuard { _guards: guards })
+    }
+}
+
+/// Locks taken by [`KeyLocks::lock`], released when it's dropped
+pub(crate) struct KeyGuard<'a> {
+    _guards: Vec<MutexGuard<'a, ()>>,
+}
+
+// The stripes `keys` fall on, in order and each once
+fn stripes<K: AsRef<[u8]>>(keys: &[K]) -> Vec<usize> {
+    let mut stripes: Vec<_> = keys
+        .iter()
+        .map(|key| {
+            let mut hasher = DefaultHasher::new();
+            key.as_ref().hash(&mut hasher);
+            (hasher.finish() % STRIPES as u64) as usize
+        })
+        .collect();
+    stripes.sort_unstable();
+    stripes.dedup();
+    stripes
+}
@@ -1,105 +1,1558 @@
+mod backup;
+mod batch;
+mod builder;
+mod bulk;
+mod catalog;
+mod changes;
+mod codec;
+mod cursor;
+#[cfg(feature = "debug-dump")]
+mod debug;
+mod dump;
 mod error;
+mod index;
+mod inspect;
+mod key;
+mod key_locks;
+mod meta;
+mod predicate;
+mod raw;
+mod record;
 pub mod schema;
+mod sequence;
+mod snapshot;
 mod storage;
+#[cfg(feature = "sync")]
+mod sync;
+mod trace;
+mod transaction;
+mod unique;
+mod verify;
 
+pub use batch::WriteBatch;
+pub use builder::DatabaseBuilder;
+pub use changes::{ChangeEvent, ChangeKind};
+pub use codec::Codec;
+pub use cursor::Cursor;
 pub use error::{DbError, SchemaError};
-pub use schema::{Schema, CompileTimeSchema};
-use storage::LsmStorage;
+pub use index::{IndexDef, IndexKind, IndexOp};
+pub use key::AsKeyBytes;
+pub use predicate::Predicate;
+pub use record::RecordMeta;
+pub use rust_db_derive::Schema;
+pub use schema::{Schema, CompileTimeSchema, Field, PrimaryKey};
+pub use snapshot::Snapshot;
+pub use storage::{BTreeMemTable, CompactionPolicy, CompactionReport, Compression, DbStats, Durability, MemTableImpl, MergeFn, Metrics, Slot, SsTableScan, VerifyProblem, VerifyReport};
+pub use transaction::Transaction;
+#[cfg(feature = "sync")]
+pub use sync::{SyncDatabase, SyncQueryBuilder};
+use changes::Changes;
+use index::{IndexKey, Indexes};
+use key::{record_key, record_range, unique_key, unique_prefix};
+use key_locks::{KeyGuard, KeyLocks};
+use meta::MetaStore;
+use storage::{LsmStorage, StorageOp};
+use unique::{unique_of, UniqueClaims};
+use std::collections::BTreeSet;
+use std::ops::RangeBounds;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
 use serde::{Serialize, de::DeserializeOwned};
-use tokio::sync::RwLock;
+use futures::{Stream, StreamExt};
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 #[derive(Debug)]
 pub struct Database {
     storage: RwLock<LsmStorage>,
+    // Changed under the storage write lock, or the shared one with the
+    // record's key locked, so it tracks what's stored
+    indexes: RwLock<Indexes>,
+    // Taken by typed writes, which share the storage lock, for the records
+    // they write and then the unique values they claim
+    record_locks: KeyLocks,
+    unique_locks: KeyLocks,
+    codec: Codec,
+    // The metadata: the sequences, the table catalog and schema versions
+    meta: Mutex<MetaStore>,
+    // Reads of a whole table, for `DbStats::table_scans`
+    table_scans: AtomicU64,
+    changes: Changes,
+    // How long to wait for the storage lock, from `DatabaseBuilder::lock_timeout`
+    lock_timeout: Option<Duration>,
+}
+
+// Wait up to `timeout` for `lock`, or fail with `DbError::Timeout`
+async fn acquire<G>(
+    timeout: Duration,
+    lock: impl std::future::Future<Output = G>,
+    mut try_lock: impl FnMut() -> Option<G>,
+) -> Result<G, DbError> {
+    if tokio::runtime::Handle::try_current().is_ok() {
+        return tokio::time::timeout(timeout, lock).await.map_err(|_| DbError::Timeout(timeout));
+    }
+    // Without a Tokio timer to race the lock against, as under
+    // `SyncDatabase`, whose thread waits on this call anyway, poll for it
+    let deadline = std::time::Instant::now() + timeout;
+    loop {
+        if let Some(guard) = try_lock() {
+            return Ok(guard);
+        }
+        if std::time::Instant::now() >= deadline {
+            return Err(DbError::Timeout(timeout));
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
 }
 
 impl Database {
     pub async fn open(path: &str) -> Result<Self, DbError> {
-        let storage = LsmStorage::new(Path::new(path), 1024 * 1024)?; // 1MB flush threshold
-        Ok(Database {
-            storage: RwLock::new(storage),
-        })
+        Self::builder(path).open().await
+    }
+
+    /// Open an existing database for reading only, e.g. as an analytics
+    /// replica. Writes fail with [`DbError::ReadOnly`] and nothing on disk
+    /// changes, so any number of read-only handles can share a directory,
+    /// with each other and with the one writable handle.
+    ///
+    /// A handle sees what was on disk as it opened, and doesn't follow
+    /// later writes. Compaction by a writer in the same directory can
+    /// delete SSTables it still reads from, so reopen it to catch up.
+    pub async fn open_read_only(path: impl AsRef<Path>) -> Result<Self, DbError> {
+        Self::builder(path).read_only(true).open().await
     }
 
-    pub async fn insert<T>(&self, item: &T) -> Result<(), DbError>
+    /// Configure a database before opening it.
+    pub fn builder(path: impl AsRef<Path>) -> DatabaseBuilder {
+        DatabaseBuilder::new(path.as_ref())
+    }
+
+    /// Validate and store a record under its primary key, and return the
+    /// storage key it went in under: `<table>:<primary key>`.
+    ///
+    /// Values of `#[unique]` fields are checked against the table's other
+    /// records first, failing with [`DbError::UniqueViolation`] if taken.
+    ///
+    /// A zero `#[auto_increment]` field is given the table's next id, which
+    /// is what's stored and what the returned key ends in.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(table = T::table_name(), key_bytes, elapsed_us))
+    )]
+    pub async fn insert<T>(&self, item: &T) -> Result<Vec<u8>, DbError>
     where
-        T: Schema + Serialize,
+        T: Schema + CompileTimeSchema + PrimaryKey + Serialize + DeserializeOwned,
     {
+        let _timer = trace::Timer::start();
         // Schema validation
-        item.schema_validate().map_err(|e| DbError::SchemaError(e.to_string()))?;
-        
-        // Serialize
-        let key = T::table_name().as_bytes().to_vec();
-        let value = bincode::serialize(item)
-            .map_err(|e| DbError::SerializationError(e.to_string()))?;
-        
-        // Store
-        self.storage.write().await.insert(key, value)?;
+        schema::validate(item)?;
+        let assigned = self.assign_id(item)?;
+
+        // Other writes share the storage lock; the record and its unique
+        // values are locked from the uniqueness check through the write,
+        // so two inserts can't claim the same value.
+        let item = assigned.as_ref().unwrap_or(item);
+        let storage = self.read_storage().await?;
+        let _record = self.lock_keys(&self.record_locks, &[item.storage_key()]).await?;
+        let key = self.store(&storage, item, None, None).await?;
+        trace::record!(key_bytes = key.len());
+        Ok(key)
+    }
+
+    /// Insert `item`, or replace the record already stored under its
+    /// primary key. Returns `true` if it created the record and `false` if
+    /// it replaced one.
+    ///
+    /// Validated and checked for `#[unique]` values like an insert. The
+    /// record is locked from the existence check through the write, so two
+    /// upserts of a new key can't both report creating it. A replaced
+    /// record keeps its `created_at`, as with `update`.
+    pub async fn upsert<T>(&self, item: &T) -> Result<bool, DbError>
+    where
+        T: Schema + CompileTimeSchema + PrimaryKey + Serialize + DeserializeOwned,
+    {
+        schema::validate(item)?;
+        let assigned = self.assign_id(item)?;
+        let item = assigned.as_ref().unwrap_or(item);
+
+        let storage = self.read_storage().await?;
+        let _record = self.lock_keys(&self.record_locks, &[item.storage_key()]).await?;
+        let existing = storage.get(&item.storage_key())?;
+        let created_at = match &existing {
+            Some(data) => record::created_at(data)?,
+            None => None,
+        };
+        self.store(&storage, item, created_at, None).await?;
+        Ok(existing.is_none())
+    }
+
+    /// [`Database::insert`] from before it returned the key.
+    pub async fn insert_ignore_key<T>(&self, item: &T) -> Result<(), DbError>
+    where
+        T: Schema + CompileTimeSchema + PrimaryKey + Serialize + DeserializeOwned,
+    {
+        self.insert(item).await.map(drop)
+    }
+
+    /// Like [`Database::insert`], but the record expires once `ttl` has
+    /// passed: reads treat it as absent from then on, and compaction
+    /// removes it from disk.
+    ///
+    /// The expiry is kept with the stored value, not in `T`. Writing the
+    /// record again, e.g. with `insert` or `update`, replaces the expiry
+    /// along with the value.
+    pub async fn insert_with_ttl<T>(&self, item: &T, ttl: Duration) -> Result<Vec<u8>, DbError>
+    where
+        T: Schema + CompileTimeSchema + PrimaryKey + Serialize + DeserializeOwned,
+    {
+        schema::validate(item)?;
+
+        let ttl = i64::try_from(ttl.as_millis()).unwrap_or(i64::MAX);
+        let expires_at = storage::now_millis().saturating_add(ttl);
+        let assigned = self.assign_id(item)?;
+        let item = assigned.as_ref().unwrap_or(item);
+        let storage = self.read_storage().await?;
+        let _record = self.lock_keys(&self.record_locks, &[item.storage_key()]).await?;
+        self.store(&storage, item, None, Some(expires_at)).await
+    }
+
+    /// Load the record stored under `key`, change it with `f` and write it
+    /// back, or return `Ok(false)` if there's no such record.
+    ///
+    /// The changed record is validated like an insert, and locked
+    /// throughout, so no other write to it can land between the read and
+    /// the write. `f` must leave the primary key alone.
+    pub async fn update<T, F>(&self, key: &str, f: F) -> Result<bool, DbError>
+    where
+        T: Schema + CompileTimeSchema + PrimaryKey + Serialize + DeserializeOwned,
+        F: FnOnce(&mut T),
+    {
+        let Some(key_bytes) = T::parse_key(key) else {
+            return Ok(false);
+        };
+        let record = record_key(T::table_name(), &key_bytes);
+        let storage = self.read_storage().await?;
+        let _record = self.lock_keys(&self.record_locks, &[&record]).await?;
+        let Some(data) = storage.get(&record)? else {
+            return Ok(false);
+        };
+        let mut item = record::decode::<T>(self.codec, &data)?;
+        let created_at = record::created_at(&data)?;
+
+        f(&mut item);
+        if item.primary_key() != key_bytes {
+            return Err(DbError::SchemaError(format!(
+                "update of {} {} changed its primary key",
+                T::table_name(),
+                key
+            )));
+        }
+        schema::validate(&item)?;
+
+        self.store(&storage, &item, created_at, None).await?;
+        Ok(true)
+    }
+
+    // The storage, shared with other readers, waited for at most the lock
+    // timeout
+    pub(crate) async fn read_storage(&self) -> Result<RwLockReadGuard<'_, LsmStorage>, DbError> {
+        match self.lock_timeout {
+            Some(timeout) => acquire(timeout, self.storage.read(), || self.storage.try_read().ok()).await,
+            None => Ok(self.storage.read().await),
+        }
+    }
+
+    // The storage, to itself, waited for at most the lock timeout
+    pub(crate) async fn write_storage(&self) -> Result<RwLockWriteGuard<'_, LsmStorage>, DbError> {
+        match self.lock_timeout {
+            Some(timeout) => acquire(timeout, self.storage.write(), || self.storage.try_write().ok()).await,
+            None => Ok(self.storage.write().await),
+        }
+    }
+
+    // `keys` locked in `locks`, under the shared storage lock, waited for at
+    // most the lock timeout like it
+    async fn lock_keys<'a, K: AsRef<[u8]>>(&self, locks: &'a KeyLocks, keys: &[K]) -> Result<KeyGuard<'a>, DbError> {
+        match self.lock_timeout {
+            Some(timeout) => acquire(timeout, locks.lock(keys), || locks.try_lock(keys)).await,
+            None => Ok(locks.lock(keys).await),
+        }
+    }
+
+    // A copy of `item` with the next id in its `#[auto_increment]` field, if
+    // that's zero. A nonzero id just moves the sequence past it.
+    pub(crate) fn assign_id<T>(&self, item: &T) -> Result<Option<T>, DbError>
+    where
+        T: Schema + CompileTimeSchema + Serialize + DeserializeOwned,
+    {
+        let Some(id) = item.auto_increment() else {
+            return Ok(None);
+        };
+        let mut meta = self.meta.lock().map_err(|_| DbError::LockPoisoned("metadata".to_string()))?;
+        if id != 0 {
+            sequence::observe(&mut meta, T::table_name(), id)?;
+            return Ok(None);
+        }
+
+        // `T` needn't be `Clone`, but it does round-trip through the codec
+        let mut assigned: T = self.codec.deserialize(&self.codec.serialize(item)?)?;
+        assigned.set_auto_increment(sequence::next(&mut meta, T::table_name())?);
+        Ok(Some(assigned))
+    }
+
+    // Write a validated record, claiming its unique values, under the
+    // caller's shared storage lock and lock on the record. `created_at` is
+    // kept from the version being updated, if any.
+    async fn store<T>(
+        &self,
+        storage: &LsmStorage,
+        item: &T,
+        created_at: Option<i64>,
+        expires_at: Option<i64>,
+    ) -> Result<Vec<u8>, DbError>
+    where
+        T: Schema + CompileTimeSchema + PrimaryKey + Serialize + DeserializeOwned,
+    {
+        self.note_table(T::table_name(), T::SCHEMA_VERSION)?;
+        let primary_key = item.primary_key();
+        let key = item.storage_key();
+        let value = record::encode(self.codec, item, created_at)?;
+        let record = match expires_at {
+            Some(expires_at) => StorageOp::InsertExpiring(key.clone(), value, expires_at),
+            None => StorageOp::Insert(key.clone(), value),
+        };
+
+        let mut ops = vec![record];
+        let unique = item.unique_values();
+        let unique_keys: Vec<_> = unique.iter().map(|(field, value)| unique_key(T::table_name(), field, value)).collect();
+        let _claimed = self.lock_keys(&self.unique_locks, &unique_keys).await?;
+        if !unique.is_empty() {
+            // An expired owner no longer holds its values, since its
+            // record reads as absent
+            let mut claims = UniqueClaims::new(self.codec);
+            claims.claim(storage, T::table_name(), &primary_key, unique, unique_of::<T>)?;
+            ops.extend(claims.into_entries().map(|(key, value)| StorageOp::Insert(key, value)));
+        }
+        self.write_with_meta(storage, ops)?;
+        self.indexes.write().await.insert(T::table_name(), &primary_key, item)?;
+        self.changes.publish(T::table_name(), &primary_key, ChangeKind::Insert);
+        Ok(key)
+    }
+
+    /// Insert a batch of records with one WAL flush.
+    ///
+    /// Every item is validated before anything is written, so a single
+    /// invalid item aborts the whole batch. That includes `#[unique]`
+    /// values, which must not repeat within the batch either.
+    pub async fn insert_many<T>(&self, items: &[T]) -> Result<(), DbError>
+    where
+        T: Schema + CompileTimeSchema + PrimaryKey + Serialize + DeserializeOwned,
+    {
+        for item in items {
+            schema::validate(item)?;
+        }
+        let assigned = items.iter().map(|item| self.assign_id(item)).collect::<Result<Vec<_>, _>>()?;
+        let items: Vec<&T> = assigned
+            .iter()
+            .zip(items)
+            .map(|(assigned, item)| assigned.as_ref().unwrap_or(item))
+            .collect();
+
+        let storage = self.read_storage().await?;
+        let records: Vec<_> = items.iter().map(|item| item.storage_key()).collect();
+        let _records = self.lock_keys(&self.record_locks, &records).await?;
+        let unique_keys: Vec<_> = items
+            .iter()
+            .flat_map(|item| item.unique_values())
+            .map(|(field, value)| unique_key(T::table_name(), field, &value))
+            .collect();
+        let _claimed = self.lock_keys(&self.unique_locks, &unique_keys).await?;
+        let mut entries = Vec::with_capacity(items.len());
+        let mut primary_keys = Vec::with_capacity(items.len());
+        let mut claims = UniqueClaims::new(self.codec);
+        for &item in &items {
+            let primary_key = item.primary_key();
+            claims.claim(&storage, T::table_name(), &primary_key, item.unique_values(), unique_of::<T>)?;
+            let value = record::encode(self.codec, item, None)?;
+            entries.push((item.storage_key(), value));
+            primary_keys.push(primary_key);
+        }
+        entries.extend(claims.into_entries());
+
+        if !items.is_empty() {
+            self.note_table(T::table_name(), T::SCHEMA_VERSION)?;
+        }
+        self.write_with_meta(&storage, entries.into_iter().map(|(key, value)| StorageOp::Insert(key, value)).collect())?;
+        let mut indexes = self.indexes.write().await;
+        for (&item, primary_key) in items.iter().zip(&primary_keys) {
+            indexes.insert(T::table_name(), primary_key, item)?;
+            self.changes.publish(T::table_name(), primary_key, ChangeKind::Insert);
+        }
         Ok(())
     }
 
+    /// Fetch a record by its primary key written out as text, parsed as
+    /// [`PrimaryKey::parse_key`] says, so `db.get::<User>("1")` works for
+    /// an integer key too.
     pub async fn get<T>(&self, key: &str) -> Result<Option<T>, DbError>
     where
-        T: Schema + DeserializeOwned,
+        T: Schema + CompileTimeSchema + PrimaryKey + DeserializeOwned,
     {
-        let key_bytes = key.as_bytes();
-        if let Some(data) = self.storage.write().await.get(key_bytes) {
-            let item = bincode::deserialize::<T>(&data)
-                .map_err(|e| DbError::SerializationError(e.to_string()))?;
+        match T::parse_key(key) {
+            Some(key_bytes) => self.get_by_key(key_bytes.as_slice()).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Like [`Database::get`], but a missing record is
+    /// [`DbError::NotFound`] rather than `Ok(None)`.
+    pub async fn get_or_err<T>(&self, key: &str) -> Result<T, DbError>
+    where
+        T: Schema + CompileTimeSchema + PrimaryKey + DeserializeOwned,
+    {
+        self.get(key).await?.ok_or_else(|| DbError::NotFound {
+            table: T::table_name().to_string(),
+            key: key.to_string(),
+        })
+    }
+
+    /// Fetch a record by a typed primary key, e.g. `db.get_by_key::<User, _>(user.id)`.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "get", level = "debug", skip_all, fields(table = T::table_name(), key_bytes, found, elapsed_us))
+    )]
+    pub async fn get_by_key<T, K>(&self, key: K) -> Result<Option<T>, DbError>
+    where
+        T: Schema + CompileTimeSchema + DeserializeOwned,
+        K: AsKeyBytes,
+    {
+        let _timer = trace::Timer::start();
+        let key_bytes = record_key(T::table_name(), &key.as_key_bytes());
+        let data = self.read_storage().await?.get(&key_bytes)?;
+        trace::record!(key_bytes = key_bytes.len(), found = data.is_some());
+        if let Some(data) = data {
+            let item = record::decode::<T>(self.codec, &data)?;
             Ok(Some(item))
         } else {
             Ok(None)
         }
     }
 
-    pub fn query<T>(&self) -> QueryBuilder<T>
+    /// Fetch the records with each of `keys`, parsed as by [`Database::get`],
+    /// in the same order: `None` where there's no such record.
+    ///
+    /// Unlike calling `get` for each key, the storage is locked once and
+    /// the keys are looked up in key order, so records stored near each
+    /// other are read together. All of them are read at one point in time.
+    ///
+    /// ```no_run
+    /// # #[derive(rust_db::Schema, serde::Serialize, serde::Deserialize)]
+    /// # #[table_name = "User"]
+    /// # struct User { #[primary_key] id: u64 }
+    /// # async fn run(db: &rust_db::Database) -> Result<(), rust_db::DbError> {
+    /// let users = db.get_many::<User>(&["1", "404", "2"]).await?;
+    /// assert!(users[1].is_none());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn get_many<T>(&self, keys: &[&str]) -> Result<Vec<Option<T>>, DbError>
+    where
+        T: Schema + CompileTimeSchema + PrimaryKey + DeserializeOwned,
+    {
+        let parsed: Vec<_> = keys
+            .iter()
+            .map(|key| T::parse_key(key).map(|key_bytes| record_key(T::table_name(), &key_bytes)))
+            .collect();
+        let lookups: Vec<_> = parsed.iter().flatten().cloned().collect();
+        let mut values = self.read_storage().await?.get_many(&lookups)?.into_iter();
+        parsed
+            .iter()
+            // A key that doesn't parse has no record, as with `get`
+            .map(|key| key.as_ref().and_then(|_| values.next().flatten()))
+            .map(|data| data.map(|data| record::decode::<T>(self.codec, &data)).transpose())
+            .collect()
+    }
+
+    /// Like [`Database::get`], along with when the record was inserted and
+    /// last written.
+    ///
+    /// `insert` sets both timestamps, so inserting over an existing record
+    /// starts it afresh; `update` keeps `created_at`.
+    pub async fn get_with_meta<T>(&self, key: &str) -> Result<Option<(T, RecordMeta)>, DbError>
+    where
+        T: Schema + CompileTimeSchema + PrimaryKey + DeserializeOwned,
+    {
+        let Some(key_bytes) = T::parse_key(key) else {
+            return Ok(None);
+        };
+        let key_bytes = record_key(T::table_name(), &key_bytes);
+        match self.read_storage().await?.get(&key_bytes)? {
+            Some(data) => record::decode_with_meta::<T>(self.codec, &data).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Every record of `T`, in primary key order.
+    pub async fn all<T>(&self) -> Result<Vec<T>, DbError>
     where
-        T: Schema + DeserializeOwned + Send + Sync,
+        T: Schema + CompileTimeSchema + DeserializeOwned,
+    {
+        Source::Live(self).all().await
+    }
+
+    /// Whether a record is stored under `key`, without reading it back.
+    pub async fn exists<T>(&self, key: &str) -> Result<bool, DbError>
+    where
+        T: Schema + PrimaryKey,
+    {
+        let Some(key_bytes) = T::parse_key(key) else {
+            return Ok(false);
+        };
+        let key_bytes = record_key(T::table_name(), &key_bytes);
+        self.read_storage().await?.contains(&key_bytes)
+    }
+
+    /// Records whose primary key falls in `range`, in key order, e.g.
+    /// `db.scan::<Event, _>("2024-01".."2024-02")` or `db.scan::<Event, &str>(..)`.
+    ///
+    /// Keys compare by their [`AsKeyBytes`] encoding, which keeps integer
+    /// ranges in numeric order, negatives included: `db.scan::<Point, _>(-5i32..5)`.
+    pub async fn scan<T, K>(&self, range: impl RangeBounds<K>) -> Result<Vec<T>, DbError>
+    where
+        T: Schema + CompileTimeSchema + DeserializeOwned,
+        K: AsKeyBytes,
+    {
+        let (start, end) = record_range(T::table_name(), &range);
+        let entries = self
+            .read_storage()
+            .await?
+            .scan_range(start.as_ref().map(Vec::as_slice), end.as_ref().map(Vec::as_slice))?;
+        entries
+            .into_iter()
+            .map(|(_, data)| {
+                record::decode::<T>(self.codec, &data)
+            })
+            .collect()
+    }
+
+    /// Records of `T` whose primary key starts with `prefix`, in key order,
+    /// each paired with its primary key, e.g. every order under
+    /// `db.scan_prefix::<Order>(b"2024:")`.
+    pub async fn scan_prefix<T>(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, T)>, DbError>
+    where
+        T: Schema + CompileTimeSchema + DeserializeOwned,
+    {
+        let table_prefix = record_key(T::table_name(), &[]);
+        let entries = self.read_storage().await?.scan_prefix(&record_key(T::table_name(), prefix))?;
+        entries
+            .into_iter()
+            .map(|(key, data)| {
+                let record = record::decode::<T>(self.codec, &data)?;
+                Ok((key[table_prefix.len()..].to_vec(), record))
+            })
+            .collect()
+    }
+
+    pub async fn delete<T>(&self, key: &str) -> Result<(), DbError>
+    where
+        T: Schema + PrimaryKey,
+    {
+        let Some(primary_key) = T::parse_key(key) else {
+            return Ok(());
+        };
+        let key_bytes = record_key(T::table_name(), &primary_key);
+        let storage = self.write_storage().await?;
+        let existed = storage.contains(&key_bytes)?;
+        storage.delete(key_bytes)?;
+        if existed {
+            self.indexes.write().await.remove(T::table_name(), &primary_key);
+            self.changes.publish(T::table_name(), &primary_key, ChangeKind::Delete);
+        }
+        Ok(())
+    }
+
+    /// Delete every record of `T` that `f` returns `true` for, all in one
+    /// atomic write, and return how many there were.
+    pub async fn delete_where<T, F>(&self, f: F) -> Result<usize, DbError>
+    where
+        T: Schema + CompileTimeSchema + DeserializeOwned,
+        F: Fn(&T) -> bool,
+    {
+        let storage = self.write_storage().await?;
+        let prefix = record_key(T::table_name(), &[]);
+        let mut keys = Vec::new();
+        for (key, data) in storage.scan_prefix(&prefix)? {
+            if f(&record::decode::<T>(self.codec, &data)?) {
+                keys.push(key);
+            }
+        }
+        self.delete_keys::<T>(&storage, keys).await
+    }
+
+    /// Delete every record of `T` whose primary key falls in `range`, as
+    /// with [`Database::scan`], and return how many there were.
+    ///
+    /// Only keys are read, not records, but each deleted record still gets
+    /// a tombstone of its own.
+    pub async fn delete_range<T, K>(&self, range: impl RangeBounds<K>) -> Result<usize, DbError>
+    where
+        T: Schema,
+        K: AsKeyBytes,
+    {
+        let (start, end) = record_range(T::table_name(), &range);
+        let storage = self.write_storage().await?;
+        let keys = storage.scan_range_keys(start.as_ref().map(Vec::as_slice), end.as_ref().map(Vec::as_slice))?;
+        self.delete_keys::<T>(&storage, keys).await
+    }
+
+    // Delete the records of `T` stored under `keys` in one write, under the
+    // caller's storage write lock
+    async fn delete_keys<T: Schema>(&self, storage: &LsmStorage, keys: Vec<Vec<u8>>) -> Result<usize, DbError> {
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        let count = keys.len();
+        let prefix_len = record_key(T::table_name(), &[]).len();
+        let primary_keys: Vec<_> = keys.iter().map(|key| key[prefix_len..].to_vec()).collect();
+        storage.write_atomic(keys.into_iter().map(StorageOp::Delete).collect())?;
+
+        let mut indexes = self.indexes.write().await;
+        for primary_key in primary_keys {
+            indexes.remove(T::table_name(), &primary_key);
+            self.changes.publish(T::table_name(), &primary_key, ChangeKind::Delete);
+        }
+        Ok(count)
+    }
+
+    /// Delete every record of `T`, along with its `#[unique]` claims.
+    ///
+    /// Indexes on the table stay defined, now empty, and its
+    /// `#[auto_increment]` sequence carries on rather than starting over.
+    /// The records' space on disk comes back as compaction gets to them;
+    /// [`Database::truncate`] frees what it can right away.
+    pub async fn drop_table<T>(&self) -> Result<(), DbError>
+    where
+        T: Schema,
+    {
+        let storage = self.write_storage().await?;
+        let deleted = storage.delete_prefixes(&table_prefixes(T::table_name()))?;
+        self.indexes.write().await.clear(T::table_name());
+        self.publish_deletes(T::table_name(), deleted);
+        Ok(())
+    }
+
+    /// Like [`Database::drop_table`], but SSTables holding nothing but
+    /// `T`'s records are deleted now instead of waiting for compaction.
+    pub async fn truncate<T>(&self) -> Result<(), DbError>
+    where
+        T: Schema,
+    {
+        let storage = self.write_storage().await?;
+        let deleted = storage.truncate_prefixes(&table_prefixes(T::table_name()))?;
+        self.indexes.write().await.clear(T::table_name());
+        self.publish_deletes(T::table_name(), deleted);
+        Ok(())
+    }
+
+    // A delete event for each record among the storage keys a table drop
+    // removed, leaving out its unique claims
+    fn publish_deletes(&self, table: &str, keys: BTreeSet<Vec<u8>>) {
+        let prefix = record_key(table, &[]);
+        for key in keys.iter().filter(|key| key.starts_with(&prefix)) {
+            self.changes.publish(table, &key[prefix.len()..], ChangeKind::Delete);
+        }
+    }
+
+    /// Receive a [`ChangeEvent`] for every record written or deleted from
+    /// now on, each record's in the order its writes were applied. Events
+    /// are only sent once the write is in the WAL, so a failed write never
+    /// shows up.
+    ///
+    /// The channel keeps the latest 1024 events. A receiver that falls
+    /// further behind gets `RecvError::Lagged` with the number it missed,
+    /// and carries on from the oldest event still kept; writers never wait
+    /// for receivers.
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<ChangeEvent> {
+        self.changes.subscribe()
+    }
+
+    /// Apply every put and delete in `batch` as one unit, under a single
+    /// lock: they're logged to the WAL together, so readers and recovery
+    /// see all of them or none. An empty batch writes nothing.
+    pub async fn apply_batch(&self, batch: WriteBatch) -> Result<(), DbError> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.write_storage().await?.write_atomic(batch.into_ops())
+    }
+
+    /// Build an in-memory index on `field` so `QueryBuilder::filter_indexed`
+    /// can look matches up instead of scanning the table. Inserts and deletes
+    /// keep it current from then on.
+    ///
+    /// Fields joined with commas, e.g. `"tenant_id,email"`, make a composite
+    /// index over the tuple of their values; filter it with a tuple such as
+    /// `(7, "ann@example.com")`.
+    ///
+    /// Indexes aren't persisted; call this again after reopening the
+    /// database to rebuild them from the stored records.
+    pub async fn create_index<T>(&self, field: &str) -> Result<(), DbError>
+    where
+        T: Schema + CompileTimeSchema + Serialize + DeserializeOwned,
+    {
+        self.create_index_with::<T>(field, IndexKind::BTree).await
+    }
+
+    /// [`Database::create_index`] with a choice of index. An
+    /// [`IndexKind::Hash`] index only serves `IndexOp::Eq` filters, but
+    /// takes about the same time whatever the number of distinct values;
+    /// ranges on a field that only has one scan the table. A field can have
+    /// both kinds, and equality filters then use the hash index.
+    pub async fn create_index_with<T>(&self, field: &str, kind: IndexKind) -> Result<(), DbError>
+    where
+        T: Schema + CompileTimeSchema + Serialize + DeserializeOwned,
+    {
+        if field.is_empty() {
+            return Err(DbError::SchemaError(
+                SchemaError::IndexFieldEmpty(field.to_string()).to_string(),
+            ));
+        }
+
+        // Writers are held off until the index is in place
+        let storage = self.write_storage().await?;
+        let prefix = record_key(T::table_name(), &[]);
+        let mut records = Vec::new();
+        for (key, data) in storage.scan_prefix(&prefix)? {
+            let item = record::decode::<T>(self.codec, &data)?;
+            records.push((key[prefix.len()..].to_vec(), item));
+        }
+
+        self.indexes.write().await.build(
+            T::table_name(),
+            field,
+            kind,
+            records.iter().map(|(primary_key, item)| (primary_key.clone(), item)),
+        );
+        Ok(())
+    }
+
+    /// Build every index [`Schema::indexes`] lists for `T`, as
+    /// [`Database::create_index_with`] does.
+    pub async fn create_indexes<T>(&self) -> Result<(), DbError>
+    where
+        T: Schema + CompileTimeSchema + Serialize + DeserializeOwned,
+    {
+        for index in T::indexes() {
+            self.create_index_with::<T>(index.name, index.kind).await?;
+        }
+        Ok(())
+    }
+
+    /// How much the database holds in memory and on disk, e.g. for
+    /// monitoring. Counting the live keys reads every key, so this costs
+    /// about as much as scanning the whole database.
+    pub async fn stats(&self) -> Result<DbStats, DbError> {
+        let mut stats = self.read_storage().await?.stats()?;
+        stats.table_scans = self.table_scans.load(Ordering::Relaxed);
+        Ok(stats)
+    }
+
+    /// Totals of the operations the storage has carried out since open:
+    /// entries written and deleted, lookups, flushes, compactions and WAL
+    /// bytes. Cheap to call, unlike [`Database::stats`].
+    pub async fn metrics(&self) -> Result<Metrics, DbError> {
+        Ok(self.read_storage().await?.metrics())
+    }
+
+    /// Take a [`Snapshot`]: reads through it see the database as of now,
+    /// ignoring every later write.
+    pub async fn snapshot(&self) -> Result<Snapshot, DbError> {
+        let storage = self.read_storage().await?.snapshot()?;
+        Ok(Snapshot::new(storage, self.codec))
+    }
+
+    /// A [`Cursor`] over every stored entry as of now, positioned before
+    /// the first one.
+    pub async fn cursor(&self) -> Result<Cursor, DbError> {
+        let storage = self.read_storage().await?.snapshot()?;
+        Ok(Cursor::new(storage))
+    }
+
+    /// Write every record still only in the memtable out to an SSTable, so
+    /// the WAL no longer has to be replayed for them.
+    pub async fn flush(&self) -> Result<(), DbError> {
+        self.read_storage().await?.flush()
+    }
+
+    /// `fsync` the WAL now, so every write made so far survives a crash or
+    /// power loss, without writing the memtable out the way
+    /// [`Database::flush`] does. Reopening replays them from the WAL.
+    ///
+    /// For checkpoints under [`Durability::Batched`] or
+    /// [`Durability::NoSync`], which otherwise leave recent writes to the
+    /// next interval or the OS. Under [`Durability::Sync`] every write is
+    /// synced already, and this costs one more `fsync`.
+    pub async fn sync_wal(&self) -> Result<(), DbError> {
+        self.read_storage().await?.sync_wal()
+    }
+
+    /// Flush, `fsync` the WAL and stop background work, reporting any
+    /// failure. Dropping the database stops background work too, but leaves
+    /// the memtable to WAL replay and only syncs the WAL if the durability
+    /// mode asks for it.
+    pub async fn close(self) -> Result<(), DbError> {
+        self.storage.into_inner().close()
+    }
+
+    /// Merge every SSTable into the last level now, dropping deleted and
+    /// expired records from disk. Compaction otherwise runs in the
+    /// background as the [`CompactionPolicy`] calls for it.
+    pub async fn compact(&self) -> Result<(), DbError> {
+        self.compact_now().await.map(|_| ())
+    }
+
+    /// Like [`Database::compact`], returning a [`CompactionReport`] of what
+    /// the merge read, wrote and dropped once it's done, e.g. for a tool
+    /// about to back the directory up. Records still in the memtable aren't
+    /// part of it; [`Database::flush`] first to include them.
+    pub async fn compact_now(&self) -> Result<CompactionReport, DbError> {
+        self.read_storage().await?.compact()
+    }
+
+    /// Stop background compaction, e.g. for a bulk load, until
+    /// [`Database::resume_compaction`]. A compaction already running
+    /// finishes.
+    pub async fn pause_compaction(&self) -> Result<(), DbError> {
+        self.read_storage().await?.pause_compaction();
+        Ok(())
+    }
+
+    /// Restart background compaction, catching up on whatever piled up
+    /// while it was paused.
+    pub async fn resume_compaction(&self) -> Result<(), DbError> {
+        self.read_storage().await?.resume_compaction()
+    }
+
+    /// Start a [`Transaction`] for writes that must apply together.
+    pub fn transaction(&self) -> Transaction<'_> {
+        Transaction::new(self)
+    }
+
+    pub fn query<T>(&self) -> QueryBuilder<'_, T>
+    where
+        T: Schema + CompileTimeSchema + DeserializeOwned + Send + Sync,
     {
         QueryBuilder::new(self)
     }
 }
 
+// Key prefixes of everything stored for `table`: its records and its
+// `#[unique]` claims
+fn table_prefixes(table: &str) -> [Vec<u8>; 2] {
+    [record_key(table, &[]), unique_prefix(table)]
+}
+
+type Comparator<T> = Box<dyn Fn(&T, &T) -> std::cmp::Ordering + Send + Sync>;
+
+// What a query reads: the live database or a snapshot of it
+#[derive(Clone, Copy)]
+enum Source<'a> {
+    Live(&'a Database),
+    Snapshot(&'a Snapshot),
+}
+
+impl Source<'_> {
+    fn codec(&self) -> Codec {
+        match self {
+            Source::Live(db) => db.codec,
+            Source::Snapshot(snapshot) => snapshot.codec,
+        }
+    }
+
+    async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, DbError> {
+        match self {
+            Source::Live(db) => db.read_storage().await?.get(key),
+            Source::Snapshot(snapshot) => snapshot.storage.get(key),
+        }
+    }
+
+    // Values of whichever `keys` exist, under a single lock
+    async fn get_many(&self, keys: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>, DbError> {
+        let mut values = Vec::with_capacity(keys.len());
+        match self {
+            Source::Live(db) => values.extend(db.read_storage().await?.get_many(&keys)?.into_iter().flatten()),
+            Source::Snapshot(snapshot) => {
+                for key in keys {
+                    values.extend(snapshot.storage.get(&key)?);
+                }
+            }
+        }
+        Ok(values)
+    }
+
+    // Serialized records of `table`, in key order
+    async fn table_values(&self, table: &str) -> Result<Vec<Vec<u8>>, DbError> {
+        let prefix = record_key(table, &[]);
+        let entries = match self {
+            Source::Live(db) => {
+                db.table_scans.fetch_add(1, Ordering::Relaxed);
+                db.read_storage().await?.scan_prefix(&prefix)?
+            }
+            Source::Snapshot(snapshot) => snapshot.storage.scan_prefix(&prefix)?,
+        };
+        Ok(entries.into_iter().map(|(_, data)| data).collect())
+    }
+
+    async fn all<T>(&self) -> Result<Vec<T>, DbError>
+    where
+        T: Schema + CompileTimeSchema + DeserializeOwned,
+    {
+        self.table_values(T::table_name())
+            .await?
+            .into_iter()
+            .map(|data| {
+                record::decode::<T>(self.codec(), &data)
+            })
+            .collect()
+    }
+
+    async fn scan_prefix_keys(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, DbError> {
+        match self {
+            Source::Live(db) => {
+                db.table_scans.fetch_add(1, Ordering::Relaxed);
+                db.read_storage().await?.scan_prefix_keys(prefix)
+            }
+            Source::Snapshot(snapshot) => snapshot.storage.scan_prefix_keys(prefix),
+        }
+    }
+}
+
 // Example query builder
 pub struct QueryBuilder<'a, T> {
-    db: &'a Database,
-    filters: Vec<Box<dyn Fn(&T) -> bool + Send + Sync>>,
+    source: Source<'a>,
+    filter: Predicate<T>,
+    // `filter_indexed` conditions every match has to meet; each one is also
+    // in `filter`
+    indexed: Vec<(String, IndexOp, Option<IndexKey>)>,
+    order_by: Option<Comparator<T>>,
+    descending: bool,
+    limit: Option<usize>,
+    offset: usize,
+    #[cfg(feature = "parallel")]
+    parallel: bool,
     _phantom: std::marker::PhantomData<T>,
 }
 
 impl<'a, T> QueryBuilder<'a, T>
 where
-    T: Schema + DeserializeOwned + Send + Sync,
+    T: Schema + CompileTimeSchema + DeserializeOwned + Send + Sync,
 {
     pub fn new(db: &'a Database) -> Self {
+        Self::from_source(Source::Live(db))
+    }
+
+    fn from_source(source: Source<'a>) -> Self {
         QueryBuilder {
-            db,
-            filters: Vec::new(),
+            source,
+            filter: Predicate::always(),
+            indexed: Vec::new(),
+            order_by: None,
+            descending: false,
+            limit: None,
+            offset: 0,
+            #[cfg(feature = "parallel")]
+            parallel: false,
             _phantom: std::marker::PhantomData,
         }
     }
 
-    pub fn filter<F>(mut self, filter: F) -> Self
+    pub fn filter<F>(self, filter: F) -> Self
+    where
+        F: Fn(&T) -> bool + 'static + Send + Sync,
+    {
+        self.matching(Predicate::new(filter))
+    }
+
+    /// Keep records that also pass `filter`, the same as
+    /// [`QueryBuilder::filter`]. It's only run on records that passed the
+    /// filters before it.
+    pub fn and<F>(self, filter: F) -> Self
     where
         F: Fn(&T) -> bool + 'static + Send + Sync,
     {
-        self.filters.push(Box::new(filter));
+        self.matching(Predicate::new(filter))
+    }
+
+    /// Keep records that pass either every filter so far or `filter`, which
+    /// only runs on records the filters so far turned down. So
+    /// `.filter(a).and(b).or(c)` keeps `(a AND b) OR c`. With no filter
+    /// before it, this is the same as [`QueryBuilder::filter`].
+    pub fn or<F>(self, filter: F) -> Self
+    where
+        F: Fn(&T) -> bool + 'static + Send + Sync,
+    {
+        self.or_matching(Predicate::new(filter))
+    }
+
+    /// Keep records that also pass `predicate`, to AND a group of
+    /// conditions built as a [`Predicate`].
+    pub fn matching(mut self, predicate: Predicate<T>) -> Self {
+        self.filter = std::mem::replace(&mut self.filter, Predicate::always()).and(predicate);
+        self
+    }
+
+    /// Keep records that pass either every filter so far or `predicate`,
+    /// as [`QueryBuilder::or`] does.
+    pub fn or_matching(mut self, predicate: Predicate<T>) -> Self {
+        if self.filter.is_always() {
+            return self.matching(predicate);
+        }
+        self.filter = std::mem::replace(&mut self.filter, Predicate::always()).or(predicate);
+        // A match no longer has to meet the indexed conditions so far, so
+        // they can't narrow the scan; `filter` still checks them
+        self.indexed.clear();
+        self
+    }
+
+    /// Keep records whose `field` compares to `value` by `op`, e.g.
+    /// `filter_indexed("age", IndexOp::Gt, 25)`.
+    ///
+    /// With an index from `Database::create_index` on `field`, only the
+    /// matching records are read. Without one this is an ordinary filter
+    /// over a full scan. `value` must serialize to a scalar (number, string,
+    /// bool or null); values of a different kind never match.
+    pub fn filter_indexed<V>(mut self, field: &str, op: IndexOp, value: V) -> Self
+    where
+        T: Serialize,
+        V: Serialize,
+    {
+        let target = IndexKey::from_value(&value);
+        let name = field.to_string();
+        let wanted = target.clone();
+        self = self.filter(move |item| {
+            match (IndexKey::from_field(item, &name), &wanted) {
+                (Some(actual), Some(wanted)) => actual.satisfies(op, wanted),
+                _ => false,
+            }
+        });
+        self.indexed.push((field.to_string(), op, target));
+        self
+    }
+
+    /// Keep records whose `field` compares to `value` by `op`, e.g.
+    /// `filter_field(User::AGE, IndexOp::Gt, 25)` with the constants
+    /// `#[derive(Schema)]` generates.
+    ///
+    /// The typed form of [`QueryBuilder::filter_indexed`]: it uses an index
+    /// on the field the same way, and compares the same way without one,
+    /// but `value` has to be of the field's type.
+    pub fn filter_field<V>(mut self, field: Field<T, V>, op: IndexOp, value: V) -> Self
+    where
+        T: 'static,
+        V: Serialize + 'static,
+    {
+        let target = IndexKey::from_value(&value);
+        let wanted = target.clone();
+        self = self.filter(move |item| {
+            match (IndexKey::from_value(field.get(item)), &wanted) {
+                (Some(actual), Some(wanted)) => actual.satisfies(op, wanted),
+                _ => false,
+            }
+        });
+        self.indexed.push((field.name().to_string(), op, target));
+        self
+    }
+
+    /// Sort matching records by `key_fn`. Records with equal keys keep scan order.
+    pub fn order_by<K, F>(mut self, key_fn: F) -> Self
+    where
+        K: Ord,
+        F: Fn(&T) -> K + 'static + Send + Sync,
+    {
+        self.order_by = Some(Box::new(move |a, b| key_fn(a).cmp(&key_fn(b))));
+        self
+    }
+
+    /// Decode and filter records on Rayon's thread pool in `execute`, for
+    /// filters costly enough to outweigh handing records between threads.
+    /// Results come back in the same order either way. Needs the `parallel`
+    /// feature.
+    ///
+    /// Every candidate record is filtered, so an unordered query with a
+    /// `limit` no longer stops at the last match it needs.
+    #[cfg(feature = "parallel")]
+    pub fn parallel(mut self) -> Self {
+        self.parallel = true;
+        self
+    }
+
+    /// Sort in ascending order (the default).
+    pub fn asc(mut self) -> Self {
+        self.descending = false;
+        self
+    }
+
+    /// Sort in descending order.
+    pub fn desc(mut self) -> Self {
+        self.descending = true;
+        self
+    }
+
+    /// Return at most `limit` matching records.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Skip the first `offset` matching records.
+    pub fn offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
         self
     }
 
     pub async fn execute(self) -> Result<Vec<T>, DbError> {
-        // Simplified: Scan all items (in real DB would use indexes)
+        if self.limit == Some(0) {
+            return Ok(Vec::new());
+        }
+
+        let entries = self.candidates().await?;
+        #[cfg(feature = "parallel")]
+        let mut results = if self.parallel {
+            self.filter_parallel(entries)?
+        } else {
+            self.filter_entries(entries)?
+        };
+        #[cfg(not(feature = "parallel"))]
+        let mut results = self.filter_entries(entries)?;
+
+        if let Some(compare) = &self.order_by {
+            // `sort_by` is stable, so equal keys stay in scan order either way
+            if self.descending {
+                results.sort_by(|a, b| compare(b, a));
+            } else {
+                results.sort_by(|a, b| compare(a, b));
+            }
+            results = results
+                .into_iter()
+                .skip(self.offset)
+                .take(self.limit.unwrap_or(usize::MAX))
+                .collect();
+        }
+
+        Ok(results)
+    }
+
+    /// [`QueryBuilder::execute`], keeping only what `project` takes from
+    /// each matching record, as `SELECT id, email` would. Records are still
+    /// decoded whole.
+    ///
+    /// ```no_run
+    /// # #[derive(rust_db::Schema, serde::Serialize, serde::Deserialize)]
+    /// # #[table_name = "User"]
+    /// # struct User { #[primary_key] id: u64, email: String, age: u32 }
+    /// # async fn run(db: &rust_db::Database) -> Result<(), rust_db::DbError> {
+    /// let contacts: Vec<(u64, String)> = db
+    ///     .query::<User>()
+    ///     .filter(|u| u.age >= 18)
+    ///     .select(|u| (u.id, u.email))
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn select<P, F>(self, project: F) -> Result<Vec<P>, DbError>
+    where
+        F: FnMut(T) -> P,
+    {
+        Ok(self.execute().await?.into_iter().map(project).collect())
+    }
+
+    /// The first matching record, in `order_by` order if there is one,
+    /// without collecting the rest.
+    ///
+    /// Unordered, records are read one at a time, as by
+    /// [`QueryBuilder::stream`], until one matches. Ordered, every match has
+    /// to be seen, but only the first so far is kept.
+    pub async fn first(self) -> Result<Option<T>, DbError>
+    where
+        T: 'a,
+    {
+        if self.limit == Some(0) {
+            return Ok(None);
+        }
+        let Some(compare) = self.order_by.as_ref().filter(|_| self.offset == 0) else {
+            let mut matches = std::pin::pin!(self.limit(1).stream());
+            return matches.next().await.transpose();
+        };
+
+        let mut first: Option<T> = None;
+        for data in self.candidates().await? {
+            let item = record::decode::<T>(self.source.codec(), &data)?;
+            if !self.filter.matches(&item) {
+                continue;
+            }
+            // Ties keep the earlier record, as the stable sort in `execute` does
+            let wanted = if self.descending { std::cmp::Ordering::Greater } else { std::cmp::Ordering::Less };
+            if first.as_ref().is_none_or(|current| compare(&item, current) == wanted) {
+                first = Some(item);
+            }
+        }
+        Ok(first)
+    }
+
+    /// The one matching record: [`DbError::NotFound`] if there's none, and
+    /// [`DbError::MultipleResults`] if there's more than one. Records are
+    /// read only up to the second match, whatever limit is set.
+    pub async fn one(self) -> Result<T, DbError>
+    where
+        T: 'a,
+    {
+        let mut matches = std::pin::pin!(self.limit(2).stream());
+        let Some(item) = matches.next().await.transpose()? else {
+            return Err(DbError::NotFound { table: T::table_name().to_string(), key: String::new() });
+        };
+        match matches.next().await.transpose()? {
+            Some(_) => Err(DbError::MultipleResults { table: T::table_name().to_string() }),
+            None => Ok(item),
+        }
+    }
+
+    // The records among `entries` that pass the filters, in scan order.
+    // Limit and offset count records that passed the filters. Sorting needs
+    // every match first, so only an unordered scan applies them, and stops
+    // early.
+    fn filter_entries(&self, entries: Vec<Vec<u8>>) -> Result<Vec<T>, DbError> {
         let mut results = Vec::new();
-        let table_name = T::table_name();
-        
-        // This is a placeholder - real implementation would iterate properly
-        if let Some(item) = self.db.get::<T>(table_name).await? {
-            if self.filters.iter().all(|f| f(&item)) {
-                results.push(item);
+        let mut skipped = 0;
+        for data in entries {
+            let item = record::decode::<T>(self.source.codec(), &data)?;
+            if !self.filter.matches(&item) {
+                continue;
+            }
+            if self.order_by.is_none() && skipped < self.offset {
+                skipped += 1;
+                continue;
+            }
+            results.push(item);
+            if self.order_by.is_none() && Some(results.len()) == self.limit {
+                break;
             }
         }
-        
         Ok(results)
     }
-}
\ No newline at end of file
+
+    // `filter_entries` on Rayon's pool. Every entry is decoded and
+    // filtered, limit or not; the matches come back in scan order.
+    #[cfg(feature = "parallel")]
+    fn filter_parallel(&self, entries: Vec<Vec<u8>>) -> Result<Vec<T>, DbError> {
+        use rayon::prelude::*;
+
+        let codec = self.source.codec();
+        let matches = entries
+            .par_iter()
+            .map(|data| {
+                let item = record::decode::<T>(codec, data)?;
+                Ok(self.filter.matches(&item).then_some(item))
+            })
+            .collect::<Result<Vec<_>, DbError>>()?
+            .into_iter()
+            .flatten();
+        Ok(match self.order_by {
+            Some(_) => matches.collect(),
+            None => matches.skip(self.offset).take(self.limit.unwrap_or(usize::MAX)).collect(),
+        })
+    }
+
+    /// Number of matching records, after `offset` and `limit`.
+    ///
+    /// Without filters this only counts keys under the table prefix and
+    /// never deserializes a value. With filters each record is decoded and
+    /// checked, but none are kept.
+    pub async fn count(self) -> Result<usize, DbError> {
+        let prefix = record_key(T::table_name(), &[]);
+        let matches = if self.filter.is_always() {
+            self.source.scan_prefix_keys(&prefix).await?.len()
+        } else {
+            let entries = self.candidates().await?;
+            let mut matches = 0;
+            for data in entries {
+                let item = record::decode::<T>(self.source.codec(), &data)?;
+                if self.filter.matches(&item) {
+                    matches += 1;
+                }
+            }
+            matches
+        };
+
+        let count = matches.saturating_sub(self.offset);
+        Ok(self.limit.map_or(count, |limit| count.min(limit)))
+    }
+
+    /// Keys of the records that can match, in key order: the intersection of
+    /// the indexed lookups, or `None` to scan the whole table. Indexes track
+    /// the live database, so snapshot queries always scan.
+    async fn candidate_keys(&self) -> Option<Vec<Vec<u8>>> {
+        let Source::Live(db) = self.source else {
+            return None;
+        };
+        let indexes = db.indexes.read().await;
+        let mut candidates: Option<BTreeSet<Vec<u8>>> = None;
+        for (field, op, target) in &self.indexed {
+            let matches = match target {
+                Some(target) => match indexes.lookup(T::table_name(), field, *op, target) {
+                    Some(matches) => matches,
+                    None => continue,
+                },
+                None => BTreeSet::new(),
+            };
+            candidates = Some(match candidates {
+                Some(current) => current.intersection(&matches).cloned().collect(),
+                None => matches,
+            });
+        }
+
+        let keys = candidates?
+            .into_iter()
+            .map(|primary_key| record_key(T::table_name(), &primary_key))
+            .collect();
+        Some(keys)
+    }
+
+    /// Serialized records to run the filters over, in key order.
+    async fn candidates(&self) -> Result<Vec<Vec<u8>>, DbError> {
+        match self.candidate_keys().await {
+            Some(keys) => self.source.get_many(keys).await,
+            None => self.source.table_values(T::table_name()).await,
+        }
+    }
+
+    /// Yield matching records lazily instead of collecting them.
+    ///
+    /// Only the table's keys are snapshotted up front; each record is read
+    /// and filtered as the stream is polled, holding the storage lock just
+    /// for that one read. Ordering needs every match, so an `order_by`
+    /// query is materialized first.
+    pub fn stream(self) -> impl Stream<Item = Result<T, DbError>> + 'a
+    where
+        T: 'a,
+    {
+        if self.order_by.is_some() {
+            return futures::stream::once(self.execute())
+                .flat_map(|results| {
+                    let items = match results {
+                        Ok(items) => items.into_iter().map(Ok).collect(),
+                        Err(e) => vec![Err(e)],
+                    };
+                    futures::stream::iter(items)
+                })
+                .left_stream();
+        }
+
+        let state = StreamState {
+            query: self,
+            keys: None,
+            skipped: 0,
+            yielded: 0,
+        };
+        futures::stream::unfold(state, |mut state| async move {
+            let item = state.next_item().await?;
+            Some((item, state))
+        })
+        .right_stream()
+    }
+}
+
+struct StreamState<'a, T> {
+    query: QueryBuilder<'a, T>,
+    keys: Option<std::vec::IntoIter<Vec<u8>>>,
+    skipped: usize,
+    yielded: usize,
+}
+
+impl<T> StreamState<'_, T>
+where
+    T: Schema + CompileTimeSchema + DeserializeOwned + Send + Sync,
+{
+    async fn next_item(&mut self) -> Option<Result<T, DbError>> {
+        if Some(self.yielded) == self.query.limit {
+            return None;
+        }
+
+        let keys = match &mut self.keys {
+            Some(keys) => keys,
+            None => {
+                let snapshot = match self.query.candidate_keys().await {
+                    Some(keys) => Ok(keys),
+                    None => {
+                        let prefix = record_key(T::table_name(), &[]);
+                        self.query.source.scan_prefix_keys(&prefix).await
+                    }
+                };
+                match snapshot {
+                    Ok(keys) => self.keys.insert(keys.into_iter()),
+                    Err(e) => {
+                        // End the stream after reporting the failed scan
+                        self.keys = Some(Vec::new().into_iter());
+                        return Some(Err(e));
+                    }
+                }
+            }
+        };
+
+        for key in keys.by_ref() {
+            // Records deleted since the key snapshot are skipped
+            let data = match self.query.source.get(&key).await {
+                Ok(Some(data)) => data,
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            };
+            let item = match record::decode::<T>(self.query.source.codec(), &data) {
+                Ok(item) => item,
+                Err(e) => return Some(Err(e)),
+            };
+            if !self.query.filter.matches(&item) {
+                continue;
+            }
+            if self.skipped < self.query.offset {
+                self.skipped += 1;
+                continue;
+            }
+            self.yielded += 1;
+            return Some(Ok(item));
+        }
+
+        None
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+
+    crate::schema! {
+        table_name: "Page",
+        #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct Page {
+            #[primary_key]
+            id: u64,
+            body: String,
+        }
+    }
+    crate::impl_basic_schema!(Page, "Page");
+
+    // Laid out like a page, so only the table tells them apart
+    crate::schema! {
+        table_name: "Note",
+        #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct Note {
+            #[primary_key]
+            id: u64,
+            body: String,
+        }
+    }
+    crate::impl_basic_schema!(Note, "Note");
+
+    fn page(id: u64) -> Page {
+        Page { id, body: format!("page {}", id) }
+    }
+
+    #[tokio::test]
+    async fn reading_another_tables_record_is_a_schema_mismatch() -> Result<(), DbError> {
+        let dir = tempfile::tempdir()?;
+        let db = Database::builder(dir.path()).open().await?;
+        let key = db.insert(&page(1)).await?;
+
+        // The stored page, copied under the note with the same id
+        let storage = db.read_storage().await?;
+        let value = storage.get(&key)?.expect("the page's entry");
+        storage.write(StorageOp::Insert(record_key("Note", &1u64.to_be_bytes()), value))?;
+        drop(storage);
+
+        let read = db.get::<Note>("1").await;
+        assert!(
+            matches!(&read, Err(DbError::SchemaMismatch { expected, found }) if expected == "Note" && found == "Page"),
+            "{:?}",
+            read
+        );
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn gets_share_the_storage_lock() -> Result<(), DbError> {
+        let dir = tempfile::tempdir()?;
+        let db = Arc::new(Database::builder(dir.path()).open().await?);
+        for id in 0..100 {
+            db.insert(&page(id)).await?;
+            if id == 50 {
+                db.flush().await?;
+            }
+        }
+
+        // Held throughout, so a get that wanted the lock to itself would
+        // never finish
+        let held = db.read_storage().await?;
+        let readers: Vec<_> = (0..16u64)
+            .map(|reader| {
+                let db = Arc::clone(&db);
+                tokio::spawn(async move {
+                    let mut pages = Vec::new();
+                    for id in (reader..100).step_by(16) {
+                        pages.push(db.get_by_key::<Page, _>(id).await?);
+                    }
+                    Ok::<_, DbError>(pages)
+                })
+            })
+            .collect();
+        for (reader, task) in readers.into_iter().enumerate() {
+            let pages = tokio::time::timeout(Duration::from_secs(5), task)
+                .await
+                .expect("get waited on the shared lock")
+                .expect("reader task")?;
+            let expected: Vec<_> = (reader as u64..100).step_by(16).map(|id| Some(page(id))).collect();
+            assert_eq!(pages, expected);
+        }
+
+        // A delete does have to wait
+        let delete = tokio::time::timeout(Duration::from_millis(50), db.delete::<Page>("1")).await;
+        assert!(delete.is_err());
+        drop(held);
+        db.delete::<Page>("1").await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn reads_give_up_after_the_lock_timeout() -> Result<(), DbError> {
+        let dir = tempfile::tempdir()?;
+        let timeout = Duration::from_millis(50);
+        let db = Arc::new(Database::builder(dir.path()).lock_timeout(timeout).open().await?);
+        db.insert(&page(1)).await?;
+
+        let (locked, wait_for_lock) = tokio::sync::oneshot::channel();
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        let holder = {
+            let db = Arc::clone(&db);
+            tokio::spawn(async move {
+                let guard = db.write_storage().await?;
+                let _ = locked.send(());
+                let _ = released.await;
+                drop(guard);
+                Ok::<_, DbError>(())
+            })
+        };
+        wait_for_lock.await.expect("holder took the lock");
+
+        let scanned = db.scan::<Page, u64>(..).await;
+        assert!(matches!(scanned, Err(DbError::Timeout(t)) if t == timeout), "{:?}", scanned);
+        let got = db.get::<Page>("1").await;
+        assert!(matches!(got, Err(DbError::Timeout(_))), "{:?}", got);
+        let metrics = db.metrics().await;
+        assert!(matches!(metrics, Err(DbError::Timeout(_))), "{:?}", metrics);
+
+        release.send(()).expect("holder waits for the release");
+        holder.await.expect("holder task")?;
+        assert_eq!(db.scan::<Page, u64>(..).await?, vec![page(1)]);
+        Ok(())
+    }
+}
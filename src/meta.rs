@@ -0,0 +1,197 @@
+//! The database's own metadata, kept in the storage next to the records,
+//! under keys of its own, so it goes through the WAL, flushes, compaction
+//! and backups like they do.
+//!
+//! Each piece is an entry of its own, integers little-endian:
+//!
+//! - `~codec`: the record codec's [`Codec::id`], `u32`
+//! - `~seq:<table>`: the last auto-increment id handed out, `u64`
+//! - `~table:<table>`: empty, for every table a record has been written to
+//! - `~schema:<table>`: the newest `CompileTimeSchema::SCHEMA_VERSION` a
+//!   record of the table was written with, `u32`
+//!
+//! It's read before anything else on open. A change is written ahead of
+//! the records that depend on it, in the same atomic WAL batch, so an id
+//! or table never reaches the disk without its metadata.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::PoisonError;
+
+use crate::codec::Codec;
+use crate::error::DbError;
+use crate::key::{meta_key, META_PREFIX};
+use crate::storage::{KeyValue, LsmStorage, StorageOp};
+use crate::Database;
+
+#[derive(Debug, Default)]
+pub(crate) struct Metadata {
+    /// [`Codec::id`] of the record codec
+    pub codec: u32,
+    /// Last auto-increment id handed out, per table
+    pub sequences: BTreeMap<String, u64>,
+    /// Every table a record has been written to
+    pub tables: BTreeSet<String>,
+    /// Newest schema version a record was written with, per table
+    pub schema_versions: BTreeMap<String, u32>,
+}
+
+impl Metadata {
+    // Its storage entries, as the module docs lay them out
+    fn entries(&self) -> BTreeMap<Vec<u8>, Vec<u8>> {
+        let mut entries = BTreeMap::new();
+        entries.insert(meta_key("codec"), self.codec.to_le_bytes().to_vec());
+        for (table, last) in &self.sequences {
+            entries.insert(meta_key(&format!("seq:{}", table)), last.to_le_bytes().to_vec());
+        }
+        for table in &self.tables {
+            entries.insert(meta_key(&format!("table:{}", table)), Vec::new());
+        }
+        for (table, version) in &self.schema_versions {
+            entries.insert(meta_key(&format!("schema:{}", table)), version.to_le_bytes().to_vec());
+        }
+        entries
+    }
+
+    // The metadata in `storage`, or `None` if it has none yet
+    fn read(storage: &LsmStorage) -> Result<Option<Self>, DbError> {
+        let mut meta = Metadata::default();
+        let mut codec = None;
+        for (key, value) in storage.scan_prefix(META_PREFIX)? {
+            let name = String::from_utf8_lossy(&key[META_PREFIX.len()..]).into_owned();
+            let (kind, table) = name.split_once(':').unwrap_or((&name, ""));
+            match kind {
+                "codec" => codec = Some(u32::from_le_bytes(fixed(&key, &value)?)),
+                "seq" => {
+                    meta.sequences.insert(table.to_string(), u64::from_le_bytes(fixed(&key, &value)?));
+                }
+                "table" => {
+                    meta.tables.insert(table.to_string());
+                }
+                "schema" => {
+                    meta.schema_versions.insert(table.to_string(), u32::from_le_bytes(fixed(&key, &value)?));
+                }
+                // Written by a later version, which knows what it is
+                _ => {}
+            }
+        }
+        Ok(codec.map(|codec| Metadata { codec, ..meta }))
+    }
+}
+
+// A metadata value of a fixed width
+fn fixed<const N: usize>(key: &[u8], value: &[u8]) -> Result<[u8; N], DbError> {
+    value
+        .try_into()
+        .map_err(|_| DbError::SerializationError(format!("malformed metadata entry {}", key.escape_ascii())))
+}
+
+/// The open database's [`Metadata`], and its changes not written yet.
+#[derive(Debug)]
+pub(crate) struct MetaStore {
+    meta: Metadata,
+    // Entries changed in `meta`, oldest first, for the next record write to
+    // take along
+    unwritten: Vec<KeyValue>,
+    // Whether changes fail with `ReadOnly` instead
+    read_only: bool,
+}
+
+impl MetaStore {
+    pub(crate) fn get(&self) -> &Metadata {
+        &self.meta
+    }
+
+    /// Change the metadata with `change`. The entries it changes are
+    /// written along with the next records written, from
+    /// [`MetaStore::unwritten`]. No change removes an entry.
+    pub(crate) fn update(&mut self, change: impl FnOnce(&mut Metadata)) -> Result<(), DbError> {
+        if self.read_only {
+            return Err(DbError::ReadOnly);
+        }
+        let before = self.meta.entries();
+        change(&mut self.meta);
+        let changed = self.meta.entries().into_iter().filter(|(key, value)| before.get(key) != Some(value));
+        self.unwritten.extend(changed);
+        Ok(())
+    }
+
+    /// The entries changed since they were last written, to write ahead
+    /// of the records that may need them.
+    pub(crate) fn unwritten(&self) -> Vec<KeyValue> {
+        self.unwritten.clone()
+    }
+
+    /// Note that the first `count` of [`MetaStore::unwritten`] are written.
+    pub(crate) fn written(&mut self, count: usize) {
+        self.unwritten.drain(..count);
+    }
+}
+
+/// Read the metadata of the database `storage` holds, before anything else,
+/// and return it with the codec to use.
+///
+/// An existing database keeps the codec it was created with; asking for a
+/// different one is an error rather than a silent switch. Opened
+/// writable, a new database has its metadata written.
+pub(crate) fn open(storage: &LsmStorage, requested: Option<Codec>) -> Result<(Codec, MetaStore), DbError> {
+    let dir = storage.dir();
+    let meta = Metadata::read(storage)?;
+    let recorded = match &meta {
+        Some(meta) => Some(
+            Codec::from_id(meta.codec)
+                .map_err(|reason| DbError::SerializationError(format!("database {}: {}", dir.display(), reason)))?,
+        ),
+        None => None,
+    };
+
+    if let (Some(recorded), Some(requested)) = (recorded, requested) {
+        if recorded != requested {
+            return Err(DbError::StorageError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "database {} stores records as {}, but {} was requested",
+                    dir.display(),
+                    recorded,
+                    requested
+                ),
+            )));
+        }
+    }
+
+    let codec = recorded.or(requested).unwrap_or_default();
+    let read_only = storage.is_read_only();
+    let meta = match meta {
+        Some(meta) => meta,
+        None => {
+            let meta = Metadata { codec: codec.id(), ..Metadata::default() };
+            if !read_only {
+                storage.write_atomic(meta.entries().into_iter().map(|(key, value)| StorageOp::Insert(key, value)).collect())?;
+            }
+            meta
+        }
+    };
+    Ok((codec, MetaStore { meta, unwritten: Vec::new(), read_only }))
+}
+
+impl Database {
+    // Write `ops` with the metadata changes not written yet ahead of them,
+    // in one atomic batch, since the records in it may need them. The
+    // metadata stays locked while there are any, so a concurrent write
+    // neither takes them along as well nor gets into the storage first.
+    pub(crate) fn write_with_meta(&self, storage: &LsmStorage, mut ops: Vec<StorageOp>) -> Result<(), DbError> {
+        let mut meta = self.meta.lock().unwrap_or_else(PoisonError::into_inner);
+        let unwritten = meta.unwritten();
+        if unwritten.is_empty() {
+            drop(meta);
+            return match ops.len() {
+                1 => storage.write(ops.remove(0)),
+                _ => storage.write_atomic(ops),
+            };
+        }
+        let count = unwritten.len();
+        ops.splice(0..0, unwritten.into_iter().map(|(key, value)| StorageOp::Insert(key, value)));
+        storage.write_atomic(ops)?;
+        meta.written(count);
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+//! Record tests combined with AND and OR, for `QueryBuilder`.
+
+type Filter<T> = Box<dyn Fn(&T) -> bool + Send + Sync>;
+
+/// Tests on a record combined with [`and`](Predicate::and) and
+/// [`or`](Predicate::or), to group conditions in a query with
+/// [`QueryBuilder::matching`](crate::QueryBuilder::matching) and
+/// [`QueryBuilder::or_matching`](crate::QueryBuilder::or_matching).
+///
+/// Tests run in the order they were combined, and stop as soon as the
+/// outcome is decided: a failing test ends an AND, a passing one ends an OR.
+/// Put the cheap tests first to spare the costly ones.
+///
+/// ```no_run
+/// # #[derive(rust_db::Schema, serde::Serialize, serde::Deserialize)]
+/// # #[table_name = "User"]
+/// # struct User { #[primary_key] id: u64, age: u32, country: String, vip: bool }
+/// # async fn run(db: &rust_db::Database) -> Result<(), rust_db::DbError> {
+/// use rust_db::Predicate;
+///
+/// // vip AND (age > 25 OR country == "US")
+/// let older = Predicate::new(|u: &User| u.age > 25);
+/// let american = Predicate::new(|u: &User| u.country == "US");
+/// let users = db.query::<User>().filter(|u| u.vip).matching(older.or(american)).execute().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Predicate<T>(Node<T>);
+
+enum Node<T> {
+    Test(Filter<T>),
+    // Every one passes; none at all always does
+    All(Vec<Predicate<T>>),
+    // Any one passes
+    Any(Vec<Predicate<T>>),
+}
+
+impl<T> Predicate<T> {
+    /// A predicate that passes when `test` does.
+    pub fn new<F>(test: F) -> Self
+    where
+        F: Fn(&T) -> bool + 'static + Send + Sync,
+    {
+        Predicate(Node::Test(Box::new(test)))
+    }
+
+    /// Passes when both `self` and `other` do. `other` only runs once
+    /// `self` has passed.
+    pub fn and(self, other: Predicate<T>) -> Self {
+        let mut all = match self.0 {
+            Node::All(all) => all,
+            node => vec![Predicate(node)],
+        };
+        match other.0 {
+            Node::All(more) => all.extend(more),
+            node => all.push(Predicate(node)),
+        }
+        Predicate(Node::All(all))
+    }
+
+    /// Passes when `self` or `other` does. `other` only runs once `self`
+    /// has failed.
+    pub fn or(self, other: Predicate<T>) -> Self {
+        let mut any = match self.0 {
+            Node::Any(any) => any,
+            node => vec![Predicate(node)],
+        };
+        match other.0 {
+            Node::Any(more) => any.extend(more),
+            node => any.push(Predicate(node)),
+        }
+        Predicate(Node::Any(any))
+    }
+
+    /// Whether `item` passes.
+    pub fn matches(&self, item: &T) -> bool {
+        match &self.0 {
+            Node::Test(test) => test(item),
+            Node::All(all) => all.iter().all(|predicate| predicate.matches(item)),
+            Node::Any(any) => any.iter().any(|predicate| predicate.matches(item)),
+        }
+    }
+
+    /// A predicate every record passes, with no tests in it.
+    pub(crate) fn always() -> Self {
+        Predicate(Node::All(Vec::new()))
+    }
+
+    /// Whether this is [`Predicate::always`], that no record can fail.
+    pub(crate) fn is_always(&self) -> bool {
+        matches!(&self.0, Node::All(all) if all.is_empty())
+    }
+}
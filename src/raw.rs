@@ -0,0 +1,58 @@
+//! Untyped values stored by key, next to the typed records.
+
+use crate::error::DbError;
+use crate::key::raw_key;
+use crate::storage::StorageOp;
+use crate::Database;
+
+impl Database {
+    /// Store `value` under `key` as plain bytes, e.g. a configuration blob:
+    /// no schema, validation or codec is involved.
+    ///
+    /// Raw keys have a namespace of their own, so any bytes are fine as a
+    /// key and never collide with a record, whatever its table and primary
+    /// key. Indexes and change subscribers don't see raw writes.
+    pub async fn put_raw(&self, key: &[u8], value: &[u8]) -> Result<(), DbError> {
+        // Raw writes touch nothing above the storage, so they share it, and
+        // go ahead side by side in different memtable shards
+        self.read_storage().await?.write(StorageOp::Insert(raw_key(key), value.to_vec()))
+    }
+
+    /// The bytes [`Database::put_raw`] last stored under `key`, if any, with
+    /// every [`Database::merge`] since combined into them.
+    pub async fn get_raw(&self, key: &[u8]) -> Result<Option<Vec<u8>>, DbError> {
+        self.read_storage().await?.get(&raw_key(key))
+    }
+
+    /// Remove the raw value under `key`, whether or not there is one.
+    pub async fn delete_raw(&self, key: &[u8]) -> Result<(), DbError> {
+        self.read_storage().await?.delete(raw_key(key))
+    }
+
+    /// Change the raw value under `key` by `operand`, without reading it:
+    /// the operand is logged like any write, and combined with the value by
+    /// the `DatabaseBuilder::merge_operator` when [`Database::get_raw`]
+    /// reads it, or earlier once a flush or compaction meets both. A key
+    /// with no value, or a deleted one, starts from `None`.
+    ///
+    /// Fails with [`DbError::NoMergeOperator`] if none is set.
+    ///
+    /// ```no_run
+    /// fn add(existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+    ///     let read = |bytes: &[u8]| u64::from_le_bytes(bytes.try_into().unwrap());
+    ///     (existing.map_or(0, read) + read(operand)).to_le_bytes().to_vec()
+    /// }
+    ///
+    /// # async fn run() -> Result<(), rust_db::DbError> {
+    /// let db = rust_db::Database::builder("./data").merge_operator(add).open().await?;
+    /// for _ in 0..3 {
+    ///     db.merge(b"visits", &1u64.to_le_bytes()).await?;
+    /// }
+    /// assert_eq!(db.get_raw(b"visits").await?, Some(3u64.to_le_bytes().to_vec()));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn merge(&self, key: &[u8], operand: &[u8]) -> Result<(), DbError> {
+        self.read_storage().await?.merge(raw_key(key), operand.to_vec())
+    }
+}
@@ -0,0 +1,160 @@
+//! The envelope every record is stored in.
+//!
+//! ```text
+//! magic: "RREC" | format: u8 | schema_version: u32 | created_at: i64 | updated_at: i64
+//!     | table_len: u16 | table | payload
+//! ```
+//!
+//! `schema_version` is the writing type's [`CompileTimeSchema::SCHEMA_VERSION`],
+//! the timestamps are Unix milliseconds, all little-endian, `table` is the
+//! writing type's [`Schema::table_name`], and `payload` is the record itself
+//! in the database's [`Codec`]. Values without the magic predate the
+//! envelope; they're read as a bare payload at schema version 1.
+
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::codec::Codec;
+use crate::error::DbError;
+use crate::schema::{CompileTimeSchema, Schema};
+use crate::storage::now_millis;
+
+const MAGIC: [u8; 4] = *b"RREC";
+const FORMAT: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 4 + 8 + 8 + 2;
+
+/// When a record was inserted and last written, from
+/// `Database::get_with_meta`.
+///
+/// Both are `None` for records stored before timestamps were kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordMeta {
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+/// A stored record, split from its envelope
+struct Envelope<'a> {
+    schema_version: u32,
+    // Unix milliseconds; these and the table are absent from a bare payload
+    created_at: Option<i64>,
+    updated_at: Option<i64>,
+    table: Option<&'a [u8]>,
+    payload: &'a [u8],
+}
+
+impl<'a> Envelope<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, DbError> {
+        if !data.starts_with(&MAGIC) {
+            return Ok(Envelope {
+                schema_version: 1,
+                created_at: None,
+                updated_at: None,
+                table: None,
+                payload: data,
+            });
+        }
+        let truncated = || DbError::SerializationError("truncated record envelope".to_string());
+        let format = *data.get(MAGIC.len()).ok_or_else(truncated)?;
+        if format != FORMAT {
+            return Err(DbError::SerializationError(format!(
+                "unknown record envelope format {}",
+                format
+            )));
+        }
+        let header = data.get(..HEADER_LEN).ok_or_else(truncated)?;
+        let schema_version = u32::from_le_bytes(header[5..9].try_into().unwrap());
+        let stamp = |at: usize| i64::from_le_bytes(header[at..at + 8].try_into().unwrap());
+        let end = HEADER_LEN + u16::from_le_bytes(header[25..27].try_into().unwrap()) as usize;
+        Ok(Envelope {
+            schema_version,
+            created_at: Some(stamp(9)),
+            updated_at: Some(stamp(17)),
+            table: Some(data.get(HEADER_LEN..end).ok_or_else(truncated)?),
+            payload: &data[end..],
+        })
+    }
+
+    fn meta(&self) -> RecordMeta {
+        RecordMeta {
+            created_at: self.created_at.and_then(DateTime::from_timestamp_millis),
+            updated_at: self.updated_at.and_then(DateTime::from_timestamp_millis),
+        }
+    }
+}
+
+/// Serialize `item` into its envelope, stamped as written now.
+///
+/// `created_at` carries over the creation time of the record this one
+/// replaces; `None` makes it now as well.
+pub(crate) fn encode<T>(codec: Codec, item: &T, created_at: Option<i64>) -> Result<Vec<u8>, DbError>
+where
+    T: Schema + CompileTimeSchema + Serialize,
+{
+    let payload = codec.serialize(item)?;
+    let table = T::table_name().as_bytes();
+    let table_len = u16::try_from(table.len())
+        .map_err(|_| DbError::SchemaError(format!("table name {} is too long", T::table_name())))?;
+    let now = now_millis();
+    let mut data = Vec::with_capacity(HEADER_LEN + table.len() + payload.len());
+    data.extend_from_slice(&MAGIC);
+    data.push(FORMAT);
+    data.extend_from_slice(&T::SCHEMA_VERSION.to_le_bytes());
+    data.extend_from_slice(&created_at.unwrap_or(now).to_le_bytes());
+    data.extend_from_slice(&now.to_le_bytes());
+    data.extend_from_slice(&table_len.to_le_bytes());
+    data.extend_from_slice(table);
+    data.extend_from_slice(&payload);
+    Ok(data)
+}
+
+/// Deserialize a stored record, passing one written under an older schema
+/// version through [`Schema::migrate`].
+///
+/// A record written by a type with a different table name is
+/// [`DbError::SchemaMismatch`] rather than whatever the codec makes of it.
+pub(crate) fn decode<T>(codec: Codec, data: &[u8]) -> Result<T, DbError>
+where
+    T: Schema + CompileTimeSchema + DeserializeOwned,
+{
+    decode_envelope(codec, &Envelope::parse(data)?)
+}
+
+/// Like [`decode`], along with the record's timestamps.
+pub(crate) fn decode_with_meta<T>(codec: Codec, data: &[u8]) -> Result<(T, RecordMeta), DbError>
+where
+    T: Schema + CompileTimeSchema + DeserializeOwned,
+{
+    let envelope = Envelope::parse(data)?;
+    Ok((decode_envelope(codec, &envelope)?, envelope.meta()))
+}
+
+/// When the stored record was first inserted, in Unix milliseconds, if
+/// it's recorded.
+pub(crate) fn created_at(data: &[u8]) -> Result<Option<i64>, DbError> {
+    Ok(Envelope::parse(data)?.created_at)
+}
+
+fn decode_envelope<T>(codec: Codec, envelope: &Envelope<'_>) -> Result<T, DbError>
+where
+    T: Schema + CompileTimeSchema + DeserializeOwned,
+{
+    if let Some(table) = envelope.table {
+        if table != T::table_name().as_bytes() {
+            return Err(DbError::SchemaMismatch {
+                expected: T::table_name().to_string(),
+                found: String::from_utf8_lossy(table).into_owned(),
+            });
+        }
+    }
+    match envelope.schema_version.cmp(&T::SCHEMA_VERSION) {
+        std::cmp::Ordering::Equal => codec.deserialize::<T>(envelope.payload),
+        std::cmp::Ordering::Less => T::migrate(envelope.schema_version, envelope.payload),
+        std::cmp::Ordering::Greater => Err(DbError::SchemaError(format!(
+            "{} record has schema version {}, newer than this build's {}",
+            T::table_name(),
+            envelope.schema_version,
+            T::SCHEMA_VERSION
+        ))),
+    }
+}
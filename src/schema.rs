@@ -2,24 +2,498 @@
 pub trait Schema {
     fn schema_validate(&self) -> Result<(), crate::SchemaError>;
     fn table_name() -> &'static str;
+
+    /// Rebuild a record stored under an older
+    /// [`CompileTimeSchema::SCHEMA_VERSION`] from its serialized `bytes`,
+    /// which [`Codec::deserialize`](crate::Codec::deserialize) can decode
+    /// with the database's codec.
+    ///
+    /// Reads call this for every record older than the current version; the
+    /// stored bytes are left as they are until the record is written again.
+    /// The default has no migrations and fails the read.
+    fn migrate(old_version: u32, bytes: &[u8]) -> Result<Self, crate::DbError>
+    where
+        Self: Sized,
+    {
+        let _ = bytes;
+        Err(crate::DbError::SchemaError(format!(
+            "{} has no migration from schema version {}",
+            Self::table_name(),
+            old_version
+        )))
+    }
+
+    /// Indexes to build for the table, by `Database::create_indexes` or as
+    /// the database opens once the type is passed to
+    /// `DatabaseBuilder::register`. The derive lists every `#[index]` here.
+    fn indexes() -> &'static [crate::IndexDef] {
+        &[]
+    }
+
+    /// The key a record is stored under: `<table>:` followed by its
+    /// [`PrimaryKey::primary_key`], for any type with a key, so `schema!`
+    /// and the derive get it from the `#[primary_key]` field.
+    ///
+    /// `Database::insert` and the other writes store records under it.
+    /// Reads and deletes by key build it from [`PrimaryKey::parse_key`]
+    /// instead, with no record to ask, so an override has to produce the
+    /// same bytes they do.
+    ///
+    /// ```
+    /// use rust_db::Schema;
+    ///
+    /// rust_db::schema! {
+    ///     table_name: "User",
+    ///     #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    ///     struct User {
+    ///         #[primary_key]
+    ///         id: u64,
+    ///         name: String,
+    ///     }
+    /// }
+    /// rust_db::impl_basic_schema!(User, "User");
+    ///
+    /// rust_db::schema! {
+    ///     table_name: "Country",
+    ///     #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    ///     struct Country {
+    ///         name: String,
+    ///         #[primary_key]
+    ///         code: String,
+    ///     }
+    /// }
+    /// rust_db::impl_basic_schema!(Country, "Country");
+    ///
+    /// let user = User { id: 7, name: "Ann".to_string() };
+    /// assert_eq!(user.storage_key(), b"User:\0\0\0\0\0\0\0\x07");
+    ///
+    /// let country = Country { name: "Norway".to_string(), code: "NO".to_string() };
+    /// assert_eq!(country.storage_key(), b"Country:NO");
+    /// ```
+    fn storage_key(&self) -> Vec<u8>
+    where
+        Self: PrimaryKey,
+    {
+        crate::key::record_key(Self::table_name(), &self.primary_key())
+    }
+}
+
+// Per-record key used to address a row inside its table
+pub trait PrimaryKey {
+    fn primary_key(&self) -> Vec<u8>;
+
+    /// Storage encoding of a primary key written out as text, as passed to
+    /// `Database::get`, or `None` if no record of this type can have it.
+    ///
+    /// The macros parse it as the key field's type, so `db.get::<User>("1")`
+    /// finds the user whose `u64` id is 1. The default takes the text as is.
+    fn parse_key(key: &str) -> Option<Vec<u8>>
+    where
+        Self: Sized,
+    {
+        Some(key.as_bytes().to_vec())
+    }
+}
+
+/// A field of record type `T` holding a `V`, for `QueryBuilder::filter_field`.
+///
+/// `#[derive(Schema)]` generates one per field as an associated constant
+/// named after it in upper case, with the field's visibility: `User::AGE`
+/// for `age`.
+pub struct Field<T, V> {
+    name: &'static str,
+    get: fn(&T) -> &V,
+}
+
+impl<T, V> Field<T, V> {
+    #[doc(hidden)]
+    pub const fn new(name: &'static str, get: fn(&T) -> &V) -> Self {
+        Field { name, get }
+    }
+
+    /// The field's name, as `Database::create_index` takes it.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub(crate) fn get<'r>(&self, record: &'r T) -> &'r V {
+        (self.get)(record)
+    }
+}
+
+// Not derived, which would require `T: Clone` and `V: Clone`
+impl<T, V> Clone for Field<T, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
 }
-// Macro to derive Schema implementation with compile-time validation
+
+impl<T, V> Copy for Field<T, V> {}
+
+impl<T, V> std::fmt::Debug for Field<T, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Field").field(&self.name).finish()
+    }
+}
+
+/// Macro to derive Schema implementation with compile-time validation.
+///
+/// Mark one field with `#[primary_key]` to generate a [`PrimaryKey`] impl,
+/// which `Database::insert` uses to store each record under its own key.
+/// The field's type must implement [`AsKeyBytes`](crate::AsKeyBytes).
+///
+/// Fields marked `#[unique]` are listed in
+/// [`CompileTimeSchema::UNIQUE_FIELDS`], and `Database::insert` rejects a
+/// record whose value for one of them is already taken. They also need an
+/// `AsKeyBytes` type.
+///
+/// A `u64` field marked `#[auto_increment]` is assigned the table's next
+/// id by `Database::insert` whenever it's zero.
+/// Ids carry on from the last one across reopens. One whose record was
+/// never written, as in a rolled-back transaction, is skipped rather than
+/// reused.
+///
+/// An optional `schema_version: N,` line after `table_name` sets
+/// [`CompileTimeSchema::SCHEMA_VERSION`].
+///
+/// Other field attributes, `#[serde(..)]` included, are passed through to
+/// the struct. So `#[serde(rename = "..")]` lets a field's Rust name change
+/// while the name it's stored, exported and indexed under stays put:
+///
+/// ```
+/// rust_db::schema! {
+///     table_name: "Account",
+///     #[derive(Debug, serde::Serialize, serde::Deserialize)]
+///     struct Account {
+///         #[primary_key]
+///         id: u64,
+///         #[serde(rename = "mail")]
+///         email: String,
+///     }
+/// }
+///
+/// let account = Account { id: 1, email: "ann@example.com".to_string() };
+/// let json = serde_json::to_string(&account).unwrap();
+/// assert_eq!(json, r#"{"id":1,"mail":"ann@example.com"}"#);
+/// ```
+///
+/// Two fields ending up with the same serialized name fail the build, since
+/// serde would write both under it. Only field-level `rename = ".."` is
+/// taken into account, not `rename_all` on the struct.
+///
+/// ```compile_fail
+/// rust_db::schema! {
+///     table_name: "Account",
+///     #[derive(Debug, serde::Serialize)]
+///     struct Account {
+///         #[primary_key]
+///         id: u64,
+///         #[serde(rename = "name")]
+///         email: String,
+///         name: String,
+///     }
+/// }
+/// ```
+///
+/// Invalid table names are rejected at compile time:
+///
+/// ```compile_fail
+/// rust_db::schema! {
+///     table_name: "Invalid-Table-Name!",  // Contains invalid characters
+///     #[derive(Debug)]
+///     struct InvalidUser {
+///         #[primary_key]
+///         id: u64,
+///         name: String,
+///     }
+/// }
+/// ```
+///
+/// So is a struct with no fields, which has nothing to key its records by:
+///
+/// ```compile_fail
+/// rust_db::schema! {
+///     table_name: "Empty",
+///     #[derive(Debug)]
+///     struct Empty {}
+/// }
+/// ```
+///
+/// And field names with characters a table name couldn't have, such as
+/// raw identifiers:
+///
+/// ```compile_fail
+/// rust_db::schema! {
+///     table_name: "Item",
+///     #[derive(Debug)]
+///     struct Item {
+///         #[primary_key]
+///         id: u64,
+///         r#type: String,  // Stringifies as `r#type`, not serde's `type`
+///     }
+/// }
+/// ```
 #[macro_export]
 macro_rules! schema {
     (
         table_name: $table:literal,
+        $(schema_version: $version:literal,)?
         $(#[$attr:meta])*
         struct $name:ident {
-            $(
-                $(#[$field_attr:meta])*
-                $field:ident: $field_type:ty $(,)?
-            )*
+            $($body:tt)*
+        }
+    ) => {
+        $crate::__schema_fields! {
+            table: [$table $($version)?],
+            attrs: [$(#[$attr])*],
+            name: $name,
+            fields: [],
+            primary_key: [],
+            unique: [],
+            auto: [],
+            pending: [],
+            mark: [],
+            unique_mark: [],
+            auto_mark: [],
+            rest: [$($body)*]
+        }
+    };
+}
+
+// Field muncher behind `schema!`: strips the `#[primary_key]`, `#[unique]`
+// and `#[auto_increment]` markers and forwards every other field attribute
+// untouched.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __schema_fields {
+    // `#[primary_key]` marker on the upcoming field
+    (
+        table: $table:tt, attrs: $attrs:tt, name: $name:ident,
+        fields: $fields:tt, primary_key: $pk:tt, unique: $unique:tt, auto: $auto:tt,
+        pending: $pending:tt, mark: [], unique_mark: $unique_mark:tt, auto_mark: $auto_mark:tt,
+        rest: [#[primary_key] $($rest:tt)*]
+    ) => {
+        $crate::__schema_fields! {
+            table: $table, attrs: $attrs, name: $name,
+            fields: $fields, primary_key: $pk, unique: $unique, auto: $auto,
+            pending: $pending, mark: [primary_key], unique_mark: $unique_mark, auto_mark: $auto_mark,
+            rest: [$($rest)*]
+        }
+    };
+
+    // `#[unique]` marker on the upcoming field
+    (
+        table: $table:tt, attrs: $attrs:tt, name: $name:ident,
+        fields: $fields:tt, primary_key: $pk:tt, unique: $unique:tt, auto: $auto:tt,
+        pending: $pending:tt, mark: $mark:tt, unique_mark: [], auto_mark: $auto_mark:tt,
+        rest: [#[unique] $($rest:tt)*]
+    ) => {
+        $crate::__schema_fields! {
+            table: $table, attrs: $attrs, name: $name,
+            fields: $fields, primary_key: $pk, unique: $unique, auto: $auto,
+            pending: $pending, mark: $mark, unique_mark: [unique], auto_mark: $auto_mark,
+            rest: [$($rest)*]
+        }
+    };
+
+    // `#[auto_increment]` marker on the upcoming field
+    (
+        table: $table:tt, attrs: $attrs:tt, name: $name:ident,
+        fields: $fields:tt, primary_key: $pk:tt, unique: $unique:tt, auto: $auto:tt,
+        pending: $pending:tt, mark: $mark:tt, unique_mark: $unique_mark:tt, auto_mark: [],
+        rest: [#[auto_increment] $($rest:tt)*]
+    ) => {
+        $crate::__schema_fields! {
+            table: $table, attrs: $attrs, name: $name,
+            fields: $fields, primary_key: $pk, unique: $unique, auto: $auto,
+            pending: $pending, mark: $mark, unique_mark: $unique_mark, auto_mark: [auto_increment],
+            rest: [$($rest)*]
+        }
+    };
+
+    // `#[serde(..)]` is kept as plain tokens, so the final step can still
+    // read a `rename` out of it
+    (
+        table: $table:tt, attrs: $attrs:tt, name: $name:ident,
+        fields: $fields:tt, primary_key: $pk:tt, unique: $unique:tt, auto: $auto:tt,
+        pending: [$($pending:tt)*], mark: $mark:tt, unique_mark: $unique_mark:tt, auto_mark: $auto_mark:tt,
+        rest: [#[serde($($serde:tt)*)] $($rest:tt)*]
+    ) => {
+        $crate::__schema_fields! {
+            table: $table, attrs: $attrs, name: $name,
+            fields: $fields, primary_key: $pk, unique: $unique, auto: $auto,
+            pending: [$($pending)* #[serde($($serde)*)]], mark: $mark, unique_mark: $unique_mark, auto_mark: $auto_mark,
+            rest: [$($rest)*]
+        }
+    };
+
+    // Any other field attribute is kept for the generated struct
+    (
+        table: $table:tt, attrs: $attrs:tt, name: $name:ident,
+        fields: $fields:tt, primary_key: $pk:tt, unique: $unique:tt, auto: $auto:tt,
+        pending: [$($pending:tt)*], mark: $mark:tt, unique_mark: $unique_mark:tt, auto_mark: $auto_mark:tt,
+        rest: [#[$field_attr:meta] $($rest:tt)*]
+    ) => {
+        $crate::__schema_fields! {
+            table: $table, attrs: $attrs, name: $name,
+            fields: $fields, primary_key: $pk, unique: $unique, auto: $auto,
+            pending: [$($pending)* #[$field_attr]], mark: $mark, unique_mark: $unique_mark, auto_mark: $auto_mark,
+            rest: [$($rest)*]
+        }
+    };
+
+    // Field, optionally followed by a comma
+    (
+        table: $table:tt, attrs: $attrs:tt, name: $name:ident,
+        fields: [$($fields:tt)*], primary_key: $pk:tt, unique: $unique:tt, auto: $auto:tt,
+        pending: [$($pending:tt)*], mark: $mark:tt, unique_mark: $unique_mark:tt, auto_mark: $auto_mark:tt,
+        rest: [$field:ident : $field_type:ty $(, $($rest:tt)*)?]
+    ) => {
+        $crate::__schema_fields! {
+            @auto $auto_mark,
+            table: $table, attrs: $attrs, name: $name,
+            fields: [$($fields)* { [$($pending)*] $field : $field_type }],
+            primary_key: $pk, unique: $unique, auto: $auto, mark: $mark, unique_mark: $unique_mark,
+            field: $field,
+            rest: [$($($rest)*)?]
+        }
+    };
+
+    (
+        @auto [],
+        table: $table:tt, attrs: $attrs:tt, name: $name:ident,
+        fields: $fields:tt, primary_key: $pk:tt, unique: $unique:tt, auto: $auto:tt,
+        mark: $mark:tt, unique_mark: $unique_mark:tt,
+        field: $field:ident,
+        rest: $rest:tt
+    ) => {
+        $crate::__schema_fields! {
+            @unique $unique_mark,
+            table: $table, attrs: $attrs, name: $name,
+            fields: $fields, primary_key: $pk, unique: $unique, auto: $auto, mark: $mark,
+            field: $field,
+            rest: $rest
+        }
+    };
+
+    (
+        @auto [auto_increment],
+        table: $table:tt, attrs: $attrs:tt, name: $name:ident,
+        fields: $fields:tt, primary_key: $pk:tt, unique: $unique:tt, auto: [],
+        mark: $mark:tt, unique_mark: $unique_mark:tt,
+        field: $field:ident,
+        rest: $rest:tt
+    ) => {
+        $crate::__schema_fields! {
+            @unique $unique_mark,
+            table: $table, attrs: $attrs, name: $name,
+            fields: $fields, primary_key: $pk, unique: $unique, auto: [$field], mark: $mark,
+            field: $field,
+            rest: $rest
+        }
+    };
+
+    (
+        @auto [auto_increment],
+        table: $table:tt, attrs: $attrs:tt, name: $name:ident,
+        fields: $fields:tt, primary_key: $pk:tt, unique: $unique:tt, auto: [$existing:ident],
+        mark: $mark:tt, unique_mark: $unique_mark:tt,
+        field: $field:ident,
+        rest: $rest:tt
+    ) => {
+        compile_error!(concat!(
+            "Only one #[auto_increment] field is allowed, found `",
+            stringify!($existing), "` and `", stringify!($field), "`"
+        ));
+    };
+
+    (
+        @unique [],
+        table: $table:tt, attrs: $attrs:tt, name: $name:ident,
+        fields: $fields:tt, primary_key: $pk:tt, unique: $unique:tt, auto: $auto:tt, mark: $mark:tt,
+        field: $field:ident,
+        rest: $rest:tt
+    ) => {
+        $crate::__schema_fields! {
+            @mark $mark,
+            table: $table, attrs: $attrs, name: $name,
+            fields: $fields, primary_key: $pk, unique: $unique, auto: $auto,
+            field: $field,
+            rest: $rest
+        }
+    };
+
+    (
+        @unique [unique],
+        table: $table:tt, attrs: $attrs:tt, name: $name:ident,
+        fields: $fields:tt, primary_key: $pk:tt, unique: [$($unique:ident)*], auto: $auto:tt, mark: $mark:tt,
+        field: $field:ident,
+        rest: $rest:tt
+    ) => {
+        $crate::__schema_fields! {
+            @mark $mark,
+            table: $table, attrs: $attrs, name: $name,
+            fields: $fields, primary_key: $pk, unique: [$($unique)* $field], auto: $auto,
+            field: $field,
+            rest: $rest
         }
+    };
+
+    (
+        @mark [],
+        table: $table:tt, attrs: $attrs:tt, name: $name:ident,
+        fields: $fields:tt, primary_key: $pk:tt, unique: $unique:tt, auto: $auto:tt,
+        field: $field:ident,
+        rest: $rest:tt
+    ) => {
+        $crate::__schema_fields! {
+            table: $table, attrs: $attrs, name: $name,
+            fields: $fields, primary_key: $pk, unique: $unique, auto: $auto,
+            pending: [], mark: [], unique_mark: [], auto_mark: [],
+            rest: $rest
+        }
+    };
+
+    (
+        @mark [primary_key],
+        table: $table:tt, attrs: $attrs:tt, name: $name:ident,
+        fields: $fields:tt, primary_key: [], unique: $unique:tt, auto: $auto:tt,
+        field: $field:ident,
+        rest: $rest:tt
+    ) => {
+        $crate::__schema_fields! {
+            table: $table, attrs: $attrs, name: $name,
+            fields: $fields, primary_key: [$field], unique: $unique, auto: $auto,
+            pending: [], mark: [], unique_mark: [], auto_mark: [],
+            rest: $rest
+        }
+    };
+
+    (
+        @mark [primary_key],
+        table: $table:tt, attrs: $attrs:tt, name: $name:ident,
+        fields: $fields:tt, primary_key: [$existing:ident], unique: $unique:tt, auto: $auto:tt,
+        field: $field:ident,
+        rest: $rest:tt
+    ) => {
+        compile_error!(concat!(
+            "Only one #[primary_key] field is allowed, found `",
+            stringify!($existing), "` and `", stringify!($field), "`"
+        ));
+    };
+
+    // All fields consumed
+    (
+        table: [$table:literal $($version:literal)?], attrs: [$($attr:tt)*], name: $name:ident,
+        fields: [$({ [$($field_attr:tt)*] $field:ident : $field_type:ty })*],
+        primary_key: [$($pk:ident)?], unique: [$($unique:ident)*], auto: [$($auto:ident)?],
+        pending: [], mark: [], unique_mark: [], auto_mark: [],
+        rest: []
     ) => {
-        $(#[$attr])*
+        $($attr)*
         pub struct $name {
             $(
-                $(#[$field_attr])*
+                $($field_attr)*
                 pub $field: $field_type,
             )*
         }
@@ -34,30 +508,70 @@ macro_rules! schema {
                 )*
                 count
             };
-        }
+            $(const SCHEMA_VERSION: u32 = $version;)?
+            const UNIQUE_FIELDS: &'static [&'static str] = &[$(stringify!($unique)),*];
 
-        // Compile-time validation
-        const _: () = {
-            // Ensure table name is not empty
-            const TABLE_NAME: &str = $table;
-            if TABLE_NAME.is_empty() {
-                panic!("Table name cannot be empty");
+            fn unique_values(&self) -> Vec<(&'static str, Vec<u8>)> {
+                vec![$((stringify!($unique), $crate::AsKeyBytes::as_key_bytes(&self.$unique))),*]
             }
-            
-            // Ensure table name contains only valid characters
-            let bytes = TABLE_NAME.as_bytes();
-            let mut i = 0;
-            while i < bytes.len() {
-                let byte = bytes[i];
-                if !((byte >= b'a' && byte <= b'z') || 
-                     (byte >= b'A' && byte <= b'Z') || 
-                     (byte >= b'0' && byte <= b'9') || 
-                     byte == b'_') {
-                    panic!("Table name contains invalid characters");
+
+            $(
+                fn auto_increment(&self) -> Option<u64> {
+                    Some(self.$auto)
+                }
+
+                fn set_auto_increment(&mut self, id: u64) {
+                    self.$auto = id;
+                }
+            )?
+        }
+
+        $(
+            impl $crate::PrimaryKey for $name {
+                fn primary_key(&self) -> Vec<u8> {
+                    $crate::AsKeyBytes::as_key_bytes(&self.$pk)
+                }
+
+                fn parse_key(key: &str) -> Option<Vec<u8>> {
+                    $crate::schema::parse_key_field(key, |record: &Self| &record.$pk)
                 }
-                i += 1;
             }
-        };
+        )?
+
+        // Compile-time validation
+        const _: () = $crate::schema::validate_table_name($table);
+        const _: () = $crate::schema::validate_field_names(&[$(stringify!($field)),*]);
+        const _: () = $crate::schema::validate_serialized_names(&[
+            $($crate::__serde_name!(field: $field, attrs: [$($field_attr)*])),*
+        ]);
+    };
+}
+
+// The name serde gives a `schema!` field: the first `rename = ".."` in its
+// `#[serde(..)]` attributes, or the field's own name.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __serde_name {
+    (field: $field:ident, attrs: [#[serde($($serde:tt)*)] $($rest:tt)*]) => {
+        $crate::__serde_name!(@args field: $field, args: [$($serde)*], attrs: [$($rest)*])
+    };
+    (field: $field:ident, attrs: [# $other:tt $($rest:tt)*]) => {
+        $crate::__serde_name!(field: $field, attrs: [$($rest)*])
+    };
+    (field: $field:ident, attrs: []) => {
+        stringify!($field)
+    };
+
+    (@args field: $field:ident, args: [rename = $rename:literal $($args:tt)*], attrs: $attrs:tt) => {
+        $rename
+    };
+    // `rename(serialize = .., deserialize = ..)` is a single group here, and
+    // skipped with the rest
+    (@args field: $field:ident, args: [$skip:tt $($args:tt)*], attrs: $attrs:tt) => {
+        $crate::__serde_name!(@args field: $field, args: [$($args)*], attrs: $attrs)
+    };
+    (@args field: $field:ident, args: [], attrs: $attrs:tt) => {
+        $crate::__serde_name!(field: $field, attrs: $attrs)
     };
 }
 
@@ -78,12 +592,176 @@ macro_rules! impl_basic_schema {
     };
 }
 
+// Table name rules shared by `schema!` and `#[derive(Schema)]`. Called in a
+// const context, so an invalid name fails the build.
+#[doc(hidden)]
+pub const fn validate_table_name(name: &str) {
+    // Ensure table name is not empty
+    if name.is_empty() {
+        panic!("Table name cannot be empty");
+    }
+
+    // Ensure table name contains only valid characters
+    let bytes = name.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = bytes[i];
+        if !((byte >= b'a' && byte <= b'z') ||
+             (byte >= b'A' && byte <= b'Z') ||
+             (byte >= b'0' && byte <= b'9') ||
+             byte == b'_') {
+            panic!("Table name contains invalid characters");
+        }
+        i += 1;
+    }
+}
+
+// Field name rules of `schema!`: at least one field, with the same
+// characters as a table name, so raw identifiers like `r#type`, whose
+// stringified name isn't the one serde uses, are out, and no name twice.
+// Called in a const context, so a bad field fails the build.
+#[doc(hidden)]
+pub const fn validate_field_names(names: &[&str]) {
+    if names.is_empty() {
+        panic!("Schema must have at least one field");
+    }
+
+    let mut i = 0;
+    while i < names.len() {
+        let bytes = names[i].as_bytes();
+        if bytes.is_empty() {
+            panic!("Field name cannot be empty");
+        }
+
+        let mut j = 0;
+        while j < bytes.len() {
+            let byte = bytes[j];
+            if !(byte.is_ascii_alphanumeric() || byte == b'_') {
+                panic!("Field name contains invalid characters");
+            }
+            j += 1;
+        }
+
+        i += 1;
+    }
+    if has_duplicate(names) {
+        panic!("Field name is used more than once");
+    }
+}
+
+// No two `schema!` fields may serialize under the same name once
+// `#[serde(rename)]` is applied, or serde writes both under it and reads
+// back only one. Called in a const context, like the checks above.
+#[doc(hidden)]
+pub const fn validate_serialized_names(names: &[&str]) {
+    if has_duplicate(names) {
+        panic!("Two fields serialize under the same name; check their #[serde(rename)]");
+    }
+}
+
+const fn has_duplicate(names: &[&str]) -> bool {
+    let mut i = 0;
+    while i < names.len() {
+        let mut k = 0;
+        while k < i {
+            if str_eq(names[k], names[i]) {
+                return true;
+            }
+            k += 1;
+        }
+        i += 1;
+    }
+    false
+}
+
+// `==` on strings, which isn't callable in a const fn
+const fn str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+// `PrimaryKey::parse_key` for a key field of type `K`, named by the
+// accessor so the macros needn't spell out its type
+#[doc(hidden)]
+pub fn parse_key_field<R, K>(key: &str, _field: fn(&R) -> &K) -> Option<Vec<u8>>
+where
+    K: std::str::FromStr + crate::AsKeyBytes,
+{
+    key.parse::<K>().ok().map(|key| key.as_key_bytes())
+}
+
+// Email check behind `#[validate(email)]`: one `@` with a non-empty local
+// part and a dotted domain
+#[doc(hidden)]
+pub fn is_valid_email(value: &str) -> bool {
+    match value.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.split('.').count() > 1
+                && domain.split('.').all(|part| !part.is_empty())
+        }
+        None => false,
+    }
+}
+
+/// Check `item` before it's written: [`Schema::schema_validate`], after
+/// refusing a type with no fields, which `schema!` and the derive already
+/// rule out at compile time but a hand-written impl might not.
+pub(crate) fn validate<T: Schema + CompileTimeSchema>(item: &T) -> Result<(), crate::DbError> {
+    if T::FIELD_COUNT == 0 {
+        return Err(crate::DbError::SchemaError(format!("{} has no fields", T::table_name())));
+    }
+    item.schema_validate().map_err(|e| crate::DbError::SchemaError(e.to_string()))
+}
+
 // Compile-time schema validator trait
 pub trait CompileTimeSchema {
     const TABLE_NAME: &'static str;
     const FIELD_COUNT: usize;
-    
+    /// Layout version stored with each record. Bump it when a change to the
+    /// struct would stop older records from deserializing, and handle the
+    /// old versions in [`Schema::migrate`].
+    const SCHEMA_VERSION: u32 = 1;
+    /// Fields marked `#[unique]`, in declaration order, then each
+    /// `#[unique(a, b, ..)]` constraint, named by its fields joined with
+    /// commas: `"a,b"`.
+    const UNIQUE_FIELDS: &'static [&'static str] = &[];
+    /// Names of the indexes declared with `#[index]`, the way
+    /// `Database::create_index` takes them. [`Schema::indexes`] has their
+    /// kinds too.
+    const INDEXES: &'static [&'static str] = &[];
+
+    /// Encoded value of each `#[unique]` field, paired with its name. A
+    /// composite constraint's value is the tuple of its fields, in the
+    /// [`AsKeyBytes`](crate::AsKeyBytes) tuple encoding.
+    fn unique_values(&self) -> Vec<(&'static str, Vec<u8>)> {
+        Vec::new()
+    }
+
+    /// Value of the `#[auto_increment]` field, if there is one. Zero asks
+    /// `Database::insert` to assign the table's next id.
+    fn auto_increment(&self) -> Option<u64> {
+        None
+    }
+
+    /// Store an id assigned by `Database::insert` in the
+    /// `#[auto_increment]` field.
+    fn set_auto_increment(&mut self, id: u64) {
+        let _ = id;
+    }
+
     fn validate_at_compile_time() -> bool {
         !Self::TABLE_NAME.is_empty() && Self::FIELD_COUNT > 0
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,33 @@
+//! Ids for `#[auto_increment]` fields.
+//!
+//! Each table's sequence is the last id it handed out, kept in the
+//! database's metadata and written in the same atomic WAL batch as the
+//! record that takes the id, so a reopen carries on from exactly the next
+//! one. An id whose record is never written, say one of a transaction
+//! rolled back, stays used: it's skipped rather than handed out again.
+
+use crate::error::DbError;
+use crate::meta::MetaStore;
+
+/// The next id for `table`, starting from 1.
+pub(crate) fn next(meta: &mut MetaStore, table: &str) -> Result<u64, DbError> {
+    let id = last(meta, table).saturating_add(1);
+    meta.update(|meta| {
+        meta.sequences.insert(table.to_string(), id);
+    })?;
+    Ok(id)
+}
+
+/// Note an id the caller chose, so the sequence never hands it out later.
+pub(crate) fn observe(meta: &mut MetaStore, table: &str, id: u64) -> Result<(), DbError> {
+    if id > last(meta, table) {
+        meta.update(|meta| {
+            meta.sequences.insert(table.to_string(), id);
+        })?;
+    }
+    Ok(())
+}
+
+fn last(meta: &MetaStore, table: &str) -> u64 {
+    meta.get().sequences.get(table).copied().unwrap_or(0)
+}
@@ -0,0 +1,89 @@
+use std::ops::RangeBounds;
+
+use serde::de::DeserializeOwned;
+
+use crate::codec::Codec;
+use crate::error::DbError;
+use crate::key::{record_key, record_range, AsKeyBytes};
+use crate::record;
+use crate::schema::{CompileTimeSchema, PrimaryKey, Schema};
+use crate::storage::StorageSnapshot;
+use crate::{QueryBuilder, Source};
+
+/// A read-only, point-in-time view of a [`Database`](crate::Database),
+/// created by [`Database::snapshot`](crate::Database::snapshot).
+///
+/// Reads through a snapshot never observe writes made after it was taken,
+/// so a long query sees one consistent state. It holds on to the SSTables
+/// it was taken over, keeping compacted files on disk until it's dropped.
+#[derive(Debug)]
+pub struct Snapshot {
+    pub(crate) storage: StorageSnapshot,
+    pub(crate) codec: Codec,
+}
+
+impl Snapshot {
+    pub(crate) fn new(storage: StorageSnapshot, codec: Codec) -> Self {
+        Snapshot { storage, codec }
+    }
+
+    pub async fn get<T>(&self, key: &str) -> Result<Option<T>, DbError>
+    where
+        T: Schema + CompileTimeSchema + PrimaryKey + DeserializeOwned,
+    {
+        match T::parse_key(key) {
+            Some(key_bytes) => self.get_by_key(key_bytes.as_slice()).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Like [`Database::get_by_key`](crate::Database::get_by_key), as of the snapshot.
+    pub async fn get_by_key<T, K>(&self, key: K) -> Result<Option<T>, DbError>
+    where
+        T: Schema + CompileTimeSchema + DeserializeOwned,
+        K: AsKeyBytes,
+    {
+        let key_bytes = record_key(T::table_name(), &key.as_key_bytes());
+        if let Some(data) = self.storage.get(&key_bytes)? {
+            let item = record::decode::<T>(self.codec, &data)?;
+            Ok(Some(item))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Like [`Database::all`](crate::Database::all), as of the snapshot.
+    pub async fn all<T>(&self) -> Result<Vec<T>, DbError>
+    where
+        T: Schema + CompileTimeSchema + DeserializeOwned,
+    {
+        Source::Snapshot(self).all().await
+    }
+
+    /// Like [`Database::scan`](crate::Database::scan), as of the snapshot.
+    pub async fn scan<T, K>(&self, range: impl RangeBounds<K>) -> Result<Vec<T>, DbError>
+    where
+        T: Schema + CompileTimeSchema + DeserializeOwned,
+        K: AsKeyBytes,
+    {
+        let (start, end) = record_range(T::table_name(), &range);
+        let entries = self
+            .storage
+            .scan_range(start.as_ref().map(Vec::as_slice), end.as_ref().map(Vec::as_slice))?;
+        entries
+            .into_iter()
+            .map(|(_, data)| {
+                record::decode::<T>(self.codec, &data)
+            })
+            .collect()
+    }
+
+    /// Query the snapshot. `filter_indexed` still works, but falls back to
+    /// a scan since indexes follow the live database.
+    pub fn query<T>(&self) -> QueryBuilder<'_, T>
+    where
+        T: Schema + CompileTimeSchema + DeserializeOwned + Send + Sync,
+    {
+        QueryBuilder::from_source(Source::Snapshot(self))
+    }
+}
@@ -1,146 +1,2477 @@
-use std::collections::BTreeMap;
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap};
 use std::fs::{File, OpenOptions};
-use std::io::{BufWriter, Write};
+use std::io::{BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, Weak};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
+use bincode::Options;
 use serde::{Serialize, Deserialize};
-use bincode;
-use chrono; // Ensure chrono is in Cargo.toml
+use tokio::sync::Notify;
 use crate::error::DbError;
+use crate::trace;
+
+mod blob;
+mod bloom;
+mod cache;
+mod compaction;
+mod compression;
+#[cfg(feature = "debug-dump")]
+mod debug;
+mod manifest;
+mod memtable;
+mod merge;
+mod metrics;
+mod sstable;
+mod verify;
+
+pub use compaction::{CompactionPolicy, CompactionReport};
+pub use compression::Compression;
+pub use memtable::{BTreeMemTable, MemTableImpl};
+pub use merge::MergeFn;
+pub use metrics::Metrics;
+pub use sstable::SsTableScan;
+pub use verify::{VerifyProblem, VerifyReport};
+
+use blob::{BlobFile, BlobFiles, BlobWriter, Stored};
+use cache::BlockCache;
+use compaction::{Compaction, Levels};
+pub(crate) use memtable::new_table;
+use memtable::{ActiveMemTable, MemTable, NewTable, Shards};
+use metrics::Counters;
+
+use sstable::{Entry, SsTable, SsTableIter, SsTableWriter};
+
+/// A live key and its value
+pub(crate) type KeyValue = (Vec<u8>, Vec<u8>);
 
 /// WAL operation enum: represents what gets logged
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) enum StorageOp {
     Insert(Vec<u8>, Vec<u8>),
     Delete(Vec<u8>),
+    // Frame a batch that must be replayed all-or-nothing
+    Begin,
+    Commit,
+    // Insert that reads as absent from the given Unix millisecond on
+    InsertExpiring(Vec<u8>, Vec<u8>, i64),
+    // Operand for the merge operator to combine with the key's value
+    Merge(Vec<u8>, Vec<u8>),
 }
 
-/// Write-Ahead Log
+impl StorageOp {
+    /// The key written, unless it's a batch marker.
+    fn key(&self) -> Option<&[u8]> {
+        match self {
+            StorageOp::Insert(key, _)
+            | StorageOp::Delete(key)
+            | StorageOp::InsertExpiring(key, _, _)
+            | StorageOp::Merge(key, _) => Some(key),
+            StorageOp::Begin | StorageOp::Commit => None,
+        }
+    }
+}
+
+/// Write-Ahead Log, kept as numbered segments `wal-000001.log`,
+/// `wal-000002.log`, ... in the database directory.
+///
+/// Each segment starts with [`WAL_MAGIC`], followed by one frame per
+/// operation: `len: u32 | crc32: u32 | bincode(op)`, little-endian, with the
+/// checksum taken over the payload. Writes go to the newest segment. The
+/// next one is started once it grows past the segment size, between writes
+/// so a batch never spans two, and whenever the memtable is frozen. The
+/// manifest records the first segment with writes not yet in an SSTable;
+/// those before it are obsolete, and only the newest few are kept.
+///
+/// The single `wal.log` of older versions is renamed into a segment on
+/// open. Logs written before framing was introduced are
+/// bare bincode records with no header; they're replayed as before and
+/// rewritten in the framed format.
 pub struct Wal {
+    dir: PathBuf,
+    // Number of the segment being appended to
+    segment: u64,
+    // Bytes in that segment, buffered ones included
+    segment_len: u64,
+    // Start a new segment once the current one holds this many bytes
+    segment_size: u64,
     writer: BufWriter<File>,
+    // Capacity of `writer`'s buffer
+    buffer_capacity: usize,
+    durability: Durability,
+    // Largest record, encoded, that's written or read back
+    max_record_size: u64,
+    // Written since the last sync, under `Durability::Batched`
+    dirty: bool,
+    last_sync: Instant,
+    // fsyncs so far, for tests to count
+    #[cfg(test)]
+    syncs: u64,
 }
 
+/// Header of a framed WAL: `"RWAL"` and format version 1
+const WAL_MAGIC: [u8; 8] = *b"RWAL\x01\x00\x00\x00";
+const WAL_FRAME_HEADER: usize = 8;
+
+/// Bytes the WAL buffers before writing to the file, outside a bulk load
+const WAL_BUFFER: usize = 8 * 1024;
+/// Bytes the WAL buffers during a bulk load, so large batches go out in
+/// fewer writes
+const BULK_WAL_BUFFER: usize = 1024 * 1024;
+/// How many times the flush threshold a bulk load lets the memtable grow
+const BULK_FLUSH_FACTOR: usize = 16;
+
 impl std::fmt::Debug for Wal {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Wal")
+            .field("dir", &self.dir)
+            .field("segment", &self.segment)
             .field("writer", &"BufWriter<File>")
+            .field("durability", &self.durability)
             .finish()
     }
 }
 
 impl Wal {
-    pub fn new(path: &Path) -> Result<Self, DbError> {
-        let file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(path)?;
+    /// Append to the newest segment in `dir` from `start` on, or to a new
+    /// one numbered at least `start` if there's none.
+    pub(crate) fn open(dir: &Path, start: u64, options: &StorageOptions) -> Result<Self, DbError> {
+        let newest = wal_segments(dir)?.last().copied();
+        let segment = match newest {
+            Some(seq) if seq >= start => seq,
+            _ => next_segment(newest, start),
+        };
+        let (writer, segment_len) = open_segment(dir, segment, WAL_BUFFER)?;
         Ok(Wal {
-            writer: BufWriter::new(file),
+            dir: dir.to_path_buf(),
+            segment,
+            segment_len,
+            segment_size: options.wal_segment_size,
+            writer,
+            buffer_capacity: WAL_BUFFER,
+            durability: options.durability,
+            max_record_size: options.max_record_size,
+            dirty: false,
+            last_sync: Instant::now(),
+            #[cfg(test)]
+            syncs: 0,
         })
     }
 
-    pub(crate) fn write(&mut self, op: &StorageOp) -> Result<(), DbError> {
-        bincode::serialize_into(&mut self.writer, op)
-            .map_err(|e| DbError::SerializationError(e.to_string()))?;
+    /// Read back every complete operation in the log, in write order.
+    ///
+    /// A torn final record (e.g. from a crash mid-write) ends the replay
+    /// instead of failing it, and is cut off so later appends stay readable.
+    /// So is a `Begin` batch with no `Commit`; none of its operations are
+    /// returned, and the markers themselves never are. A record that fails
+    /// its checksum anywhere else is [`DbError::Corruption`], and one longer
+    /// than `limit` is [`DbError::ValueTooLarge`].
+    pub(crate) fn replay(path: &Path, limit: u64) -> Result<Vec<StorageOp>, DbError> {
+        let mut file = match OpenOptions::new().read(true).write(true).open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        let (ops, valid_len, legacy) = Self::decode(path, &bytes, limit)?;
+
+        if legacy {
+            // Upgrade in place so new frames aren't appended to old records
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&encode_log(&ops, limit)?)?;
+            file.sync_all()?;
+        } else if valid_len < bytes.len() as u64 {
+            file.set_len(valid_len)?;
+        }
+
+        Ok(ops)
+    }
+
+    /// Like [`Wal::recover`], but only reading: nothing is repaired,
+    /// upgraded or renamed, as a storage opened read-only needs.
+    pub(crate) fn read(dir: &Path, start: u64, limit: u64) -> Result<Vec<StorageOp>, DbError> {
+        let mut ops = Vec::new();
+        for seq in wal_segments(dir)?.into_iter().filter(|&seq| seq >= start) {
+            ops.extend(Self::read_log(&dir.join(segment_name(seq)), limit)?);
+        }
+        // Where `recover` would rename it to
+        ops.extend(Self::read_log(&dir.join(LEGACY_WAL), limit)?);
+        Ok(ops)
+    }
+
+    fn read_log(path: &Path, limit: u64) -> Result<Vec<StorageOp>, DbError> {
+        match std::fs::read(path) {
+            Ok(bytes) => Ok(Self::decode(path, &bytes, limit)?.0),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // The committed operations in a log, how many of its bytes hold them,
+    // and whether it's in the pre-framing format
+    fn decode(path: &Path, bytes: &[u8], limit: u64) -> Result<(Vec<StorageOp>, u64, bool), DbError> {
+        let legacy = !bytes.is_empty() && !bytes.starts_with(&WAL_MAGIC);
+        let (records, start) = if legacy {
+            (Self::decode_legacy(bytes, limit), 0)
+        } else {
+            (Self::decode_framed(path, bytes, limit)?, WAL_MAGIC.len().min(bytes.len()) as u64)
+        };
+
+        let mut ops = Vec::new();
+        // Operations of an open batch, held back until its `Commit`
+        let mut batch: Option<Vec<StorageOp>> = None;
+        let mut valid_len = start;
+        for (op, end) in records {
+            match op {
+                StorageOp::Begin => batch = Some(Vec::new()),
+                StorageOp::Commit => {
+                    ops.extend(batch.take().unwrap_or_default());
+                    valid_len = end;
+                }
+                op => match &mut batch {
+                    Some(batch) => batch.push(op),
+                    None => {
+                        ops.push(op);
+                        valid_len = end;
+                    }
+                },
+            }
+        }
+        if let Some(batch) = batch {
+            log::warn!(
+                "Discarding {} operations of an uncommitted batch at WAL offset {}",
+                batch.len(),
+                valid_len
+            );
+        }
+        Ok((ops, valid_len, legacy))
+    }
+
+    // Framed records and the offset each one ends at. Stops at a torn tail,
+    // but fails on a bad record with more log after it, and on a length over
+    // `limit`, which no crash leaves behind.
+    fn decode_framed(path: &Path, bytes: &[u8], limit: u64) -> Result<Vec<(StorageOp, u64)>, DbError> {
+        let mut records = Vec::new();
+        let mut pos = WAL_MAGIC.len();
+        while pos < bytes.len() {
+            let Some(header) = bytes.get(pos..pos + WAL_FRAME_HEADER) else {
+                log::warn!("Stopping WAL replay at offset {}: torn frame header", pos);
+                break;
+            };
+            let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+            let checksum = u32::from_le_bytes(header[4..8].try_into().unwrap());
+            if len as u64 > limit {
+                return Err(DbError::ValueTooLarge { size: len as u64, limit });
+            }
+            let end = pos + WAL_FRAME_HEADER + len;
+            let Some(payload) = bytes.get(pos + WAL_FRAME_HEADER..end) else {
+                log::warn!("Stopping WAL replay at offset {}: torn record", pos);
+                break;
+            };
+
+            if crc32fast::hash(payload) != checksum {
+                if end == bytes.len() {
+                    log::warn!("Stopping WAL replay at offset {}: torn final record", pos);
+                    break;
+                }
+                return Err(DbError::Corruption { file: path.to_path_buf(), offset: pos as u64 });
+            }
+            let op = record_options(limit)
+                .deserialize(payload)
+                .map_err(|_| DbError::Corruption { file: path.to_path_buf(), offset: pos as u64 })?;
+            records.push((op, end as u64));
+            pos = end;
+        }
+        Ok(records)
+    }
+
+    // Records of a pre-framing log, up to the first one that doesn't decode
+    // or whose lengths claim more than `limit` bytes, before anything is
+    // allocated for them
+    fn decode_legacy(bytes: &[u8], limit: u64) -> Vec<(StorageOp, u64)> {
+        let mut cursor = Cursor::new(bytes);
+        let mut records = Vec::new();
+        while (cursor.position() as usize) < bytes.len() {
+            match record_options(limit).deserialize_from(&mut cursor) {
+                Ok(op) => records.push((op, cursor.position())),
+                Err(e) => {
+                    log::warn!("Stopping WAL replay at offset {}: {}", cursor.position(), e);
+                    break;
+                }
+            }
+        }
+        records
+    }
+
+    /// Replay every segment in `dir` from `start` on, oldest first, each
+    /// as [`Wal::replay`] does.
+    ///
+    /// The log of older versions is renamed into the segment after those
+    /// first.
+    pub(crate) fn recover(dir: &Path, start: u64, limit: u64) -> Result<Vec<StorageOp>, DbError> {
+        let legacy = dir.join(LEGACY_WAL);
+        if legacy.exists() {
+            let seq = next_segment(wal_segments(dir)?.last().copied(), start);
+            log::info!("Moving {} into WAL segment {}", LEGACY_WAL, segment_name(seq));
+            std::fs::rename(&legacy, dir.join(segment_name(seq)))?;
+            sync_dir(dir)?;
+        }
+
+        let mut ops = Vec::new();
+        for seq in wal_segments(dir)?.into_iter().filter(|&seq| seq >= start) {
+            ops.extend(Self::replay(&dir.join(segment_name(seq)), limit)?);
+        }
+        Ok(ops)
+    }
+
+    /// Carry on in a new segment, unless the current one has nothing in it
+    /// yet, and return the number of the segment written to from now on.
+    pub(crate) fn roll(&mut self) -> Result<u64, DbError> {
+        if self.segment_len <= WAL_MAGIC.len() as u64 {
+            return Ok(self.segment);
+        }
+        self.settle()?;
+        let (writer, segment_len) = open_segment(&self.dir, self.segment + 1, self.buffer_capacity)?;
+        self.writer = writer;
+        self.segment += 1;
+        self.segment_len = segment_len;
+        sync_dir(&self.dir)?;
+        Ok(self.segment)
+    }
+
+    /// Buffer up to `capacity` bytes before writing to the file, from the
+    /// next write on.
+    fn set_buffer_capacity(&mut self, capacity: usize) -> Result<(), DbError> {
+        if capacity != self.buffer_capacity {
+            self.writer.flush()?;
+            let file = self.writer.get_ref().try_clone()?;
+            self.writer = BufWriter::with_capacity(capacity, file);
+            self.buffer_capacity = capacity;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn write(&mut self, op: &StorageOp) -> Result<u64, DbError> {
+        self.write_batch(std::slice::from_ref(op))
+    }
+
+    /// Append several operations, then make them as durable as the
+    /// configured [`Durability`] asks for, and start the next segment if
+    /// the current one is full. Returns the bytes appended.
+    pub(crate) fn write_batch(&mut self, ops: &[StorageOp]) -> Result<u64, DbError> {
+        // All encoded first, so one too large leaves none of them half logged
+        let frames = ops.iter().map(|op| encode_frame(op, self.max_record_size)).collect::<Result<Vec<_>, _>>()?;
+        let mut written = 0;
+        for frame in frames {
+            self.writer.write_all(&frame)?;
+            written += frame.len() as u64;
+        }
+        self.segment_len += written;
+        match self.durability {
+            Durability::Sync => self.sync()?,
+            Durability::Batched(interval) => {
+                self.dirty = true;
+                if self.last_sync.elapsed() >= interval {
+                    self.sync()?;
+                }
+            }
+            Durability::NoSync => self.writer.flush()?,
+        }
+        if self.segment_len >= self.segment_size {
+            self.roll()?;
+        }
+        Ok(written)
+    }
+
+    /// Flush buffered records and `fsync` the file.
+    fn sync(&mut self) -> Result<(), DbError> {
         self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        self.dirty = false;
+        self.last_sync = Instant::now();
+        #[cfg(test)]
+        {
+            self.syncs += 1;
+        }
         Ok(())
     }
+
+    // Leave everything written so far as durable as the mode ever makes it,
+    // before the file is closed
+    fn settle(&mut self) -> Result<(), DbError> {
+        match self.durability {
+            Durability::NoSync => Ok(self.writer.flush()?),
+            Durability::Sync | Durability::Batched(_) => self.sync(),
+        }
+    }
+
+    // Sync a `Batched` log that's had writes since the last sync but no
+    // later write to trigger one
+    fn sync_if_due(&mut self) -> Result<(), DbError> {
+        match self.durability {
+            Durability::Batched(interval) if self.dirty && self.last_sync.elapsed() >= interval => {
+                self.sync()
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
-/// In-memory table
-#[derive(Debug)]
-pub struct MemTable {
-    data: BTreeMap<Vec<u8>, Vec<u8>>,
-    size: usize,
+impl Drop for Wal {
+    fn drop(&mut self) {
+        if let Err(e) = self.settle() {
+            log::warn!("Failed to sync WAL segment {} on close: {}", segment_name(self.segment), e);
+        }
+    }
 }
 
-impl MemTable {
-    pub fn new() -> Self {
-        MemTable {
-            data: BTreeMap::new(),
-            size: 0,
+// Sync a `Batched` WAL every `interval` until its storage is dropped
+fn spawn_wal_syncer(wal: Weak<RwLock<Wal>>, interval: Duration) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        let Some(wal) = wal.upgrade() else { break };
+        let Ok(mut wal) = wal.write() else { break };
+        if let Err(e) = wal.sync_if_due() {
+            log::warn!("Background WAL sync failed: {}", e);
         }
+    });
+}
+
+/// Name of WAL segment `seq`
+fn segment_name(seq: u64) -> String {
+    format!("wal-{:06}.log", seq)
+}
+
+/// The number a WAL segment's `wal-<seq>.log` name was given.
+fn segment_seq(name: &str) -> Option<u64> {
+    let digits = name.strip_prefix("wal-")?.strip_suffix(".log")?;
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
     }
+    digits.parse().ok()
+}
 
-    pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
-        self.size += key.len() + value.len();
-        self.data.insert(key, value);
+/// Numbers of the WAL segments in `dir`, oldest first.
+fn wal_segments(dir: &Path) -> Result<Vec<u64>, DbError> {
+    let mut segments = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        if let Some(seq) = entry?.file_name().to_str().and_then(segment_seq) {
+            segments.push(seq);
+        }
     }
+    segments.sort_unstable();
+    Ok(segments)
+}
 
-    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.data.get(key).cloned()
+// Number for a new segment: past `newest`, and no lower than `start`, which
+// recovery begins at. Segments count from 1.
+fn next_segment(newest: Option<u64>, start: u64) -> u64 {
+    newest.map_or(1, |seq| seq + 1).max(start)
+}
+
+// Open segment `seq` in `dir` for appending, headed with the magic if it's
+// new, and the bytes it holds
+fn open_segment(dir: &Path, seq: u64, capacity: usize) -> Result<(BufWriter<File>, u64), DbError> {
+    let file = OpenOptions::new().create(true).append(true).open(dir.join(segment_name(seq)))?;
+    let mut len = file.metadata()?.len();
+    let mut writer = BufWriter::with_capacity(capacity, file);
+    if len == 0 {
+        writer.write_all(&WAL_MAGIC)?;
+        writer.flush()?;
+        len = WAL_MAGIC.len() as u64;
     }
+    Ok((writer, len))
+}
+
+/// Delete the segments in `dir` before `start`, whose writes are all in
+/// SSTables, except the newest `retain` of them.
+fn remove_obsolete_segments(dir: &Path, start: u64, retain: usize) -> Result<(), DbError> {
+    let obsolete: Vec<u64> = wal_segments(dir)?.into_iter().filter(|&seq| seq < start).collect();
+    for &seq in &obsolete[..obsolete.len().saturating_sub(retain)] {
+        std::fs::remove_file(dir.join(segment_name(seq)))?;
+    }
+    Ok(())
+}
+
+// How WAL records are encoded, as `bincode::serialize` does, but refusing
+// a record that claims more than `limit` bytes
+fn record_options(limit: u64) -> impl bincode::Options {
+    bincode::DefaultOptions::new().with_fixint_encoding().allow_trailing_bytes().with_limit(limit)
+}
+
+// A whole framed log holding `ops`
+fn encode_log(ops: &[StorageOp], limit: u64) -> Result<Vec<u8>, DbError> {
+    let mut log = WAL_MAGIC.to_vec();
+    for op in ops {
+        log.extend(encode_frame(op, limit)?);
+    }
+    Ok(log)
+}
+
+// Fails with `ValueTooLarge` rather than encode more than `limit` bytes, or
+// more than the frame's length can say
+fn encode_frame(op: &StorageOp, limit: u64) -> Result<Vec<u8>, DbError> {
+    let limit = limit.min(u32::MAX.into());
+    let size = bincode::serialized_size(op).map_err(|e| DbError::SerializationError(e.to_string()))?;
+    if size > limit {
+        return Err(DbError::ValueTooLarge { size, limit });
+    }
+    let payload = bincode::serialize(op).map_err(|e| DbError::SerializationError(e.to_string()))?;
+    let mut frame = Vec::with_capacity(WAL_FRAME_HEADER + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// What the memtable or an SSTable holds for a key.
+///
+/// Generic over the value so lookups can borrow it, or skip it entirely.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Slot<V = Vec<u8>> {
+    Value(V),
+    /// A value that reads as absent once the clock passes `expires_at`
+    /// (Unix milliseconds)
+    Expiring(V, i64),
+    /// Left by a delete; hides every older value of the key
+    Tombstone,
+    /// Merge operands, oldest first, waiting to be applied to the older
+    /// value of the key by the [`MergeFn`]
+    Merge(Vec<V>),
+}
+
+impl<V> Slot<V> {
+    pub(crate) fn map<W>(self, mut f: impl FnMut(V) -> W) -> Slot<W> {
+        match self {
+            Slot::Value(value) => Slot::Value(f(value)),
+            Slot::Expiring(value, expires_at) => Slot::Expiring(f(value), expires_at),
+            Slot::Tombstone => Slot::Tombstone,
+            Slot::Merge(operands) => Slot::Merge(operands.into_iter().map(f).collect()),
+        }
+    }
+
+    pub(crate) fn try_map<W, E>(self, mut f: impl FnMut(V) -> Result<W, E>) -> Result<Slot<W>, E> {
+        Ok(match self {
+            Slot::Value(value) => Slot::Value(f(value)?),
+            Slot::Expiring(value, expires_at) => Slot::Expiring(f(value)?, expires_at),
+            Slot::Tombstone => Slot::Tombstone,
+            Slot::Merge(operands) => Slot::Merge(operands.into_iter().map(f).collect::<Result<_, _>>()?),
+        })
+    }
+
+    fn as_ref(&self) -> Slot<&V> {
+        match self {
+            Slot::Value(value) => Slot::Value(value),
+            Slot::Expiring(value, expires_at) => Slot::Expiring(value, *expires_at),
+            Slot::Tombstone => Slot::Tombstone,
+            Slot::Merge(operands) => Slot::Merge(operands.iter().collect()),
+        }
+    }
+
+    /// Whether the key has a value by `now`. Merge operands always make one.
+    fn is_live(&self, now: i64) -> bool {
+        match self {
+            Slot::Value(_) | Slot::Merge(_) => true,
+            Slot::Expiring(_, expires_at) => now < *expires_at,
+            Slot::Tombstone => false,
+        }
+    }
+
+    /// The value, unless it's deleted or has expired by `now`. An expired
+    /// value hides older ones just like a tombstone. Merge operands are
+    /// [collapsed](Slot::collapse) first, so there are none left here.
+    fn live(self, now: i64) -> Option<V> {
+        match self {
+            Slot::Value(value) => Some(value),
+            Slot::Expiring(value, expires_at) if now < expires_at => Some(value),
+            Slot::Expiring(..) | Slot::Tombstone => None,
+            Slot::Merge(_) => unreachable!("merge operands are collapsed before they're read"),
+        }
+    }
+}
+
+/// What a point lookup found for a key, telling a key that was deleted
+/// apart from one with no entry at all
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Lookup {
+    Value(Vec<u8>),
+    /// Deleted, or expired, by an entry that's still stored
+    Tombstone,
+    /// No entry anywhere, including once compaction has dropped the
+    /// tombstone of a deleted key
+    Missing,
+}
+
+impl Lookup {
+    /// The value, with deleted and missing keys alike `None`.
+    pub(crate) fn into_value(self) -> Option<Vec<u8>> {
+        match self {
+            Lookup::Value(value) => Some(value),
+            Lookup::Tombstone | Lookup::Missing => None,
+        }
+    }
+}
+
+// What a key's `entries`, newest first, add up to as of `now`. A tombstone
+// hides any older value for the key; merge operands send the search on for
+// the value under them.
+fn lookup_entries(
+    entries: impl Iterator<Item = Result<Option<Slot>, DbError>>,
+    merge: Option<MergeFn>,
+    now: i64,
+) -> Result<Lookup, DbError> {
+    let mut found: Option<Slot> = None;
+    for entry in entries {
+        let Some(older) = entry? else { continue };
+        let slot = match found.take() {
+            Some(newer) => newer.over(older, merge, now)?,
+            None => older,
+        };
+        let settled = !matches!(slot, Slot::Merge(_));
+        found = Some(slot);
+        if settled {
+            break;
+        }
+    }
+
+    let Some(slot) = found else { return Ok(Lookup::Missing) };
+    match slot.collapse(merge)?.live(now) {
+        Some(value) => Ok(Lookup::Value(value)),
+        None => Ok(Lookup::Tombstone),
+    }
+}
+
+/// Current time in Unix milliseconds, the clock expiry is measured by
+pub(crate) fn now_millis() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+/// Size figures for a database, from `Database::stats`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DbStats {
+    /// Bytes of keys and values held in memory, counting a memtable that's
+    /// still being flushed
+    pub memtable_bytes: usize,
+    /// Keys held in memory, tombstones included
+    pub memtable_keys: usize,
+    pub sstables: usize,
+    /// Total size of the SSTable files
+    pub sstable_bytes: u64,
+    /// Total size of the blob files the SSTables refer to
+    pub blob_bytes: u64,
+    /// Keys with a live value, found by merging every layer. `#[unique]`
+    /// claims and the database's metadata are stored as keys of their own
+    /// and count too.
+    pub live_keys: usize,
+    /// Size of the live write-ahead log segments, with those of a memtable
+    /// being flushed, but not the obsolete ones kept
+    pub wal_bytes: u64,
+    /// SSTable block reads served from the block cache since open
+    pub block_cache_hits: u64,
+    /// SSTable block reads that went to disk since open
+    pub block_cache_misses: u64,
+    /// Queries and `all` calls since open that read every record or key of
+    /// a table, rather than only those an index picked out
+    pub table_scans: u64,
+}
+
+/// When WAL writes are forced to disk, set through
+/// `DatabaseBuilder::durability`.
+///
+/// Every mode survives a process crash once a write returns, except that
+/// `Batched` may lose writes still buffered in the process. They differ in
+/// what survives a power loss or OS crash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// `fsync` after every write. Nothing acknowledged is ever lost, at the
+    /// cost of a disk round trip per write.
+    Sync,
+    /// Buffer writes and `fsync` them together at most once per interval,
+    /// so up to that much recent history can be lost.
+    Batched(Duration),
+    /// Hand every write to the OS and leave flushing it to disk up to the OS.
+    #[default]
+    NoSync,
+}
+
+/// Tunables for [`LsmStorage`], set through `Database::builder`
+#[derive(Debug, Clone)]
+pub(crate) struct StorageOptions {
+    // Flush the memtable once it holds this many bytes: the high watermark
+    pub flush_threshold: usize,
+    // Flush it from this many bytes if no flush is running; `None` is the
+    // same as the threshold
+    pub flush_low_watermark: Option<usize>,
+    pub create_if_missing: bool,
+    pub compaction: CompactionPolicy,
+    // Target false-positive rate of each SSTable's bloom filter
+    pub bloom_false_positive_rate: f64,
+    // Codec for the data blocks of newly written SSTables
+    pub compression: Compression,
+    pub durability: Durability,
+    // Bytes of SSTable blocks kept in memory for point lookups
+    pub block_cache_size: usize,
+    // Open without a WAL to append to, failing every write with `ReadOnly`
+    pub read_only: bool,
+    // Makes the map each new memtable keeps its entries in
+    pub memtable: NewTable,
+    // Shards the memtable is split into, each written under its own lock
+    pub memtable_shards: usize,
+    // Combines merge operands with the values under them
+    pub merge: Option<MergeFn>,
+    // Start a new WAL segment once the current one holds this many bytes
+    pub wal_segment_size: u64,
+    // Obsolete WAL segments kept rather than deleted, newest first
+    pub wal_retained_segments: usize,
+    // Values longer than this go to blob files when written to an SSTable
+    pub blob_threshold: Option<usize>,
+    // Largest WAL record, encoded, that's written or read back
+    pub max_record_size: u64,
+}
 
-    pub fn size(&self) -> usize {
-        self.size
+impl Default for StorageOptions {
+    fn default() -> Self {
+        StorageOptions {
+            flush_threshold: 1024 * 1024, // 1MB
+            flush_low_watermark: None,
+            create_if_missing: true,
+            compaction: CompactionPolicy::default(),
+            bloom_false_positive_rate: 0.01,
+            compression: Compression::None,
+            durability: Durability::NoSync,
+            block_cache_size: 8 * 1024 * 1024, // 8MB
+            read_only: false,
+            memtable: memtable::new_table::<BTreeMemTable>,
+            memtable_shards: 1,
+            merge: None,
+            wal_segment_size: 4 * 1024 * 1024, // 4MB
+            wal_retained_segments: 2,
+            blob_threshold: None,
+            max_record_size: 64 * 1024 * 1024, // 64MB
+        }
     }
 }
 
 /// Main LSM storage engine
 #[derive(Debug)]
 pub struct LsmStorage {
-    memtable: Arc<RwLock<MemTable>>,
-    wal: RwLock<Wal>,
-    sstables: RwLock<Vec<PathBuf>>,
-    flush_threshold: usize,
+    // Locked for reading by each write, which then locks the shards it
+    // writes to, and for writing to freeze it
+    memtable: Arc<RwLock<ActiveMemTable>>,
+    // `None` when opened read-only
+    wal: Option<Arc<RwLock<Wal>>>,
+    shared: Arc<Shared>,
+    // The running background flush, if any
+    flusher: Mutex<Option<JoinHandle<Result<(), DbError>>>>,
+    // The locked `LOCK` file, keeping other writable handles out until the
+    // storage is dropped; `None` when opened read-only
+    _lock: Option<File>,
 }
 
+/// The part of [`LsmStorage`] a background flush works on
+#[derive(Debug)]
+struct Shared {
+    dir: PathBuf,
+    options: StorageOptions,
+    // The shards of a full memtable waiting to be flushed, read until its
+    // SSTables are in place
+    immutable: RwLock<Option<Vec<MemTable>>>,
+    sstables: RwLock<Levels>,
+    blobs: BlobFiles,
+    cache: Arc<BlockCache>,
+    // Distinguishes SSTables flushed within the same second
+    flush_seq: AtomicU64,
+    // Whether a task runs compactions; without a Tokio runtime to spawn it
+    // on, flushes compact inline instead
+    scheduled: bool,
+    compaction_wakeup: Notify,
+    compaction_paused: AtomicBool,
+    // Held by the compaction in progress
+    compacting: Mutex<()>,
+    counters: Counters,
+    // Bulk loads under way, which raise the flush threshold while any is
+    bulk_loads: AtomicUsize,
+    // First WAL segment with writes not yet in an SSTable, as recorded in
+    // the manifest
+    wal_start: AtomicU64,
+    // The segment after the frozen memtable's, which `wal_start` moves to
+    // once it's flushed
+    frozen_wal_end: AtomicU64,
+    // Set once the storage is dropped, to stop the compaction task
+    closed: AtomicBool,
+}
+
+/// The single WAL of versions before segments
+const LEGACY_WAL: &str = "wal.log";
+
+/// Locked by the writable handle that has the directory open
+const LOCK_FILE: &str = "LOCK";
+
 impl LsmStorage {
-    pub fn new(path: &Path, flush_threshold: usize) -> Result<Self, DbError> {
-        let wal_path = path.join("wal.log");
-        let wal = Wal::new(&wal_path)?;
+    pub(crate) fn new(path: &Path, options: StorageOptions) -> Result<Self, DbError> {
+        if options.create_if_missing && !options.read_only {
+            std::fs::create_dir_all(path)?;
+        } else if !path.is_dir() {
+            return Err(DbError::StorageError(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("database directory {} does not exist", path.display()),
+            )));
+        }
+        // Taken before anything is repaired or replayed, which only the one
+        // writable handle may do
+        let lock = if options.read_only { None } else { Some(lock_dir(path)?) };
+
+        let blobs = BlobFiles::new(path)?;
+        let (sstables, wal_start) = manifest::load(path, &options.compaction, options.read_only, &blobs)?;
+        if !options.read_only {
+            blobs.remove_unreferenced()?;
+        }
+        // New tables are numbered past every one already listed, so none is
+        // written over
+        let flush_seq = sstables
+            .iter()
+            .filter_map(|table| manifest::sstable_seq(&table.file_name()))
+            .map(|(_, seq)| seq + 1)
+            .max()
+            .unwrap_or(0);
+
+        // Rebuild the memtable from anything logged but not yet flushed,
+        // including a frozen memtable whose flush was cut short
+        let mut memtable = ActiveMemTable::new(options.memtable_shards, options.memtable, options.merge);
+        let logged = if options.read_only {
+            Wal::read(path, wal_start, options.max_record_size)?
+        } else {
+            Wal::recover(path, wal_start, options.max_record_size)?
+        };
+        if options.merge.is_none() && logged.iter().any(|op| matches!(op, StorageOp::Merge(..))) {
+            return Err(DbError::NoMergeOperator);
+        }
+        for op in logged {
+            memtable.apply(op);
+        }
+
+        let wal = if options.read_only {
+            None
+        } else {
+            let wal = Wal::open(path, wal_start, &options)?;
+            let wal = Arc::new(RwLock::new(wal));
+            remove_obsolete_segments(path, wal_start, options.wal_retained_segments)?;
+            // A new WAL segment or LOCK file isn't durable until its
+            // directory entry is
+            sync_dir(path)?;
+            if let Durability::Batched(interval) = options.durability {
+                spawn_wal_syncer(Arc::downgrade(&wal), interval);
+            }
+            Some(wal)
+        };
+
+        // Read-only storage never compacts, so it has nothing to schedule
+        let runtime = tokio::runtime::Handle::try_current().ok().filter(|_| !options.read_only);
+        let shared = Arc::new(Shared {
+            dir: path.to_path_buf(),
+            immutable: RwLock::new(None),
+            sstables: RwLock::new(sstables),
+            blobs,
+            cache: Arc::new(BlockCache::new(options.block_cache_size)),
+            options,
+            flush_seq: AtomicU64::new(flush_seq),
+            scheduled: runtime.is_some(),
+            compaction_wakeup: Notify::new(),
+            compaction_paused: AtomicBool::new(false),
+            compacting: Mutex::new(()),
+            counters: Counters::default(),
+            bulk_loads: AtomicUsize::new(0),
+            wal_start: AtomicU64::new(wal_start),
+            frozen_wal_end: AtomicU64::new(wal_start),
+            closed: AtomicBool::new(false),
+        });
+        if let Some(runtime) = runtime {
+            runtime.spawn(schedule_compactions(Arc::clone(&shared)));
+        }
 
         Ok(LsmStorage {
-            memtable: Arc::new(RwLock::new(MemTable::new())),
-            wal: RwLock::new(wal),
-            sstables: RwLock::new(Vec::new()),
-            flush_threshold,
+            memtable: Arc::new(RwLock::new(memtable)),
+            wal,
+            shared,
+            flusher: Mutex::new(None),
+            _lock: lock,
+        })
+    }
+
+    /// The database directory.
+    pub(crate) fn dir(&self) -> &Path {
+        &self.shared.dir
+    }
+
+    /// Whether the storage was opened read-only, so every write fails with
+    /// [`DbError::ReadOnly`].
+    pub(crate) fn is_read_only(&self) -> bool {
+        self.wal.is_none()
+    }
+
+    // The WAL to log a write to
+    fn wal(&self) -> Result<&RwLock<Wal>, DbError> {
+        self.wal.as_deref().ok_or(DbError::ReadOnly)
+    }
+
+    // Fail a change that may not come down to a write, e.g. one with
+    // nothing to delete, the same way as a write
+    fn check_writable(&self) -> Result<(), DbError> {
+        self.wal().map(|_| ())
+    }
+
+    /// Log and apply a single operation.
+    ///
+    /// The shard the key goes in stays locked from before the op is logged
+    /// until it's applied, so writes to one key reach the memtable in the
+    /// order the WAL has them, while writes to other shards go ahead.
+    pub(crate) fn write(&self, op: StorageOp) -> Result<(), DbError> {
+        let wal = self.wal()?;
+        {
+            let memtable = self.memtable.read().map_err(|_| poisoned("memtable"))?;
+            let mut shards = memtable.lock(op.key())?;
+            let logged = wal.write().map_err(|_| poisoned("wal"))?.write(&op)?;
+            self.shared.counters.logged(std::slice::from_ref(&op), logged);
+            shards.apply(op);
+        }
+        self.freeze_if_full();
+        Ok(())
+    }
+
+    /// Apply inserts and deletes as one unit: they're logged between
+    /// `Begin`/`Commit` markers, so WAL replay sees all of them or none.
+    pub(crate) fn write_atomic(&self, ops: Vec<StorageOp>) -> Result<(), DbError> {
+        let mut framed = Vec::with_capacity(ops.len() + 2);
+        framed.push(StorageOp::Begin);
+        framed.extend(ops);
+        framed.push(StorageOp::Commit);
+
+        let wal = self.wal()?;
+        {
+            let memtable = self.memtable.read().map_err(|_| poisoned("memtable"))?;
+            let mut shards = memtable.lock(framed.iter().filter_map(StorageOp::key))?;
+            let logged = wal.write().map_err(|_| poisoned("wal"))?.write_batch(&framed)?;
+            self.shared.counters.logged(&framed, logged);
+            shards.reserve(framed.len() - 2);
+            for op in framed {
+                shards.apply(op);
+            }
+        }
+        self.freeze_if_full();
+        Ok(())
+    }
+
+    pub fn delete(&self, key: Vec<u8>) -> Result<(), DbError> {
+        self.write(StorageOp::Delete(key))
+    }
+
+    /// Log a merge operand for `key`, to be combined with its value by the
+    /// merge operator. Fails with [`DbError::NoMergeOperator`] if there's
+    /// none, before anything is written.
+    pub(crate) fn merge(&self, key: Vec<u8>, operand: Vec<u8>) -> Result<(), DbError> {
+        if self.shared.options.merge.is_none() {
+            return Err(DbError::NoMergeOperator);
+        }
+        self.write(StorageOp::Merge(key, operand))
+    }
+
+    /// Delete every live key starting with one of `prefixes`, as one
+    /// atomic write, and return those keys.
+    pub(crate) fn delete_prefixes(&self, prefixes: &[Vec<u8>]) -> Result<BTreeSet<Vec<u8>>, DbError> {
+        self.check_writable()?;
+        let keys = self.live_keys(prefixes)?;
+        self.delete_all(keys.clone())?;
+        Ok(keys)
+    }
+
+    /// Like [`LsmStorage::delete_prefixes`], but SSTables holding nothing
+    /// but such keys are removed outright rather than left for compaction
+    /// to rewrite.
+    ///
+    /// Every key of a removed table is deleted, live or not, so no value one
+    /// of its tombstones stood over in an older table can come back. Only
+    /// the live ones are returned.
+    pub(crate) fn truncate_prefixes(&self, prefixes: &[Vec<u8>]) -> Result<BTreeSet<Vec<u8>>, DbError> {
+        self.check_writable()?;
+        let matches = |key: &[u8]| prefixes.iter().any(|prefix| key.starts_with(prefix));
+        let sstables = self.shared.sstables.read().map_err(|_| poisoned("sstables"))?.clone();
+        let live = self.live_keys(prefixes)?;
+        let mut keys = live.clone();
+        let mut removed = Vec::new();
+        'tables: for table in sstables.iter() {
+            if !table.key_range().is_some_and(|(first, last)| matches(first) && matches(last)) {
+                continue;
+            }
+            let mut table_keys = Vec::new();
+            for entry in table.iter()? {
+                let (key, _) = entry?;
+                if !matches(&key) {
+                    continue 'tables;
+                }
+                table_keys.push(key);
+            }
+            keys.extend(table_keys);
+            removed.push(Arc::clone(table));
+        }
+
+        // The deletes are written first: they're newer than every table
+        // picked, so the tables can go whenever
+        self.delete_all(keys)?;
+        self.shared.remove_sstables(&removed)?;
+        Ok(live)
+    }
+
+    // Live keys under any of `prefixes`, read at one point in time
+    fn live_keys(&self, prefixes: &[Vec<u8>]) -> Result<BTreeSet<Vec<u8>>, DbError> {
+        self.read(|view| {
+            let mut keys = BTreeSet::new();
+            for prefix in prefixes {
+                keys.extend(view.scan_prefix_keys(prefix)?);
+            }
+            Ok(keys)
+        })
+    }
+
+    fn delete_all(&self, keys: BTreeSet<Vec<u8>>) -> Result<(), DbError> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        self.write_atomic(keys.into_iter().map(StorageOp::Delete).collect())
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, DbError> {
+        Ok(self.lookup(key)?.into_value())
+    }
+
+    /// The value of each of `keys`, in the same order, all read at one
+    /// point in time. Looking them up in key order reads each SSTable block
+    /// once however many of the keys are in it.
+    pub(crate) fn get_many(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, DbError> {
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by_key(|&i| &keys[i]);
+        let mut values = vec![None; keys.len()];
+        self.read(|view| {
+            for i in order {
+                self.shared.counters.got();
+                values[i] = view.lookup(&keys[i])?.into_value();
+            }
+            Ok(())
+        })?;
+        Ok(values)
+    }
+
+    /// Like [`LsmStorage::get`], but telling a deleted key from a missing one.
+    pub(crate) fn lookup(&self, key: &[u8]) -> Result<Lookup, DbError> {
+        self.shared.counters.got();
+        self.read(|view| view.lookup(key))
+    }
+
+    /// Whether `key` has a live value, found the same way as by
+    /// [`LsmStorage::get`] but without copying the value.
+    pub fn contains(&self, key: &[u8]) -> Result<bool, DbError> {
+        self.shared.counters.got();
+        self.read(|view| view.contains(key))
+    }
+
+    /// The entries of the SSTable in use named `name`, read straight
+    /// from its file.
+    pub(crate) fn scan_sstable(&self, name: &str) -> Result<SsTableScan, DbError> {
+        let sstables = self.shared.sstables.read().map_err(|_| poisoned("sstables"))?;
+        let table = sstables.iter().find(|table| table.file_name() == name).cloned().ok_or_else(|| {
+            DbError::StorageError(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no SSTable named {} is in use", name),
+            ))
+        })?;
+        drop(sstables);
+        table.scan()
+    }
+
+    /// Operation counts since open.
+    pub(crate) fn metrics(&self) -> Metrics {
+        self.shared.counters.snapshot(self.shared.cache.counters())
+    }
+
+    /// Every live entry whose key starts with `prefix`, in key order.
+    ///
+    /// SSTables and the memtables are merged newest-wins, and keys whose
+    /// latest entry is a tombstone are left out.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<KeyValue>, DbError> {
+        self.read(|view| view.scan_prefix(prefix))
+    }
+
+    /// Like [`LsmStorage::scan_prefix`], but without holding on to the values.
+    pub fn scan_prefix_keys(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, DbError> {
+        self.read(|view| view.scan_prefix_keys(prefix))
+    }
+
+    /// Every live entry with a key between `start` and `end`, in key order,
+    /// merged the same way as [`LsmStorage::scan_prefix`].
+    pub fn scan_range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<Vec<KeyValue>, DbError> {
+        self.read(|view| view.scan_range(start, end))
+    }
+
+    /// Like [`LsmStorage::scan_range`], but without holding on to the values.
+    pub(crate) fn scan_range_keys(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<Vec<Vec<u8>>, DbError> {
+        self.read(|view| view.scan_range_keys(start, end))
+    }
+
+    /// Sizes of every layer. Counting live keys reads every key, so this
+    /// costs about as much as a scan of the whole database.
+    pub(crate) fn stats(&self) -> Result<DbStats, DbError> {
+        let wal_bytes = self.wal_bytes()?;
+        let (block_cache_hits, block_cache_misses) = self.shared.cache.counters();
+        self.read(|view| {
+            let memtables = view.immutable.iter().chain([&view.memtable]);
+            Ok(DbStats {
+                memtable_bytes: memtables.clone().map(Shards::size).sum(),
+                memtable_keys: memtables.map(Shards::len).sum(),
+                sstables: view.sstables.len(),
+                sstable_bytes: view.sstables.iter().map(|table| table.file_len()).sum(),
+                blob_bytes: blob_files(view.sstables).iter().map(|blob| file_len(blob.path())).sum::<Result<_, _>>()?,
+                live_keys: view.scan_prefix_keys(&[])?.len(),
+                wal_bytes,
+                block_cache_hits,
+                block_cache_misses,
+                // Counted by `Database`, above the storage
+                table_scans: 0,
+            })
+        })
+    }
+
+    // Bytes of the WAL that recovery would replay, including writes still
+    // buffered in memory
+    fn wal_bytes(&self) -> Result<u64, DbError> {
+        let dir = &self.shared.dir;
+        let wal_start = self.shared.wal_start.load(Ordering::SeqCst);
+        let mut wal_bytes = 0;
+        for seq in wal_segments(dir)?.into_iter().filter(|&seq| seq >= wal_start) {
+            wal_bytes += file_len(&dir.join(segment_name(seq)))?;
+        }
+        match &self.wal {
+            // Not written out to the file yet
+            Some(wal) => wal_bytes += wal.read().map_err(|_| poisoned("wal"))?.writer.buffer().len() as u64,
+            None => wal_bytes += file_len(&dir.join(LEGACY_WAL))?,
+        }
+        Ok(wal_bytes)
+    }
+
+    /// Freeze the current memtables and SSTable set for later reads.
+    ///
+    /// The memtable is copy-on-write, so this is cheap; the first write after
+    /// it pays for one copy. SSTables a later compaction replaces stay on disk
+    /// until the snapshot is dropped. Expiry is judged as of when it was
+    /// taken, so records don't expire out of it either.
+    pub(crate) fn snapshot(&self) -> Result<StorageSnapshot, DbError> {
+        self.read(|view| {
+            Ok(StorageSnapshot {
+                memtable: view.memtable.to_vec(),
+                immutable: view.immutable.as_ref().map(Shards::to_vec),
+                sstables: view.sstables.clone(),
+                cache: Arc::clone(&self.shared.cache),
+                merge: view.merge,
+                taken_at: view.now,
+            })
+        })
+    }
+
+    // Run `f` over the current state. Every layer stays locked throughout,
+    // in the same order the flush takes them, so a flush finishing
+    // concurrently can't move entries from one to the next mid-read.
+    fn read<R>(&self, f: impl FnOnce(&ReadView<'_>) -> Result<R, DbError>) -> Result<R, DbError> {
+        let memtable = self.memtable.read().map_err(|_| poisoned("memtable"))?;
+        let shards = memtable.read()?;
+        let immutable = self.shared.immutable.read().map_err(|_| poisoned("immutable memtable"))?;
+        let sstables = self.shared.sstables.read().map_err(|_| poisoned("sstables"))?;
+        f(&ReadView {
+            memtable: Shards::new(shards.iter().map(|shard| &**shard)),
+            immutable: immutable.as_deref().map(Shards::new),
+            sstables: &sstables,
+            cache: &self.shared.cache,
+            merge: self.shared.options.merge,
+            now: now_millis(),
+        })
+    }
+
+    // Freeze the memtable if a write took it to the high watermark, or to
+    // the low one with no flush running. Past the low mark, a memtable
+    // reached while the last one is still being flushed is left to grow
+    // rather than wait on it, and is frozen once that flush is done or at
+    // the high mark, whichever comes first, so no flush is started on a
+    // memtable under the low mark. The size is checked again once it's
+    // locked, as another writer may have frozen it first.
+    //
+    // The write that got here is already logged and applied, so nothing
+    // that goes wrong is its error: it's logged, the memtable stays as it
+    // is, and the next write or flush tries again. A flush that keeps
+    // failing is reported by `flush` and `close`.
+    fn freeze_if_full(&self) {
+        if let Err(e) = self.try_freeze_if_full() {
+            log::warn!("Memtable flush failed; its writes stay in the WAL and the flush is retried: {}", e);
+        }
+    }
+
+    fn try_freeze_if_full(&self) -> Result<(), DbError> {
+        let (low, high) = self.shared.flush_watermarks();
+        if self.memtable.read().map_err(|_| poisoned("memtable"))?.size() < low {
+            return Ok(());
+        }
+        let mut memtable = self.memtable.write().map_err(|_| poisoned("memtable"))?;
+        let mut wal = self.wal()?.write().map_err(|_| poisoned("wal"))?;
+        let size = memtable.size();
+        if size >= high || (size >= low && !self.flush_running()?) {
+            // Only one memtable is frozen at a time, so this waits on a
+            // flush still running at the high mark. Its result is its own.
+            self.join_flush();
+            if self.shared.immutable.read().map_err(|_| poisoned("immutable memtable"))?.is_some() {
+                // It failed, and left its memtable frozen: retry that in
+                // the background, and freeze this one on a later write
+                return self.spawn_flush();
+            }
+            self.freeze(&mut wal, &mut memtable)?;
+        }
+        Ok(())
+    }
+
+    // Whether a flush is under way, or one that failed left its memtable
+    // frozen to be retried
+    fn flush_running(&self) -> Result<bool, DbError> {
+        // The flush thread is asked first, as it holds the frozen memtable's
+        // lock while it puts its SSTables in place
+        let flusher = self.flusher.lock().map_err(|_| poisoned("flusher"))?;
+        if flusher.as_ref().is_some_and(|handle| !handle.is_finished()) {
+            return Ok(true);
+        }
+        Ok(self.shared.immutable.read().map_err(|_| poisoned("immutable memtable"))?.is_some())
+    }
+
+    /// Swap in an empty memtable and flush the full one in the background.
+    ///
+    /// Runs under the caller's memtable and WAL guards, and every write
+    /// holds the memtable lock from logging to applying, so the log moves
+    /// on to a new segment exactly between the last write of the frozen
+    /// memtable and the first of the new one. Writes carry on into the new
+    /// memtable while the flush runs. Only one memtable is frozen at a
+    /// time, so the caller first makes sure no flush is running and none
+    /// left its memtable frozen.
+    fn freeze(&self, wal: &mut Wal, memtable: &mut ActiveMemTable) -> Result<(), DbError> {
+        let next = wal.roll()?;
+        self.shared.frozen_wal_end.store(next, Ordering::SeqCst);
+        *self.shared.immutable.write().map_err(|_| poisoned("immutable memtable"))? = Some(memtable.take());
+        self.spawn_flush()
+    }
+
+    // Flush the frozen memtable on a thread of its own
+    fn spawn_flush(&self) -> Result<(), DbError> {
+        let shared = Arc::clone(&self.shared);
+        let handle = std::thread::spawn(move || shared.flush_immutable());
+        *self.flusher.lock().map_err(|_| poisoned("flusher"))? = Some(handle);
+        Ok(())
+    }
+
+    /// Write the memtable out as an SSTable, and wait until it and any
+    /// flush already running are done. A memtable a failed flush left
+    /// frozen is written out too, and the error is this flush's if that
+    /// fails again.
+    pub(crate) fn flush(&self) -> Result<(), DbError> {
+        {
+            let mut memtable = self.memtable.write().map_err(|_| poisoned("memtable"))?;
+            let mut wal = self.wal()?.write().map_err(|_| poisoned("wal"))?;
+            self.join_flush();
+            self.shared.flush_immutable()?;
+            if memtable.size() > 0 {
+                self.freeze(&mut wal, &mut memtable)?;
+            }
+        }
+        self.wait_for_flush()
+    }
+
+    /// Write out the WAL's buffered records and `fsync` it, whatever the
+    /// durability mode, leaving the memtable where it is. Earlier segments
+    /// were synced as they were finished.
+    pub(crate) fn sync_wal(&self) -> Result<(), DbError> {
+        self.wal()?.write().map_err(|_| poisoned("wal"))?.sync()
+    }
+
+    /// Flush, `fsync` the WAL whatever the durability mode, and stop the
+    /// background flush and compaction. Unlike dropping the storage, this
+    /// reports what goes wrong along the way.
+    /// Read-only storage has nothing to flush or sync, and just closes.
+    pub(crate) fn close(self) -> Result<(), DbError> {
+        if let Some(wal) = &self.wal {
+            self.flush()?;
+            wal.write().map_err(|_| poisoned("wal"))?.sync()?;
+        }
+        // Dropping waits for a running compaction
+        drop(self);
+        Ok(())
+    }
+
+    /// Merge every SSTable into the last level, dropping tombstones and
+    /// expired values. Runs even while compaction is paused.
+    pub(crate) fn compact(&self) -> Result<CompactionReport, DbError> {
+        self.check_writable()?;
+        self.shared.compact_all()
+    }
+
+    /// Stop starting compactions until [`LsmStorage::resume_compaction`].
+    /// One already running finishes.
+    pub(crate) fn pause_compaction(&self) {
+        self.shared.compaction_paused.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn resume_compaction(&self) -> Result<(), DbError> {
+        self.shared.compaction_paused.store(false, Ordering::SeqCst);
+        if self.is_read_only() {
+            Ok(())
+        } else if self.shared.scheduled {
+            self.shared.compaction_wakeup.notify_one();
+            Ok(())
+        } else {
+            self.shared.compact_due()
+        }
+    }
+
+    /// Let the memtable grow [`BULK_FLUSH_FACTOR`] times the flush
+    /// threshold, and the WAL buffer [`BULK_WAL_BUFFER`] bytes, until the
+    /// returned guard is dropped. Bulk loads may overlap: the settings go
+    /// back once the last one ends.
+    pub(crate) fn begin_bulk(&self) -> Result<BulkMode, DbError> {
+        let wal = Arc::clone(self.wal.as_ref().ok_or(DbError::ReadOnly)?);
+        {
+            // Counted under the WAL lock, so the buffer follows the count
+            let mut log = wal.write().map_err(|_| poisoned("wal"))?;
+            if self.shared.bulk_loads.load(Ordering::SeqCst) == 0 {
+                log.set_buffer_capacity(BULK_WAL_BUFFER)?;
+            }
+            self.shared.bulk_loads.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(BulkMode { wal, shared: Arc::clone(&self.shared) })
+    }
+
+    /// End a bulk load, and freeze the memtable if it's now past the
+    /// threshold, rather than leave that to the next write.
+    pub(crate) fn end_bulk(&self, bulk: BulkMode) -> Result<(), DbError> {
+        drop(bulk);
+        self.check_writable()?;
+        self.freeze_if_full();
+        Ok(())
+    }
+
+    // Wait for the background flush like `wait_for_flush`, logging rather
+    // than returning its error: a failed flush leaves its memtable frozen,
+    // so the retry that's next has the error to report, if any
+    fn join_flush(&self) {
+        if let Err(e) = self.wait_for_flush() {
+            log::warn!("Background memtable flush failed; it's retried: {}", e);
+        }
+    }
+
+    /// Block until the background flush, if one is running, finishes, and
+    /// return its error.
+    fn wait_for_flush(&self) -> Result<(), DbError> {
+        let Some(handle) = self.flusher.lock().map_err(|_| poisoned("flusher"))?.take() else {
+            return Ok(());
+        };
+        handle.join().unwrap_or_else(|_| {
+            Err(DbError::StorageError(std::io::Error::other("memtable flush panicked")))
         })
     }
+}
+
+impl Drop for LsmStorage {
+    // A flush or compaction left running would race a reopen of the same
+    // directory for its files
+    fn drop(&mut self) {
+        if let Err(e) = self.wait_for_flush() {
+            log::error!("Memtable flush failed; its writes stay in the WAL: {}", e);
+        }
+        self.shared.closed.store(true, Ordering::SeqCst);
+        self.shared.compaction_wakeup.notify_one();
+        drop(self.shared.compacting.lock());
+    }
+}
+
+/// A bulk load under way, from [`LsmStorage::begin_bulk`]
+#[derive(Debug)]
+pub(crate) struct BulkMode {
+    wal: Arc<RwLock<Wal>>,
+    shared: Arc<Shared>,
+}
+
+impl Drop for BulkMode {
+    fn drop(&mut self) {
+        let mut wal = self.wal.write().unwrap_or_else(PoisonError::into_inner);
+        if self.shared.bulk_loads.fetch_sub(1, Ordering::SeqCst) == 1 {
+            if let Err(e) = wal.set_buffer_capacity(WAL_BUFFER) {
+                log::warn!("Failed to shrink the WAL buffer after a bulk load: {}", e);
+            }
+        }
+    }
+}
+
+impl Shared {
+    // Memtable sizes at which it's frozen and flushed, the low watermark
+    // and the high one, both raised while a bulk load is under way
+    fn flush_watermarks(&self) -> (usize, usize) {
+        let high = self.options.flush_threshold;
+        let low = self.options.flush_low_watermark.map_or(high, |low| low.min(high));
+        if self.bulk_loads.load(Ordering::SeqCst) > 0 {
+            (low.saturating_mul(BULK_FLUSH_FACTOR), high.saturating_mul(BULK_FLUSH_FACTOR))
+        } else {
+            (low, high)
+        }
+    }
 
-    pub fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), DbError> {
-        let mut wal = self.wal.write().unwrap();
-        wal.write(&StorageOp::Insert(key.clone(), value.clone()))?;
+    fn next_sstable_path(&self) -> PathBuf {
+        let sstable_name = format!(
+            "sst-{}-{}.bin",
+            chrono::Utc::now().timestamp(),
+            self.flush_seq.fetch_add(1, Ordering::SeqCst)
+        );
+        self.dir.join(sstable_name)
+    }
+
+    /// Write the frozen memtable out as SSTables, put them in the
+    /// memtable's place, and mark the WAL segments it came from obsolete.
+    ///
+    /// A memtable holding more than [`CompactionPolicy::target_file_size`]
+    /// is split into tables of about that size, which don't overlap and all
+    /// go into level 0.
+    ///
+    /// If the SSTable or the manifest can't be written, say on a full disk,
+    /// the frozen memtable stays where reads find it, nothing of the failed
+    /// SSTable is left on disk, and the error is a [`DbError::FlushFailed`].
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "flush", skip_all, fields(entries, bytes, tables, elapsed_us))
+    )]
+    fn flush_immutable(&self) -> Result<(), DbError> {
+        let Some(frozen) = self.immutable.read().map_err(|_| poisoned("immutable memtable"))?.clone() else {
+            return Ok(());
+        };
+        let frozen = Shards::new(&frozen);
+        let _timer = trace::Timer::start();
+        trace::record!(entries = frozen.len(), bytes = frozen.size());
 
-        let mut memtable = self.memtable.write().unwrap();
-        memtable.insert(key, value);
+        // The SSTables must be durable before the WAL entries are dropped
+        let tables = self.write_sstables(&frozen).map_err(flush_failed)?;
+        trace::record!(tables = tables.len());
+        let wal_start = self.frozen_wal_end.load(Ordering::SeqCst);
 
-        if memtable.size() >= self.flush_threshold {
-            drop(memtable); // unlock before flush
-            self.flush_memtable()?;
+        {
+            // Locked in the same order as reads take them
+            let mut immutable = self.immutable.write().map_err(|_| poisoned("immutable memtable"))?;
+            let mut sstables = self.sstables.write().map_err(|_| poisoned("sstables"))?;
+            let mut flushed = sstables.clone();
+            for table in &tables {
+                flushed.push_flushed(Arc::clone(table));
+            }
+            if let Err(e) = manifest::store(&self.dir, &flushed, wal_start) {
+                for table in tables {
+                    table.mark_obsolete();
+                }
+                return Err(flush_failed(e));
+            }
+            *sstables = flushed;
+            *immutable = None;
+            self.wal_start.store(wal_start, Ordering::SeqCst);
         }
 
+        remove_obsolete_segments(&self.dir, wal_start, self.options.wal_retained_segments)?;
+        self.counters.flushed();
+
+        if self.scheduled {
+            self.compaction_wakeup.notify_one();
+            Ok(())
+        } else {
+            self.compact_due()
+        }
+    }
+
+    fn write_sstables(&self, memtable: &Shards<'_>) -> Result<Vec<Arc<SsTable>>, DbError> {
+        let mut writer = SplitWriter::new(self, self.options.compaction.target_file_size.max(1));
+        for (key, value) in memtable.iter() {
+            writer.add(key, value.as_ref().map(|value| Stored::Inline(value.as_slice())))?;
+        }
+        writer.finish()
+    }
+
+    /// Run the compactions the policy calls for until none is left, unless
+    /// compaction is paused.
+    fn compact_due(&self) -> Result<(), DbError> {
+        let _running = self.compacting.lock().map_err(|_| poisoned("compaction"))?;
+        while !self.compaction_paused.load(Ordering::SeqCst) && !self.closed.load(Ordering::SeqCst) {
+            let due = self.sstables.read().map_err(|_| poisoned("sstables"))?.due(&self.options.compaction);
+            match due {
+                Some(compaction) => {
+                    self.run_compaction(compaction)?;
+                }
+                None => break,
+            }
+        }
         Ok(())
     }
 
-    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        let memtable = self.memtable.read().unwrap();
-        if let Some(value) = memtable.get(key) {
-            return Some(value);
+    /// Merge every SSTable into the last level, paused or not.
+    fn compact_all(&self) -> Result<CompactionReport, DbError> {
+        let _running = self.compacting.lock().map_err(|_| poisoned("compaction"))?;
+        let full = self.sstables.read().map_err(|_| poisoned("sstables"))?.full();
+        match full {
+            Some(compaction) => self.run_compaction(compaction),
+            None => Ok(CompactionReport::default()),
+        }
+    }
+
+    /// Take `tables` out of the table set, skipping any a compaction has
+    /// already replaced, and delete their files.
+    fn remove_sstables(&self, tables: &[Arc<SsTable>]) -> Result<(), DbError> {
+        if tables.is_empty() {
+            return Ok(());
+        }
+        let _running = self.compacting.lock().map_err(|_| poisoned("compaction"))?;
+        let removed = {
+            let mut sstables = self.sstables.write().map_err(|_| poisoned("sstables"))?;
+            let mut remaining = sstables.clone();
+            let removed = remaining.remove(tables);
+            manifest::store(&self.dir, &remaining, self.wal_start.load(Ordering::SeqCst))?;
+            *sstables = remaining;
+            removed
+        };
+        for table in removed {
+            self.cache.evict_table(table.id());
+            table.mark_obsolete();
+        }
+        Ok(())
+    }
+
+    /// Merge the inputs of `compaction`, keeping only the newest entry for
+    /// each key, and swap the output in for them.
+    ///
+    /// Below level 0 the output is split into tables of about
+    /// [`CompactionPolicy::target_file_size`], which don't overlap since
+    /// the merge writes keys in order.
+    ///
+    /// Reads and flushes carry on while the merge runs; the table set is
+    /// only locked to swap the output in. The caller holds `compacting`, so
+    /// no other compaction can touch the same inputs meanwhile.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "compaction",
+            skip_all,
+            fields(
+                level = compaction.output_level,
+                inputs = compaction.inputs.len(),
+                outputs,
+                bytes_read,
+                bytes_written,
+                elapsed_us
+            )
+        )
+    )]
+    fn run_compaction(&self, compaction: Compaction) -> Result<CompactionReport, DbError> {
+        let _timer = trace::Timer::start();
+        let inputs = compaction
+            .inputs
+            .iter()
+            .map(|table| table.iter())
+            .collect::<Result<Vec<_>, _>>()?;
+        // Split level 0 output would only trip the next compaction of it
+        let target_file_size = match compaction.output_level {
+            0 => u64::MAX,
+            _ => self.options.compaction.target_file_size.max(1),
+        };
+
+        let mut writer = SplitWriter::new(self, target_file_size);
+        let now = now_millis();
+        let merge = self.options.merge;
+        let mut records_dropped = 0;
+        for entry in MergeIter::new(inputs, merge, now)? {
+            let (key, mut value) = entry?;
+            if compaction.bottom {
+                // Nothing older is left for merge operands to wait for
+                if matches!(value, Slot::Merge(_)) {
+                    value = value.load()?.collapse(merge)?.map(Stored::Inline);
+                }
+                if !value.is_live(now) {
+                    records_dropped += 1;
+                    continue;
+                }
+            } else if !value.is_live(now) {
+                // Still needed only to hide a value in an older table
+                let older = compaction.older.iter().map(|table| table.get(&key, &self.cache));
+                if !matches!(lookup_entries(older, merge, now)?, Lookup::Value(_)) {
+                    records_dropped += 1;
+                    continue;
+                }
+            }
+            // References to blob files are copied as they are
+            writer.add(&key, value.as_ref().map(Stored::as_deref))?;
+        }
+        let outputs = writer.finish()?;
+        let report = CompactionReport {
+            files_merged: compaction.inputs.len(),
+            files_written: outputs.len(),
+            bytes_read: compaction.inputs.iter().map(|table| table.file_len()).sum(),
+            bytes_written: outputs.iter().map(|table| table.file_len()).sum(),
+            records_dropped,
+        };
+        trace::record!(
+            outputs = report.files_written,
+            bytes_read = report.bytes_read,
+            bytes_written = report.bytes_written
+        );
+
+        // A read or snapshot may still hold the inputs, so each file is
+        // removed along with the last reference to its table, once the
+        // manifest no longer lists it
+        let replaced = {
+            let mut sstables = self.sstables.write().map_err(|_| poisoned("sstables"))?;
+            let mut compacted = sstables.clone();
+            let replaced = compacted.install(&compaction, outputs.clone());
+            if let Err(e) = manifest::store(&self.dir, &compacted, self.wal_start.load(Ordering::SeqCst)) {
+                for output in outputs {
+                    output.mark_obsolete();
+                }
+                return Err(e);
+            }
+            *sstables = compacted;
+            replaced
+        };
+        for table in replaced {
+            self.cache.evict_table(table.id());
+            table.mark_obsolete();
+        }
+        self.counters.compacted();
+
+        Ok(report)
+    }
+}
+
+/// Writes entries given in key order into new SSTables, starting another
+/// once one holds `target` bytes of data blocks.
+///
+/// Values over [`StorageOptions::blob_threshold`] go to a blob file, which
+/// is likewise replaced by a new one once it holds `target` bytes.
+struct SplitWriter<'a> {
+    shared: &'a Shared,
+    target: u64,
+    current: Option<SsTableWriter>,
+    finished: Vec<Arc<SsTable>>,
+    blob: Option<BlobWriter>,
+    // Every blob file started, to be deleted if no table ends up referring to it
+    blobs_written: Vec<Arc<BlobFile>>,
+}
+
+impl<'a> SplitWriter<'a> {
+    fn new(shared: &'a Shared, target: u64) -> Self {
+        SplitWriter {
+            shared,
+            target,
+            current: None,
+            finished: Vec::new(),
+            blob: None,
+            blobs_written: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, key: &[u8], value: Slot<Stored<&[u8]>>) -> Result<(), DbError> {
+        let value = match value {
+            Slot::Value(Stored::Inline(value)) if self.is_large(value) => Slot::Value(self.write_blob(value)?),
+            Slot::Expiring(Stored::Inline(value), expires_at) if self.is_large(value) => {
+                Slot::Expiring(self.write_blob(value)?, expires_at)
+            }
+            value => value,
+        };
+        let current = match &mut self.current {
+            Some(current) => current,
+            None => self.current.insert(SsTableWriter::create(
+                &self.shared.next_sstable_path(),
+                self.shared.options.bloom_false_positive_rate,
+                self.shared.options.compression,
+            )?),
+        };
+        current.add(key, &value)?;
+        if current.data_len() >= self.target {
+            let full = self.current.take().expect("writer in use");
+            self.finish_table(full)?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Vec<Arc<SsTable>>, DbError> {
+        if let Some(last) = self.current.take() {
+            self.finish_table(last)?;
+        }
+        Ok(std::mem::take(&mut self.finished))
+    }
+
+    fn is_large(&self, value: &[u8]) -> bool {
+        self.shared.options.blob_threshold.is_some_and(|threshold| value.len() > threshold)
+    }
+
+    fn write_blob<'v>(&mut self, value: &[u8]) -> Result<Stored<&'v [u8]>, DbError> {
+        let blob = match &mut self.blob {
+            Some(blob) => blob,
+            None => {
+                let blob = self.shared.blobs.create()?;
+                self.blobs_written.push(Arc::clone(blob.file()));
+                self.blob.insert(blob)
+            }
+        };
+        let stored = blob.add(value)?;
+        if blob.len() >= self.target {
+            // Tables already written refer to it, so it's synced before any is finished
+            let mut full = self.blob.take().expect("blob writer in use");
+            full.sync()?;
+        }
+        Ok(Stored::Blob(stored))
+    }
+
+    // The blob file has to be durable before a table referring to it is
+    fn finish_table(&mut self, table: SsTableWriter) -> Result<(), DbError> {
+        if let Some(blob) = &mut self.blob {
+            blob.sync()?;
+        }
+        self.finished.push(Arc::new(table.finish(&self.shared.blobs)?));
+        Ok(())
+    }
+}
+
+impl Drop for SplitWriter<'_> {
+    // Tables finished before a failed write never made it into the
+    // manifest, so they go with the writer, and so do blob files no
+    // finished table refers to
+    fn drop(&mut self) {
+        for table in &self.finished {
+            table.mark_obsolete();
+        }
+        for blob in &self.blobs_written {
+            blob.discard_if_unreferenced();
+        }
+    }
+}
+
+// Run due compactions on the blocking pool whenever a flush asks for one,
+// until the storage is dropped
+async fn schedule_compactions(shared: Arc<Shared>) {
+    loop {
+        shared.compaction_wakeup.notified().await;
+        if shared.closed.load(Ordering::SeqCst) {
+            break;
+        }
+        let worker = Arc::clone(&shared);
+        match tokio::task::spawn_blocking(move || worker.compact_due()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => log::error!("Background compaction failed: {}", e),
+            Err(e) => log::error!("Background compaction panicked: {}", e),
+        }
+    }
+}
+
+/// Reads over the memtable, the one being flushed if any, and the SSTables
+/// under them, newest last
+struct ReadView<'a> {
+    memtable: Shards<'a>,
+    immutable: Option<Shards<'a>>,
+    sstables: &'a Levels,
+    cache: &'a BlockCache,
+    merge: Option<MergeFn>,
+    // Values expiring at or before this instant read as absent
+    now: i64,
+}
+
+impl ReadView<'_> {
+    /// See [`LsmStorage::lookup`].
+    pub(crate) fn lookup(&self, key: &[u8]) -> Result<Lookup, DbError> {
+        // Newest entry wins, so search the memtables, then the SSTables from
+        // the top level down
+        let memtables = [Some(&self.memtable), self.immutable.as_ref()].into_iter().flatten();
+        let entries = memtables
+            .map(|memtable| Ok(memtable.get(key).cloned()))
+            .chain(self.sstables.lookup(key).map(|table| table.get(key, self.cache)));
+        lookup_entries(entries, self.merge, self.now)
+    }
+
+    /// See [`LsmStorage::contains`].
+    pub(crate) fn contains(&self, key: &[u8]) -> Result<bool, DbError> {
+        if let Some(value) = self.memtable_get(key) {
+            return Ok(value.is_live(self.now));
+        }
+        for table in self.sstables.lookup(key) {
+            if let Some(value) = table.contains(key, self.cache)? {
+                return Ok(value.is_live(self.now));
+            }
+        }
+        Ok(false)
+    }
+
+    // The newest memtable entry for `key`
+    fn memtable_get(&self, key: &[u8]) -> Option<&Slot> {
+        self.memtable.get(key).or_else(|| self.immutable.as_ref().and_then(|immutable| immutable.get(key)))
+    }
+
+    /// See [`LsmStorage::scan_prefix`].
+    pub(crate) fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<KeyValue>, DbError> {
+        let end = prefix_end(prefix);
+        self.merge_range(Bound::Included(prefix), end.as_deref().map_or(Bound::Unbounded, Bound::Excluded), <[u8]>::to_vec, true)
+    }
+
+    /// See [`LsmStorage::scan_prefix_keys`].
+    pub(crate) fn scan_prefix_keys(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, DbError> {
+        let end = prefix_end(prefix);
+        Ok(self
+            .merge_range(Bound::Included(prefix), end.as_deref().map_or(Bound::Unbounded, Bound::Excluded), |_| (), false)?
+            .into_iter()
+            .map(|(key, ())| key)
+            .collect())
+    }
+
+    /// See [`LsmStorage::scan_range`].
+    pub(crate) fn scan_range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<Vec<KeyValue>, DbError> {
+        self.merge_range(start, end, <[u8]>::to_vec, true)
+    }
+
+    /// See [`LsmStorage::scan_range_keys`].
+    pub(crate) fn scan_range_keys(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<Vec<Vec<u8>>, DbError> {
+        Ok(self.merge_range(start, end, |_| (), false)?.into_iter().map(|(key, ())| key).collect())
+    }
+
+    // Live entries between `start` and `end`, with `project` applied to
+    // each value. Without `values`, `project` doesn't look at them, so
+    // those kept in blob files aren't read unless merge operands need them.
+    fn merge_range<V>(
+        &self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        project: impl Fn(&[u8]) -> V,
+        values: bool,
+    ) -> Result<Vec<(Vec<u8>, V)>, DbError> {
+        // `BTreeMap::range` panics on some empty ranges, so catch them all here
+        let empty = match (start, end) {
+            (Bound::Included(s), Bound::Included(e)) => s > e,
+            (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) => s >= e,
+            _ => false,
+        };
+        if empty {
+            return Ok(Vec::new());
+        }
+
+        let seek = match start {
+            Bound::Included(key) | Bound::Excluded(key) => key,
+            Bound::Unbounded => &[],
+        };
+        let inputs = self
+            .sstables
+            .iter()
+            .map(|table| table.iter_from(seek))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Layer the memtables first, the one being flushed under the live
+        // one, so each key's newest entry can go over the SSTables' as the
+        // merge below reaches it
+        let mut newest: BTreeMap<&[u8], Slot<Cow<'_, [u8]>>> = BTreeMap::new();
+        // No two shards hold the same key, so they go in in any order
+        let memtables = self.immutable.iter().chain([&self.memtable]).flat_map(Shards::tables);
+        for (key, value) in memtables.flat_map(|memtable| memtable.range(start, end)) {
+            let value = value.as_ref().map(|value| Cow::Borrowed(value.as_slice()));
+            let value = match newest.remove(key) {
+                Some(older) => value.over(older, self.merge, self.now)?,
+                None => value,
+            };
+            newest.insert(key, value);
+        }
+        let finish = |slot: Slot<Cow<'_, [u8]>>| -> Result<_, DbError> {
+            Ok(slot.collapse(self.merge)?.live(self.now).as_deref().map(&project))
+        };
+
+        let mut merged = BTreeMap::new();
+        for entry in MergeIter::new(inputs, self.merge, self.now)? {
+            let (key, value) = entry?;
+            if matches!(start, Bound::Excluded(start) if key.as_slice() == start) {
+                continue;
+            }
+            let past_end = match end {
+                Bound::Included(end) => key.as_slice() > end,
+                Bound::Excluded(end) => key.as_slice() >= end,
+                Bound::Unbounded => false,
+            };
+            if past_end {
+                break;
+            }
+            let newer = newest.remove(key.as_slice());
+            let value = if values || matches!(newer, Some(Slot::Merge(_))) {
+                value.load()?.map(Cow::Owned)
+            } else {
+                // Only whether the key is live matters
+                value.map(|value| match value {
+                    Stored::Inline(value) => Cow::Owned(value),
+                    Stored::Blob(_) => Cow::Borrowed(&[][..]),
+                })
+            };
+            let value = match newer {
+                Some(newer) => newer.over(value, self.merge, self.now)?,
+                None => value,
+            };
+            merged.insert(key, finish(value)?);
+        }
+        for (key, value) in newest {
+            merged.insert(key.to_vec(), finish(value)?);
+        }
+
+        Ok(merged
+            .into_iter()
+            .filter_map(|(key, value)| value.map(|value| (key, value)))
+            .collect())
+    }
+}
+
+/// Memtable entries a cursor reads at a time, from each memtable shard
+const CURSOR_WINDOW: usize = 64;
+
+/// A frozen memtable and SSTable set, from [`LsmStorage::snapshot`]
+#[derive(Debug)]
+pub(crate) struct StorageSnapshot {
+    // By shard, as are those of the memtable being flushed
+    memtable: Vec<MemTable>,
+    immutable: Option<Vec<MemTable>>,
+    sstables: Levels,
+    cache: Arc<BlockCache>,
+    merge: Option<MergeFn>,
+    taken_at: i64,
+}
+
+impl StorageSnapshot {
+    /// Put every SSTable of the snapshot and the blob files they refer to
+    /// into `dest`, hard-linked where the filesystem allows and copied
+    /// otherwise, along with a manifest listing the tables by level. The
+    /// memtables aren't included, so flush before taking the snapshot.
+    pub(crate) fn link_sstables(&self, dest: &Path) -> Result<(), DbError> {
+        let tables = self.sstables.iter().map(|table| (table.path(), table.file_name()));
+        let blobs = blob_files(&self.sstables);
+        for (path, name) in tables.chain(blobs.iter().map(|blob| (blob.path(), blob.file_name()))) {
+            let target = dest.join(name);
+            if std::fs::hard_link(path, &target).is_err() {
+                std::fs::copy(path, &target)?;
+                File::open(&target)?.sync_all()?;
+            }
         }
+        // With no WAL to go with them
+        manifest::store(dest, &self.sstables, 0)
+    }
 
-        // SSTable lookup (not implemented here yet)
-        None
+    pub(crate) fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, DbError> {
+        Ok(self.lookup(key)?.into_value())
     }
 
-    fn flush_memtable(&self) -> Result<(), DbError> {
-        let mut memtable = self.memtable.write().unwrap();
-        let snapshot = std::mem::replace(&mut *memtable, MemTable::new());
+    /// See [`LsmStorage::lookup`].
+    pub(crate) fn lookup(&self, key: &[u8]) -> Result<Lookup, DbError> {
+        self.view().lookup(key)
+    }
 
-        let sstable_name = format!("sst-{}.bin", chrono::Utc::now().timestamp());
-        let sstable_path = PathBuf::from(&sstable_name);
-        let mut file = File::create(&sstable_path)?;
+    pub(crate) fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<KeyValue>, DbError> {
+        self.view().scan_prefix(prefix)
+    }
 
-        for (key, value) in snapshot.data {
-            bincode::serialize_into(&mut file, &(key, value))
-                .map_err(|e| DbError::SerializationError(e.to_string()))?;
+    pub(crate) fn scan_prefix_keys(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, DbError> {
+        self.view().scan_prefix_keys(prefix)
+    }
+
+    pub(crate) fn scan_range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<Vec<KeyValue>, DbError> {
+        self.view().scan_range(start, end)
+    }
+
+    /// Live entries from `start` on, up to a key picked so that only a
+    /// window of each memtable and about a block of each SSTable is read,
+    /// and that key. `None` means the window reached the end.
+    pub(crate) fn window_after(&self, start: Bound<&[u8]>) -> Result<(Vec<KeyValue>, Option<Vec<u8>>), DbError> {
+        let tables = self.sstables.iter().filter_map(|table| table.block_end_after(start));
+        let memtables = self
+            .memtables()
+            .filter_map(|memtable| memtable.range(start, Bound::Unbounded).nth(CURSOR_WINDOW - 1))
+            .map(|(key, _)| key);
+        let limit = tables.chain(memtables).min().map(<[u8]>::to_vec);
+        let end = limit.as_deref().map_or(Bound::Unbounded, Bound::Included);
+        Ok((self.view().scan_range(start, end)?, limit))
+    }
+
+    /// [`StorageSnapshot::window_after`] the other way: live entries before
+    /// `end`, down to the key returned, or to the first key if that's `None`.
+    pub(crate) fn window_before(&self, end: Bound<&[u8]>) -> Result<(Vec<KeyValue>, Option<Vec<u8>>), DbError> {
+        let tables = self.sstables.iter().filter_map(|table| table.block_start_before(end));
+        let memtables = self
+            .memtables()
+            .filter_map(|memtable| memtable.range(Bound::Unbounded, end).nth_back(CURSOR_WINDOW - 1))
+            .map(|(key, _)| key);
+        let limit = tables.chain(memtables).max().map(<[u8]>::to_vec);
+        let start = limit.as_deref().map_or(Bound::Unbounded, Bound::Included);
+        Ok((self.view().scan_range(start, end)?, limit))
+    }
+
+    // Every shard of both memtables
+    fn memtables(&self) -> impl Iterator<Item = &MemTable> {
+        self.immutable.iter().flatten().chain(&self.memtable)
+    }
+
+    fn view(&self) -> ReadView<'_> {
+        ReadView {
+            memtable: Shards::new(&self.memtable),
+            immutable: self.immutable.as_deref().map(Shards::new),
+            sstables: &self.sstables,
+            cache: &self.cache,
+            merge: self.merge,
+            now: self.taken_at,
         }
+    }
+}
+
+// Smallest key above every key that starts with `prefix`, or `None` if
+// there isn't one (an empty or all-0xff prefix)
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+// Take the exclusive lock on `dir`'s `LOCK` file. It's advisory and held
+// through the open file, so the OS drops it with the handle, even after a
+// crash.
+fn lock_dir(dir: &Path) -> Result<File, DbError> {
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(dir.join(LOCK_FILE))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(std::fs::TryLockError::WouldBlock) => Err(DbError::AlreadyOpen { path: dir.to_path_buf() }),
+        Err(std::fs::TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// `fsync` the directory `dir`, so the files created, renamed or linked in
+/// it survive a power loss. Syncing a file covers only its contents, not its
+/// name. A no-op where directories can't be opened and synced, as on
+/// Windows.
+pub(crate) fn sync_dir(dir: &Path) -> Result<(), DbError> {
+    #[cfg(test)]
+    DIR_SYNCS.lock().unwrap().push(dir.to_path_buf());
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
 
-        self.sstables.write().unwrap().push(sstable_path);
+// Directories `sync_dir` was called on, for tests to count
+#[cfg(test)]
+static DIR_SYNCS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
 
-        self.wal.write().unwrap().writer.flush()?;
+/// The directory holding `path`, for [`sync_dir`].
+pub(crate) fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+// Every blob file `levels` refers to, once each, by id
+fn blob_files(levels: &Levels) -> Vec<&Arc<BlobFile>> {
+    let blobs: BTreeMap<u64, &Arc<BlobFile>> =
+        levels.iter().flat_map(|table| table.blobs()).map(|blob| (blob.id(), blob)).collect();
+    blobs.into_values().collect()
+}
+
+// Size of the file at `path`, or 0 if there's none
+fn file_len(path: &Path) -> Result<u64, DbError> {
+    match std::fs::metadata(path) {
+        Ok(metadata) => Ok(metadata.len()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn flush_failed(e: DbError) -> DbError {
+    DbError::FlushFailed { source: Box::new(e) }
+}
+
+fn poisoned(lock: &str) -> DbError {
+    DbError::LockPoisoned(lock.to_string())
+}
 
+/// Streaming k-way merge over sorted SSTables, oldest first.
+///
+/// Only one entry per input is buffered at a time. When a key appears in
+/// several inputs, the entry from the newest one wins, with any merge
+/// operands in it laid over the older ones (see [`Slot::over`]).
+struct MergeIter<'a> {
+    sources: Vec<SsTableIter<'a>>,
+    heads: Vec<Option<Slot<Stored>>>,
+    // Min-heap on key; ties pop the newest (highest index) source first
+    heap: BinaryHeap<Reverse<(Vec<u8>, Reverse<usize>)>>,
+    merge: Option<MergeFn>,
+    now: i64,
+}
+
+impl<'a> MergeIter<'a> {
+    fn new(sources: Vec<SsTableIter<'a>>, merge: Option<MergeFn>, now: i64) -> Result<Self, DbError> {
+        let mut iter = MergeIter {
+            heads: vec![None; sources.len()],
+            sources,
+            heap: BinaryHeap::new(),
+            merge,
+            now,
+        };
+        for source in 0..iter.sources.len() {
+            iter.advance(source)?;
+        }
+        Ok(iter)
+    }
+
+    fn advance(&mut self, source: usize) -> Result<(), DbError> {
+        if let Some(entry) = self.sources[source].next() {
+            let (key, value) = entry?;
+            self.heads[source] = Some(value);
+            self.heap.push(Reverse((key, Reverse(source))));
+        }
         Ok(())
     }
 }
+
+impl Iterator for MergeIter<'_> {
+    type Item = Result<Entry, DbError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((key, Reverse(source))) = self.heap.pop()?;
+        // Every source in the heap has its head buffered
+        let mut value = self.heads[source].take().unwrap_or(Slot::Tombstone);
+        if let Err(e) = self.advance(source) {
+            return Some(Err(e));
+        }
+
+        // Skip older versions of the same key, unless merge operands still
+        // need one
+        while let Some(Reverse((next_key, Reverse(older)))) = self.heap.peek() {
+            if *next_key != key {
+                break;
+            }
+            let older = *older;
+            self.heap.pop();
+            let older_value = self.heads[older].take().unwrap_or(Slot::Tombstone);
+            if matches!(value, Slot::Merge(_)) {
+                // Operands apply to the value itself, wherever it's stored
+                let merged = older_value.load().and_then(|older| value.load()?.over(older, self.merge, self.now));
+                value = match merged {
+                    Ok(value) => value.map(Stored::Inline),
+                    Err(e) => return Some(Err(e)),
+                };
+            }
+            if let Err(e) = self.advance(older) {
+                return Some(Err(e));
+            }
+        }
+
+        Some(Ok((key, value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(dir: &Path) -> LsmStorage {
+        LsmStorage::new(dir, StorageOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn poisoned_lock_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(dir.path());
+        storage.write(StorageOp::Insert(b"k".to_vec(), b"v".to_vec())).unwrap();
+
+        let memtable = Arc::clone(&storage.memtable);
+        std::thread::spawn(move || {
+            let _guard = memtable.write().unwrap();
+            panic!("poisoning the memtable lock");
+        })
+        .join()
+        .unwrap_err();
+
+        assert!(matches!(storage.get(b"k"), Err(DbError::LockPoisoned(_))));
+        let write = storage.write(StorageOp::Insert(b"k".to_vec(), b"w".to_vec()));
+        assert!(matches!(write, Err(DbError::LockPoisoned(_))));
+        assert!(matches!(storage.flush(), Err(DbError::LockPoisoned(_))));
+    }
+
+    // fsyncs of the WAL after 10k single writes under `durability`
+    fn wal_syncs(durability: Durability) -> u64 {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LsmStorage::new(dir.path(), StorageOptions { durability, ..StorageOptions::default() }).unwrap();
+        for i in 0..10_000u32 {
+            storage.write(StorageOp::Insert(i.to_be_bytes().to_vec(), vec![0; 16])).unwrap();
+        }
+        let syncs = storage.wal.as_ref().unwrap().read().unwrap().syncs;
+        syncs
+    }
+
+    #[test]
+    fn batched_durability_syncs_far_less_than_sync() {
+        let sync = wal_syncs(Durability::Sync);
+        let batched = wal_syncs(Durability::Batched(Duration::from_secs(60)));
+        let no_sync = wal_syncs(Durability::NoSync);
+        assert!(sync >= 10_000, "{} fsyncs", sync);
+        // Only those of the segments a flush or full segment closed
+        assert!(batched < 10, "{} fsyncs", batched);
+        assert_eq!(no_sync, 0);
+    }
+
+    // Whether a write to a key in another shard than the one locked
+    // finishes, with the memtable split into `shards`
+    fn write_beside_a_locked_shard(shards: usize) -> bool {
+        let dir = tempfile::tempdir().unwrap();
+        let options = StorageOptions { memtable_shards: shards, ..StorageOptions::default() };
+        let storage = LsmStorage::new(dir.path(), options).unwrap();
+        let other = (0..100u32)
+            .map(|i| i.to_be_bytes().to_vec())
+            .find(|key| memtable::shard_of(key, shards) != memtable::shard_of(b"locked", shards))
+            .unwrap_or_else(|| b"other".to_vec());
+
+        let memtable = storage.memtable.read().unwrap();
+        let locked = memtable.lock([b"locked".as_slice()]).unwrap();
+        std::thread::scope(|scope| {
+            let write = scope.spawn(|| storage.write(StorageOp::Insert(other.clone(), b"v".to_vec())));
+            std::thread::sleep(Duration::from_millis(200));
+            let finished = write.is_finished();
+            drop(locked);
+            write.join().unwrap().unwrap();
+            finished
+        })
+    }
+
+    #[test]
+    fn writes_to_other_shards_go_ahead() {
+        assert!(write_beside_a_locked_shard(8));
+        assert!(!write_beside_a_locked_shard(1));
+    }
+
+    #[test]
+    fn past_the_low_watermark_a_memtable_waits_for_the_running_flush() {
+        let dir = tempfile::tempdir().unwrap();
+        let options =
+            StorageOptions { flush_threshold: 64 * 1024, flush_low_watermark: Some(16 * 1024), ..StorageOptions::default() };
+        let storage = LsmStorage::new(dir.path(), options).unwrap();
+        let memtable_size = || storage.memtable.read().unwrap().size();
+        let write = |i: u32| storage.write(StorageOp::Insert(i.to_be_bytes().to_vec(), vec![0; 1024])).unwrap();
+
+        // Flushes can write their SSTables, but not finish until this goes
+        let sstables = storage.shared.sstables.write().unwrap();
+        // No flush is running, so the low mark starts one
+        (0..16).for_each(write);
+        assert_eq!(memtable_size(), 0);
+        // It is now, so the next memtable grows past the low mark, without
+        // the writes waiting on it
+        (16..48).for_each(write);
+        assert!(memtable_size() > 16 * 1024 * 2);
+        drop(sstables);
+        storage.wait_for_flush().unwrap();
+
+        // Once that flush is done, the next write starts another
+        write(48);
+        assert_eq!(memtable_size(), 0);
+        storage.flush().unwrap();
+        assert_eq!(storage.stats().unwrap().sstables, 2);
+        assert_eq!(storage.get(&30u32.to_be_bytes()).unwrap(), Some(vec![0; 1024]));
+    }
+
+    #[test]
+    fn writes_carry_on_while_a_flush_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(dir.path());
+        for i in 0..10_000u32 {
+            storage.write(StorageOp::Insert(i.to_be_bytes().to_vec(), vec![0; 100])).unwrap();
+        }
+
+        // The flush can write its SSTable, but not add it to the set until
+        // this guard goes
+        let sstables = storage.shared.sstables.write().unwrap();
+        std::thread::scope(|scope| {
+            let flush = scope.spawn(|| storage.flush());
+            while storage.memtable.read().unwrap().size() > 0 {
+                std::thread::yield_now();
+            }
+            for i in 10_000..11_000u32 {
+                storage.write(StorageOp::Insert(i.to_be_bytes().to_vec(), vec![1; 100])).unwrap();
+            }
+            assert_eq!(sstables.len(), 0);
+            assert!(!flush.is_finished());
+
+            drop(sstables);
+            flush.join().unwrap().unwrap();
+        });
+
+        assert_eq!(storage.stats().unwrap().sstables, 1);
+        assert_eq!(storage.get(&5_000u32.to_be_bytes()).unwrap(), Some(vec![0; 100]));
+        assert_eq!(storage.get(&10_500u32.to_be_bytes()).unwrap(), Some(vec![1; 100]));
+    }
+
+    #[test]
+    fn stats_count_the_memtable_and_sstables() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(dir.path());
+        let entries: Vec<(Vec<u8>, Vec<u8>)> =
+            (0..100u32).map(|i| (format!("key-{}", i).into_bytes(), vec![7; i as usize])).collect();
+        for (key, value) in &entries {
+            storage.write(StorageOp::Insert(key.clone(), value.clone())).unwrap();
+        }
+
+        let stats = storage.stats().unwrap();
+        let bytes: usize = entries.iter().map(|(key, value)| key.len() + value.len()).sum();
+        assert_eq!(stats.memtable_bytes, bytes);
+        assert_eq!(stats.memtable_keys, 100);
+        assert_eq!(stats.live_keys, 100);
+        assert_eq!((stats.sstables, stats.sstable_bytes), (0, 0));
+        assert!(stats.wal_bytes > bytes as u64);
+
+        storage.flush().unwrap();
+        let stats = storage.stats().unwrap();
+        assert_eq!((stats.memtable_bytes, stats.memtable_keys), (0, 0));
+        assert_eq!(stats.sstables, 1);
+        let sstables = storage.shared.sstables.read().unwrap();
+        let file = sstables.iter().next().unwrap().path();
+        assert_eq!(stats.sstable_bytes, std::fs::metadata(file).unwrap().len());
+        assert_eq!(stats.live_keys, 100);
+    }
+
+    #[test]
+    fn a_bulk_load_logs_a_batch_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let options =
+            StorageOptions { durability: Durability::Sync, flush_threshold: 64 * 1024, ..StorageOptions::default() };
+        let storage = LsmStorage::new(dir.path(), options).unwrap();
+        let bulk = storage.begin_bulk().unwrap();
+        assert_eq!(storage.wal.as_ref().unwrap().read().unwrap().buffer_capacity, BULK_WAL_BUFFER);
+        for chunk in 0..10u32 {
+            let ops =
+                (chunk * 1000..(chunk + 1) * 1000).map(|i| StorageOp::Insert(i.to_be_bytes().to_vec(), vec![0; 50])).collect();
+            storage.write_atomic(ops).unwrap();
+        }
+        // About 540KB, well past the normal threshold but under the 1MB a
+        // bulk load allows
+        assert_eq!(storage.metrics().flushes, 0);
+        storage.end_bulk(bulk).unwrap();
+        storage.flush().unwrap();
+
+        // One fsync per batch, plus those of the segments closed along the
+        // way, against one per record without batching
+        let wal = storage.wal.as_ref().unwrap().read().unwrap();
+        assert!(wal.syncs < 20, "{} fsyncs", wal.syncs);
+        assert_eq!(wal.buffer_capacity, WAL_BUFFER);
+        drop(wal);
+        for i in 0..10_000u32 {
+            assert_eq!(storage.get(&i.to_be_bytes()).unwrap(), Some(vec![0; 50]), "key {}", i);
+        }
+    }
+
+    fn dir_syncs(dir: &Path) -> usize {
+        DIR_SYNCS.lock().unwrap().iter().filter(|synced| *synced == dir).count()
+    }
+
+    #[test]
+    fn new_files_are_followed_by_a_directory_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(dir.path());
+        let opened = dir_syncs(dir.path());
+        assert!(opened > 0);
+
+        for i in 0..100u32 {
+            storage.write(StorageOp::Insert(i.to_be_bytes().to_vec(), vec![0; 100])).unwrap();
+        }
+        storage.flush().unwrap();
+        let flushed = dir_syncs(dir.path());
+        assert!(flushed > opened);
+        storage.write(StorageOp::Delete(7u32.to_be_bytes().to_vec())).unwrap();
+        storage.flush().unwrap();
+        storage.compact().unwrap();
+        assert!(dir_syncs(dir.path()) > flushed + 1);
+
+        // The files as a crash would leave them, with the storage never closed
+        storage.write(StorageOp::Insert(100u32.to_be_bytes().to_vec(), vec![1; 100])).unwrap();
+        let crashed = tempfile::tempdir().unwrap();
+        for entry in std::fs::read_dir(dir.path()).unwrap() {
+            let entry = entry.unwrap();
+            std::fs::copy(entry.path(), crashed.path().join(entry.file_name())).unwrap();
+        }
+        let reopened = open(crashed.path());
+        assert_eq!(reopened.stats().unwrap().sstables, 1);
+        assert_eq!(reopened.get(&7u32.to_be_bytes()).unwrap(), None);
+        assert_eq!(reopened.get(&8u32.to_be_bytes()).unwrap(), Some(vec![0; 100]));
+        assert_eq!(reopened.get(&100u32.to_be_bytes()).unwrap(), Some(vec![1; 100]));
+        drop(storage);
+    }
+
+    #[test]
+    fn a_flushed_delete_looks_up_as_a_tombstone() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(dir.path());
+        storage.write(StorageOp::Insert(b"kept".to_vec(), b"1".to_vec())).unwrap();
+        storage.write(StorageOp::Insert(b"deleted".to_vec(), b"2".to_vec())).unwrap();
+        storage.flush().unwrap();
+        storage.delete(b"deleted".to_vec()).unwrap();
+        storage.flush().unwrap();
+
+        assert_eq!(storage.lookup(b"kept").unwrap(), Lookup::Value(b"1".to_vec()));
+        assert_eq!(storage.lookup(b"deleted").unwrap(), Lookup::Tombstone);
+        assert_eq!(storage.lookup(b"never").unwrap(), Lookup::Missing);
+        assert_eq!(storage.get(b"deleted").unwrap(), None);
+        assert_eq!(storage.get(b"never").unwrap(), None);
+
+        // Compaction drops the tombstone with the value it hid
+        storage.compact().unwrap();
+        assert_eq!(storage.lookup(b"deleted").unwrap(), Lookup::Missing);
+        assert_eq!(storage.get(b"kept").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn compaction_above_older_tables_keeps_only_tombstones_that_hide_something() {
+        let dir = tempfile::tempdir().unwrap();
+        let compaction = CompactionPolicy { levels: 3, max_sstables_per_level: 1, ..CompactionPolicy::default() };
+        let storage = LsmStorage::new(dir.path(), StorageOptions { compaction, ..StorageOptions::default() }).unwrap();
+        storage.write(StorageOp::Insert(b"a".to_vec(), b"1".to_vec())).unwrap();
+        storage.write(StorageOp::Insert(b"z".to_vec(), b"2".to_vec())).unwrap();
+        storage.flush().unwrap();
+        storage.compact().unwrap();
+
+        storage.write(StorageOp::Insert(b"b".to_vec(), b"3".to_vec())).unwrap();
+        storage.write(StorageOp::Insert(b"m".to_vec(), b"4".to_vec())).unwrap();
+        storage.flush().unwrap();
+        storage.delete(b"m".to_vec()).unwrap();
+        storage.delete(b"a".to_vec()).unwrap();
+        // Level 0 is now over its limit and merges into level 1, above the
+        // table holding a and z
+        storage.flush().unwrap();
+
+        let sstables = storage.shared.sstables.read().unwrap();
+        let levels = sstables.levels();
+        assert!(levels[0].is_empty());
+        assert_eq!((levels[1].len(), levels[2].len()), (1, 1));
+        let merged = &levels[1][0];
+        assert_eq!(merged.get(b"a", &storage.shared.cache).unwrap(), Some(Slot::Tombstone));
+        assert!(merged.get(b"b", &storage.shared.cache).unwrap().is_some());
+        assert_eq!(merged.get(b"m", &storage.shared.cache).unwrap(), None);
+        drop(sstables);
+
+        assert_eq!(storage.lookup(b"a").unwrap(), Lookup::Tombstone);
+        assert_eq!(storage.lookup(b"m").unwrap(), Lookup::Missing);
+        assert_eq!(storage.get(b"b").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(storage.get(b"z").unwrap(), Some(b"2".to_vec()));
+    }
+}
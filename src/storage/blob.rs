@@ -0,0 +1,275 @@
+//! Blob files, holding values too large to keep in SSTable blocks.
+//!
+//! When a flush or compaction writes a value longer than
+//! `StorageOptions::blob_threshold`, the value goes to a blob file,
+//! `blob-<id>.blob`, and the SSTable entry holds a [`BlobRef`] to it
+//! instead: the blob file's id, the offset of the value's record and the
+//! value's length. A blob file is records back to back, each
+//! `crc32: u32 | value`, with the checksum over the value.
+//!
+//! Compaction copies references, never the values behind them, so a blob
+//! file is written once and stays until no SSTable in use refers to it any
+//! more. Until then, values in it that were overwritten or deleted still
+//! take up space.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+
+use super::Slot;
+use crate::error::DbError;
+
+/// A value as an SSTable holds it: in the entry itself, or in a blob file.
+#[derive(Debug, Clone)]
+pub(crate) enum Stored<B = Vec<u8>> {
+    Inline(B),
+    Blob(BlobRef),
+}
+
+impl<B> Stored<B> {
+    pub(crate) fn map<C>(self, f: impl FnOnce(B) -> C) -> Stored<C> {
+        match self {
+            Stored::Inline(value) => Stored::Inline(f(value)),
+            Stored::Blob(blob) => Stored::Blob(blob),
+        }
+    }
+}
+
+impl Stored {
+    /// The value, read from its blob file if it's in one.
+    pub(crate) fn load(self) -> Result<Vec<u8>, DbError> {
+        match self {
+            Stored::Inline(value) => Ok(value),
+            Stored::Blob(blob) => blob.load(),
+        }
+    }
+
+    pub(crate) fn as_deref(&self) -> Stored<&[u8]> {
+        match self {
+            Stored::Inline(value) => Stored::Inline(value),
+            Stored::Blob(blob) => Stored::Blob(blob.clone()),
+        }
+    }
+}
+
+impl Slot<Stored> {
+    /// The slot with its value read out of any blob file.
+    pub(crate) fn load(self) -> Result<Slot, DbError> {
+        self.try_map(Stored::load)
+    }
+}
+
+/// Where a value in a blob file is
+#[derive(Debug, Clone)]
+pub(crate) struct BlobRef {
+    pub file: Arc<BlobFile>,
+    /// Of the value's record, checksum first
+    pub offset: u64,
+    pub len: u32,
+}
+
+impl BlobRef {
+    pub(crate) fn load(&self) -> Result<Vec<u8>, DbError> {
+        let mut file = File::open(&self.file.path)?;
+        // A damaged reference can't have more allocated than the file holds
+        let file_len = file.metadata()?.len();
+        if self.offset.checked_add(4 + self.len as u64).is_none_or(|end| end > file_len) {
+            return Err(self.corruption());
+        }
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut record = vec![0u8; 4 + self.len as usize];
+        file.read_exact(&mut record).map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => self.corruption(),
+            _ => e.into(),
+        })?;
+        let value = record.split_off(4);
+        if crc32fast::hash(&value) != u32::from_le_bytes(record.try_into().unwrap()) {
+            return Err(self.corruption());
+        }
+        Ok(value)
+    }
+
+    fn corruption(&self) -> DbError {
+        DbError::Corruption { file: self.file.path.clone(), offset: self.offset }
+    }
+}
+
+/// One blob file, shared by every SSTable that refers to it
+#[derive(Debug)]
+pub(crate) struct BlobFile {
+    id: u64,
+    path: PathBuf,
+    // SSTables in use that refer to the file. Once the last one is replaced
+    // the file is obsolete, and it's deleted with the last handle to it, so
+    // reads and snapshots still holding a replaced table can finish.
+    tables: AtomicUsize,
+    obsolete: AtomicBool,
+}
+
+impl BlobFile {
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Name of the file within the database directory.
+    pub(crate) fn file_name(&self) -> String {
+        blob_name(self.id)
+    }
+
+    /// Count an SSTable that refers to the file.
+    pub(crate) fn add_table(&self) {
+        self.tables.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Stop counting an SSTable that was replaced, making the file obsolete
+    /// if it was the last one.
+    pub(crate) fn remove_table(&self) {
+        if self.tables.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.obsolete.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Make the file obsolete if no SSTable refers to it, as when the
+    /// SSTables written along with it failed.
+    pub(crate) fn discard_if_unreferenced(&self) {
+        if self.tables.load(Ordering::SeqCst) == 0 {
+            self.obsolete.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Drop for BlobFile {
+    fn drop(&mut self) {
+        if self.obsolete.load(Ordering::SeqCst) {
+            if let Err(e) = std::fs::remove_file(&self.path) {
+                log::warn!("Failed to remove blob file {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+/// The blob files of a database directory, each opened once however many
+/// SSTables refer to it
+#[derive(Debug)]
+pub(crate) struct BlobFiles {
+    dir: PathBuf,
+    open: Mutex<HashMap<u64, Weak<BlobFile>>>,
+    next_id: AtomicU64,
+}
+
+impl BlobFiles {
+    pub(crate) fn new(dir: &Path) -> Result<Self, DbError> {
+        let next_id = blob_files(dir)?.into_iter().max().map_or(0, |id| id + 1);
+        Ok(BlobFiles {
+            dir: dir.to_path_buf(),
+            open: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(next_id),
+        })
+    }
+
+    /// The blob file numbered `id`, for an SSTable that refers to it.
+    pub(crate) fn get(&self, id: u64) -> Result<Arc<BlobFile>, DbError> {
+        let mut open = self.open.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(file) = open.get(&id).and_then(Weak::upgrade) {
+            return Ok(file);
+        }
+        let path = self.dir.join(blob_name(id));
+        if !path.is_file() {
+            return Err(DbError::StorageError(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("blob file {} is missing", path.display()),
+            )));
+        }
+        let file = Arc::new(BlobFile {
+            id,
+            path,
+            tables: AtomicUsize::new(0),
+            obsolete: AtomicBool::new(false),
+        });
+        open.insert(id, Arc::downgrade(&file));
+        Ok(file)
+    }
+
+    /// Start a new blob file.
+    pub(crate) fn create(&self) -> Result<BlobWriter, DbError> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let path = self.dir.join(blob_name(id));
+        let writer = BufWriter::new(File::create(&path)?);
+        let file = Arc::new(BlobFile {
+            id,
+            path,
+            tables: AtomicUsize::new(0),
+            obsolete: AtomicBool::new(false),
+        });
+        self.open.lock().unwrap_or_else(PoisonError::into_inner).insert(id, Arc::downgrade(&file));
+        Ok(BlobWriter { file, writer, offset: 0 })
+    }
+
+    /// Delete the blob files no opened SSTable refers to, which a crash
+    /// left behind before the SSTables referring to them were in place.
+    pub(crate) fn remove_unreferenced(&self) -> Result<(), DbError> {
+        let open = self.open.lock().unwrap_or_else(PoisonError::into_inner);
+        for id in blob_files(&self.dir)? {
+            if open.get(&id).and_then(Weak::upgrade).is_none() {
+                log::warn!("Removing blob file {} no SSTable refers to", blob_name(id));
+                std::fs::remove_file(self.dir.join(blob_name(id)))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Appends values to a new blob file. The file has to be
+/// [synced](BlobWriter::sync) before an SSTable referring to it is finished.
+pub(crate) struct BlobWriter {
+    file: Arc<BlobFile>,
+    writer: BufWriter<File>,
+    offset: u64,
+}
+
+impl BlobWriter {
+    pub(crate) fn add(&mut self, value: &[u8]) -> Result<BlobRef, DbError> {
+        self.writer.write_all(&crc32fast::hash(value).to_le_bytes())?;
+        self.writer.write_all(value)?;
+        let blob = BlobRef { file: Arc::clone(&self.file), offset: self.offset, len: value.len() as u32 };
+        self.offset += 4 + value.len() as u64;
+        Ok(blob)
+    }
+
+    /// Bytes written so far.
+    pub(crate) fn len(&self) -> u64 {
+        self.offset
+    }
+
+    pub(crate) fn file(&self) -> &Arc<BlobFile> {
+        &self.file
+    }
+
+    pub(crate) fn sync(&mut self) -> Result<(), DbError> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Ok(())
+    }
+}
+
+fn blob_name(id: u64) -> String {
+    format!("blob-{:06}.blob", id)
+}
+
+// Ids of the blob files in `dir`
+fn blob_files(dir: &Path) -> Result<Vec<u64>, DbError> {
+    let mut ids = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let id = name.to_str().and_then(|name| name.strip_prefix("blob-")?.strip_suffix(".blob")?.parse::<u64>().ok());
+        ids.extend(id);
+    }
+    Ok(ids)
+}
@@ -0,0 +1,75 @@
+//! Bloom filter over the keys of one SSTable.
+//!
+//! Serialized as `num_hashes: u32 | bits`, with the bit count implied by the
+//! length of `bits`. Probe positions use double hashing over a 64-bit FNV-1a
+//! hash, which is stable across builds so persisted filters stay valid.
+
+/// Probabilistic set of keys: no false negatives, tunable false positives
+#[derive(Debug, Clone)]
+pub(crate) struct BloomFilter {
+    bits: Vec<u8>,
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    /// Build a filter sized for `key_hashes.len()` keys at the given
+    /// false-positive rate. Hashes come from [`BloomFilter::hash`].
+    pub(crate) fn build(key_hashes: &[u64], false_positive_rate: f64) -> Self {
+        let rate = false_positive_rate.clamp(1e-9, 0.5);
+        let keys = key_hashes.len().max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+
+        let num_bits = ((-keys * rate.ln()) / (ln2 * ln2)).ceil().max(64.0) as usize;
+        let num_hashes = ((num_bits as f64 / keys) * ln2).round().clamp(1.0, 30.0) as u32;
+
+        let mut filter = BloomFilter {
+            bits: vec![0; num_bits.div_ceil(8)],
+            num_hashes,
+        };
+        for &hash in key_hashes {
+            for bit in filter.probes(hash) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    pub(crate) fn hash(key: &[u8]) -> u64 {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for &byte in key {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+        hash
+    }
+
+    /// `false` means the key is definitely absent.
+    pub(crate) fn may_contain(&self, key: &[u8]) -> bool {
+        let hash = Self::hash(key);
+        self.probes(hash)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(4 + self.bits.len());
+        buf.extend_from_slice(&self.num_hashes.to_le_bytes());
+        buf.extend_from_slice(&self.bits);
+        buf
+    }
+
+    pub(crate) fn decode(buf: &[u8]) -> Option<Self> {
+        let num_hashes = u32::from_le_bytes(buf.get(0..4)?.try_into().ok()?);
+        let bits = buf[4..].to_vec();
+        if bits.is_empty() || num_hashes == 0 {
+            return None;
+        }
+        Some(BloomFilter { bits, num_hashes })
+    }
+
+    fn probes(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let num_bits = self.bits.len() as u64 * 8;
+        let h1 = hash & 0xffff_ffff;
+        let h2 = (hash >> 32) | 1;
+        (0..self.num_hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+    }
+}
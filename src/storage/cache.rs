@@ -0,0 +1,91 @@
+//! LRU cache of the SSTable data blocks point lookups read.
+//!
+//! Blocks are cached decompressed and checksum-verified, keyed by the
+//! table they came from and their offset in it. Scans read past the cache,
+//! so one pass over the database doesn't push out the blocks lookups keep
+//! coming back to.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use lru::LruCache;
+
+/// A cached block: [`SsTable`](super::sstable::SsTable) id and offset
+type BlockKey = (u64, u64);
+
+#[derive(Debug)]
+pub(crate) struct BlockCache {
+    // Bytes of blocks held at most; 0 turns the cache off
+    capacity: usize,
+    state: Mutex<CacheState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug)]
+struct CacheState {
+    blocks: LruCache<BlockKey, Arc<Vec<u8>>>,
+    size: usize,
+}
+
+impl BlockCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        BlockCache {
+            capacity,
+            state: Mutex::new(CacheState { blocks: LruCache::unbounded(), size: 0 }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// The block at `offset` in table `table`, reading it with `read` and
+    /// keeping it if it isn't cached yet.
+    pub(crate) fn get_or_read<E>(
+        &self,
+        table: u64,
+        offset: u64,
+        read: impl FnOnce() -> Result<Vec<u8>, E>,
+    ) -> Result<Arc<Vec<u8>>, E> {
+        if self.capacity > 0 {
+            // A poisoned cache is skipped rather than failing the read
+            if let Some(block) = self.state.lock().ok().and_then(|mut state| state.blocks.get(&(table, offset)).cloned()) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(block);
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let block = Arc::new(read()?);
+        if self.capacity > 0 && block.len() <= self.capacity {
+            if let Ok(mut state) = self.state.lock() {
+                state.size += block.len();
+                if let Some(old) = state.blocks.put((table, offset), Arc::clone(&block)) {
+                    state.size -= old.len();
+                }
+                while state.size > self.capacity {
+                    let Some((_, evicted)) = state.blocks.pop_lru() else { break };
+                    state.size -= evicted.len();
+                }
+            }
+        }
+        Ok(block)
+    }
+
+    /// Drop every block of `table`, once compaction has replaced it.
+    pub(crate) fn evict_table(&self, table: u64) {
+        if let Ok(mut state) = self.state.lock() {
+            let keys: Vec<BlockKey> = state.blocks.iter().map(|(key, _)| *key).filter(|key| key.0 == table).collect();
+            for key in keys {
+                if let Some(block) = state.blocks.pop(&key) {
+                    state.size -= block.len();
+                }
+            }
+        }
+    }
+
+    /// Lookups served from the cache, and those that read the block from
+    /// disk, so far.
+    pub(crate) fn counters(&self) -> (u64, u64) {
+        (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed))
+    }
+}
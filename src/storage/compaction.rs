@@ -0,0 +1,322 @@
+//! Which SSTables compaction merges, and when.
+//!
+//! SSTables are kept in levels. Flushes land in level 0, where tables may
+//! overlap; once it holds more than [`CompactionPolicy::max_sstables_per_level`]
+//! tables, all of them are merged into level 1 together with the level 1
+//! tables they overlap. Every level below 0 is a run of tables with disjoint
+//! key ranges, so a lookup reads at most one table per level. A level that
+//! outgrows its byte budget pushes one table down the same way into the
+//! next, and the last level takes whatever reaches it. Data only ever moves
+//! down, so every table in a level is newer than every table below it.
+
+use std::sync::Arc;
+
+use super::sstable::SsTable;
+
+/// How SSTables are compacted, set through
+/// `DatabaseBuilder::compaction_policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionPolicy {
+    /// Number of levels, at least 1.
+    pub levels: usize,
+    /// Tables level 0 can hold before it's merged into level 1, at least 1.
+    /// Level 1 holds this many tables' worth of `target_file_size` bytes.
+    pub max_sstables_per_level: usize,
+    /// How many times more bytes each level below 1 holds than the one
+    /// above it. Larger ratios make fewer levels do but rewrite each more
+    /// often.
+    pub size_ratio: u64,
+    /// Size flushes and compaction split their output at, in bytes.
+    pub target_file_size: u64,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        CompactionPolicy {
+            levels: 4,
+            max_sstables_per_level: 4,
+            size_ratio: 10,
+            target_file_size: 2 * 1024 * 1024,
+        }
+    }
+}
+
+impl CompactionPolicy {
+    // Bytes level `level` (1 and below) holds before a table is pushed down
+    fn level_budget(&self, level: usize) -> u64 {
+        let base = self.target_file_size.saturating_mul(self.max_sstables_per_level.max(1) as u64);
+        (1..level).fold(base, |budget, _| budget.saturating_mul(self.size_ratio.max(1)))
+    }
+}
+
+/// What a compaction did, from `Database::compact_now`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CompactionReport {
+    /// SSTables merged. No merge at all leaves every figure at 0.
+    pub files_merged: usize,
+    /// SSTables written in their place
+    pub files_written: usize,
+    /// Total size of the merged SSTable files
+    pub bytes_read: u64,
+    /// Total size of the SSTable files written
+    pub bytes_written: u64,
+    /// Tombstones and expired values left out of the output. Older values
+    /// of a key that a newer one overwrote don't count.
+    pub records_dropped: usize,
+}
+
+/// The SSTables of a database, by level, as described in the module docs
+#[derive(Debug, Clone)]
+pub(crate) struct Levels {
+    // Level 0 oldest first; every other level in key order
+    levels: Vec<Vec<Arc<SsTable>>>,
+}
+
+/// A merge picked by [`Levels::due`] or [`Levels::full`]
+#[derive(Debug)]
+pub(crate) struct Compaction {
+    /// Oldest first, as `MergeIter` expects
+    pub inputs: Vec<Arc<SsTable>>,
+    pub output_level: usize,
+    /// Tables below the output level that overlap the inputs, newest first,
+    /// for telling which tombstones and expired values still hide a value
+    pub older: Vec<Arc<SsTable>>,
+    /// Whether no table older than the inputs overlaps them, so tombstones
+    /// and expired values have nothing to hide and can be dropped
+    pub bottom: bool,
+}
+
+impl Levels {
+    pub(crate) fn new(policy: &CompactionPolicy) -> Self {
+        Levels { levels: vec![Vec::new(); policy.levels.max(1)] }
+    }
+
+    /// Levels holding `tables`, top level first, as read back from the
+    /// manifest. Levels beyond what `policy` has are folded into its last
+    /// one, and every level down to the deepest one whose tables overlap
+    /// into level 0, so each level below 0 is disjoint again.
+    pub(crate) fn restore(policy: &CompactionPolicy, tables: Vec<Vec<Arc<SsTable>>>) -> Self {
+        let mut restored = Levels::new(policy);
+        let last = restored.levels.len() - 1;
+        for (level, tables) in tables.into_iter().enumerate() {
+            if level < last {
+                restored.levels[level] = tables;
+            } else {
+                // Deeper levels come later and hold older tables
+                restored.levels[last].splice(0..0, tables);
+            }
+        }
+
+        // Levels that were written oldest first, before they were kept
+        // disjoint, can't be sorted by key without losing which is newer
+        let mut deepest_overlap = None;
+        for level in 1..=last {
+            let mut sorted = restored.levels[level].clone();
+            sort_by_key_range(&mut sorted);
+            if sorted.windows(2).any(|pair| overlaps(&pair[0], &pair[1])) {
+                deepest_overlap = Some(level);
+            } else {
+                restored.levels[level] = sorted;
+            }
+        }
+        if let Some(deepest) = deepest_overlap {
+            // Oldest first: the deepest level's tables go in front
+            let merged = restored.levels[..=deepest].iter_mut().rev().flat_map(std::mem::take).collect();
+            restored.levels[0] = merged;
+        }
+        restored
+    }
+
+    /// File name of every table, level by level, each level in its order.
+    pub(crate) fn file_names(&self) -> Vec<Vec<String>> {
+        self.levels
+            .iter()
+            .map(|level| level.iter().map(|table| table.file_name()).collect())
+            .collect()
+    }
+
+    /// The tables of each level, top level first, each level in its order.
+    #[cfg(any(test, feature = "debug-dump"))]
+    pub(crate) fn levels(&self) -> &[Vec<Arc<SsTable>>] {
+        &self.levels
+    }
+
+    /// Every table, oldest first.
+    pub(crate) fn iter(&self) -> impl DoubleEndedIterator<Item = &Arc<SsTable>> {
+        self.levels.iter().rev().flatten()
+    }
+
+    /// The tables that could hold `key`, newest first: any of level 0, then
+    /// at most one per level below it.
+    pub(crate) fn lookup<'a>(&'a self, key: &'a [u8]) -> impl Iterator<Item = &'a Arc<SsTable>> + 'a {
+        let level0 = self.levels[0].iter().rev();
+        let lower = self.levels[1..].iter().filter_map(move |level| {
+            let at = level.partition_point(|table| table.key_range().is_none_or(|(_, last)| last < key));
+            level.get(at).filter(|table| table.key_range().is_some_and(|(first, _)| first <= key))
+        });
+        level0.chain(lower)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.levels.iter().map(Vec::len).sum()
+    }
+
+    /// Add a freshly flushed table, the newest of all.
+    pub(crate) fn push_flushed(&mut self, table: Arc<SsTable>) {
+        self.levels[0].push(table);
+    }
+
+    /// The next compaction `policy` calls for, starting from the top level.
+    pub(crate) fn due(&self, policy: &CompactionPolicy) -> Option<Compaction> {
+        let last = self.levels.len() - 1;
+        if self.levels[0].len() > policy.max_sstables_per_level.max(1) {
+            let output_level = 1.min(last);
+            return Some(self.merge_into(self.levels[0].clone(), output_level));
+        }
+
+        // Push down the table that overlaps the least of the level below,
+        // to rewrite as little as possible
+        let level = (1..last).find(|&level| {
+            self.levels[level].iter().map(|table| table.file_len()).sum::<u64>() > policy.level_budget(level)
+        })?;
+        let table = self.levels[level].iter().min_by_key(|table| {
+            self.levels[level + 1]
+                .iter()
+                .filter(|below| overlaps(table, below))
+                .map(|below| below.file_len())
+                .sum::<u64>()
+        })?;
+        Some(self.merge_into(vec![Arc::clone(table)], level + 1))
+    }
+
+    /// A merge of every table into the last level, unless there's nothing
+    /// it would change.
+    pub(crate) fn full(&self) -> Option<Compaction> {
+        let last = self.levels.len() - 1;
+        if self.len() == 0 || (self.len() == 1 && self.levels[last].len() == 1) {
+            return None;
+        }
+        Some(Compaction {
+            inputs: self.iter().cloned().collect(),
+            output_level: last,
+            older: Vec::new(),
+            bottom: true,
+        })
+    }
+
+    // Merge `upper` into `output_level`, along with the tables there it
+    // overlaps
+    fn merge_into(&self, upper: Vec<Arc<SsTable>>, output_level: usize) -> Compaction {
+        let mut inputs: Vec<_> = if output_level == 0 {
+            Vec::new()
+        } else {
+            self.levels[output_level]
+                .iter()
+                .filter(|table| upper.iter().any(|input| overlaps(input, table)))
+                .cloned()
+                .collect()
+        };
+        inputs.extend(upper);
+
+        let older: Vec<_> = self.levels[output_level + 1..]
+            .iter()
+            .flatten()
+            .filter(|older| inputs.iter().any(|input| overlaps(input, older)))
+            .cloned()
+            .collect();
+        let bottom = older.is_empty();
+        Compaction { inputs, output_level, older, bottom }
+    }
+
+    /// Replace the inputs of `compaction` with `outputs`, and return them.
+    ///
+    /// Tables flushed while the merge ran are newer than its inputs and
+    /// stay where they are.
+    pub(crate) fn install(&mut self, compaction: &Compaction, outputs: Vec<Arc<SsTable>>) -> Vec<Arc<SsTable>> {
+        // In level 0 the outputs take the place of the oldest input, or go
+        // on top if it had none; below it they go in key order
+        let at = self.levels[compaction.output_level]
+            .iter()
+            .take_while(|table| !compaction.inputs.iter().any(|input| Arc::ptr_eq(input, table)))
+            .count();
+        let replaced = self.remove(&compaction.inputs);
+
+        let level = &mut self.levels[compaction.output_level];
+        if compaction.output_level == 0 {
+            level.splice(at..at, outputs);
+        } else {
+            level.extend(outputs);
+            sort_by_key_range(level);
+        }
+        replaced
+    }
+
+    /// Take `tables` out of whichever level holds them, and return those
+    /// that were there.
+    pub(crate) fn remove(&mut self, tables: &[Arc<SsTable>]) -> Vec<Arc<SsTable>> {
+        let is_listed = |table: &Arc<SsTable>| tables.iter().any(|listed| Arc::ptr_eq(listed, table));
+        let mut removed = Vec::new();
+        for level in &mut self.levels {
+            let (gone, kept) = std::mem::take(level).into_iter().partition(is_listed);
+            *level = kept;
+            removed.extend::<Vec<_>>(gone);
+        }
+        removed
+    }
+}
+
+fn sort_by_key_range(level: &mut [Arc<SsTable>]) {
+    level.sort_by(|a, b| a.key_range().cmp(&b.key_range()));
+}
+
+// Whether two tables' key ranges share any key
+fn overlaps(a: &SsTable, b: &SsTable) -> bool {
+    match (a.key_range(), b.key_range()) {
+        (Some((a_first, a_last)), Some((b_first, b_last))) => a_first <= b_last && b_first <= a_last,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{LsmStorage, StorageOp, StorageOptions};
+    use super::*;
+
+    #[test]
+    fn lower_levels_stay_disjoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let policy = CompactionPolicy { levels: 3, max_sstables_per_level: 2, size_ratio: 4, target_file_size: 4096 };
+        let options = StorageOptions { flush_threshold: 8192, compaction: policy, ..StorageOptions::default() };
+        let storage = LsmStorage::new(dir.path(), options).unwrap();
+        // Spread over the whole key space by every flush
+        let key = |i: u32| ((i * 7919) % 5000).to_be_bytes().to_vec();
+        for i in 0..20_000u32 {
+            storage.write(StorageOp::Insert(key(i), i.to_be_bytes().to_vec())).unwrap();
+        }
+        storage.flush().unwrap();
+
+        let sstables = storage.shared.sstables.read().unwrap();
+        for level in &sstables.levels[1..] {
+            let ranges: Vec<_> = level.iter().map(|table| table.key_range().unwrap()).collect();
+            assert!(ranges.windows(2).all(|pair| pair[0].1 < pair[1].0), "{:?}", ranges);
+        }
+        assert!(sstables.levels[1..].iter().any(|level| level.len() > 1), "{:?}", sstables.file_names());
+
+        // Any of level 0, and then one table per level at most, holding the key
+        let level_of = |table: &Arc<SsTable>| {
+            sstables.levels.iter().position(|level| level.iter().any(|listed| Arc::ptr_eq(listed, table))).unwrap()
+        };
+        for i in (0..5000u32).step_by(97) {
+            let key = i.to_be_bytes();
+            let below: Vec<_> = sstables.lookup(&key).filter(|table| level_of(table) > 0).collect();
+            assert!(below.windows(2).all(|pair| level_of(pair[0]) < level_of(pair[1])));
+            for table in below {
+                let (first, last) = table.key_range().unwrap();
+                assert!(first <= &key[..] && &key[..] <= last);
+            }
+        }
+        drop(sstables);
+        // The newest write of key 0 was i = 15000, the last multiple of 5000
+        assert_eq!(storage.get(&0u32.to_be_bytes()).unwrap(), Some(15_000u32.to_be_bytes().to_vec()));
+    }
+}
@@ -0,0 +1,67 @@
+//! Block compression codecs for SSTables.
+//!
+//! The codec is recorded in each SSTable's footer by [`Compression::id`], so
+//! a table is always read back with the codec it was written with,
+//! whatever the database is currently configured to use.
+
+/// How SSTable data blocks are compressed, set through
+/// `DatabaseBuilder::compression`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// Blocks are stored as-is.
+    #[default]
+    None,
+    /// LZ4 block compression. Requires the `lz4` feature.
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+const ID_NONE: u32 = 0;
+const ID_LZ4: u32 = 1;
+
+impl Compression {
+    pub(crate) fn id(self) -> u32 {
+        match self {
+            Compression::None => ID_NONE,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => ID_LZ4,
+        }
+    }
+
+    /// The codec for a footer id, or why this build can't read it.
+    pub(crate) fn from_id(id: u32) -> Result<Self, &'static str> {
+        match id {
+            ID_NONE => Ok(Compression::None),
+            #[cfg(feature = "lz4")]
+            ID_LZ4 => Ok(Compression::Lz4),
+            #[cfg(not(feature = "lz4"))]
+            ID_LZ4 => Err("blocks are LZ4-compressed, but the `lz4` feature is disabled"),
+            _ => Err("unknown compression codec"),
+        }
+    }
+
+    pub(crate) fn compress(self, block: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::None => block.to_vec(),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => lz4_flex::compress_prepend_size(block),
+        }
+    }
+
+    /// `None` if `block` isn't valid output of [`Compression::compress`].
+    pub(crate) fn decompress(self, block: Vec<u8>) -> Option<Vec<u8>> {
+        match self {
+            Compression::None => Some(block),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                // LZ4 expands at most ~255x, so a bigger size prefix is
+                // corruption rather than a reason to allocate it
+                let size = u32::from_le_bytes(block.get(..4)?.try_into().ok()?) as usize;
+                if size > block.len().saturating_mul(255) {
+                    return None;
+                }
+                lz4_flex::decompress_size_prepended(&block).ok()
+            }
+        }
+    }
+}
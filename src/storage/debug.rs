@@ -0,0 +1,68 @@
+//! A human-readable printout of the storage, from `Database::debug_dump`.
+
+use std::io::Write;
+
+use super::{LsmStorage, Shards, Slot};
+use crate::error::DbError;
+
+impl LsmStorage {
+    /// Write every memtable entry, every SSTable by level with its key
+    /// range and entry count, and the WAL size to `out`.
+    ///
+    /// The layers stay locked while it runs, as for a read, so writes wait.
+    /// Counting entries reads every SSTable through.
+    pub(crate) fn debug_dump(&self, out: &mut dyn Write) -> Result<(), DbError> {
+        let wal_bytes = self.wal_bytes()?;
+        self.read(|view| {
+            dump_memtable(out, "memtable", &view.memtable)?;
+            if let Some(immutable) = &view.immutable {
+                dump_memtable(out, "memtable being flushed", immutable)?;
+            }
+            writeln!(out, "sstables: {}", view.sstables.len())?;
+            for (level, tables) in view.sstables.levels().iter().enumerate() {
+                writeln!(out, "  level {}: {} tables", level, tables.len())?;
+                for table in tables {
+                    let mut entries = 0;
+                    for entry in table.iter()? {
+                        entry?;
+                        entries += 1;
+                    }
+                    let range = match table.key_range() {
+                        Some((first, last)) => format!("{} to {}", show_key(first), show_key(last)),
+                        None => "empty".to_string(),
+                    };
+                    writeln!(
+                        out,
+                        "    {}: {} entries, {} bytes, {}",
+                        table.file_name(),
+                        entries,
+                        table.file_len(),
+                        range
+                    )?;
+                }
+            }
+            writeln!(out, "wal: {} bytes", wal_bytes)?;
+            Ok(())
+        })
+    }
+}
+
+fn dump_memtable(out: &mut dyn Write, name: &str, memtable: &Shards<'_>) -> Result<(), DbError> {
+    writeln!(out, "{}: {} entries, {} bytes", name, memtable.len(), memtable.size())?;
+    for (key, slot) in memtable.iter() {
+        let value = match slot {
+            Slot::Value(value) => format!("value, {} bytes", value.len()),
+            Slot::Expiring(value, expires_at) => format!("value, {} bytes, expires at {}", value.len(), expires_at),
+            Slot::Tombstone => "tombstone".to_string(),
+            Slot::Merge(operands) => format!("{} merge operands", operands.len()),
+        };
+        writeln!(out, "  {}: {}", show_key(key), value)?;
+    }
+    Ok(())
+}
+
+// A key in quotes, with any byte that isn't printable ASCII in hex, so
+// `User:` followed by an integer key shows as `"User:\x00...\x01"`
+fn show_key(key: &[u8]) -> String {
+    format!("\"{}\"", key.escape_ascii())
+}
@@ -0,0 +1,131 @@
+//! The `MANIFEST` file, listing the SSTables of each level so a reopened
+//! database finds them again.
+//!
+//! It's JSON, like `meta.json`: `{"levels": [[name, ...], ...], "wal": seq}`,
+//! level 0 first, oldest first, and every other level in key order. `wal`
+//! is the first WAL segment with writes not yet in an SSTable, which
+//! recovery replays from; manifests older than segments lack it, and
+//! everything is replayed. Every flush
+//! and compaction replaces it before the files it supersedes go away, so an
+//! SSTable missing from it is one a crash cut off before it was put in
+//! place, and it's deleted on open, as is any `.bin.tmp` file a crash left
+//! while an SSTable was still being written.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use super::blob::BlobFiles;
+use super::compaction::{CompactionPolicy, Levels};
+use super::sstable::SsTable;
+use crate::error::DbError;
+
+const MANIFEST_FILE: &str = "MANIFEST";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    levels: Vec<Vec<String>>,
+    #[serde(default)]
+    wal: u64,
+}
+
+/// Open the SSTables `dir`'s manifest lists, laid out in levels for
+/// `policy`, along with the WAL segment recovery starts at. The blob files
+/// they refer to are opened through `blobs`.
+///
+/// A database without a manifest predates it, and takes every SSTable in
+/// the directory into level 0 in the order they were written; the manifest
+/// is written for it then.
+///
+/// With `read_only` set, nothing in `dir` is removed or written: unlisted
+/// and unfinished SSTables are just skipped, and a missing manifest stays
+/// missing.
+pub(crate) fn load(
+    dir: &Path,
+    policy: &CompactionPolicy,
+    read_only: bool,
+    blobs: &BlobFiles,
+) -> Result<(Levels, u64), DbError> {
+    let path = dir.join(MANIFEST_FILE);
+    let manifest = match std::fs::read_to_string(&path) {
+        Ok(text) => Some(
+            serde_json::from_str::<Manifest>(&text)
+                .map_err(|e| DbError::SerializationError(format!("{}: {}", path.display(), e)))?,
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut on_disk = sstable_files(dir, read_only)?;
+    let Some(manifest) = manifest else {
+        on_disk.sort_by_key(|name| sstable_seq(name));
+        if !on_disk.is_empty() {
+            log::info!("Adopting {} SSTables in {} into a new manifest", on_disk.len(), dir.display());
+        }
+        let level = on_disk.iter().map(|name| open(dir, name, blobs)).collect::<Result<Vec<_>, _>>()?;
+        let levels = Levels::restore(policy, vec![level]);
+        if !read_only {
+            store(dir, &levels, 0)?;
+        }
+        return Ok((levels, 0));
+    };
+
+    if !read_only {
+        for name in on_disk.iter().filter(|name| !manifest.levels.iter().flatten().any(|listed| listed == *name)) {
+            log::warn!("Removing SSTable {} left unlisted by an interrupted write", name);
+            std::fs::remove_file(dir.join(name))?;
+        }
+    }
+    let levels = manifest
+        .levels
+        .iter()
+        .map(|level| level.iter().map(|name| open(dir, name, blobs)).collect())
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((Levels::restore(policy, levels), manifest.wal))
+}
+
+/// Replace the manifest in `dir` with `levels` and `wal_start`, the first
+/// WAL segment to replay. Written to a temporary file
+/// and renamed over the old one, so a crash leaves one version or the other,
+/// and `dir` is synced so the new one stays.
+pub(crate) fn store(dir: &Path, levels: &Levels, wal_start: u64) -> Result<(), DbError> {
+    let manifest = Manifest { levels: levels.file_names(), wal: wal_start };
+    let text = serde_json::to_vec(&manifest).map_err(|e| DbError::SerializationError(e.to_string()))?;
+    let temp = dir.join(format!("{}.tmp", MANIFEST_FILE));
+    let mut file = File::create(&temp)?;
+    file.write_all(&text)?;
+    file.sync_all()?;
+    std::fs::rename(&temp, dir.join(MANIFEST_FILE))?;
+    super::sync_dir(dir)
+}
+
+/// The `(seconds, sequence)` an SSTable's `sst-<seconds>-<sequence>.bin`
+/// name was given, which orders tables by when they were written.
+pub(crate) fn sstable_seq(name: &str) -> Option<(i64, u64)> {
+    let (seconds, seq) = name.strip_prefix("sst-")?.strip_suffix(".bin")?.split_once('-')?;
+    Some((seconds.parse().ok()?, seq.parse().ok()?))
+}
+
+// Names of the SSTable files in `dir`, removing unfinished ones on the way
+// unless it's `read_only`
+fn sstable_files(dir: &Path, read_only: bool) -> Result<Vec<String>, DbError> {
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let Some(name) = name.to_str() else { continue };
+        if sstable_seq(name).is_some() {
+            names.push(name.to_string());
+        } else if !read_only && name.strip_suffix(".tmp").and_then(sstable_seq).is_some() {
+            log::warn!("Removing unfinished SSTable {}", name);
+            std::fs::remove_file(dir.join(name))?;
+        }
+    }
+    Ok(names)
+}
+
+fn open(dir: &Path, name: &str, blobs: &BlobFiles) -> Result<Arc<SsTable>, DbError> {
+    SsTable::open(&dir.join(name), blobs).map(Arc::new)
+}
@@ -0,0 +1,424 @@
+//! The in-memory table writes land in until they're flushed, and the maps
+//! it can keep its entries in.
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use super::{now_millis, poisoned, MergeFn, Slot, StorageOp};
+use crate::error::DbError;
+
+/// A sorted map from key to the latest [`Slot`] written for it, that
+/// memtables keep their entries in. [`BTreeMemTable`] is the default;
+/// `DatabaseBuilder::memtable` picks another.
+///
+/// Each shard of a memtable has a map of its own, written one write at a
+/// time under the shard's lock while reads share it, so an implementation
+/// needs no locking of its own.
+/// Byte counts for flushing are kept outside it, from what
+/// [`MemTableImpl::insert`] returns.
+pub trait MemTableImpl: Debug + Send + Sync {
+    /// Set the entry for `key`, and return the one it replaced.
+    fn insert(&mut self, key: Vec<u8>, slot: Slot) -> Option<Slot>;
+
+    /// `None` means the key was never written here.
+    fn get(&self, key: &[u8]) -> Option<&Slot>;
+
+    /// Entries with keys between `start` and `end`, in key order, from
+    /// either end. Never called with `start` past `end`.
+    fn range<'a>(
+        &'a self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> Box<dyn DoubleEndedIterator<Item = (&'a [u8], &'a Slot)> + 'a>;
+
+    /// Number of keys held, tombstones included.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Make room for `additional` more keys ahead of a large batch, if the
+    /// map can. Does nothing by default.
+    fn reserve(&mut self, _additional: usize) {}
+
+    /// A copy with the same entries, that writes to either don't show in.
+    /// Taken on the first write after a snapshot, so the snapshot keeps
+    /// what it saw.
+    fn clone_table(&self) -> Box<dyn MemTableImpl>;
+}
+
+/// The default [`MemTableImpl`], a `BTreeMap`
+#[derive(Debug, Clone, Default)]
+pub struct BTreeMemTable(BTreeMap<Vec<u8>, Slot>);
+
+impl MemTableImpl for BTreeMemTable {
+    fn insert(&mut self, key: Vec<u8>, slot: Slot) -> Option<Slot> {
+        self.0.insert(key, slot)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&Slot> {
+        self.0.get(key)
+    }
+
+    fn range<'a>(
+        &'a self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> Box<dyn DoubleEndedIterator<Item = (&'a [u8], &'a Slot)> + 'a> {
+        Box::new(self.0.range::<[u8], _>((start, end)).map(|(key, slot)| (key.as_slice(), slot)))
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn clone_table(&self) -> Box<dyn MemTableImpl> {
+        Box::new(self.clone())
+    }
+}
+
+/// Makes the empty tables memtables start from, as set by
+/// `DatabaseBuilder::memtable`
+pub(crate) type NewTable = fn() -> Box<dyn MemTableImpl>;
+
+pub(crate) fn new_table<M: MemTableImpl + Default + 'static>() -> Box<dyn MemTableImpl> {
+    Box::new(M::default())
+}
+
+/// In-memory table, holding the latest [`Slot`] written for each key: a
+/// whole memtable, or one shard of one.
+///
+/// Cloning is cheap: clones share the entries until one of them is written.
+#[derive(Debug, Clone)]
+pub(crate) struct MemTable {
+    data: Arc<dyn MemTableImpl>,
+    // Bytes of the entries held now: each key, plus its value and expiry or
+    // merge operands
+    size: usize,
+    merge: Option<MergeFn>,
+}
+
+impl MemTable {
+    pub(crate) fn new(new_table: NewTable, merge: Option<MergeFn>) -> Self {
+        MemTable {
+            data: Arc::from(new_table()),
+            size: 0,
+            merge,
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.put(key, Slot::Value(value));
+    }
+
+    /// Insert a value that expires at `expires_at` (Unix milliseconds).
+    pub(crate) fn insert_expiring(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: i64) {
+        self.put(key, Slot::Expiring(value, expires_at));
+    }
+
+    /// Leave a tombstone for `key`, which takes the place of any value
+    /// written here before.
+    pub(crate) fn delete(&mut self, key: Vec<u8>) {
+        self.put(key, Slot::Tombstone);
+    }
+
+    /// Add a merge operand for `key`. It's applied right away to a value
+    /// written here before, and otherwise kept, after any operands already
+    /// waiting, to be applied to an older value on read. Only called with a
+    /// merge operator set.
+    pub(crate) fn merge(&mut self, key: Vec<u8>, operand: Vec<u8>) {
+        let mut slot = Slot::Merge(vec![operand]);
+        if self.data.get(&key).is_some() {
+            // Taken out rather than cloned, so waiting operands aren't copied
+            let older = self.table_mut().insert(key.clone(), Slot::Tombstone).expect("entry was just found");
+            self.size -= slot_size(&older);
+            slot = slot
+                .over(older, self.merge, now_millis())
+                .expect("merge operands are only written with a merge operator");
+        }
+        self.put(key, slot);
+    }
+
+    /// Make room for `additional` more keys, where the map allows.
+    pub(crate) fn reserve(&mut self, additional: usize) {
+        self.table_mut().reserve(additional);
+    }
+
+    // Replace the entry for `key`, counting only what's held afterwards
+    fn put(&mut self, key: Vec<u8>, slot: Slot) {
+        let key_len = key.len();
+        self.size += slot_size(&slot);
+        match self.table_mut().insert(key, slot) {
+            Some(old) => self.size -= slot_size(&old),
+            None => self.size += key_len,
+        }
+    }
+
+    // The map to write to, copied first if a clone still shares it
+    fn table_mut(&mut self) -> &mut dyn MemTableImpl {
+        if Arc::get_mut(&mut self.data).is_none() {
+            self.data = Arc::from(self.data.clone_table());
+        }
+        Arc::get_mut(&mut self.data).expect("memtable was just copied")
+    }
+
+    pub(crate) fn apply(&mut self, op: StorageOp) {
+        match op {
+            StorageOp::Insert(key, value) => self.insert(key, value),
+            StorageOp::InsertExpiring(key, value, expires_at) => self.insert_expiring(key, value, expires_at),
+            StorageOp::Delete(key) => self.delete(key),
+            StorageOp::Merge(key, operand) => self.merge(key, operand),
+            StorageOp::Begin | StorageOp::Commit => {}
+        }
+    }
+
+    /// `None` means the key was never written here.
+    pub(crate) fn get(&self, key: &[u8]) -> Option<&Slot> {
+        self.data.get(key)
+    }
+
+    /// Entries (including tombstones) with keys between `start` and `end`, in key order.
+    pub(crate) fn range<'a>(
+        &'a self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> impl DoubleEndedIterator<Item = (&'a [u8], &'a Slot)> + 'a {
+        self.data.range(start, end)
+    }
+
+    /// Every entry, in key order.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&[u8], &Slot)> {
+        self.data.range(Bound::Unbounded, Bound::Unbounded)
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Number of keys written here, tombstones included.
+    pub(crate) fn len(&self) -> usize {
+        self.data.len()
+    }
+}
+
+/// Which of `shards` shards of a memtable `key` goes in, by an FNV-1a hash
+/// of the key. Always the first of a single shard.
+pub(crate) fn shard_of(key: &[u8], shards: usize) -> usize {
+    if shards <= 1 {
+        return 0;
+    }
+    let hash = key.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3));
+    (hash % shards as u64) as usize
+}
+
+/// The memtable writes go to, split into shards by [`shard_of`], each
+/// behind a lock of its own, so writers to keys in different shards don't
+/// wait for each other. Freezing it takes it whole, under the storage's
+/// lock on it.
+#[derive(Debug)]
+pub(crate) struct ActiveMemTable {
+    shards: Vec<RwLock<MemTable>>,
+    // Bytes held across the shards, kept up to date by each write
+    size: AtomicUsize,
+    new_table: NewTable,
+    merge: Option<MergeFn>,
+}
+
+impl ActiveMemTable {
+    pub(crate) fn new(shards: usize, new_table: NewTable, merge: Option<MergeFn>) -> Self {
+        ActiveMemTable {
+            shards: (0..shards.max(1)).map(|_| RwLock::new(MemTable::new(new_table, merge))).collect(),
+            size: AtomicUsize::new(0),
+            new_table,
+            merge,
+        }
+    }
+
+    /// Lock the shards `keys` go in for writing. They're taken in shard
+    /// order, so two writers never each hold a shard the other waits for.
+    pub(crate) fn lock<'k>(&self, keys: impl IntoIterator<Item = &'k [u8]>) -> Result<ShardLocks<'_>, DbError> {
+        let mut wanted = vec![false; self.shards.len()];
+        for key in keys {
+            wanted[shard_of(key, self.shards.len())] = true;
+        }
+        let guards = self
+            .shards
+            .iter()
+            .zip(wanted)
+            .map(|(shard, wanted)| wanted.then(|| shard.write().map_err(|_| poisoned("memtable"))).transpose())
+            .collect::<Result<_, _>>()?;
+        Ok(ShardLocks { size: &self.size, guards })
+    }
+
+    /// Lock every shard for reading.
+    pub(crate) fn read(&self) -> Result<Vec<RwLockReadGuard<'_, MemTable>>, DbError> {
+        self.shards.iter().map(|shard| shard.read().map_err(|_| poisoned("memtable"))).collect()
+    }
+
+    /// Apply `op` with the memtable to itself, as while the WAL is replayed.
+    pub(crate) fn apply(&mut self, op: StorageOp) {
+        let Some(key) = op.key() else { return };
+        let shard = shard_of(key, self.shards.len());
+        let shard = self.shards[shard].get_mut().unwrap_or_else(PoisonError::into_inner);
+        let before = shard.size();
+        shard.apply(op);
+        *self.size.get_mut() = *self.size.get_mut() + shard.size() - before;
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        self.size.load(Ordering::SeqCst)
+    }
+
+    /// Swap every shard for an empty one, and return the full ones.
+    pub(crate) fn take(&mut self) -> Vec<MemTable> {
+        *self.size.get_mut() = 0;
+        let (new_table, merge) = (self.new_table, self.merge);
+        self.shards
+            .iter_mut()
+            .map(|shard| {
+                let shard = shard.get_mut().unwrap_or_else(PoisonError::into_inner);
+                std::mem::replace(shard, MemTable::new(new_table, merge))
+            })
+            .collect()
+    }
+}
+
+/// Shards of an [`ActiveMemTable`] locked for writing, from
+/// [`ActiveMemTable::lock`]
+pub(crate) struct ShardLocks<'a> {
+    size: &'a AtomicUsize,
+    // By shard, with `None` for those not locked
+    guards: Vec<Option<RwLockWriteGuard<'a, MemTable>>>,
+}
+
+impl ShardLocks<'_> {
+    /// Apply `op` to the shard of its key, which must be one of those locked.
+    pub(crate) fn apply(&mut self, op: StorageOp) {
+        let Some(key) = op.key() else { return };
+        let shard = shard_of(key, self.guards.len());
+        let shard = self.guards[shard].as_mut().expect("the shard of every key written is locked");
+        let before = shard.size();
+        shard.apply(op);
+        let after = shard.size();
+        if after >= before {
+            self.size.fetch_add(after - before, Ordering::SeqCst);
+        } else {
+            self.size.fetch_sub(before - after, Ordering::SeqCst);
+        }
+    }
+
+    /// Make room for about `additional` more keys across the locked shards.
+    pub(crate) fn reserve(&mut self, additional: usize) {
+        let locked = self.guards.iter().flatten().count().max(1);
+        for shard in self.guards.iter_mut().flatten() {
+            shard.reserve(additional / locked);
+        }
+    }
+}
+
+/// The shards of one memtable, borrowed for a read. A key is only ever in
+/// the shard [`shard_of`] picks, so no two shards hold the same key.
+#[derive(Debug, Clone)]
+pub(crate) struct Shards<'a>(Vec<&'a MemTable>);
+
+impl<'a> Shards<'a> {
+    pub(crate) fn new(shards: impl IntoIterator<Item = &'a MemTable>) -> Self {
+        Shards(shards.into_iter().collect())
+    }
+
+    /// `None` means the key was never written here.
+    pub(crate) fn get(&self, key: &[u8]) -> Option<&'a Slot> {
+        self.0[shard_of(key, self.0.len())].get(key)
+    }
+
+    /// Each shard, for reads that go through all of them in any order.
+    pub(crate) fn tables(&self) -> impl Iterator<Item = &'a MemTable> + '_ {
+        self.0.iter().copied()
+    }
+
+    /// Every entry, in key order across the shards.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&'a [u8], &'a Slot)> + use<'a> {
+        let mut shards: Vec<_> = self.0.iter().map(|shard| shard.iter().peekable()).collect();
+        std::iter::from_fn(move || {
+            let (_, next) = shards.iter_mut().enumerate().filter_map(|(i, shard)| Some((shard.peek()?.0, i))).min()?;
+            shards[next].next()
+        })
+    }
+
+    /// Copies of the shards, sharing their entries until either is written.
+    pub(crate) fn to_vec(&self) -> Vec<MemTable> {
+        self.0.iter().map(|&shard| shard.clone()).collect()
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        self.0.iter().map(|shard| shard.size()).sum()
+    }
+
+    /// Number of keys written here, tombstones included.
+    pub(crate) fn len(&self) -> usize {
+        self.0.iter().map(|shard| shard.len()).sum()
+    }
+}
+
+// Bytes a slot holds besides its key
+fn slot_size(slot: &Slot) -> usize {
+    match slot {
+        Slot::Value(value) => value.len(),
+        Slot::Expiring(value, _) => value.len() + 8,
+        Slot::Tombstone => 0,
+        Slot::Merge(operands) => operands.iter().map(Vec::len).sum(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memtable() -> MemTable {
+        MemTable::new(new_table::<BTreeMemTable>, None)
+    }
+
+    #[test]
+    fn size_counts_only_the_latest_entry_per_key() {
+        let mut memtable = memtable();
+        memtable.insert(b"key".to_vec(), vec![0; 100]);
+        memtable.insert(b"key".to_vec(), vec![0; 10]);
+        assert_eq!(memtable.size(), 3 + 10);
+        assert_eq!(memtable.len(), 1);
+
+        memtable.insert(b"other".to_vec(), vec![0; 20]);
+        assert_eq!(memtable.size(), 3 + 10 + 5 + 20);
+
+        // A tombstone keeps its key but drops the value
+        memtable.delete(b"key".to_vec());
+        assert_eq!(memtable.size(), 3 + 5 + 20);
+        assert_eq!(memtable.len(), 2);
+        memtable.insert(b"key".to_vec(), vec![0; 1]);
+        assert_eq!(memtable.size(), 3 + 1 + 5 + 20);
+    }
+
+    #[test]
+    fn shards_read_back_as_one_memtable() {
+        let mut sharded = ActiveMemTable::new(8, new_table::<BTreeMemTable>, None);
+        let mut single = memtable();
+        for i in 0..1_000u32 {
+            sharded.apply(StorageOp::Insert(i.to_be_bytes().to_vec(), vec![0; (i % 7) as usize]));
+            single.insert(i.to_be_bytes().to_vec(), vec![0; (i % 7) as usize]);
+        }
+        sharded.apply(StorageOp::Delete(7u32.to_be_bytes().to_vec()));
+        single.delete(7u32.to_be_bytes().to_vec());
+        assert_eq!(sharded.size(), single.size());
+
+        let shards = sharded.read().unwrap();
+        let shards = Shards::new(shards.iter().map(|shard| &**shard));
+        // Every shard got some of the keys
+        assert!(shards.tables().all(|shard| shard.len() > 0));
+        assert_eq!((shards.len(), shards.size()), (single.len(), single.size()));
+        assert!(shards.iter().eq(single.iter()));
+        assert!((0..1_000u32).all(|i| shards.get(&i.to_be_bytes()) == single.get(&i.to_be_bytes())));
+    }
+}
@@ -0,0 +1,56 @@
+//! Merge operands: writes that are combined with whatever value a key
+//! already has when it's read, rather than replacing it.
+
+use crate::error::DbError;
+
+use super::Slot;
+
+/// Combines a merge operand with the value it's written over, set through
+/// `DatabaseBuilder::merge_operator`.
+///
+/// `existing` is the key's value before the operand, `None` if it has none
+/// or it was deleted. Operands are applied oldest first, each to what the
+/// one before it returned, so the function is all that decides what they
+/// add up to. It must give the same result every time: a key's operands
+/// may be combined on any read, or once and for all by a flush or
+/// compaction.
+pub type MergeFn = fn(existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8>;
+
+impl<V: AsRef<[u8]> + From<Vec<u8>>> Slot<V> {
+    /// Lay `self` over `older`, the next older entry for the same key.
+    /// Merge operands are applied to the value under them, or added to
+    /// older ones still waiting for a value; any other entry hides `older`.
+    ///
+    /// An expiring value is judged live or not as of `now`, and merging
+    /// into it gives a value that doesn't expire.
+    pub(crate) fn over(self, older: Slot<V>, merge: Option<MergeFn>, now: i64) -> Result<Slot<V>, DbError> {
+        match (self, older) {
+            (Slot::Merge(newer), Slot::Merge(mut operands)) => {
+                operands.extend(newer);
+                Ok(Slot::Merge(operands))
+            }
+            (Slot::Merge(operands), base) => Ok(Slot::Value(apply(base.live(now), &operands, merge)?.into())),
+            (newer, _) => Ok(newer),
+        }
+    }
+
+    /// Apply merge operands with nothing older under them to no value at
+    /// all. Any other entry is returned as it is.
+    pub(crate) fn collapse(self, merge: Option<MergeFn>) -> Result<Slot<V>, DbError> {
+        match self {
+            Slot::Merge(operands) => Ok(Slot::Value(apply(None, &operands, merge)?.into())),
+            slot => Ok(slot),
+        }
+    }
+}
+
+// Fold `operands`, oldest first, into `base`
+fn apply<V: AsRef<[u8]>>(base: Option<V>, operands: &[V], merge: Option<MergeFn>) -> Result<Vec<u8>, DbError> {
+    let merge = merge.ok_or(DbError::NoMergeOperator)?;
+    let (first, rest) = operands.split_first().expect("a merge entry holds at least one operand");
+    let mut value = merge(base.as_ref().map(AsRef::as_ref), first.as_ref());
+    for operand in rest {
+        value = merge(Some(&value), operand.as_ref());
+    }
+    Ok(value)
+}
@@ -0,0 +1,88 @@
+//! Running totals of what the storage has done since it was opened.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::StorageOp;
+
+/// Operation counts since open, from `Database::metrics`. Unlike
+/// [`DbStats`](super::DbStats), which measures what's held now, every
+/// figure only ever goes up.
+///
+/// Counts are of storage entries, not records: a record with `#[unique]`
+/// fields is one insert for itself and one per claim, and the lookups an
+/// insert makes to check those claims count as gets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Metrics {
+    /// Entries written, expiring ones and merge operands included
+    pub inserts: u64,
+    /// Tombstones written
+    pub deletes: u64,
+    /// Point lookups of a single key, through `Database` rather than a
+    /// snapshot
+    pub gets: u64,
+    /// SSTable block reads served from the block cache
+    pub block_cache_hits: u64,
+    /// SSTable block reads that went to disk
+    pub block_cache_misses: u64,
+    /// Memtables written out to SSTables
+    pub flushes: u64,
+    /// SSTable merges, in the background or asked for
+    pub compactions: u64,
+    /// Bytes appended to the write-ahead log
+    pub wal_bytes_written: u64,
+}
+
+/// The counters behind [`Metrics`], bumped as the storage works
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    inserts: AtomicU64,
+    deletes: AtomicU64,
+    gets: AtomicU64,
+    flushes: AtomicU64,
+    compactions: AtomicU64,
+    wal_bytes_written: AtomicU64,
+}
+
+impl Counters {
+    /// Count the entries of `ops`, just logged in `bytes` of WAL.
+    pub(crate) fn logged(&self, ops: &[StorageOp], bytes: u64) {
+        for op in ops {
+            match op {
+                StorageOp::Insert(..) | StorageOp::InsertExpiring(..) | StorageOp::Merge(..) => bump(&self.inserts),
+                StorageOp::Delete(_) => bump(&self.deletes),
+                StorageOp::Begin | StorageOp::Commit => {}
+            }
+        }
+        self.wal_bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn got(&self) {
+        bump(&self.gets);
+    }
+
+    pub(crate) fn flushed(&self) {
+        bump(&self.flushes);
+    }
+
+    pub(crate) fn compacted(&self) {
+        bump(&self.compactions);
+    }
+
+    /// The counts so far, with the block cache's `(hits, misses)` added.
+    pub(crate) fn snapshot(&self, (block_cache_hits, block_cache_misses): (u64, u64)) -> Metrics {
+        Metrics {
+            inserts: self.inserts.load(Ordering::Relaxed),
+            deletes: self.deletes.load(Ordering::Relaxed),
+            gets: self.gets.load(Ordering::Relaxed),
+            block_cache_hits,
+            block_cache_misses,
+            flushes: self.flushes.load(Ordering::Relaxed),
+            compactions: self.compactions.load(Ordering::Relaxed),
+            wal_bytes_written: self.wal_bytes_written.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn bump(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
@@ -0,0 +1,821 @@
+//! On-disk SSTable format.
+//!
+//! An SSTable is an immutable, sorted run of entries split into blocks,
+//! followed by an index of those blocks and a fixed-size footer:
+//!
+//! ```text
+//! +---------+---------+-----+---------+-------+-------+--------+
+//! | block 0 | block 1 | ... | block N | index | bloom | footer |
+//! +---------+---------+-----+---------+-------+-------+--------+
+//! ```
+//!
+//! All integers are little-endian.
+//!
+//! - **Block**: entries back to back, each one
+//!   `key_len: u32 | key | tag: u8 | [expires_at: i64] | [value_len: u32 | value]`,
+//!   where tag `0` is a tombstone (nothing follows), tag `1` a value and tag
+//!   `2` a value with an expiry in Unix milliseconds. Tag `3` is instead
+//!   followed by `count: u32` and that many `operand_len: u32 | operand`,
+//!   merge operands oldest first. Tags `4` and `5` are a value and an
+//!   expiring value kept in a blob file (see [`super::blob`]), followed
+//!   by `[expires_at: i64] | blob_file: u64 | offset: u64 | len: u32`
+//!   instead of the value. A block is closed once it grows past
+//!   [`BLOCK_SIZE`].
+//! - **Index**: `block_count: u32`, then per block
+//!   `first_key_len: u32 | first_key | last_key_len: u32 | last_key | offset: u64 | len: u32 | crc32: u32`,
+//!   where `crc32` covers the block's stored bytes, then `blob_count: u32`
+//!   and that many `blob_file: u64`, every blob file the entries refer to.
+//! - **Bloom**: a [`BloomFilter`] over every key in the table.
+//! - **Footer** (48 bytes): `index_offset: u64 | index_len: u64 |
+//!   bloom_offset: u64 | bloom_len: u64 | compression: u32 | meta_crc32: u32 |
+//!   version: u32 | magic: u32`. `compression` is a [`Compression`] id, and
+//!   applies to every data block; block offsets and lengths in the index are
+//!   those of the stored (compressed) bytes. `meta_crc32` covers the index
+//!   and bloom sections together.
+//!
+//! A checksum mismatch is reported as [`DbError::Corruption`] at the offset
+//! of the damaged section.
+//!
+//! Readers load the footer, index and bloom filter once on open. A point
+//! lookup first asks the filter, then binary-searches the index by key range
+//! and reads a single block, through the [`BlockCache`], and then the blob
+//! file if the value is in one.
+
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use super::blob::{BlobFile, BlobFiles, BlobRef, Stored};
+use super::bloom::BloomFilter;
+use super::cache::BlockCache;
+use super::compression::Compression;
+use super::verify::{reason, VerifyReport};
+use super::Slot;
+use crate::error::DbError;
+
+/// A key and what it holds
+pub(crate) type Entry = (Vec<u8>, Slot<Stored>);
+
+// An entry borrowed from its block
+type EntryRef<'a> = (&'a [u8], Slot<Stored<&'a [u8]>>);
+
+/// Target uncompressed size of a data block
+pub(crate) const BLOCK_SIZE: usize = 4096;
+
+const MAGIC: u32 = u32::from_le_bytes(*b"RSST");
+
+// Source of `SsTable::id`
+static NEXT_TABLE_ID: AtomicU64 = AtomicU64::new(0);
+const VERSION: u32 = 5;
+const FOOTER_LEN: u64 = 48;
+
+const TAG_TOMBSTONE: u8 = 0;
+const TAG_VALUE: u8 = 1;
+const TAG_EXPIRING: u8 = 2;
+const TAG_MERGE: u8 = 3;
+const TAG_BLOB: u8 = 4;
+const TAG_EXPIRING_BLOB: u8 = 5;
+
+/// Location and key range of one data block
+#[derive(Debug, Clone)]
+struct BlockHandle {
+    first_key: Vec<u8>,
+    last_key: Vec<u8>,
+    offset: u64,
+    len: u32,
+    checksum: u32,
+}
+
+/// An opened SSTable with its block index held in memory
+#[derive(Debug)]
+pub(crate) struct SsTable {
+    // Unique within the process, to key the table's blocks in the cache
+    id: u64,
+    path: PathBuf,
+    index: Vec<BlockHandle>,
+    bloom: BloomFilter,
+    compression: Compression,
+    file_len: u64,
+    // The blob files its entries refer to
+    blobs: Vec<Arc<BlobFile>>,
+    // Set once compaction has replaced the table; the file is deleted on drop
+    obsolete: AtomicBool,
+}
+
+impl SsTable {
+    /// Open the table at `path`, finding the blob files it refers to in
+    /// `blobs`.
+    pub(crate) fn open(path: &Path, blobs: &BlobFiles) -> Result<Self, DbError> {
+        let Layout { index, blob_ids, bloom, compression, file_len } = Layout::read(path)?;
+        let blobs = blob_ids.into_iter().map(|id| blobs.get(id)).collect::<Result<Vec<_>, _>>()?;
+        for blob in &blobs {
+            blob.add_table();
+        }
+
+        Ok(SsTable {
+            id: NEXT_TABLE_ID.fetch_add(1, Ordering::Relaxed),
+            path: path.to_path_buf(),
+            index,
+            bloom,
+            compression,
+            file_len,
+            blobs,
+            obsolete: AtomicBool::new(false),
+        })
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Size of the file in bytes.
+    pub(crate) fn file_len(&self) -> u64 {
+        self.file_len
+    }
+
+    /// First and last key the table holds, or `None` if it's empty.
+    pub(crate) fn key_range(&self) -> Option<(&[u8], &[u8])> {
+        let first = self.index.first()?;
+        let last = self.index.last()?;
+        Some((&first.first_key, &last.last_key))
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Name of the file within the database directory.
+    pub(crate) fn file_name(&self) -> String {
+        self.path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+    }
+
+    /// The blob files the table's entries refer to.
+    pub(crate) fn blobs(&self) -> &[Arc<BlobFile>] {
+        &self.blobs
+    }
+
+    /// Delete the file once this table is dropped, along with any blob
+    /// file no other table refers to.
+    pub(crate) fn mark_obsolete(&self) {
+        if !self.obsolete.swap(true, Ordering::SeqCst) {
+            for blob in &self.blobs {
+                blob.remove_table();
+            }
+        }
+    }
+
+    /// Look up `key`, reading the value from its blob file if it's in one.
+    /// `None` means the table holds nothing for it, not even a tombstone.
+    pub(crate) fn get(&self, key: &[u8], cache: &BlockCache) -> Result<Option<Slot>, DbError> {
+        self.lookup(key, cache, |value| value.map(<[u8]>::to_vec).load())
+    }
+
+    /// Like [`SsTable::get`], but without copying the value out of the block.
+    pub(crate) fn contains(&self, key: &[u8], cache: &BlockCache) -> Result<Option<Slot<()>>, DbError> {
+        self.lookup(key, cache, |_| Ok(()))
+    }
+
+    fn lookup<V>(
+        &self,
+        key: &[u8],
+        cache: &BlockCache,
+        project: impl Fn(Stored<&[u8]>) -> Result<V, DbError>,
+    ) -> Result<Option<Slot<V>>, DbError> {
+        // Skip the file entirely when the filter rules the key out
+        if !self.bloom.may_contain(key) {
+            return Ok(None);
+        }
+
+        // First block whose range could still contain the key
+        let block = self.index.partition_point(|handle| handle.last_key.as_slice() < key);
+        let Some(handle) = self.index.get(block) else {
+            return Ok(None);
+        };
+        if handle.first_key.as_slice() > key {
+            return Ok(None);
+        }
+
+        let data = cache.get_or_read(self.id, handle.offset, || {
+            let mut file = File::open(&self.path)?;
+            self.read_block(&mut file, handle)
+        })?;
+        let mut pos = 0;
+        while pos < data.len() {
+            let (entry_key, value) =
+                decode_entry_ref(&data, &mut pos, &self.blobs).ok_or_else(|| corrupt(&self.path, "malformed block"))?;
+            match entry_key.cmp(key) {
+                std::cmp::Ordering::Less => continue,
+                std::cmp::Ordering::Equal => return value.try_map(&project).map(Some),
+                std::cmp::Ordering::Greater => break,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Iterate over every entry in key order, one block in memory at a time.
+    pub(crate) fn iter(&self) -> Result<SsTableIter<'_>, DbError> {
+        self.iter_from(&[])
+    }
+
+    /// Like [`SsTable::iter`], but starting at the first key `>= start`.
+    pub(crate) fn iter_from(&self, start: &[u8]) -> Result<SsTableIter<'_>, DbError> {
+        let first_block = self.index.partition_point(|handle| handle.last_key.as_slice() < start);
+        Ok(SsTableIter {
+            table: self,
+            blocks: Blocks::new(self, first_block)?,
+            start: start.to_vec(),
+        })
+    }
+
+    /// Like [`SsTable::iter`], but holding on to the table, with values in
+    /// blob files read out.
+    pub(crate) fn scan(self: Arc<Self>) -> Result<SsTableScan, DbError> {
+        Ok(SsTableScan { blocks: Blocks::new(&self, 0)?, table: self, failed: false })
+    }
+
+    /// Last key of the first block holding a key after `start`, which
+    /// bounds a read from `start` to one block of this table.
+    pub(crate) fn block_end_after(&self, start: Bound<&[u8]>) -> Option<&[u8]> {
+        let at = self.index.partition_point(|handle| match start {
+            Bound::Included(start) => handle.last_key.as_slice() < start,
+            Bound::Excluded(start) => handle.last_key.as_slice() <= start,
+            Bound::Unbounded => false,
+        });
+        self.index.get(at).map(|handle| handle.last_key.as_slice())
+    }
+
+    /// First key of the last block holding a key before `end`, the reverse
+    /// of [`SsTable::block_end_after`].
+    pub(crate) fn block_start_before(&self, end: Bound<&[u8]>) -> Option<&[u8]> {
+        let at = self.index.partition_point(|handle| match end {
+            Bound::Included(end) => handle.first_key.as_slice() <= end,
+            Bound::Excluded(end) => handle.first_key.as_slice() < end,
+            Bound::Unbounded => true,
+        });
+        at.checked_sub(1).map(|at| self.index[at].first_key.as_slice())
+    }
+
+    /// Read the whole table back, bypassing the block cache, and add what
+    /// it holds and any damage to `report`. A damaged block is reported
+    /// once, and none of its entries are counted.
+    pub(crate) fn verify(&self, report: &mut VerifyReport) {
+        // Checked on open, but the file may have been damaged since
+        if let Err(e) = Layout::read(&self.path) {
+            let index_offset = self.index.last().map_or(0, |handle| handle.offset + handle.len as u64);
+            report.problem(&self.path, index_offset, None, e.to_string());
+        }
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) => return report.problem(&self.path, 0, None, e.to_string()),
+        };
+        for handle in &self.index {
+            let block = match self.read_block(&mut file, handle) {
+                Ok(block) => block,
+                Err(e) => {
+                    report.problem(&self.path, handle.offset, None, reason(e, "block"));
+                    continue;
+                }
+            };
+            let mut pos = 0;
+            while pos < block.len() {
+                let Some((key, value)) = decode_entry(&block, &mut pos, &self.blobs) else {
+                    report.problem(&self.path, handle.offset, None, "malformed block");
+                    break;
+                };
+                if let Slot::Value(Stored::Blob(blob)) | Slot::Expiring(Stored::Blob(blob), _) = value {
+                    if let Err(e) = blob.load() {
+                        report.problem(blob.file.path(), blob.offset, Some(key), reason(e, "blob value"));
+                        continue;
+                    }
+                    report.blob_values += 1;
+                }
+                report.sstable_entries += 1;
+            }
+        }
+    }
+
+    fn read_block(&self, file: &mut File, handle: &BlockHandle) -> Result<Vec<u8>, DbError> {
+        let mut data = vec![0u8; handle.len as usize];
+        file.seek(SeekFrom::Start(handle.offset))?;
+        file.read_exact(&mut data)?;
+        if crc32fast::hash(&data) != handle.checksum {
+            return Err(DbError::Corruption { file: self.path.clone(), offset: handle.offset });
+        }
+        self.compression
+            .decompress(data)
+            .ok_or_else(|| corrupt(&self.path, "block does not decompress"))
+    }
+}
+
+// What a table's footer, index and bloom filter say about it
+struct Layout {
+    index: Vec<BlockHandle>,
+    blob_ids: Vec<u64>,
+    bloom: BloomFilter,
+    compression: Compression,
+    file_len: u64,
+}
+
+impl Layout {
+    fn read(path: &Path) -> Result<Self, DbError> {
+        let mut file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        if file_len < FOOTER_LEN {
+            return Err(corrupt(path, "file is shorter than the footer"));
+        }
+
+        let mut footer = [0u8; FOOTER_LEN as usize];
+        file.seek(SeekFrom::End(-(FOOTER_LEN as i64)))?;
+        file.read_exact(&mut footer)?;
+        let version = u32::from_le_bytes(footer[40..44].try_into().unwrap());
+        let magic = u32::from_le_bytes(footer[44..48].try_into().unwrap());
+        if magic != MAGIC {
+            return Err(corrupt(path, "bad magic number"));
+        }
+        if version != VERSION {
+            return Err(corrupt(path, "unsupported format version"));
+        }
+        let index_offset = u64::from_le_bytes(footer[0..8].try_into().unwrap());
+        let index_len = u64::from_le_bytes(footer[8..16].try_into().unwrap());
+        let bloom_offset = u64::from_le_bytes(footer[16..24].try_into().unwrap());
+        let bloom_len = u64::from_le_bytes(footer[24..32].try_into().unwrap());
+        let compression_id = u32::from_le_bytes(footer[32..36].try_into().unwrap());
+        let compression = Compression::from_id(compression_id).map_err(|reason| corrupt(path, reason))?;
+        let meta_checksum = u32::from_le_bytes(footer[36..40].try_into().unwrap());
+        // Checked before anything is allocated for them, and without
+        // overflowing on a damaged footer
+        let lined_up = index_offset.checked_add(index_len) == Some(bloom_offset)
+            && bloom_len.checked_add(FOOTER_LEN).and_then(|tail| tail.checked_add(bloom_offset)) == Some(file_len);
+        if !lined_up {
+            return Err(corrupt(path, "sections do not line up with the footer"));
+        }
+
+        let mut raw_index = vec![0u8; index_len as usize];
+        file.seek(SeekFrom::Start(index_offset))?;
+        file.read_exact(&mut raw_index)?;
+        let mut raw_bloom = vec![0u8; bloom_len as usize];
+        file.read_exact(&mut raw_bloom)?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&raw_index);
+        hasher.update(&raw_bloom);
+        if hasher.finalize() != meta_checksum {
+            return Err(DbError::Corruption { file: path.to_path_buf(), offset: index_offset });
+        }
+
+        let (index, blob_ids) = decode_index(&raw_index).ok_or_else(|| corrupt(path, "malformed index"))?;
+        // So a damaged index can't have a block read allocate past the data
+        let past_data = |handle: &BlockHandle| handle.offset.checked_add(handle.len as u64).is_none_or(|end| end > index_offset);
+        if index.iter().any(past_data) {
+            return Err(corrupt(path, "block runs past the data"));
+        }
+        let bloom = BloomFilter::decode(&raw_bloom).ok_or_else(|| corrupt(path, "malformed bloom filter"))?;
+
+        Ok(Layout { index, blob_ids, bloom, compression, file_len })
+    }
+}
+
+impl Drop for SsTable {
+    fn drop(&mut self) {
+        if self.obsolete.load(Ordering::SeqCst) {
+            if let Err(e) = std::fs::remove_file(&self.path) {
+                log::warn!("Failed to remove compacted SSTable {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+// Reads a table's entries one block at a time, from a given block on
+struct Blocks {
+    file: File,
+    next_block: usize,
+    block: Vec<u8>,
+    pos: usize,
+}
+
+impl Blocks {
+    fn new(table: &SsTable, first_block: usize) -> Result<Self, DbError> {
+        Ok(Blocks { file: File::open(&table.path)?, next_block: first_block, block: Vec::new(), pos: 0 })
+    }
+
+    fn next(&mut self, table: &SsTable) -> Option<Result<Entry, DbError>> {
+        while self.pos >= self.block.len() {
+            let handle = table.index.get(self.next_block)?;
+            self.next_block += 1;
+            self.block = match table.read_block(&mut self.file, handle) {
+                Ok(block) => block,
+                Err(e) => return Some(Err(e)),
+            };
+            self.pos = 0;
+        }
+        Some(decode_entry(&self.block, &mut self.pos, &table.blobs).ok_or_else(|| corrupt(&table.path, "malformed block")))
+    }
+}
+
+/// Sequential reader over the entries of one SSTable, in key order
+pub(crate) struct SsTableIter<'a> {
+    table: &'a SsTable,
+    blocks: Blocks,
+    // Entries below this key are skipped
+    start: Vec<u8>,
+}
+
+impl Iterator for SsTableIter<'_> {
+    type Item = Result<Entry, DbError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.blocks.next(self.table)? {
+                Ok((key, _)) if key < self.start => continue,
+                entry => return Some(entry),
+            }
+        }
+    }
+}
+
+/// Every entry of one SSTable file, in key order, from
+/// [`Database::scan_sstable`](crate::Database::scan_sstable).
+///
+/// Each entry is the storage key and exactly what the table holds for it,
+/// tombstones, expired values and merge operands included, with nothing
+/// from other tables merged in. Blocks are read one at a time and their
+/// checksums checked, as for any read: damage comes out as an `Err`, which
+/// ends the scan. The scan holds on to the table, so compaction can't
+/// delete the file from under it.
+pub struct SsTableScan {
+    table: Arc<SsTable>,
+    blocks: Blocks,
+    failed: bool,
+}
+
+impl std::fmt::Debug for SsTableScan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SsTableScan").field("file", &self.table.path).finish()
+    }
+}
+
+impl Iterator for SsTableScan {
+    type Item = Result<(Vec<u8>, Slot), DbError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let entry = self.blocks.next(&self.table)?.and_then(|(key, slot)| Ok((key, slot.load()?)));
+        self.failed = entry.is_err();
+        Some(entry)
+    }
+}
+
+/// Builds an SSTable from entries supplied in ascending key order.
+///
+/// The table is written to a temporary file next to `path` and only renamed
+/// into place once it's complete, so a failed write never leaves a partial
+/// SSTable behind. A writer dropped before [`SsTableWriter::finish`]
+/// succeeds removes its temporary file.
+pub(crate) struct SsTableWriter {
+    path: PathBuf,
+    temp: PathBuf,
+    // Cleared once the temporary file has been renamed into place
+    pending: bool,
+    writer: BufWriter<File>,
+    offset: u64,
+    block: Vec<u8>,
+    block_first_key: Option<Vec<u8>>,
+    last_key: Vec<u8>,
+    index: Vec<BlockHandle>,
+    key_hashes: Vec<u64>,
+    // Ids of the blob files the entries refer to
+    blob_ids: BTreeSet<u64>,
+    bloom_false_positive_rate: f64,
+    compression: Compression,
+}
+
+impl SsTableWriter {
+    pub(crate) fn create(path: &Path, bloom_false_positive_rate: f64, compression: Compression) -> Result<Self, DbError> {
+        let temp = temp_path(path);
+        Ok(SsTableWriter {
+            path: path.to_path_buf(),
+            writer: BufWriter::new(File::create(&temp)?),
+            temp,
+            pending: true,
+            offset: 0,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            block_first_key: None,
+            last_key: Vec::new(),
+            index: Vec::new(),
+            key_hashes: Vec::new(),
+            blob_ids: BTreeSet::new(),
+            bloom_false_positive_rate,
+            compression,
+        })
+    }
+
+    /// Add the next entry. Blob files it refers to must be synced before
+    /// the table is finished.
+    pub(crate) fn add(&mut self, key: &[u8], value: &Slot<Stored<&[u8]>>) -> Result<(), DbError> {
+        if self.block_first_key.is_none() {
+            self.block_first_key = Some(key.to_vec());
+        }
+        if let Slot::Value(Stored::Blob(blob)) | Slot::Expiring(Stored::Blob(blob), _) = value {
+            self.blob_ids.insert(blob.file.id());
+        }
+        encode_entry(&mut self.block, key, value);
+        self.key_hashes.push(BloomFilter::hash(key));
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+
+        if self.block.len() >= BLOCK_SIZE {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// Bytes of data blocks written so far, counting the one being filled.
+    pub(crate) fn data_len(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    /// Write the index and footer, fsync, rename the file into place, and
+    /// reopen the finished table with the blob files it refers to from
+    /// `blobs`.
+    pub(crate) fn finish(mut self, blobs: &BlobFiles) -> Result<SsTable, DbError> {
+        self.finish_block()?;
+
+        let mut raw_index = Vec::new();
+        raw_index.extend_from_slice(&(self.index.len() as u32).to_le_bytes());
+        for handle in &self.index {
+            put_bytes(&mut raw_index, &handle.first_key);
+            put_bytes(&mut raw_index, &handle.last_key);
+            raw_index.extend_from_slice(&handle.offset.to_le_bytes());
+            raw_index.extend_from_slice(&handle.len.to_le_bytes());
+            raw_index.extend_from_slice(&handle.checksum.to_le_bytes());
+        }
+        raw_index.extend_from_slice(&(self.blob_ids.len() as u32).to_le_bytes());
+        for id in &self.blob_ids {
+            raw_index.extend_from_slice(&id.to_le_bytes());
+        }
+        self.writer.write_all(&raw_index)?;
+
+        let raw_bloom = BloomFilter::build(&self.key_hashes, self.bloom_false_positive_rate).encode();
+        self.writer.write_all(&raw_bloom)?;
+
+        let mut footer = Vec::with_capacity(FOOTER_LEN as usize);
+        footer.extend_from_slice(&self.offset.to_le_bytes());
+        footer.extend_from_slice(&(raw_index.len() as u64).to_le_bytes());
+        footer.extend_from_slice(&(self.offset + raw_index.len() as u64).to_le_bytes());
+        footer.extend_from_slice(&(raw_bloom.len() as u64).to_le_bytes());
+        footer.extend_from_slice(&self.compression.id().to_le_bytes());
+        let mut meta = crc32fast::Hasher::new();
+        meta.update(&raw_index);
+        meta.update(&raw_bloom);
+        footer.extend_from_slice(&meta.finalize().to_le_bytes());
+        footer.extend_from_slice(&VERSION.to_le_bytes());
+        footer.extend_from_slice(&MAGIC.to_le_bytes());
+        self.writer.write_all(&footer)?;
+
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        std::fs::rename(&self.temp, &self.path)?;
+        self.pending = false;
+
+        // Make the new name durable, then read the footer back so a bad
+        // write surfaces now, not on first lookup
+        super::sync_dir(super::parent_dir(&self.path)).and_then(|()| SsTable::open(&self.path, blobs)).inspect_err(|_| {
+            if let Err(e) = std::fs::remove_file(&self.path) {
+                log::warn!("Failed to remove unreadable SSTable {}: {}", self.path.display(), e);
+            }
+        })
+    }
+
+    fn finish_block(&mut self) -> Result<(), DbError> {
+        let Some(first_key) = self.block_first_key.take() else {
+            return Ok(());
+        };
+        let stored = self.compression.compress(&self.block);
+        self.writer.write_all(&stored)?;
+        self.index.push(BlockHandle {
+            first_key,
+            last_key: self.last_key.clone(),
+            offset: self.offset,
+            len: stored.len() as u32,
+            checksum: crc32fast::hash(&stored),
+        });
+        self.offset += stored.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+}
+
+impl Drop for SsTableWriter {
+    fn drop(&mut self) {
+        if self.pending {
+            if let Err(e) = std::fs::remove_file(&self.temp) {
+                log::warn!("Failed to remove unfinished SSTable {}: {}", self.temp.display(), e);
+            }
+        }
+    }
+}
+
+/// Where the table bound for `path` is written until it's complete:
+/// `sst-<seconds>-<sequence>.bin.tmp`.
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+fn corrupt(path: &Path, reason: &str) -> DbError {
+    DbError::SerializationError(format!("corrupt SSTable {}: {}", path.display(), reason))
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn encode_entry(buf: &mut Vec<u8>, key: &[u8], value: &Slot<Stored<&[u8]>>) {
+    put_bytes(buf, key);
+    match value {
+        Slot::Value(Stored::Inline(value)) => {
+            buf.push(TAG_VALUE);
+            put_bytes(buf, value);
+        }
+        Slot::Expiring(Stored::Inline(value), expires_at) => {
+            buf.push(TAG_EXPIRING);
+            buf.extend_from_slice(&expires_at.to_le_bytes());
+            put_bytes(buf, value);
+        }
+        Slot::Value(Stored::Blob(blob)) => {
+            buf.push(TAG_BLOB);
+            put_blob(buf, blob);
+        }
+        Slot::Expiring(Stored::Blob(blob), expires_at) => {
+            buf.push(TAG_EXPIRING_BLOB);
+            buf.extend_from_slice(&expires_at.to_le_bytes());
+            put_blob(buf, blob);
+        }
+        Slot::Tombstone => buf.push(TAG_TOMBSTONE),
+        Slot::Merge(operands) => {
+            buf.push(TAG_MERGE);
+            buf.extend_from_slice(&(operands.len() as u32).to_le_bytes());
+            for operand in operands {
+                match operand {
+                    Stored::Inline(operand) => put_bytes(buf, operand),
+                    Stored::Blob(_) => unreachable!("merge operands are never put in blob files"),
+                }
+            }
+        }
+    }
+}
+
+fn put_blob(buf: &mut Vec<u8>, blob: &BlobRef) {
+    buf.extend_from_slice(&blob.file.id().to_le_bytes());
+    buf.extend_from_slice(&blob.offset.to_le_bytes());
+    buf.extend_from_slice(&blob.len.to_le_bytes());
+}
+
+fn take<'a>(buf: &'a [u8], pos: &mut usize, len: usize) -> Option<&'a [u8]> {
+    let bytes = buf.get(*pos..pos.checked_add(len)?)?;
+    *pos += len;
+    Some(bytes)
+}
+
+fn take_u32(buf: &[u8], pos: &mut usize) -> Option<u32> {
+    take(buf, pos, 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn take_u64(buf: &[u8], pos: &mut usize) -> Option<u64> {
+    take(buf, pos, 8).map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn take_bytes(buf: &[u8], pos: &mut usize) -> Option<Vec<u8>> {
+    take_slice(buf, pos).map(<[u8]>::to_vec)
+}
+
+fn take_slice<'a>(buf: &'a [u8], pos: &mut usize) -> Option<&'a [u8]> {
+    let len = take_u32(buf, pos)? as usize;
+    take(buf, pos, len)
+}
+
+fn decode_entry(buf: &[u8], pos: &mut usize, blobs: &[Arc<BlobFile>]) -> Option<Entry> {
+    let (key, value) = decode_entry_ref(buf, pos, blobs)?;
+    Some((key.to_vec(), value.map(|value| value.map(<[u8]>::to_vec))))
+}
+
+// An entry borrowed from its block. A reference to a blob file that isn't
+// among `blobs`, the table's, makes the entry malformed.
+fn decode_entry_ref<'a>(
+    buf: &'a [u8],
+    pos: &mut usize,
+    blobs: &[Arc<BlobFile>],
+) -> Option<EntryRef<'a>> {
+    let key = take_slice(buf, pos)?;
+    let value = match *take(buf, pos, 1)?.first()? {
+        TAG_TOMBSTONE => Slot::Tombstone,
+        TAG_VALUE => Slot::Value(Stored::Inline(take_slice(buf, pos)?)),
+        TAG_EXPIRING => {
+            let expires_at = i64::from_le_bytes(take(buf, pos, 8)?.try_into().ok()?);
+            Slot::Expiring(Stored::Inline(take_slice(buf, pos)?), expires_at)
+        }
+        TAG_BLOB => Slot::Value(Stored::Blob(take_blob(buf, pos, blobs)?)),
+        TAG_EXPIRING_BLOB => {
+            let expires_at = i64::from_le_bytes(take(buf, pos, 8)?.try_into().ok()?);
+            Slot::Expiring(Stored::Blob(take_blob(buf, pos, blobs)?), expires_at)
+        }
+        TAG_MERGE => {
+            let count = take_u32(buf, pos)?;
+            let operands = (0..count).map(|_| take_slice(buf, pos).map(Stored::Inline)).collect::<Option<Vec<_>>>()?;
+            // Written from at least one operand
+            if operands.is_empty() {
+                return None;
+            }
+            Slot::Merge(operands)
+        }
+        _ => return None,
+    };
+    Some((key, value))
+}
+
+fn take_blob(buf: &[u8], pos: &mut usize, blobs: &[Arc<BlobFile>]) -> Option<BlobRef> {
+    let id = take_u64(buf, pos)?;
+    let offset = take_u64(buf, pos)?;
+    let len = take_u32(buf, pos)?;
+    let file = blobs.iter().find(|blob| blob.id() == id)?;
+    Some(BlobRef { file: Arc::clone(file), offset, len })
+}
+
+// The block index and the ids of the blob files listed after it
+fn decode_index(buf: &[u8]) -> Option<(Vec<BlockHandle>, Vec<u64>)> {
+    let mut pos = 0;
+    let count = take_u32(buf, &mut pos)? as usize;
+    let mut index = Vec::new();
+    for _ in 0..count {
+        index.push(BlockHandle {
+            first_key: take_bytes(buf, &mut pos)?,
+            last_key: take_bytes(buf, &mut pos)?,
+            offset: take_u64(buf, &mut pos)?,
+            len: take_u32(buf, &mut pos)?,
+            checksum: take_u32(buf, &mut pos)?,
+        });
+    }
+    let mut blob_ids = Vec::new();
+    for _ in 0..take_u32(buf, &mut pos)? {
+        blob_ids.push(take_u64(buf, &mut pos)?);
+    }
+    (pos == buf.len()).then_some((index, blob_ids))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A table of `count` keys `key-000000`, ... with 100-byte values
+    fn write_table(dir: &Path, count: usize) -> SsTable {
+        let mut writer = SsTableWriter::create(&dir.join("sst-1-0.bin"), 0.01, Compression::None).unwrap();
+        for i in 0..count {
+            let key = format!("key-{:06}", i);
+            writer.add(key.as_bytes(), &Slot::Value(Stored::Inline(&[i as u8; 100][..]))).unwrap();
+        }
+        writer.finish(&BlobFiles::new(dir).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn lookup_reads_one_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let table = write_table(dir.path(), 20_000);
+        assert!(table.index.len() > 100);
+
+        let key: &[u8] = b"key-019998";
+        let cache = BlockCache::new(0);
+        let found = table.get(key, &cache).unwrap();
+        assert_eq!(found, Some(Slot::Value(vec![(19_998 % 256) as u8; 100])));
+        // Every read goes through the cache, so its misses count the blocks
+        // read from disk
+        assert_eq!(cache.counters(), (0, 1));
+        let block = &table.index[table.index.partition_point(|handle| handle.last_key.as_slice() < key)];
+        assert!((block.len as u64) < table.file_len() / 100);
+    }
+
+    #[test]
+    fn absent_keys_skip_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let table = write_table(dir.path(), 1000);
+        // Deleted from under the table, so any read of a block would fail
+        std::fs::remove_file(table.path()).unwrap();
+
+        let cache = BlockCache::new(0);
+        let absent: Vec<String> = (0..1000).map(|i| format!("key-{:06}x", i)).collect();
+        let ruled_out: Vec<&String> = absent.iter().filter(|key| !table.bloom.may_contain(key.as_bytes())).collect();
+        // 1% false positives, give or take
+        assert!(ruled_out.len() > 950, "only {} ruled out", ruled_out.len());
+        for key in ruled_out {
+            assert_eq!(table.get(key.as_bytes(), &cache).unwrap(), None);
+        }
+        assert_eq!(cache.counters(), (0, 0));
+    }
+}
@@ -0,0 +1,150 @@
+//! Checking every file of a database for damage, from `Database::verify`.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+use bincode::Options;
+
+use super::{
+    poisoned, record_options, segment_name, wal_segments, LsmStorage, StorageOp, Wal, LEGACY_WAL,
+    WAL_FRAME_HEADER, WAL_MAGIC,
+};
+use crate::error::DbError;
+
+/// What [`Database::verify`](crate::Database::verify) read and the damage
+/// it found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// SSTables checked
+    pub sstables: usize,
+    /// SSTable entries that read back intact, tombstones included
+    pub sstable_entries: usize,
+    /// Values in blob files that read back intact, also counted among
+    /// `sstable_entries`
+    pub blob_values: usize,
+    /// Live WAL segments checked
+    pub wal_segments: usize,
+    /// WAL records that read back intact
+    pub wal_records: usize,
+    /// Everything that didn't, in the order it was found
+    pub problems: Vec<VerifyProblem>,
+}
+
+impl VerifyReport {
+    /// Whether nothing damaged was found.
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub(crate) fn problem(&mut self, file: &Path, offset: u64, key: Option<Vec<u8>>, reason: impl Into<String>) {
+        self.problems.push(VerifyProblem {
+            file: file.to_path_buf(),
+            offset,
+            key,
+            reason: reason.into(),
+        });
+    }
+}
+
+/// One damaged part of a file, from [`VerifyReport::problems`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyProblem {
+    pub file: PathBuf,
+    /// Where the damaged SSTable block or index, blob value or WAL record
+    /// starts
+    pub offset: u64,
+    /// The storage key of the damaged entry, when enough of it is left to
+    /// tell. A damaged SSTable block has no single key.
+    pub key: Option<Vec<u8>>,
+    pub reason: String,
+}
+
+impl LsmStorage {
+    /// Read every SSTable, the blob values they refer to and the live WAL
+    /// segments, checking each checksum and decoding each entry.
+    /// Damage is collected in the report rather than failing the check.
+    ///
+    /// The SSTables are those in place when it starts; compaction can run
+    /// meanwhile without deleting them. Writes wait while the WAL is read.
+    pub(crate) fn verify(&self) -> Result<VerifyReport, DbError> {
+        let mut report = VerifyReport::default();
+        let sstables = self.shared.sstables.read().map_err(|_| poisoned("sstables"))?.clone();
+        for table in sstables.iter() {
+            report.sstables += 1;
+            table.verify(&mut report);
+        }
+
+        let dir = &self.shared.dir;
+        // Held so no frame is only half written while it's read
+        let _wal = match &self.wal {
+            Some(wal) => Some(wal.read().map_err(|_| poisoned("wal"))?),
+            None => None,
+        };
+        let start = self.shared.wal_start.load(Ordering::SeqCst);
+        let mut logs: Vec<_> = wal_segments(dir)?.into_iter().filter(|&seq| seq >= start).map(segment_name).collect();
+        if self.wal.is_none() {
+            // Left for a writable open to move into a segment
+            logs.push(LEGACY_WAL.to_string());
+        }
+        for name in logs {
+            verify_log(&dir.join(name), self.shared.options.max_record_size, &mut report)?;
+        }
+        Ok(report)
+    }
+}
+
+// What's wrong with `what`, without repeating the file and offset a
+// `DbError::Corruption` names
+pub(crate) fn reason(e: DbError, what: &str) -> String {
+    match e {
+        DbError::Corruption { .. } => format!("{} checksum mismatch", what),
+        e => e.to_string(),
+    }
+}
+
+// Check one WAL file frame by frame. A torn final frame, which recovery
+// cuts off, isn't damage; a bad one with more log after it is, and the
+// check carries on with the frame after it. A frame longer than `limit`
+// ends the check, with no way to tell where the next one starts.
+fn verify_log(path: &Path, limit: u64, report: &mut VerifyReport) -> Result<(), DbError> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        // An obsolete segment a flush just removed, or a legacy log that
+        // isn't there
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    report.wal_segments += 1;
+    if !bytes.starts_with(&WAL_MAGIC) {
+        // From before framing, with nothing to check but that it decodes
+        report.wal_records += Wal::decode_legacy(&bytes, limit).len();
+        return Ok(());
+    }
+
+    let mut pos = WAL_MAGIC.len();
+    while let Some(header) = bytes.get(pos..pos + WAL_FRAME_HEADER) {
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        if len as u64 > limit {
+            let e = DbError::ValueTooLarge { size: len as u64, limit };
+            report.problem(path, pos as u64, None, format!("WAL record: {}", e));
+            break;
+        }
+        let end = pos + WAL_FRAME_HEADER + len;
+        let Some(payload) = bytes.get(pos + WAL_FRAME_HEADER..end) else { break };
+        let op = record_options(limit).deserialize::<StorageOp>(payload);
+        if crc32fast::hash(payload) != checksum {
+            if end == bytes.len() {
+                break;
+            }
+            let key = op.ok().and_then(|op| op.key().map(<[u8]>::to_vec));
+            report.problem(path, pos as u64, key, "WAL record checksum mismatch");
+        } else if op.is_err() {
+            report.problem(path, pos as u64, None, "WAL record does not decode");
+        } else {
+            report.wal_records += 1;
+        }
+        pos = end;
+    }
+    Ok(())
+}
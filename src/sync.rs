@@ -0,0 +1,411 @@
+//! A blocking front end to [`Database`], for callers without an async
+//! runtime.
+//!
+//! The storage engine underneath is synchronous already, and the locks
+//! `Database` takes don't depend on a runtime, so each call just runs the
+//! async method to completion on the calling thread. Without a Tokio
+//! runtime to hand it to, compaction runs as part of the flush that calls
+//! for it.
+//!
+//! Don't call it from inside an async task: blocking there stalls
+//! whatever else shares the thread.
+
+use std::ops::RangeBounds;
+use std::path::Path;
+
+use futures::executor::block_on;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    AsKeyBytes, ChangeEvent, CompactionReport, CompileTimeSchema, Database, DatabaseBuilder, DbError, DbStats, Field,
+    IndexKind, IndexOp, Metrics, Predicate, PrimaryKey, QueryBuilder, Schema, SsTableScan, VerifyReport, WriteBatch,
+};
+
+/// [`Database`] with blocking methods, enabled by the `sync` feature.
+///
+/// ```no_run
+/// # #[derive(rust_db::Schema, serde::Serialize, serde::Deserialize)]
+/// # #[table_name = "User"]
+/// # struct User { #[primary_key] id: u64, name: String }
+/// let db = rust_db::SyncDatabase::open("./data")?;
+/// db.insert(&User { id: 1, name: "Ann".to_string() })?;
+/// let user = db.get::<User>("1")?;
+/// # Ok::<(), rust_db::DbError>(())
+/// ```
+#[derive(Debug)]
+pub struct SyncDatabase {
+    inner: Database,
+}
+
+impl SyncDatabase {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, DbError> {
+        Database::builder(path).open_sync()
+    }
+
+    /// See [`Database::open_read_only`].
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<Self, DbError> {
+        Database::builder(path).read_only(true).open_sync()
+    }
+
+    /// The async [`Database`] underneath.
+    pub fn as_async(&self) -> &Database {
+        &self.inner
+    }
+
+    /// See [`Database::insert`].
+    pub fn insert<T>(&self, item: &T) -> Result<Vec<u8>, DbError>
+    where
+        T: Schema + CompileTimeSchema + PrimaryKey + Serialize + DeserializeOwned,
+    {
+        block_on(self.inner.insert(item))
+    }
+
+    /// See [`Database::insert_many`].
+    pub fn insert_many<T>(&self, items: &[T]) -> Result<(), DbError>
+    where
+        T: Schema + CompileTimeSchema + PrimaryKey + Serialize + DeserializeOwned,
+    {
+        block_on(self.inner.insert_many(items))
+    }
+
+    /// See [`Database::upsert`].
+    pub fn upsert<T>(&self, item: &T) -> Result<bool, DbError>
+    where
+        T: Schema + CompileTimeSchema + PrimaryKey + Serialize + DeserializeOwned,
+    {
+        block_on(self.inner.upsert(item))
+    }
+
+    /// See [`Database::update`].
+    pub fn update<T, F>(&self, key: &str, f: F) -> Result<bool, DbError>
+    where
+        T: Schema + CompileTimeSchema + PrimaryKey + Serialize + DeserializeOwned,
+        F: FnOnce(&mut T),
+    {
+        block_on(self.inner.update(key, f))
+    }
+
+    /// See [`Database::get`].
+    pub fn get<T>(&self, key: &str) -> Result<Option<T>, DbError>
+    where
+        T: Schema + CompileTimeSchema + PrimaryKey + DeserializeOwned,
+    {
+        block_on(self.inner.get(key))
+    }
+
+    /// See [`Database::get_many`].
+    pub fn get_many<T>(&self, keys: &[&str]) -> Result<Vec<Option<T>>, DbError>
+    where
+        T: Schema + CompileTimeSchema + PrimaryKey + DeserializeOwned,
+    {
+        block_on(self.inner.get_many(keys))
+    }
+
+    /// See [`Database::get_by_key`].
+    pub fn get_by_key<T, K>(&self, key: K) -> Result<Option<T>, DbError>
+    where
+        T: Schema + CompileTimeSchema + DeserializeOwned,
+        K: AsKeyBytes,
+    {
+        block_on(self.inner.get_by_key(key))
+    }
+
+    /// See [`Database::all`].
+    pub fn all<T>(&self) -> Result<Vec<T>, DbError>
+    where
+        T: Schema + CompileTimeSchema + DeserializeOwned,
+    {
+        block_on(self.inner.all())
+    }
+
+    /// See [`Database::exists`].
+    pub fn exists<T>(&self, key: &str) -> Result<bool, DbError>
+    where
+        T: Schema + PrimaryKey,
+    {
+        block_on(self.inner.exists::<T>(key))
+    }
+
+    /// See [`Database::scan`].
+    pub fn scan<T, K>(&self, range: impl RangeBounds<K>) -> Result<Vec<T>, DbError>
+    where
+        T: Schema + CompileTimeSchema + DeserializeOwned,
+        K: AsKeyBytes,
+    {
+        block_on(self.inner.scan(range))
+    }
+
+    /// See [`Database::delete`].
+    pub fn delete<T>(&self, key: &str) -> Result<(), DbError>
+    where
+        T: Schema + PrimaryKey,
+    {
+        block_on(self.inner.delete::<T>(key))
+    }
+
+    /// See [`Database::put_raw`].
+    pub fn put_raw(&self, key: &[u8], value: &[u8]) -> Result<(), DbError> {
+        block_on(self.inner.put_raw(key, value))
+    }
+
+    /// See [`Database::get_raw`].
+    pub fn get_raw(&self, key: &[u8]) -> Result<Option<Vec<u8>>, DbError> {
+        block_on(self.inner.get_raw(key))
+    }
+
+    /// See [`Database::delete_raw`].
+    pub fn delete_raw(&self, key: &[u8]) -> Result<(), DbError> {
+        block_on(self.inner.delete_raw(key))
+    }
+
+    /// See [`Database::merge`].
+    pub fn merge(&self, key: &[u8], operand: &[u8]) -> Result<(), DbError> {
+        block_on(self.inner.merge(key, operand))
+    }
+
+    /// See [`Database::table_names`].
+    pub fn table_names(&self) -> Vec<String> {
+        block_on(self.inner.table_names())
+    }
+
+    /// See [`Database::schema_version`].
+    pub fn schema_version(&self, table: &str) -> Option<u32> {
+        block_on(self.inner.schema_version(table))
+    }
+
+    /// See [`Database::apply_batch`].
+    pub fn apply_batch(&self, batch: WriteBatch) -> Result<(), DbError> {
+        block_on(self.inner.apply_batch(batch))
+    }
+
+    /// See [`Database::bulk_load`].
+    pub fn bulk_load<F, R>(&self, load: F) -> Result<R, DbError>
+    where
+        F: FnOnce(&SyncDatabase) -> Result<R, DbError>,
+    {
+        // `load` blocks on its own calls, so it can't run inside the async
+        // `bulk_load`
+        let bulk = block_on(self.inner.read_storage())?.begin_bulk()?;
+        let loaded = load(self);
+        let ended = block_on(self.inner.read_storage()).and_then(|storage| storage.end_bulk(bulk));
+        let value = loaded?;
+        ended?;
+        Ok(value)
+    }
+
+    /// See [`Database::create_index`].
+    pub fn create_index<T>(&self, field: &str) -> Result<(), DbError>
+    where
+        T: Schema + CompileTimeSchema + Serialize + DeserializeOwned,
+    {
+        block_on(self.inner.create_index::<T>(field))
+    }
+
+    /// See [`Database::create_index_with`].
+    pub fn create_index_with<T>(&self, field: &str, kind: IndexKind) -> Result<(), DbError>
+    where
+        T: Schema + CompileTimeSchema + Serialize + DeserializeOwned,
+    {
+        block_on(self.inner.create_index_with::<T>(field, kind))
+    }
+
+    pub fn query<T>(&self) -> SyncQueryBuilder<'_, T>
+    where
+        T: Schema + CompileTimeSchema + DeserializeOwned + Send + Sync,
+    {
+        SyncQueryBuilder { inner: self.inner.query() }
+    }
+
+    /// See [`Database::subscribe`]. Without a runtime, receive with
+    /// `blocking_recv`.
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<ChangeEvent> {
+        self.inner.subscribe()
+    }
+
+    /// See [`Database::stats`].
+    pub fn stats(&self) -> Result<DbStats, DbError> {
+        block_on(self.inner.stats())
+    }
+
+    /// See [`Database::metrics`].
+    pub fn metrics(&self) -> Result<Metrics, DbError> {
+        block_on(self.inner.metrics())
+    }
+
+    /// See [`Database::flush`].
+    pub fn flush(&self) -> Result<(), DbError> {
+        block_on(self.inner.flush())
+    }
+
+    /// See [`Database::sync_wal`].
+    pub fn sync_wal(&self) -> Result<(), DbError> {
+        block_on(self.inner.sync_wal())
+    }
+
+    /// See [`Database::compact`].
+    pub fn compact(&self) -> Result<(), DbError> {
+        block_on(self.inner.compact())
+    }
+
+    /// See [`Database::compact_now`].
+    pub fn compact_now(&self) -> Result<CompactionReport, DbError> {
+        block_on(self.inner.compact_now())
+    }
+
+    /// See [`Database::debug_dump`].
+    #[cfg(feature = "debug-dump")]
+    pub fn debug_dump(&self, writer: impl std::io::Write) -> Result<(), DbError> {
+        block_on(self.inner.debug_dump(writer))
+    }
+
+    /// See [`Database::verify`].
+    pub fn verify(&self) -> Result<VerifyReport, DbError> {
+        block_on(self.inner.verify())
+    }
+
+    /// See [`Database::scan_sstable`].
+    pub fn scan_sstable(&self, file: impl AsRef<Path>) -> Result<SsTableScan, DbError> {
+        block_on(self.inner.scan_sstable(file))
+    }
+
+    /// See [`Database::backup`].
+    pub fn backup(&self, dest: impl AsRef<Path>) -> Result<(), DbError> {
+        block_on(self.inner.backup(dest))
+    }
+
+    /// See [`Database::close`].
+    pub fn close(self) -> Result<(), DbError> {
+        block_on(self.inner.close())
+    }
+}
+
+impl From<Database> for SyncDatabase {
+    fn from(inner: Database) -> Self {
+        SyncDatabase { inner }
+    }
+}
+
+/// [`QueryBuilder`] with blocking `execute`, `select`, `count`, `first` and
+/// `one`, from [`SyncDatabase::query`].
+pub struct SyncQueryBuilder<'a, T> {
+    inner: QueryBuilder<'a, T>,
+}
+
+impl<T> SyncQueryBuilder<'_, T>
+where
+    T: Schema + CompileTimeSchema + DeserializeOwned + Send + Sync,
+{
+    pub fn filter<F>(self, filter: F) -> Self
+    where
+        F: Fn(&T) -> bool + 'static + Send + Sync,
+    {
+        SyncQueryBuilder { inner: self.inner.filter(filter) }
+    }
+
+    /// See [`QueryBuilder::and`].
+    pub fn and<F>(self, filter: F) -> Self
+    where
+        F: Fn(&T) -> bool + 'static + Send + Sync,
+    {
+        SyncQueryBuilder { inner: self.inner.and(filter) }
+    }
+
+    /// See [`QueryBuilder::or`].
+    pub fn or<F>(self, filter: F) -> Self
+    where
+        F: Fn(&T) -> bool + 'static + Send + Sync,
+    {
+        SyncQueryBuilder { inner: self.inner.or(filter) }
+    }
+
+    /// See [`QueryBuilder::matching`].
+    pub fn matching(self, predicate: Predicate<T>) -> Self {
+        SyncQueryBuilder { inner: self.inner.matching(predicate) }
+    }
+
+    /// See [`QueryBuilder::or_matching`].
+    pub fn or_matching(self, predicate: Predicate<T>) -> Self {
+        SyncQueryBuilder { inner: self.inner.or_matching(predicate) }
+    }
+
+    /// See [`QueryBuilder::filter_indexed`].
+    pub fn filter_indexed<V>(self, field: &str, op: IndexOp, value: V) -> Self
+    where
+        T: Serialize,
+        V: Serialize,
+    {
+        SyncQueryBuilder { inner: self.inner.filter_indexed(field, op, value) }
+    }
+
+    /// See [`QueryBuilder::filter_field`].
+    pub fn filter_field<V>(self, field: Field<T, V>, op: IndexOp, value: V) -> Self
+    where
+        T: 'static,
+        V: Serialize + 'static,
+    {
+        SyncQueryBuilder { inner: self.inner.filter_field(field, op, value) }
+    }
+
+    pub fn order_by<K, F>(self, key_fn: F) -> Self
+    where
+        K: Ord,
+        F: Fn(&T) -> K + 'static + Send + Sync,
+    {
+        SyncQueryBuilder { inner: self.inner.order_by(key_fn) }
+    }
+
+    /// See [`QueryBuilder::parallel`].
+    #[cfg(feature = "parallel")]
+    pub fn parallel(self) -> Self {
+        SyncQueryBuilder { inner: self.inner.parallel() }
+    }
+
+    pub fn asc(self) -> Self {
+        SyncQueryBuilder { inner: self.inner.asc() }
+    }
+
+    pub fn desc(self) -> Self {
+        SyncQueryBuilder { inner: self.inner.desc() }
+    }
+
+    pub fn limit(self, limit: usize) -> Self {
+        SyncQueryBuilder { inner: self.inner.limit(limit) }
+    }
+
+    pub fn offset(self, offset: usize) -> Self {
+        SyncQueryBuilder { inner: self.inner.offset(offset) }
+    }
+
+    pub fn execute(self) -> Result<Vec<T>, DbError> {
+        block_on(self.inner.execute())
+    }
+
+    /// See [`QueryBuilder::select`].
+    pub fn select<P, F>(self, project: F) -> Result<Vec<P>, DbError>
+    where
+        F: FnMut(T) -> P,
+    {
+        block_on(self.inner.select(project))
+    }
+
+    pub fn count(self) -> Result<usize, DbError> {
+        block_on(self.inner.count())
+    }
+
+    /// See [`QueryBuilder::first`].
+    pub fn first(self) -> Result<Option<T>, DbError> {
+        block_on(self.inner.first())
+    }
+
+    /// See [`QueryBuilder::one`].
+    pub fn one(self) -> Result<T, DbError> {
+        block_on(self.inner.one())
+    }
+}
+
+impl DatabaseBuilder {
+    /// Like [`DatabaseBuilder::open`], for a [`SyncDatabase`].
+    pub fn open_sync(self) -> Result<SyncDatabase, DbError> {
+        block_on(self.open()).map(SyncDatabase::from)
+    }
+}
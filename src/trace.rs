@@ -0,0 +1,48 @@
+//! Spans around inserts, gets, flushes and compactions, behind the
+//! `tracing` feature. Without it, [`Timer`] and [`record!`] compile to
+//! nothing, and the functions carry no `#[instrument]`.
+
+#[cfg(feature = "tracing")]
+use std::time::Instant;
+
+/// Records how long the span it was started in ran, in microseconds, in
+/// the span's `elapsed_us` field when it's dropped, so a `?` that returns
+/// early still records it.
+pub(crate) struct Timer {
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+    #[cfg(feature = "tracing")]
+    started: Instant,
+}
+
+impl Timer {
+    pub(crate) fn start() -> Self {
+        Timer {
+            #[cfg(feature = "tracing")]
+            span: tracing::Span::current(),
+            #[cfg(feature = "tracing")]
+            started: Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.span.record("elapsed_us", self.started.elapsed().as_micros() as u64);
+    }
+}
+
+/// Fill in fields of the current span, e.g. `trace::record!(found = true)`.
+/// The values aren't evaluated without the `tracing` feature.
+macro_rules! record {
+    ($($field:ident = $value:expr),+ $(,)?) => {
+        #[cfg(feature = "tracing")]
+        {
+            let span = tracing::Span::current();
+            $(span.record(stringify!($field), $value);)+
+        }
+    };
+}
+
+pub(crate) use record;
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::changes::ChangeKind;
+use crate::codec::Codec;
+use crate::error::DbError;
+use crate::key::record_key;
+use crate::record;
+use crate::schema::{self, CompileTimeSchema, PrimaryKey, Schema};
+use crate::storage::StorageOp;
+use crate::unique::{unique_of, UniqueClaims, UniqueOf, UniqueValues};
+use crate::Database;
+
+/// A group of writes that apply all together or not at all, created by
+/// [`Database::transaction`].
+///
+/// Operations are only buffered until [`Transaction::commit`], so nothing is
+/// visible to readers before then. Dropping the transaction, or calling
+/// [`Transaction::rollback`], discards them.
+///
+/// ```no_run
+/// # async fn run(db: &rust_db::Database) -> Result<(), rust_db::DbError> {
+/// # #[derive(rust_db::Schema, serde::Serialize, serde::Deserialize)]
+/// # #[table_name = "User"]
+/// # struct User { #[primary_key] id: u64 }
+/// let mut tx = db.transaction();
+/// tx.insert(&User { id: 1 })?;
+/// tx.delete::<User>("2");
+/// tx.commit().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Transaction<'a> {
+    db: &'a Database,
+    ops: Vec<PendingOp>,
+}
+
+enum PendingOp {
+    Insert {
+        table: &'static str,
+        schema_version: u32,
+        primary_key: Vec<u8>,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        unique: UniqueValues,
+        unique_of: UniqueOf,
+        to_json: fn(Codec, &[u8]) -> Result<Value, DbError>,
+    },
+    Delete {
+        table: &'static str,
+        primary_key: Vec<u8>,
+    },
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(db: &'a Database) -> Self {
+        Transaction { db, ops: Vec::new() }
+    }
+
+    /// Buffer an insert. The record is validated now, so an invalid one is
+    /// rejected here rather than failing the commit. An `#[auto_increment]`
+    /// id is assigned now too, and isn't reused if the transaction is
+    /// rolled back.
+    pub fn insert<T>(&mut self, item: &T) -> Result<(), DbError>
+    where
+        T: Schema + CompileTimeSchema + PrimaryKey + Serialize + DeserializeOwned,
+    {
+        schema::validate(item)?;
+        let assigned = self.db.assign_id(item)?;
+        let item = assigned.as_ref().unwrap_or(item);
+        let value = record::encode(self.db.codec, item, None)?;
+
+        self.ops.push(PendingOp::Insert {
+            table: T::table_name(),
+            schema_version: T::SCHEMA_VERSION,
+            primary_key: item.primary_key(),
+            key: item.storage_key(),
+            value,
+            unique: item.unique_values(),
+            unique_of: unique_of::<T>,
+            to_json: record_json::<T>,
+        });
+        Ok(())
+    }
+
+    /// Buffer a delete. A key no record of `T` could have is ignored.
+    pub fn delete<T>(&mut self, key: &str)
+    where
+        T: Schema + PrimaryKey,
+    {
+        let Some(primary_key) = T::parse_key(key) else {
+            return;
+        };
+        self.ops.push(PendingOp::Delete {
+            table: T::table_name(),
+            primary_key,
+        });
+    }
+
+    /// Apply every buffered operation atomically.
+    ///
+    /// `#[unique]` values are checked against the database as it stands
+    /// once earlier operations in the transaction are taken into account;
+    /// any violation aborts the whole transaction.
+    pub async fn commit(self) -> Result<(), DbError> {
+        if self.ops.is_empty() {
+            return Ok(());
+        }
+
+        let storage = self.db.write_storage().await?;
+        let mut claims = UniqueClaims::new(self.db.codec);
+        let mut writes = Vec::with_capacity(self.ops.len());
+        // Whether each record key holds a record, as of the ops so far, and
+        // whether each delete removed one
+        let mut present = HashMap::new();
+        let mut removed = Vec::new();
+        for op in &self.ops {
+            match op {
+                PendingOp::Insert { table, primary_key, key, value, unique, unique_of, .. } => {
+                    claims.claim(&storage, table, primary_key, unique.clone(), *unique_of)?;
+                    present.insert(key.clone(), true);
+                    writes.push(StorageOp::Insert(key.clone(), value.clone()));
+                }
+                PendingOp::Delete { table, primary_key } => {
+                    claims.release(table, primary_key);
+                    let key = record_key(table, primary_key);
+                    removed.push(match present.insert(key.clone(), false) {
+                        Some(held) => held,
+                        None => storage.contains(&key)?,
+                    });
+                    writes.push(StorageOp::Delete(key));
+                }
+            }
+        }
+        writes.extend(claims.into_entries().map(|(key, value)| StorageOp::Insert(key, value)));
+        for op in &self.ops {
+            if let PendingOp::Insert { table, schema_version, .. } = op {
+                self.db.note_table(table, *schema_version)?;
+            }
+        }
+        self.db.write_with_meta(&storage, writes)?;
+
+        let mut indexes = self.db.indexes.write().await;
+        let mut removed = removed.into_iter();
+        for op in &self.ops {
+            match op {
+                PendingOp::Insert { table, primary_key, value, to_json, .. } => {
+                    if indexes.is_indexed(table) {
+                        indexes.insert_json(table, primary_key, &to_json(self.db.codec, value)?);
+                    }
+                    self.db.changes.publish(table, primary_key, ChangeKind::Insert);
+                }
+                PendingOp::Delete { table, primary_key } => {
+                    if removed.next() == Some(true) {
+                        indexes.remove(table, primary_key);
+                        self.db.changes.publish(table, primary_key, ChangeKind::Delete);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Discard every buffered operation. Same as dropping the transaction.
+    pub fn rollback(self) {}
+}
+
+// Stored record of `T` as JSON, for secondary indexing at commit
+fn record_json<T>(codec: Codec, data: &[u8]) -> Result<Value, DbError>
+where
+    T: Schema + CompileTimeSchema + Serialize + DeserializeOwned,
+{
+    let record = record::decode::<T>(codec, data)?;
+    serde_json::to_value(&record).map_err(|e| DbError::SerializationError(e.to_string()))
+}
@@ -0,0 +1,135 @@
+//! Enforcement of `#[unique]` fields.
+//!
+//! Each claimed value is stored as an ordinary entry, `<table>#<field>:<value>`
+//! -> primary key of the owning record. Entries aren't removed when their
+//! record is deleted or overwritten, so an existing entry only counts as
+//! taken if its record still holds the value.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::de::DeserializeOwned;
+
+use crate::codec::Codec;
+use crate::key::{record_key, tuple_elements, unique_key};
+use crate::record;
+use crate::schema::{CompileTimeSchema, Schema};
+use crate::storage::{KeyValue, LsmStorage};
+use crate::DbError;
+
+/// Named `#[unique]` values of one record, as from `CompileTimeSchema::unique_values`
+pub(crate) type UniqueValues = Vec<(&'static str, Vec<u8>)>;
+
+/// Decodes a stored record of some table into its unique values
+pub(crate) type UniqueOf = fn(Codec, &[u8]) -> Result<UniqueValues, DbError>;
+
+pub(crate) fn unique_of<T>(codec: Codec, data: &[u8]) -> Result<UniqueValues, DbError>
+where
+    T: Schema + CompileTimeSchema + DeserializeOwned,
+{
+    let record = record::decode::<T>(codec, data)?;
+    Ok(record.unique_values())
+}
+
+// A claimed value as text for `DbError::UniqueViolation`: a composite one
+// as `(a, b)`
+fn display_value(field: &str, value: &[u8]) -> String {
+    if !field.contains(',') {
+        return String::from_utf8_lossy(value).into_owned();
+    }
+    let elements: Vec<_> = tuple_elements(value)
+        .iter()
+        .map(|element| String::from_utf8_lossy(element).into_owned())
+        .collect();
+    format!("({})", elements.join(", "))
+}
+
+/// Unique values claimed by one write batch, checked against storage and
+/// each other before anything is written.
+pub(crate) struct UniqueClaims {
+    // Codec of the stored records, for checking their current values
+    codec: Codec,
+    // Unique key -> (record key, primary key) of the claiming record
+    claimed: HashMap<Vec<u8>, (Vec<u8>, Vec<u8>)>,
+    // Record keys written or deleted by this batch. Their stored values are
+    // stale, so only `claimed` says what they hold.
+    written: HashSet<Vec<u8>>,
+}
+
+impl UniqueClaims {
+    pub(crate) fn new(codec: Codec) -> Self {
+        UniqueClaims {
+            codec,
+            claimed: HashMap::new(),
+            written: HashSet::new(),
+        }
+    }
+
+    /// Claim `values` for the record `primary_key` of `table`, failing if
+    /// another record already holds one of them.
+    pub(crate) fn claim(
+        &mut self,
+        storage: &LsmStorage,
+        table: &str,
+        primary_key: &[u8],
+        values: UniqueValues,
+        unique_of: UniqueOf,
+    ) -> Result<(), DbError> {
+        // An overwrite within the batch gives up the record's earlier values
+        self.release(table, primary_key);
+
+        for (field, value) in values {
+            let key = unique_key(table, field, &value);
+            if let Some(owner) = self.owner(storage, table, &key, field, &value, unique_of)? {
+                if owner != primary_key {
+                    return Err(DbError::UniqueViolation(field.to_string(), display_value(field, &value)));
+                }
+            }
+            self.claimed.insert(key, (record_key(table, primary_key), primary_key.to_vec()));
+        }
+        Ok(())
+    }
+
+    /// Give up every value held by the record, e.g. because it's deleted.
+    pub(crate) fn release(&mut self, table: &str, primary_key: &[u8]) {
+        let record = record_key(table, primary_key);
+        self.claimed.retain(|_, (owner, _)| *owner != record);
+        self.written.insert(record);
+    }
+
+    /// Entries to write alongside the batch's records.
+    pub(crate) fn into_entries(self) -> impl Iterator<Item = KeyValue> {
+        self.claimed
+            .into_iter()
+            .map(|(key, (_, primary_key))| (key, primary_key))
+    }
+
+    // Primary key of the record currently holding `value` in `field`
+    fn owner(
+        &self,
+        storage: &LsmStorage,
+        table: &str,
+        key: &[u8],
+        field: &str,
+        value: &[u8],
+        unique_of: UniqueOf,
+    ) -> Result<Option<Vec<u8>>, DbError> {
+        if let Some((_, owner)) = self.claimed.get(key) {
+            return Ok(Some(owner.clone()));
+        }
+        let Some(owner) = storage.get(key)? else {
+            return Ok(None);
+        };
+
+        let record = record_key(table, &owner);
+        if self.written.contains(&record) {
+            return Ok(None);
+        }
+        let Some(data) = storage.get(&record)? else {
+            return Ok(None);
+        };
+        let holds = unique_of(self.codec, &data)?
+            .into_iter()
+            .any(|(name, held)| name == field && held == value);
+        Ok(holds.then_some(owner))
+    }
+}
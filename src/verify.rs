@@ -0,0 +1,31 @@
+//! Checking a database's files for damage.
+
+use crate::error::DbError;
+use crate::storage::VerifyReport;
+use crate::Database;
+
+impl Database {
+    /// Read back every SSTable, blob file value and live WAL record, check
+    /// each checksum and decode each entry, like an `fsck` of the
+    /// database. Damage is listed in the report with where it was found,
+    /// rather than failing on the first bad entry, so the call only fails
+    /// when a file can't be read at all.
+    ///
+    /// Entries are checked as storage entries, not deserialized into
+    /// records, which would need their types. The memtables aren't
+    /// checked: they're in memory, and their writes are checked in the WAL.
+    /// Writes wait while the WAL is read, but not while the SSTables are.
+    ///
+    /// ```no_run
+    /// # async fn run(db: &rust_db::Database) -> Result<(), rust_db::DbError> {
+    /// let report = db.verify().await?;
+    /// for problem in &report.problems {
+    ///     eprintln!("{} at {}: {}", problem.file.display(), problem.offset, problem.reason);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn verify(&self) -> Result<VerifyReport, DbError> {
+        self.read_storage().await?.verify()
+    }
+}
@@ -0,0 +1,7 @@
+//! Schemas the macros must reject at compile time, with the errors they
+//! give, kept in `tests/ui`.
+
+#[test]
+fn invalid_schemas_fail_to_compile() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,106 @@
+//! Moving tables in and out as JSON and CSV.
+
+use rust_db::{Database, DbError, Schema};
+use serde::{Deserialize, Serialize};
+
+#[derive(Schema, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[table_name = "User"]
+struct User {
+    #[primary_key]
+    id: u64,
+    name: String,
+    #[validate(max = 150)]
+    age: u32,
+}
+
+fn user(id: u64) -> User {
+    User { id, name: format!("user {}", id), age: 20 + id as u32 }
+}
+
+async fn open(dir: &tempfile::TempDir) -> Result<Database, DbError> {
+    Database::builder(dir.path()).open().await
+}
+
+#[tokio::test]
+async fn exported_json_imports_into_a_fresh_database() -> Result<(), DbError> {
+    let source_dir = tempfile::tempdir()?;
+    let source = open(&source_dir).await?;
+    let users: Vec<User> = (1..=20).map(user).collect();
+    source.insert_many(&users).await?;
+    source.delete::<User>("5").await?;
+
+    let mut json = Vec::new();
+    assert_eq!(source.export_json::<User>(&mut json).await?, 19);
+
+    let target_dir = tempfile::tempdir()?;
+    let target = open(&target_dir).await?;
+    assert_eq!(target.import_json::<User>(json.as_slice()).await?, 19);
+    assert_eq!(target.all::<User>().await?, source.all::<User>().await?);
+    assert_eq!(target.get::<User>("5").await?, None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn csv_rows_load_as_records() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    // Columns go by header name, not position
+    let csv = "name,age,id\nada,36,1\ngrace,45,2\nalan,41,3\n";
+    assert_eq!(db.import_csv::<User>(csv.as_bytes()).await?, 3);
+
+    assert_eq!(db.get::<User>("2").await?, Some(User { id: 2, name: "grace".to_string(), age: 45 }));
+    let over_40 = db.query::<User>().filter(|user| user.age > 40).execute().await?;
+    assert_eq!(over_40.iter().map(|user| user.id).collect::<Vec<_>>(), vec![2, 3]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn a_bad_csv_row_names_its_line_and_loads_nothing() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+
+    let unparsable = db.import_csv::<User>("id,name,age\n1,ada,36\n2,grace,old\n".as_bytes()).await;
+    assert!(
+        matches!(&unparsable, Err(DbError::SerializationError(message)) if message.contains("line 3")),
+        "{:?}",
+        unparsable
+    );
+    let invalid = db.import_csv::<User>("id,name,age\n1,ada,36\n2,grace,45\n3,alan,200\n".as_bytes()).await;
+    assert!(
+        matches!(&invalid, Err(DbError::SchemaError(message)) if message.contains("line 4")),
+        "{:?}",
+        invalid
+    );
+    assert!(db.all::<User>().await?.is_empty());
+    Ok(())
+}
+
+rust_db::schema! {
+    table_name: "Account",
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Account {
+        #[primary_key]
+        id: u64,
+        #[serde(rename = "mail")]
+        email: String,
+    }
+}
+rust_db::impl_basic_schema!(Account, "Account");
+
+#[tokio::test]
+async fn exported_json_uses_the_renamed_field() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    let account = Account { id: 1, email: "ada@example.com".to_string() };
+    db.insert(&account).await?;
+
+    let mut json = Vec::new();
+    db.export_json::<Account>(&mut json).await?;
+    let exported: serde_json::Value = serde_json::from_slice(&json).map_err(|e| DbError::SerializationError(e.to_string()))?;
+    assert_eq!(exported, serde_json::json!([{ "id": 1, "mail": "ada@example.com" }]));
+
+    let csv = "id,mail\n2,grace@example.com\n";
+    db.import_csv::<Account>(csv.as_bytes()).await?;
+    assert_eq!(db.get::<Account>("2").await?.map(|account| account.email), Some("grace@example.com".to_string()));
+    Ok(())
+}
@@ -0,0 +1,517 @@
+//! `QueryBuilder`: filters, paging, ordering, streaming and counting.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use futures::StreamExt;
+use rust_db::{Database, DbError, IndexOp, Predicate, Schema};
+use serde::{Deserialize, Serialize};
+
+#[derive(Schema, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[table_name = "User"]
+struct User {
+    #[primary_key]
+    id: u64,
+    name: String,
+    age: u32,
+}
+
+// Records decoded so far, through `counted`
+static DECODED: AtomicUsize = AtomicUsize::new(0);
+
+fn counted<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    DECODED.fetch_add(1, Ordering::SeqCst);
+    u32::deserialize(deserializer)
+}
+
+#[derive(Schema, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[table_name = "Visit"]
+struct Visit {
+    #[primary_key]
+    id: u64,
+    #[serde(deserialize_with = "counted")]
+    page: u32,
+}
+
+static MEMBERS_DECODED: AtomicUsize = AtomicUsize::new(0);
+
+fn counted_age<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    MEMBERS_DECODED.fetch_add(1, Ordering::SeqCst);
+    u32::deserialize(deserializer)
+}
+
+#[derive(Schema, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[table_name = "Member"]
+struct Member {
+    #[primary_key]
+    id: u64,
+    #[serde(deserialize_with = "counted_age")]
+    age: u32,
+}
+
+fn user(id: u64, age: u32) -> User {
+    User { id, name: format!("user {}", id), age }
+}
+
+async fn open(dir: &tempfile::TempDir) -> Result<Database, DbError> {
+    Database::builder(dir.path()).open().await
+}
+
+#[tokio::test]
+async fn execute_filters_every_record_of_the_table() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    for (id, age) in [(1, 20), (2, 30), (3, 25), (4, 40), (5, 18)] {
+        db.insert(&user(id, age)).await?;
+    }
+    // Some in an SSTable, some still in the memtable
+    db.flush().await?;
+    db.insert(&user(6, 50)).await?;
+
+    let older = db.query::<User>().filter(|u| u.age > 25).execute().await?;
+    assert_eq!(older, vec![user(2, 30), user(4, 40), user(6, 50)]);
+    assert_eq!(db.query::<User>().execute().await?.len(), 6);
+    Ok(())
+}
+
+#[tokio::test]
+async fn offset_and_limit_page_through_matches() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    for id in 1..=30 {
+        db.insert(&user(id, 20 + id as u32)).await?;
+    }
+
+    let page = db.query::<User>().offset(25).limit(10).execute().await?;
+    assert_eq!(page.iter().map(|u| u.id).collect::<Vec<_>>(), vec![26, 27, 28, 29, 30]);
+
+    // Paging counts matches, not records scanned
+    let page = db.query::<User>().filter(|u| u.id % 2 == 0).offset(2).limit(3).execute().await?;
+    assert_eq!(page.iter().map(|u| u.id).collect::<Vec<_>>(), vec![6, 8, 10]);
+
+    assert!(db.query::<User>().offset(31).execute().await?.is_empty());
+    assert!(db.query::<User>().limit(0).execute().await?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn order_by_sorts_before_paging() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    for (id, age) in [(1, 40), (2, 18), (3, 33), (4, 18), (5, 25)] {
+        db.insert(&user(id, age)).await?;
+    }
+
+    let ids = |users: Vec<User>| users.iter().map(|u| u.id).collect::<Vec<_>>();
+    // Equal ages keep their key order
+    assert_eq!(ids(db.query::<User>().order_by(|u| u.age).execute().await?), vec![2, 4, 5, 3, 1]);
+    assert_eq!(ids(db.query::<User>().order_by(|u| u.age).desc().execute().await?), vec![1, 3, 5, 2, 4]);
+    assert_eq!(ids(db.query::<User>().order_by(|u| u.age).offset(1).limit(2).execute().await?), vec![4, 5]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn stream_yields_matches_one_at_a_time() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    for id in 1..=100 {
+        db.insert(&user(id, (id % 50) as u32)).await?;
+    }
+
+    let stream = db.query::<User>().filter(|u| u.age >= 40).stream();
+    futures::pin_mut!(stream);
+    let mut count = 0;
+    while let Some(u) = stream.next().await {
+        assert!(u?.age >= 40);
+        count += 1;
+    }
+    assert_eq!(count, 20);
+    Ok(())
+}
+
+#[tokio::test]
+async fn count_without_filters_decodes_nothing() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    let visits: Vec<Visit> = (1..=100).map(|id| Visit { id, page: (id % 10) as u32 }).collect();
+    db.insert_many(&visits).await?;
+    db.insert(&user(1, 30)).await?;
+
+    let before = DECODED.load(Ordering::SeqCst);
+    assert_eq!(db.query::<Visit>().count().await?, 100);
+    assert_eq!(db.query::<Visit>().offset(90).limit(5).count().await?, 5);
+    assert_eq!(DECODED.load(Ordering::SeqCst), before);
+
+    // A filter needs the records, but gets the same count as collecting them
+    assert_eq!(db.query::<Visit>().filter(|v| v.page == 3).count().await?, 10);
+    assert_eq!(DECODED.load(Ordering::SeqCst), before + 100);
+    Ok(())
+}
+
+#[tokio::test]
+async fn indexed_range_reads_only_the_matches() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    db.create_index::<Member>("age").await?;
+    let members: Vec<Member> = (1..=1000).map(|id| Member { id, age: (id % 100) as u32 }).collect();
+    db.insert_many(&members).await?;
+
+    let before = MEMBERS_DECODED.load(Ordering::SeqCst);
+    let scans = db.stats().await?.table_scans;
+    let indexed = db.query::<Member>().filter_indexed("age", IndexOp::Ge, 95).execute().await?;
+    let indexed_reads = MEMBERS_DECODED.load(Ordering::SeqCst) - before;
+    assert_eq!(db.stats().await?.table_scans, scans);
+
+    let before = MEMBERS_DECODED.load(Ordering::SeqCst);
+    let scanned = db.query::<Member>().filter(|m| m.age >= 95).execute().await?;
+    let scan_reads = MEMBERS_DECODED.load(Ordering::SeqCst) - before;
+    assert_eq!(db.stats().await?.table_scans, scans + 1);
+
+    assert_eq!(indexed, scanned);
+    assert_eq!(indexed.len(), 50);
+    assert_eq!(indexed_reads, 50);
+    assert_eq!(scan_reads, 1000);
+
+    // Kept current by later writes
+    db.delete::<Member>("99").await?;
+    db.insert(&Member { id: 1001, age: 97 }).await?;
+    let ids: Vec<u64> = db.query::<Member>().filter_indexed("age", IndexOp::Eq, 99).execute().await?.iter().map(|m| m.id).collect();
+    assert_eq!(ids.len(), 9);
+    assert!(!ids.contains(&99));
+    assert_eq!(db.query::<Member>().filter_indexed("age", IndexOp::Eq, 97).count().await?, 11);
+    Ok(())
+}
+
+#[tokio::test]
+async fn scan_returns_a_key_range_in_order() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    for id in 1..100 {
+        db.insert(&user(id, 20)).await?;
+    }
+    db.flush().await?;
+    // Newer than the flushed copy, so it's the one the range returns
+    db.insert(&user(15, 99)).await?;
+
+    let ids = |users: Vec<User>| users.iter().map(|u| u.id).collect::<Vec<_>>();
+    let range = db.scan::<User, u64>(10..20).await?;
+    assert_eq!(ids(range.clone()), (10..20).collect::<Vec<_>>());
+    assert_eq!(range[5], user(15, 99));
+
+    assert_eq!(ids(db.scan::<User, u64>(10..=20).await?), (10..=20).collect::<Vec<_>>());
+    assert_eq!(ids(db.scan::<User, u64>(95..).await?), vec![95, 96, 97, 98, 99]);
+    assert_eq!(ids(db.scan::<User, u64>(..3).await?), vec![1, 2]);
+    assert_eq!(db.scan::<User, u64>(..).await?.len(), 99);
+    Ok(())
+}
+
+#[derive(Schema, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[table_name = "Event"]
+struct Event {
+    #[primary_key]
+    key: String,
+    count: u32,
+}
+
+fn event(key: &str) -> Event {
+    Event { key: key.to_string(), count: 1 }
+}
+
+#[tokio::test]
+async fn scan_prefix_returns_only_matching_keys() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    for key in ["2024:03", "2023:12", "2024:01", "2024:02", "2025:01"] {
+        db.insert(&event(key)).await?;
+    }
+    db.flush().await?;
+    db.delete::<Event>("2024:02").await?;
+
+    let found = db.scan_prefix::<Event>(b"2024:").await?;
+    assert_eq!(
+        found,
+        vec![(b"2024:01".to_vec(), event("2024:01")), (b"2024:03".to_vec(), event("2024:03"))]
+    );
+    assert!(db.scan_prefix::<Event>(b"2026:").await?.is_empty());
+    Ok(())
+}
+
+#[derive(Schema, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[table_name = "Reading"]
+struct Reading {
+    #[primary_key]
+    celsius: i32,
+}
+
+#[tokio::test]
+async fn signed_keys_scan_in_numeric_order() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    for celsius in [5, -1, 300, 0, -40, i32::MIN, 1, i32::MAX, -300] {
+        db.insert(&Reading { celsius }).await?;
+    }
+    db.flush().await?;
+    db.compact().await?;
+
+    let celsius = |readings: Vec<Reading>| readings.iter().map(|r| r.celsius).collect::<Vec<_>>();
+    assert_eq!(
+        celsius(db.all::<Reading>().await?),
+        vec![i32::MIN, -300, -40, -1, 0, 1, 5, 300, i32::MAX]
+    );
+    assert_eq!(celsius(db.scan::<Reading, i32>(-100..10).await?), vec![-40, -1, 0, 1, 5]);
+    Ok(())
+}
+
+static LOGINS_DECODED: AtomicUsize = AtomicUsize::new(0);
+
+fn counted_day<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    LOGINS_DECODED.fetch_add(1, Ordering::SeqCst);
+    u32::deserialize(deserializer)
+}
+
+#[derive(Schema, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[table_name = "Login"]
+struct Login {
+    #[primary_key]
+    id: u64,
+    #[serde(deserialize_with = "counted_day")]
+    day: u32,
+}
+
+#[tokio::test]
+async fn first_stops_at_the_first_match_and_one_wants_exactly_one() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    let logins: Vec<Login> = (1..=100).map(|id| Login { id, day: (id % 10) as u32 }).collect();
+    db.insert_many(&logins).await?;
+
+    let before = LOGINS_DECODED.load(Ordering::SeqCst);
+    let first = db.query::<Login>().filter(|login| login.day == 3).first().await?;
+    assert_eq!(first, Some(Login { id: 3, day: 3 }));
+    assert_eq!(LOGINS_DECODED.load(Ordering::SeqCst), before + 3);
+    // Ordered, the first is by the ordering, not the key
+    let latest = db.query::<Login>().filter(|login| login.day == 3).order_by(|login| login.id).desc().first().await?;
+    assert_eq!(latest, Some(Login { id: 93, day: 3 }));
+
+    assert_eq!(db.query::<Login>().filter(|login| login.id == 42).one().await?, Login { id: 42, day: 2 });
+    let ambiguous = db.query::<Login>().filter(|login| login.day == 3).one().await;
+    assert!(matches!(&ambiguous, Err(DbError::MultipleResults { table }) if table == "Login"), "{:?}", ambiguous);
+    // A limit of one doesn't hide the second match
+    let limited = db.query::<Login>().filter(|login| login.day == 3).limit(1).one().await;
+    assert!(matches!(limited, Err(DbError::MultipleResults { .. })), "{:?}", limited);
+    let none = db.query::<Login>().filter(|login| login.day == 10).one().await;
+    assert!(matches!(none, Err(DbError::NotFound { .. })), "{:?}", none);
+    Ok(())
+}
+
+#[tokio::test]
+async fn filter_field_goes_through_the_index() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    db.create_index::<User>("age").await?;
+    for id in 1..=100 {
+        db.insert(&user(id, (id % 50) as u32)).await?;
+    }
+
+    let scans = db.stats().await?.table_scans;
+    let over_45 = db.query::<User>().filter_field(User::AGE, IndexOp::Gt, 45).execute().await?;
+    assert_eq!(db.stats().await?.table_scans, scans);
+    assert_eq!(over_45.len(), 8);
+    assert!(over_45.iter().all(|u| u.age > 45));
+
+    // Combined with a closure, which still needs no scan
+    let odd = db.query::<User>().filter_field(User::AGE, IndexOp::Gt, 45).filter(|u| u.id % 2 == 1).execute().await?;
+    assert_eq!(odd.iter().map(|u| u.id).collect::<Vec<_>>(), vec![47, 49, 97, 99]);
+    assert_eq!(db.stats().await?.table_scans, scans);
+
+    // A closure alone scans the table, with the same answer
+    let scanned = db.query::<User>().filter(|u| u.age > 45).execute().await?;
+    assert_eq!(db.stats().await?.table_scans, scans + 1);
+    assert_eq!(scanned, over_45);
+    Ok(())
+}
+
+#[cfg(feature = "parallel")]
+#[tokio::test]
+async fn parallel_filtering_matches_the_sequential_results() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    let users: Vec<User> = (1..=2000).map(|id| user(id, (id * 7919 % 97) as u32)).collect();
+    db.insert_many(&users).await?;
+    db.flush().await?;
+    // Newer than the flushed copies
+    for id in (1..=2000).step_by(3) {
+        db.insert(&user(id, 200)).await?;
+    }
+
+    let unordered = || db.query::<User>().filter(|u| u.age % 3 == 0 || u.age == 200);
+    assert_eq!(unordered().parallel().execute().await?, unordered().execute().await?);
+    assert_eq!(
+        unordered().offset(50).limit(100).parallel().execute().await?,
+        unordered().offset(50).limit(100).execute().await?
+    );
+
+    // Ties in age keep key order either way
+    let ordered = || unordered().order_by(|u| u.age).desc().offset(10).limit(500);
+    let parallel = ordered().parallel().execute().await?;
+    assert_eq!(parallel, ordered().execute().await?);
+    assert_eq!(parallel.len(), 500);
+    Ok(())
+}
+
+static SUBSCRIBERS_DECODED: AtomicUsize = AtomicUsize::new(0);
+
+fn counted_email<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    SUBSCRIBERS_DECODED.fetch_add(1, Ordering::SeqCst);
+    String::deserialize(deserializer)
+}
+
+#[derive(Schema, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[table_name = "Subscriber"]
+struct Subscriber {
+    #[primary_key]
+    id: u64,
+    #[serde(deserialize_with = "counted_email")]
+    email: String,
+}
+
+fn email(id: u64) -> String {
+    format!("user{}@example.com", id)
+}
+
+#[tokio::test]
+async fn a_hash_index_lookup_reads_one_record() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    let subscribers: Vec<Subscriber> = (0..5000).map(|id| Subscriber { id, email: email(id) }).collect();
+    db.insert_many(&subscribers).await?;
+    db.create_index_with::<Subscriber>("email", rust_db::IndexKind::Hash).await?;
+
+    let scans = db.stats().await?.table_scans;
+    let before = SUBSCRIBERS_DECODED.load(Ordering::SeqCst);
+    for id in (0..5000).step_by(500) {
+        let found = db.query::<Subscriber>().filter_field(Subscriber::EMAIL, IndexOp::Eq, email(id)).one().await?;
+        assert_eq!(found.id, id);
+    }
+    assert_eq!(SUBSCRIBERS_DECODED.load(Ordering::SeqCst) - before, 10);
+    assert_eq!(db.stats().await?.table_scans, scans);
+
+    // The same lookup as a closure reads the whole table
+    let before = SUBSCRIBERS_DECODED.load(Ordering::SeqCst);
+    let wanted = email(2500);
+    let scanned = db.query::<Subscriber>().filter(move |s| s.email == wanted).one().await?;
+    assert_eq!(scanned.id, 2500);
+    assert_eq!(SUBSCRIBERS_DECODED.load(Ordering::SeqCst) - before, 5000);
+    assert_eq!(db.stats().await?.table_scans, scans + 1);
+    Ok(())
+}
+
+#[derive(Schema, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[table_name = "Product"]
+struct Product {
+    #[primary_key]
+    id: u64,
+    #[index]
+    category: String,
+}
+
+#[tokio::test]
+async fn declared_indexes_are_built_on_open() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let product = |id: u64| Product { id, category: ["books", "games", "tools"][id as usize % 3].to_string() };
+    {
+        let db = open(&dir).await?;
+        for id in 0..30 {
+            db.insert(&product(id)).await?;
+        }
+    }
+
+    // Indexes aren't stored, so the reopen rebuilds it from the records
+    let db = Database::builder(dir.path()).register::<Product>().open().await?;
+    let scans = db.stats().await?.table_scans;
+    let games = db.query::<Product>().filter_indexed("category", IndexOp::Eq, "games").execute().await?;
+    assert_eq!(db.stats().await?.table_scans, scans);
+    assert_eq!(games, (0..30).filter(|id| id % 3 == 1).map(product).collect::<Vec<_>>());
+    Ok(())
+}
+
+#[derive(Schema, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[table_name = "Recipient"]
+struct Recipient {
+    #[primary_key]
+    id: u64,
+    email: String,
+    age: u32,
+}
+
+#[tokio::test]
+async fn select_returns_only_the_projected_fields() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    let recipients: Vec<Recipient> =
+        (0..20).map(|id| Recipient { id, email: email(id), age: 10 + id as u32 }).collect();
+    db.insert_many(&recipients).await?;
+
+    let contacts: Vec<(u64, String)> = db
+        .query::<Recipient>()
+        .filter(|r| r.age >= 25)
+        .order_by(|r| r.id)
+        .desc()
+        .limit(3)
+        .select(|r| (r.id, r.email))
+        .await?;
+    assert_eq!(contacts, vec![(19, email(19)), (18, email(18)), (17, email(17))]);
+    Ok(())
+}
+
+#[derive(Schema, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[table_name = "Customer"]
+struct Customer {
+    #[primary_key]
+    id: u64,
+    age: u32,
+    country: String,
+    vip: bool,
+}
+
+#[tokio::test]
+async fn or_keeps_either_match_and_skips_its_closure_once_decided() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    let customers = vec![
+        Customer { id: 1, age: 30, country: "US".to_string(), vip: false },
+        Customer { id: 2, age: 30, country: "NO".to_string(), vip: true },
+        Customer { id: 3, age: 20, country: "US".to_string(), vip: false },
+        Customer { id: 4, age: 40, country: "US".to_string(), vip: true },
+        Customer { id: 5, age: 20, country: "NO".to_string(), vip: false },
+    ];
+    db.insert_many(&customers).await?;
+
+    // (age > 25 AND country == "US") OR vip
+    let vip_checks = Arc::new(AtomicUsize::new(0));
+    let checks = Arc::clone(&vip_checks);
+    let found = db
+        .query::<Customer>()
+        .filter(|c| c.age > 25)
+        .and(|c| c.country == "US")
+        .or(move |c| {
+            checks.fetch_add(1, Ordering::SeqCst);
+            c.vip
+        })
+        .execute()
+        .await?;
+    assert_eq!(found.iter().map(|c| c.id).collect::<Vec<_>>(), vec![1, 2, 4]);
+    // Not for 1 and 4, which the first group already kept
+    assert_eq!(vip_checks.load(Ordering::SeqCst), 3);
+
+    // The same grouping as a `Predicate`, under one more condition
+    let adult_us = Predicate::new(|c: &Customer| c.age > 25).and(Predicate::new(|c: &Customer| c.country == "US"));
+    let found = db
+        .query::<Customer>()
+        .matching(adult_us.or(Predicate::new(|c: &Customer| c.vip)))
+        .and(|c| c.age < 35)
+        .execute()
+        .await?;
+    assert_eq!(found.iter().map(|c| c.id).collect::<Vec<_>>(), vec![1, 2]);
+    Ok(())
+}
@@ -0,0 +1,97 @@
+//! Untyped values under raw keys, and merging into them.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rust_db::{Database, DbError, Schema};
+use serde::{Deserialize, Serialize};
+
+#[derive(Schema, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[table_name = "User"]
+struct User {
+    #[primary_key]
+    id: u64,
+    name: String,
+}
+
+fn user(id: u64) -> User {
+    User { id, name: format!("user {}", id) }
+}
+
+#[tokio::test]
+async fn raw_values_round_trip_beside_typed_records() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = Database::builder(dir.path()).open().await?;
+    db.insert(&user(1)).await?;
+    db.insert(&user(2)).await?;
+
+    // Bytes no codec could read, under a key spelled like a record's
+    let record_key = user(1).storage_key();
+    let payload: Vec<u8> = (0..=255).rev().collect();
+    db.put_raw(&record_key, &payload).await?;
+    db.put_raw(b"", b"empty key").await?;
+    db.put_raw(b"config", &[]).await?;
+
+    assert_eq!(db.get_raw(&record_key).await?, Some(payload));
+    assert_eq!(db.get_raw(b"").await?, Some(b"empty key".to_vec()));
+    assert_eq!(db.get_raw(b"config").await?, Some(Vec::new()));
+    assert_eq!(db.get_raw(b"missing").await?, None);
+    assert_eq!(db.get::<User>("1").await?, Some(user(1)));
+    assert_eq!(db.all::<User>().await?, vec![user(1), user(2)]);
+
+    // Deleting either side leaves the other
+    db.delete_raw(&record_key).await?;
+    assert_eq!(db.get_raw(&record_key).await?, None);
+    assert_eq!(db.get::<User>("1").await?, Some(user(1)));
+    db.delete::<User>("2").await?;
+    db.flush().await?;
+    assert_eq!(db.get_raw(b"config").await?, Some(Vec::new()));
+    assert_eq!(db.all::<User>().await?, vec![user(1)]);
+    Ok(())
+}
+
+// Operands combined so far, by `add`
+static MERGED: AtomicUsize = AtomicUsize::new(0);
+
+fn add(existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+    MERGED.fetch_add(1, Ordering::SeqCst);
+    let read = |bytes: &[u8]| u64::from_le_bytes(bytes.try_into().unwrap());
+    (existing.map_or(0, read) + read(operand)).to_le_bytes().to_vec()
+}
+
+#[tokio::test]
+async fn merges_add_up_without_reading_the_value() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = Database::builder(dir.path()).merge_operator(add).open().await?;
+    db.pause_compaction().await?;
+    db.put_raw(b"visits", &10u64.to_le_bytes()).await?;
+    for _ in 0..5 {
+        db.merge(b"visits", &1u64.to_le_bytes()).await?;
+    }
+    db.merge(b"new", &7u64.to_le_bytes()).await?;
+
+    assert_eq!(db.get_raw(b"visits").await?, Some(15u64.to_le_bytes().to_vec()));
+    assert_eq!(db.get_raw(b"new").await?, Some(7u64.to_le_bytes().to_vec()));
+
+    // An operand in one SSTable over the value in another is combined on
+    // every read, until compaction collapses the two
+    db.flush().await?;
+    db.merge(b"visits", &100u64.to_le_bytes()).await?;
+    db.flush().await?;
+    let merged = MERGED.load(Ordering::SeqCst);
+    assert_eq!(db.get_raw(b"visits").await?, Some(115u64.to_le_bytes().to_vec()));
+    assert_eq!(MERGED.load(Ordering::SeqCst), merged + 1);
+    db.compact_now().await?;
+    let merged = MERGED.load(Ordering::SeqCst);
+    assert_eq!(db.get_raw(b"visits").await?, Some(115u64.to_le_bytes().to_vec()));
+    assert_eq!(MERGED.load(Ordering::SeqCst), merged);
+
+    // A deleted key starts over from nothing
+    db.delete_raw(b"new").await?;
+    db.merge(b"new", &2u64.to_le_bytes()).await?;
+    assert_eq!(db.get_raw(b"new").await?, Some(2u64.to_le_bytes().to_vec()));
+
+    let plain = tempfile::tempdir()?;
+    let plain = Database::builder(plain.path()).open().await?;
+    assert!(matches!(plain.merge(b"visits", b"1").await, Err(DbError::NoMergeOperator)));
+    Ok(())
+}
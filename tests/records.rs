@@ -0,0 +1,598 @@
+//! Writing and reading typed records: inserts, keys, validation and
+//! constraints.
+
+use rust_db::{Codec, Database, DbError, Schema};
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+
+#[derive(Schema, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[table_name = "User"]
+struct User {
+    #[primary_key]
+    id: u64,
+    #[validate(email)]
+    email: String,
+    #[validate(max = 150)]
+    age: u32,
+}
+
+fn user(id: u64) -> User {
+    User { id, email: format!("user{}@example.com", id), age: 30 }
+}
+
+async fn open(dir: &tempfile::TempDir) -> Result<Database, DbError> {
+    Database::builder(dir.path()).open().await
+}
+
+#[tokio::test]
+async fn insert_many_stores_every_record() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    let users: Vec<User> = (1..=1000).map(user).collect();
+    db.insert_many(&users).await?;
+
+    assert_eq!(db.all::<User>().await?, users);
+    assert_eq!(db.get_by_key::<User, _>(500u64).await?, Some(user(500)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn insert_many_writes_nothing_if_one_is_invalid() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    let mut users: Vec<User> = (1..=10).map(user).collect();
+    users[7].age = 200;
+
+    let result = db.insert_many(&users).await;
+    assert!(matches!(result, Err(DbError::SchemaError(_))), "{:?}", result);
+    assert!(db.all::<User>().await?.is_empty());
+    Ok(())
+}
+
+#[derive(Schema, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[table_name = "Tag"]
+struct Tag {
+    #[primary_key]
+    name: String,
+    uses: u32,
+}
+
+#[tokio::test]
+async fn get_by_key_takes_the_primary_key_type() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    db.insert(&user(42)).await?;
+    let tag = Tag { name: "rust".to_string(), uses: 3 };
+    db.insert(&tag).await?;
+
+    assert_eq!(db.get_by_key::<User, _>(42u64).await?, Some(user(42)));
+    assert_eq!(db.get_by_key::<User, _>(43u64).await?, None);
+    assert_eq!(db.get_by_key::<Tag, _>("rust".to_string()).await?, Some(tag.clone()));
+    assert_eq!(db.get_by_key::<Tag, _>("rust").await?, Some(tag.clone()));
+    assert_eq!(db.get_by_key::<Tag, _>("go").await?, None);
+    // The string form parses to the same key
+    assert_eq!(db.get::<User>("42").await?, Some(user(42)));
+    assert_eq!(db.get::<Tag>("rust").await?, Some(tag));
+    Ok(())
+}
+
+#[tokio::test]
+async fn table_names_lists_each_table_written_to() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    {
+        let db = open(&dir).await?;
+        assert!(db.table_names().await.is_empty());
+        db.insert(&user(1)).await?;
+        db.insert(&Tag { name: "rust".to_string(), uses: 3 }).await?;
+        db.insert(&user(2)).await?;
+        db.put_raw(b"Other:1", b"raw").await?;
+        assert_eq!(db.table_names().await, vec!["Tag".to_string(), "User".to_string()]);
+        db.delete::<Tag>("rust").await?;
+    }
+
+    let db = open(&dir).await?;
+    assert_eq!(db.table_names().await, vec!["Tag".to_string(), "User".to_string()]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn get_many_answers_in_the_order_asked() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    for id in 1..=5 {
+        db.insert(&user(id)).await?;
+    }
+    db.flush().await?;
+    for id in 6..=10 {
+        db.insert(&user(id)).await?;
+    }
+    db.delete::<User>("4").await?;
+
+    let users = db.get_many::<User>(&["9", "404", "1", "4", "not a number", "9", "5"]).await?;
+    assert_eq!(users, vec![Some(user(9)), None, Some(user(1)), None, None, Some(user(9)), Some(user(5))]);
+    assert!(db.get_many::<User>(&[]).await?.is_empty());
+    Ok(())
+}
+
+#[derive(Schema, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[table_name = "Account"]
+struct Account {
+    #[primary_key]
+    id: u64,
+    #[unique]
+    email: String,
+}
+
+fn account(id: u64, email: &str) -> Account {
+    Account { id, email: email.to_string() }
+}
+
+#[tokio::test]
+async fn unique_field_rejects_a_repeated_value() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    db.insert(&account(1, "ada@example.com")).await?;
+
+    let duplicate = db.insert(&account(2, "ada@example.com")).await;
+    assert!(
+        matches!(&duplicate, Err(DbError::UniqueViolation(field, value)) if field == "email" && value.contains("ada@example.com")),
+        "{:?}",
+        duplicate
+    );
+    assert_eq!(db.get::<Account>("2").await?, None);
+    db.insert(&account(2, "grace@example.com")).await?;
+
+    // Still enforced once the claims are on disk
+    db.flush().await?;
+    assert!(db.insert(&account(3, "grace@example.com")).await.is_err());
+    // Deleting the owner frees its value
+    db.delete::<Account>("1").await?;
+    db.insert(&account(3, "ada@example.com")).await?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_inserts_claim_a_unique_value_once() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = Arc::new(open(&dir).await?);
+
+    for round in 0..20u64 {
+        let email = format!("round{}@example.com", round);
+        let inserts: Vec<_> = (0..4)
+            .map(|writer| {
+                let db = Arc::clone(&db);
+                let account = account(round * 4 + writer, &email);
+                tokio::spawn(async move { db.insert(&account).await })
+            })
+            .collect();
+        let mut inserted = 0;
+        for insert in inserts {
+            match insert.await.unwrap() {
+                Ok(_) => inserted += 1,
+                Err(DbError::UniqueViolation(..)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        assert_eq!(inserted, 1, "{} was claimed {} times", email, inserted);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn update_rewrites_a_stored_record() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    db.insert(&user(1)).await?;
+
+    assert!(db.update::<User, _>("1", |user| user.age = 31).await?);
+    assert_eq!(db.get::<User>("1").await?.map(|user| user.age), Some(31));
+    assert!(!db.update::<User, _>("2", |user| user.age = 31).await?);
+    assert_eq!(db.get::<User>("2").await?, None);
+
+    // Validated like an insert, and left alone if it fails
+    let invalid = db.update::<User, _>("1", |user| user.age = 200).await;
+    assert!(matches!(invalid, Err(DbError::SchemaError(_))), "{:?}", invalid);
+    assert_eq!(db.get::<User>("1").await?.map(|user| user.age), Some(31));
+    Ok(())
+}
+
+#[tokio::test]
+async fn exists_follows_inserts_and_deletes() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    db.insert(&user(1)).await?;
+    db.insert(&user(2)).await?;
+    assert!(db.exists::<User>("1").await?);
+
+    // A tombstone in the memtable hides the value in the SSTable
+    db.flush().await?;
+    db.delete::<User>("1").await?;
+    assert!(!db.exists::<User>("1").await?);
+    assert!(db.exists::<User>("2").await?);
+    assert!(!db.exists::<User>("3").await?);
+    assert!(!db.exists::<User>("not a number").await?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn all_returns_one_table_only() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    for id in 1..=3 {
+        db.insert(&user(id)).await?;
+    }
+    db.insert(&Tag { name: "rust".to_string(), uses: 1 }).await?;
+    db.flush().await?;
+    // Newer than the flushed copies, so these win
+    db.insert(&User { age: 40, ..user(2) }).await?;
+    db.delete::<User>("3").await?;
+    db.insert(&Tag { name: "go".to_string(), uses: 2 }).await?;
+
+    assert_eq!(db.all::<User>().await?, vec![user(1), User { age: 40, ..user(2) }]);
+    assert_eq!(db.all::<Tag>().await?.len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn a_record_is_gone_once_its_ttl_passes() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    db.insert_with_ttl(&user(1), std::time::Duration::from_millis(50)).await?;
+    db.insert(&user(2)).await?;
+    assert_eq!(db.get::<User>("1").await?, Some(user(1)));
+
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert_eq!(db.get::<User>("1").await?, None);
+    assert_eq!(db.all::<User>().await?, vec![user(2)]);
+    assert_eq!(db.query::<User>().count().await?, 1);
+
+    // Compaction leaves it out of the files it writes
+    db.flush().await?;
+    db.compact_now().await?;
+    let stats = db.stats().await?;
+    let mut records = 0;
+    for name in std::fs::read_dir(dir.path())? {
+        let name = name?.file_name().to_string_lossy().into_owned();
+        let Ok(scan) = db.scan_sstable(&name).await else { continue };
+        for entry in scan {
+            records += entry?.0.starts_with(b"User:") as usize;
+        }
+    }
+    assert_eq!(stats.sstables, 1);
+    assert_eq!(records, 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn get_or_err_names_the_missing_record() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    db.insert(&user(1)).await?;
+
+    assert_eq!(db.get_or_err::<User>("1").await?, user(1));
+    let missing = db.get_or_err::<User>("2").await;
+    assert!(
+        matches!(&missing, Err(DbError::NotFound { table, key }) if table == "User" && key == "2"),
+        "{:?}",
+        missing
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn the_json_codec_is_kept_across_a_reopen() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    {
+        let db = Database::builder(dir.path()).codec(Codec::Json).open().await?;
+        db.insert_many(&[user(1), user(2)]).await?;
+        db.flush().await?;
+        db.insert(&user(3)).await?;
+    }
+    // JSON spells out field names, which bincode leaves out
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir.path())? {
+        files.extend(std::fs::read(entry?.path())?);
+    }
+    assert!(files.windows(7).any(|window| window == b"\"email\""));
+
+    // Without a codec the recorded one is used, and a different one is refused
+    let db = Database::builder(dir.path()).open().await?;
+    assert_eq!(db.all::<User>().await?, vec![user(1), user(2), user(3)]);
+    drop(db);
+    let bincode = Database::builder(dir.path()).codec(Codec::Bincode).open().await;
+    assert!(matches!(bincode, Err(DbError::StorageError(_))), "{:?}", bincode.err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn insert_returns_the_storage_key() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+
+    let key = db.insert(&user(258)).await?;
+    let mut expected = b"User:".to_vec();
+    expected.extend_from_slice(&258u64.to_be_bytes());
+    assert_eq!(key, expected);
+    assert_eq!(db.insert(&Tag { name: "rust".to_string(), uses: 1 }).await?, b"Tag:rust");
+    Ok(())
+}
+
+#[tokio::test]
+async fn storage_key_follows_each_schemas_key_field() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    let user = user(258);
+    let tag = Tag { name: "rust".to_string(), uses: 1 };
+
+    assert_eq!(user.storage_key(), b"User:\0\0\0\0\0\0\x01\x02");
+    assert_eq!(tag.storage_key(), b"Tag:rust");
+    // The other fields aren't part of it
+    assert_eq!(Tag { uses: 9, ..tag.clone() }.storage_key(), tag.storage_key());
+    assert_eq!(db.insert(&user).await?, user.storage_key());
+    assert_eq!(db.insert(&tag).await?, tag.storage_key());
+    Ok(())
+}
+
+#[derive(Schema, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[table_name = "Ticket"]
+struct Ticket {
+    #[primary_key]
+    #[auto_increment]
+    id: u64,
+    title: String,
+}
+
+fn ticket(title: &str) -> Ticket {
+    Ticket { id: 0, title: title.to_string() }
+}
+
+#[tokio::test]
+async fn auto_increment_assigns_one_two_three() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    let mut keys = Vec::new();
+    for title in ["a", "b", "c"] {
+        keys.push(db.insert(&ticket(title)).await?);
+    }
+
+    let ids: Vec<u64> = db.all::<Ticket>().await?.iter().map(|ticket| ticket.id).collect();
+    assert_eq!(ids, vec![1, 2, 3]);
+    assert_eq!(keys[2], [b"Ticket:".as_slice(), &3u64.to_be_bytes()].concat());
+    assert_eq!(db.get::<Ticket>("2").await?, Some(Ticket { id: 2, title: "b".to_string() }));
+    Ok(())
+}
+
+#[tokio::test]
+async fn auto_increment_carries_on_after_a_reopen() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let ids = |tickets: Vec<Ticket>| tickets.iter().map(|ticket| ticket.id).collect::<Vec<_>>();
+    {
+        let db = open(&dir).await?;
+        for title in ["a", "b", "c"] {
+            db.insert(&ticket(title)).await?;
+        }
+        db.close().await?;
+    }
+    {
+        // From the WAL this time, with an id chosen by hand to skip past
+        let db = open(&dir).await?;
+        db.insert(&ticket("d")).await?;
+        db.insert(&Ticket { id: 10, title: "e".to_string() }).await?;
+    }
+
+    let db = open(&dir).await?;
+    db.insert(&ticket("f")).await?;
+    assert_eq!(ids(db.all::<Ticket>().await?), vec![1, 2, 3, 4, 10, 11]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn concurrent_inserts_get_distinct_ids() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = Arc::new(open(&dir).await?);
+    let tasks: Vec<_> = (0..8)
+        .map(|task| {
+            let db = Arc::clone(&db);
+            tokio::spawn(async move {
+                let mut keys = Vec::new();
+                for i in 0..25 {
+                    keys.push(db.insert(&ticket(&format!("{} {}", task, i))).await?);
+                }
+                Ok::<_, DbError>(keys)
+            })
+        })
+        .collect();
+    for task in tasks {
+        let keys = task.await.expect("insert task")?;
+        // Each task sees its own ids go up
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    let ids: Vec<u64> = db.all::<Ticket>().await?.iter().map(|ticket| ticket.id).collect();
+    assert_eq!(ids, (1..=200).collect::<Vec<_>>());
+    Ok(())
+}
+
+#[tokio::test]
+async fn update_moves_updated_at_past_created_at() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    db.insert(&user(1)).await?;
+    let (_, inserted) = db.get_with_meta::<User>("1").await?.expect("inserted");
+    let created_at = inserted.created_at.expect("created_at");
+    assert_eq!(inserted.updated_at, Some(created_at));
+
+    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    db.update::<User, _>("1", |user| user.age = 31).await?;
+    let (updated, meta) = db.get_with_meta::<User>("1").await?.expect("updated");
+    assert_eq!(updated.age, 31);
+    assert_eq!(meta.created_at, Some(created_at));
+    assert!(meta.updated_at.expect("updated_at") > created_at, "{:?}", meta);
+    Ok(())
+}
+
+#[tokio::test]
+async fn drop_table_empties_only_that_table() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    for id in 1..=10 {
+        db.insert(&user(id)).await?;
+    }
+    db.insert(&account(1, "ada@example.com")).await?;
+    db.flush().await?;
+    db.insert(&user(11)).await?;
+    db.insert(&Tag { name: "rust".to_string(), uses: 1 }).await?;
+
+    db.drop_table::<User>().await?;
+    db.drop_table::<Account>().await?;
+    assert!(db.all::<User>().await?.is_empty());
+    assert_eq!(db.get::<User>("11").await?, None);
+    assert_eq!(db.all::<Tag>().await?.len(), 1);
+    // Its unique claims went with it
+    db.insert(&account(2, "ada@example.com")).await?;
+
+    // The table can be written again afterwards
+    db.insert(&user(3)).await?;
+    assert_eq!(db.all::<User>().await?, vec![user(3)]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn truncate_deletes_sstables_of_only_that_table() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    db.pause_compaction().await?;
+    // The table's first write brings its metadata along; the later flush
+    // holds nothing but records
+    db.insert(&user(1)).await?;
+    db.insert(&Tag { name: "rust".to_string(), uses: 1 }).await?;
+    db.flush().await?;
+    for id in 2..=10 {
+        db.insert(&user(id)).await?;
+    }
+    db.flush().await?;
+    let before = db.stats().await?.sstables;
+
+    db.truncate::<User>().await?;
+    assert_eq!(db.stats().await?.sstables, before - 1);
+    assert!(db.all::<User>().await?.is_empty());
+    assert_eq!(db.all::<Tag>().await?.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn delete_where_removes_the_matches() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    let users: Vec<User> = (1..=50).map(|id| User { age: 20 + id as u32 % 40, ..user(id) }).collect();
+    db.insert_many(&users).await?;
+    db.flush().await?;
+
+    let older = users.iter().filter(|user| user.age > 40).count();
+    assert_eq!(db.delete_where::<User, _>(|user| user.age > 40).await?, older);
+    let younger: Vec<User> = users.iter().filter(|user| user.age <= 40).cloned().collect();
+    assert_eq!(db.all::<User>().await?, younger);
+
+    // A key range, without reading the records
+    assert_eq!(db.delete_range::<User, u64>(1..=10).await?, younger.iter().filter(|user| user.id <= 10).count());
+    assert!(db.all::<User>().await?.iter().all(|user| user.id > 10 && user.age <= 40));
+    Ok(())
+}
+
+#[derive(Schema, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[table_name = "Seat"]
+#[unique(tenant_id, email)]
+#[index(tenant_id, email)]
+struct Seat {
+    #[primary_key]
+    id: u64,
+    tenant_id: u64,
+    email: String,
+}
+
+fn seat(id: u64, tenant_id: u64, email: &str) -> Seat {
+    Seat { id, tenant_id, email: email.to_string() }
+}
+
+#[tokio::test]
+async fn composite_unique_rejects_only_the_repeated_pair() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    db.create_indexes::<Seat>().await?;
+    db.insert(&seat(1, 7, "ann@example.com")).await?;
+    // Either value alone may repeat
+    db.insert(&seat(2, 7, "bob@example.com")).await?;
+    db.insert(&seat(3, 8, "ann@example.com")).await?;
+
+    let duplicate = db.insert(&seat(4, 7, "ann@example.com")).await;
+    assert!(
+        matches!(&duplicate, Err(DbError::UniqueViolation(field, _)) if field == "tenant_id,email"),
+        "{:?}",
+        duplicate
+    );
+    assert_eq!(db.get::<Seat>("4").await?, None);
+
+    let ann = db
+        .query::<Seat>()
+        .filter_indexed("tenant_id,email", rust_db::IndexOp::Eq, (7u64, "ann@example.com"))
+        .execute()
+        .await?;
+    assert_eq!(ann, vec![seat(1, 7, "ann@example.com")]);
+    // Tuples order by their leading field first
+    let later_tenants = db
+        .query::<Seat>()
+        .filter_indexed("tenant_id,email", rust_db::IndexOp::Ge, (8u64, ""))
+        .execute()
+        .await?;
+    assert_eq!(later_tenants, vec![seat(3, 8, "ann@example.com")]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn upsert_reports_whether_it_created_the_record() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+
+    assert!(db.upsert(&user(1)).await?);
+    db.flush().await?;
+    // Replacing one that's only on disk still counts as existing
+    assert!(!db.upsert(&User { age: 31, ..user(1) }).await?);
+    assert_eq!(db.get::<User>("1").await?, Some(User { age: 31, ..user(1) }));
+
+    db.delete::<User>("1").await?;
+    assert!(db.upsert(&user(1)).await?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn subscribers_see_writes_in_order() -> Result<(), DbError> {
+    use rust_db::{ChangeEvent, ChangeKind};
+    use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    let mut changes = db.subscribe();
+    db.insert(&user(1)).await?;
+    // A rejected write sends nothing
+    assert!(db.insert(&User { age: 200, ..user(2) }).await.is_err());
+    db.delete::<User>("1").await?;
+    // Nor does a delete with no record to remove, alone or in a transaction
+    db.delete::<User>("1").await?;
+    db.delete::<User>("3").await?;
+    let mut tx = db.transaction();
+    tx.delete::<User>("3");
+    tx.commit().await?;
+
+    let event = |kind| ChangeEvent { table: "User".to_string(), key: 1u64.to_be_bytes().to_vec(), kind };
+    assert_eq!(changes.try_recv().ok(), Some(event(ChangeKind::Insert)));
+    assert_eq!(changes.try_recv().ok(), Some(event(ChangeKind::Delete)));
+    assert_eq!(changes.try_recv(), Err(TryRecvError::Empty));
+
+    // Writers don't wait for a subscriber that fell behind; it's told
+    let mut lagging = db.subscribe();
+    for id in 0..1100 {
+        db.insert(&user(id)).await?;
+    }
+    assert!(matches!(lagging.recv().await, Err(RecvError::Lagged(_))));
+    Ok(())
+}
@@ -0,0 +1,254 @@
+//! What `#[derive(Schema)]` and `schema!` generate.
+
+use rust_db::{CompileTimeSchema, Schema};
+use serde::{Deserialize, Serialize};
+
+#[derive(Schema, Debug, Clone, Serialize, Deserialize)]
+#[table_name = "Order"]
+struct Order {
+    #[primary_key]
+    id: u64,
+    customer: String,
+    total_cents: u64,
+}
+
+rust_db::schema! {
+    table_name: "Invoice",
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct Invoice {
+        #[primary_key]
+        id: u64,
+        order_id: u64,
+    }
+}
+rust_db::impl_basic_schema!(Invoice, "Invoice");
+
+#[test]
+fn derive_reports_table_name_and_field_count() {
+    assert_eq!(Order::TABLE_NAME, "Order");
+    assert_eq!(Order::FIELD_COUNT, 3);
+    assert_eq!(Order::table_name(), "Order");
+    // The same as the macro it stands in for
+    assert_eq!(Invoice::TABLE_NAME, "Invoice");
+    assert_eq!(Invoice::FIELD_COUNT, 2);
+}
+
+#[derive(Schema, Debug, Clone, Serialize, Deserialize)]
+#[table_name = "Signup"]
+struct Signup {
+    #[primary_key]
+    id: u64,
+    #[validate(non_empty)]
+    name: String,
+    #[validate(email)]
+    email: String,
+    #[validate(min = 13, max = 150)]
+    age: u32,
+}
+
+fn signup() -> Signup {
+    Signup { id: 1, name: "Ada".to_string(), email: "ada@example.com".to_string(), age: 36 }
+}
+
+fn rejection(signup: Signup) -> String {
+    match signup.schema_validate() {
+        Err(rust_db::SchemaError::ValidationError(message)) => message,
+        other => panic!("expected a validation error, got {:?}", other),
+    }
+}
+
+#[test]
+fn each_constraint_rejects_a_bad_value() {
+    assert!(signup().schema_validate().is_ok());
+    assert_eq!(rejection(Signup { name: String::new(), ..signup() }), "name cannot be empty");
+    assert_eq!(rejection(Signup { email: "ada.example.com".to_string(), ..signup() }), "email is not a valid email address");
+    assert_eq!(rejection(Signup { age: 12, ..signup() }), "age must be at least 13");
+    assert_eq!(rejection(Signup { age: 151, ..signup() }), "age must be at most 150");
+    // Bounds are inclusive
+    assert!(Signup { age: 13, ..signup() }.schema_validate().is_ok());
+    assert!(Signup { age: 150, ..signup() }.schema_validate().is_ok());
+    // The first failing field, in declaration order, is the one reported
+    assert_eq!(rejection(Signup { name: String::new(), age: 0, ..signup() }), "name cannot be empty");
+}
+
+#[derive(Schema, Debug, Clone, Serialize, Deserialize)]
+#[table_name = "Profile"]
+struct Profile {
+    #[primary_key]
+    id: u64,
+    #[validate(required, non_empty)]
+    display_name: Option<String>,
+    #[validate(non_empty)]
+    bio: Option<String>,
+}
+
+#[test]
+fn optional_fields_check_only_what_they_hold() {
+    let profile = |display_name: Option<&str>, bio: Option<&str>| Profile {
+        id: 1,
+        display_name: display_name.map(str::to_string),
+        bio: bio.map(str::to_string),
+    };
+    assert!(profile(Some("ada"), None).schema_validate().is_ok());
+    assert!(profile(Some("ada"), Some("mathematician")).schema_validate().is_ok());
+    assert!(matches!(
+        profile(None, Some("mathematician")).schema_validate(),
+        Err(rust_db::SchemaError::ValidationError(message)) if message == "display_name is required"
+    ));
+    // The value inside is still checked
+    assert!(profile(Some(""), None).schema_validate().is_err());
+    assert!(profile(Some("ada"), Some("")).schema_validate().is_err());
+}
+
+#[tokio::test]
+async fn insert_rejects_an_invalid_record() -> Result<(), rust_db::DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = rust_db::Database::builder(dir.path()).open().await?;
+    let result = db.insert(&Signup { age: 7, ..signup() }).await;
+    assert!(matches!(&result, Err(rust_db::DbError::SchemaError(message)) if message.contains("age")), "{:?}", result);
+    assert!(db.get::<Signup>("1").await?.is_none());
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum Status {
+    Pending,
+    Shipped,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum Delivery {
+    Pickup,
+    Courier { tracking: String },
+}
+
+#[derive(Schema, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[table_name = "Shipment"]
+struct Shipment {
+    #[primary_key]
+    id: u64,
+    #[validate(one_of(Status::Pending, Status::Shipped))]
+    status: Status,
+    delivery: Delivery,
+}
+
+rust_db::schema! {
+    table_name: "Parcel",
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Parcel {
+        #[primary_key]
+        id: u64,
+        status: Status,
+        delivery: Delivery,
+    }
+}
+rust_db::impl_basic_schema!(Parcel, "Parcel");
+
+#[tokio::test]
+async fn enum_fields_count_validate_and_read_back() -> Result<(), rust_db::DbError> {
+    assert_eq!(Shipment::FIELD_COUNT, 3);
+    assert_eq!(Parcel::FIELD_COUNT, 3);
+
+    let dir = tempfile::tempdir()?;
+    let db = rust_db::Database::builder(dir.path()).open().await?;
+    let courier = Delivery::Courier { tracking: "ZX-81".to_string() };
+    let shipment = Shipment { id: 1, status: Status::Shipped, delivery: courier.clone() };
+    let parcel = Parcel { id: 1, status: Status::Cancelled, delivery: courier };
+    db.insert(&shipment).await?;
+    db.insert(&parcel).await?;
+    assert_eq!(db.get::<Shipment>("1").await?, Some(shipment));
+    assert_eq!(db.get::<Parcel>("1").await?, Some(parcel));
+
+    let cancelled = Shipment { id: 2, status: Status::Cancelled, delivery: Delivery::Pickup };
+    let result = db.insert(&cancelled).await;
+    assert!(
+        matches!(&result, Err(rust_db::DbError::SchemaError(message)) if message.ends_with("status must be one of Status::Pending, Status::Shipped")),
+        "{:?}",
+        result
+    );
+    assert!(db.get::<Shipment>("2").await?.is_none());
+    Ok(())
+}
+
+// The same table before and after a field was added
+mod v1 {
+    use super::*;
+
+    #[derive(Schema, Debug, Clone, Serialize, Deserialize)]
+    #[table_name = "Contact"]
+    pub struct Contact {
+        #[primary_key]
+        pub id: u64,
+        pub name: String,
+    }
+}
+
+#[derive(Schema, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[table_name = "Contact"]
+#[schema_version = 2]
+#[migrate = "contact_from"]
+struct Contact {
+    #[primary_key]
+    id: u64,
+    name: String,
+    phone: String,
+}
+
+fn contact_from(old_version: u32, bytes: &[u8]) -> Result<Contact, rust_db::DbError> {
+    assert_eq!(old_version, 1);
+    let old: v1::Contact = rust_db::Codec::Bincode.deserialize(bytes)?;
+    Ok(Contact { id: old.id, name: old.name, phone: "unknown".to_string() })
+}
+
+#[tokio::test]
+async fn older_records_read_through_the_migration() -> Result<(), rust_db::DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = rust_db::Database::builder(dir.path()).open().await?;
+    db.insert(&v1::Contact { id: 1, name: "ada".to_string() }).await?;
+    db.flush().await?;
+
+    let migrated = Contact { id: 1, name: "ada".to_string(), phone: "unknown".to_string() };
+    assert_eq!(db.get::<Contact>("1").await?, Some(migrated.clone()));
+    assert_eq!(db.all::<Contact>().await?, vec![migrated]);
+
+    // Written at the new version, so read without migrating
+    let current = Contact { id: 2, name: "grace".to_string(), phone: "555-0100".to_string() };
+    db.insert(&current).await?;
+    assert_eq!(db.get::<Contact>("2").await?, Some(current));
+    Ok(())
+}
+
+// Hand-written, so nothing stops it having no fields before the insert does
+#[derive(Debug, Serialize, Deserialize)]
+struct Marker {}
+
+rust_db::impl_basic_schema!(Marker, "Marker");
+
+impl CompileTimeSchema for Marker {
+    const TABLE_NAME: &'static str = "Marker";
+    const FIELD_COUNT: usize = 0;
+}
+
+impl rust_db::PrimaryKey for Marker {
+    fn primary_key(&self) -> Vec<u8> {
+        Vec::new()
+    }
+}
+
+#[tokio::test]
+async fn insert_refuses_a_schema_with_no_fields() -> Result<(), rust_db::DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = rust_db::Database::builder(dir.path()).open().await?;
+    let keys = db.stats().await?.memtable_keys;
+
+    let result = db.insert(&Marker {}).await;
+    assert!(
+        matches!(&result, Err(rust_db::DbError::SchemaError(message)) if message == "Marker has no fields"),
+        "{:?}",
+        result
+    );
+    assert!(db.insert_many(&[Marker {}]).await.is_err());
+    assert_eq!(db.stats().await?.memtable_keys, keys);
+    Ok(())
+}
@@ -0,0 +1,113 @@
+//! Point-in-time views: snapshots, cursors and backups.
+
+use rust_db::{Database, DbError, Schema};
+use serde::{Deserialize, Serialize};
+
+#[derive(Schema, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[table_name = "Note"]
+struct Note {
+    #[primary_key]
+    id: u64,
+    text: String,
+}
+
+fn note(id: u64, text: &str) -> Note {
+    Note { id, text: text.to_string() }
+}
+
+async fn open(dir: &tempfile::TempDir) -> Result<Database, DbError> {
+    Database::builder(dir.path()).open().await
+}
+
+#[tokio::test]
+async fn snapshot_ignores_later_writes() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    db.insert(&note(1, "first")).await?;
+    db.insert(&note(2, "second")).await?;
+    let snapshot = db.snapshot().await?;
+
+    db.insert(&note(3, "third")).await?;
+    db.insert(&note(1, "rewritten")).await?;
+    db.delete::<Note>("2").await?;
+    // Moving the data into SSTables doesn't change what it sees
+    db.flush().await?;
+    db.compact().await?;
+
+    assert_eq!(snapshot.get::<Note>("3").await?, None);
+    assert_eq!(snapshot.get::<Note>("1").await?, Some(note(1, "first")));
+    assert_eq!(snapshot.all::<Note>().await?, vec![note(1, "first"), note(2, "second")]);
+    assert_eq!(snapshot.query::<Note>().count().await?, 2);
+
+    assert_eq!(db.get::<Note>("3").await?, Some(note(3, "third")));
+    assert_eq!(db.all::<Note>().await?, vec![note(1, "rewritten"), note(3, "third")]);
+    Ok(())
+}
+
+fn note_key(id: u64) -> Vec<u8> {
+    [b"Note:".as_slice(), &id.to_be_bytes()].concat()
+}
+
+#[tokio::test]
+async fn cursor_steps_across_the_memtable_and_sstables() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    // Even ids on disk, odd ones in the memtable, and 10 deleted after its flush
+    for id in (0..20).step_by(2) {
+        db.insert(&note(id, "on disk")).await?;
+    }
+    db.flush().await?;
+    for id in (1..20).step_by(2) {
+        db.insert(&note(id, "in memory")).await?;
+    }
+    db.delete::<Note>("10").await?;
+
+    let mut cursor = db.cursor().await?;
+    cursor.seek(&note_key(8));
+    let mut keys = Vec::new();
+    for entry in cursor.by_ref() {
+        let (key, _) = entry?;
+        if !key.starts_with(b"Note:") {
+            break;
+        }
+        keys.push(key);
+    }
+    let expected: Vec<_> = (8..20).filter(|&id| id != 10).map(note_key).collect();
+    assert_eq!(keys, expected);
+
+    // And back again from a key with no entry
+    cursor.seek(&note_key(10));
+    let (key, _) = cursor.prev().expect("an entry before 10")?;
+    assert_eq!(key, note_key(9));
+    let (key, _) = cursor.prev().expect("an entry before 9")?;
+    assert_eq!(key, note_key(8));
+    Ok(())
+}
+
+#[tokio::test]
+async fn a_backup_opens_with_only_the_data_before_it() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let dest = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    for id in 0..10 {
+        db.insert(&note(id, "before")).await?;
+    }
+    db.flush().await?;
+    // Some of it still in the memtable when the backup starts
+    db.insert(&note(10, "before")).await?;
+    db.delete::<Note>("0").await?;
+
+    db.backup(dest.path().join("backup")).await?;
+    db.insert(&note(11, "after")).await?;
+    db.insert(&note(1, "after")).await?;
+    db.flush().await?;
+    db.compact().await?;
+    // A second backup into the same directory would mix the two
+    assert!(db.backup(dest.path().join("backup")).await.is_err());
+
+    let backup = Database::builder(dest.path().join("backup")).open().await?;
+    let expected: Vec<_> = (1..=10).map(|id| note(id, "before")).collect();
+    assert_eq!(backup.all::<Note>().await?, expected);
+    assert_eq!(db.get::<Note>("1").await?, Some(note(1, "after")));
+    Ok(())
+}
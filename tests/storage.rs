@@ -0,0 +1,1179 @@
+//! The storage engine as `Database` drives it: flushes, the WAL, SSTables
+//! and compaction.
+
+use rust_db::{CompactionPolicy, Database, DbError, Durability, MemTableImpl, Schema, Slot, WriteBatch};
+use std::ops::Bound;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+
+#[derive(Schema, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[table_name = "Item"]
+struct Item {
+    #[primary_key]
+    id: u64,
+    name: String,
+}
+
+fn item(id: u64) -> Item {
+    Item { id, name: format!("item {}", id) }
+}
+
+// Names of the `sst-*.bin` files in `dir`, the database's or ones it no
+// longer uses alike
+fn sstable_files(dir: &std::path::Path) -> std::io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.starts_with("sst-") && name.ends_with(".bin") {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+#[tokio::test]
+async fn get_finds_flushed_keys() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = Database::builder(dir.path()).flush_threshold(1024).open().await?;
+    for id in 0..200 {
+        db.insert(&item(id)).await?;
+    }
+    // Each freeze waits out the flush before it, so all but the last are done
+    assert!(db.stats().await?.sstables > 0);
+
+    for id in 0..200 {
+        assert_eq!(db.get_by_key::<Item, _>(id).await?, Some(item(id)));
+    }
+    assert_eq!(db.get_by_key::<Item, _>(200u64).await?, None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn delete_holds_across_a_flush() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = Database::open(dir.path().to_str().unwrap()).await?;
+    db.insert(&item(1)).await?;
+    db.insert(&item(2)).await?;
+    // 1 is in an SSTable by the time it's deleted, 3 only in the memtable
+    db.flush().await?;
+    db.delete::<Item>("1").await?;
+    db.insert(&item(3)).await?;
+    db.delete::<Item>("3").await?;
+    db.flush().await?;
+
+    assert_eq!(db.get::<Item>("1").await?, None);
+    assert_eq!(db.get::<Item>("2").await?, Some(item(2)));
+    assert_eq!(db.get::<Item>("3").await?, None);
+
+    // Compaction mustn't bring the older SSTable's value back either
+    db.compact().await?;
+    assert_eq!(db.get::<Item>("1").await?, None);
+    assert_eq!(db.all::<Item>().await?, vec![item(2)]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn reopen_replays_the_wal() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    {
+        let db = Database::open(dir.path().to_str().unwrap()).await?;
+        for id in 0..50 {
+            db.insert(&item(id)).await?;
+        }
+        db.delete::<Item>("7").await?;
+        // Dropped without a flush, so everything is only in the WAL
+        assert_eq!(db.stats().await?.sstables, 0);
+    }
+
+    let db = Database::open(dir.path().to_str().unwrap()).await?;
+    let expected: Vec<Item> = (0..50).filter(|&id| id != 7).map(item).collect();
+    assert_eq!(db.all::<Item>().await?, expected);
+    Ok(())
+}
+
+#[tokio::test]
+async fn flush_shrinks_the_wal() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    {
+        let db = Database::open(dir.path().to_str().unwrap()).await?;
+        for id in 0..100 {
+            db.insert(&item(id)).await?;
+        }
+        let before = db.stats().await?.wal_bytes;
+        db.flush().await?;
+        let after = db.stats().await?.wal_bytes;
+        assert!(after < before, "WAL went from {} to {} bytes", before, after);
+
+        // Written after the flush, so only the WAL has it
+        db.insert(&item(100)).await?;
+    }
+
+    let db = Database::open(dir.path().to_str().unwrap()).await?;
+    assert_eq!(db.all::<Item>().await?, (0..=100).map(item).collect::<Vec<_>>());
+    Ok(())
+}
+
+#[tokio::test]
+async fn closed_data_is_in_sstables_without_the_wal() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = Database::open(dir.path().to_str().unwrap()).await?;
+    for id in 0..50 {
+        db.insert(&item(id)).await?;
+    }
+    db.flush().await?;
+    db.insert(&item(50)).await?;
+    db.close().await?;
+
+    // Nothing is left to replay, so losing the WAL loses nothing
+    for entry in std::fs::read_dir(dir.path())? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "log") {
+            std::fs::remove_file(path)?;
+        }
+    }
+    let db = Database::open(dir.path().to_str().unwrap()).await?;
+    assert_eq!(db.all::<Item>().await?, (0..=50).map(item).collect::<Vec<_>>());
+    assert_eq!(db.stats().await?.memtable_keys, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn the_manifest_brings_back_the_sstables() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let sstables = {
+        let db = Database::open(dir.path().to_str().unwrap()).await?;
+        db.pause_compaction().await?;
+        for id in 0..30 {
+            db.insert(&item(id)).await?;
+            if id % 10 == 9 {
+                db.flush().await?;
+            }
+        }
+        db.stats().await?.sstables
+    };
+    assert_eq!(sstables, 3);
+    assert!(dir.path().join("MANIFEST").exists());
+
+    let db = Database::open(dir.path().to_str().unwrap()).await?;
+    assert_eq!(db.stats().await?.sstables, 3);
+    assert_eq!(db.stats().await?.memtable_keys, 0);
+    assert_eq!(db.all::<Item>().await?, (0..30).map(item).collect::<Vec<_>>());
+    Ok(())
+}
+
+#[tokio::test]
+async fn sstables_are_written_into_the_database_directory() -> Result<(), DbError> {
+    let parent = tempfile::tempdir()?;
+    // Not there yet, so opening has to create it
+    let path = parent.path().join("nested").join("db");
+    let db = Database::builder(&path).open().await?;
+    db.insert(&item(1)).await?;
+    db.flush().await?;
+
+    assert_eq!(sstable_files(&path)?.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn compaction_keeps_the_newest_value_of_each_key() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = Database::open(dir.path().to_str().unwrap()).await?;
+    db.pause_compaction().await?;
+
+    // Four SSTables over the same keys, each rewriting some and deleting one
+    for round in 0..4u64 {
+        for id in round..10 {
+            db.insert(&Item { id, name: format!("round {}", round) }).await?;
+        }
+        db.delete::<Item>(&round.to_string()).await?;
+        db.flush().await?;
+    }
+    assert_eq!(db.stats().await?.sstables, 4);
+
+    let report = db.compact_now().await?;
+    assert_eq!(report.files_merged, 4);
+    assert_eq!(report.records_dropped, 4);
+    let mut live = Vec::new();
+    for name in sstable_files(dir.path())? {
+        // Files the compaction replaced are no longer the database's
+        let Ok(scan) = db.scan_sstable(&name).await else { continue };
+        for entry in scan {
+            let (key, slot) = entry?;
+            assert!(matches!(slot, Slot::Value(_)), "{:?} left in the output", slot);
+            live.push(key);
+        }
+    }
+    // One entry per key, and none for the deleted ones, plus the metadata
+    let records = live.iter().filter(|key| key.starts_with(b"Item:")).count();
+    assert_eq!(records, 6);
+
+    let expected: Vec<Item> = (4..10).map(|id| Item { id, name: "round 3".to_string() }).collect();
+    assert_eq!(db.all::<Item>().await?, expected);
+    for id in 0..4u64 {
+        assert_eq!(db.get_by_key::<Item, _>(id).await?, None);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn compact_now_reports_the_bytes_it_moved() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = Database::open(dir.path().to_str().unwrap()).await?;
+    db.pause_compaction().await?;
+    for round in 0..3u64 {
+        for id in 0..100 {
+            db.insert(&Item { id, name: format!("round {}", round) }).await?;
+        }
+        db.delete::<Item>(&round.to_string()).await?;
+        db.flush().await?;
+    }
+    let before = db.stats().await?;
+
+    let report = db.compact_now().await?;
+    let after = db.stats().await?;
+    assert_eq!(report.files_merged, 3);
+    assert_eq!(report.files_written, after.sstables);
+    assert_eq!(report.bytes_read, before.sstable_bytes);
+    assert_eq!(report.bytes_written, after.sstable_bytes);
+    assert!(report.bytes_written < report.bytes_read);
+    // Rounds 0 and 1 deleted keys the next round wrote again, so only the
+    // last tombstone is still the newest entry of its key
+    assert_eq!(report.records_dropped, 1);
+    assert_eq!(db.get::<Item>("50").await?.map(|item| item.name), Some("round 2".to_string()));
+    assert_eq!(db.get::<Item>("2").await?, None);
+
+    // With a single table left there's nothing more to merge
+    assert_eq!(db.compact_now().await?, rust_db::CompactionReport::default());
+    Ok(())
+}
+
+#[tokio::test]
+async fn metrics_count_each_operation() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = Database::open(dir.path().to_str().unwrap()).await?;
+    db.pause_compaction().await?;
+    // The first insert of a table also writes its metadata keys, so count
+    // from after it
+    db.insert(&item(0)).await?;
+    let start = db.metrics().await?;
+    let wal_start = db.stats().await?.wal_bytes;
+
+    for id in 1..10 {
+        db.insert(&item(id)).await?;
+    }
+    db.delete::<Item>("3").await?;
+    db.delete::<Item>("4").await?;
+    let wal_grew = db.stats().await?.wal_bytes - wal_start;
+    assert_eq!(db.metrics().await?.wal_bytes_written - start.wal_bytes_written, wal_grew);
+    db.flush().await?;
+    db.insert(&item(10)).await?;
+    db.flush().await?;
+    db.compact_now().await?;
+    // One block read from disk, then the same block again from the cache,
+    // and a key the bloom filter rules out without reading one
+    assert!(db.get::<Item>("5").await?.is_some());
+    assert!(db.get::<Item>("6").await?.is_some());
+    assert_eq!(db.get::<Item>("3").await?, None);
+
+    let metrics = db.metrics().await?;
+    assert_eq!(metrics.inserts - start.inserts, 10);
+    assert_eq!(metrics.deletes - start.deletes, 2);
+    // Each delete also looks its record up, to tell subscribers if it had one
+    assert_eq!(metrics.gets - start.gets, 5);
+    assert_eq!(metrics.flushes - start.flushes, 2);
+    assert_eq!(metrics.compactions - start.compactions, 1);
+    assert_eq!(metrics.block_cache_misses - start.block_cache_misses, 1);
+    assert_eq!(metrics.block_cache_hits - start.block_cache_hits, 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn a_bulk_load_flushes_less_and_keeps_every_record() -> Result<(), DbError> {
+    let items: Vec<Item> = (0..20_000).map(item).collect();
+    let naive_dir = tempfile::tempdir()?;
+    let naive = Database::builder(naive_dir.path()).flush_threshold(64 * 1024).open().await?;
+    for item in &items {
+        naive.insert(item).await?;
+    }
+    naive.flush().await?;
+
+    let bulk_dir = tempfile::tempdir()?;
+    let bulk = Database::builder(bulk_dir.path()).flush_threshold(64 * 1024).open().await?;
+    bulk.bulk_load(async |db| {
+        for chunk in items.chunks(5_000) {
+            db.insert_many(chunk).await?;
+        }
+        Ok(())
+    })
+    .await?;
+    bulk.flush().await?;
+
+    let (naive_metrics, bulk_metrics) = (naive.metrics().await?, bulk.metrics().await?);
+    assert_eq!(bulk_metrics.inserts, naive_metrics.inserts);
+    assert!(bulk_metrics.flushes * 4 < naive_metrics.flushes, "{:?} against {:?}", bulk_metrics, naive_metrics);
+    assert_eq!(bulk.all::<Item>().await?, items);
+    assert_eq!(bulk.get::<Item>("19999").await?, Some(item(19_999)));
+    Ok(())
+}
+
+// Names of the `.blob` files in `dir`, with their lengths
+fn blob_files(dir: &std::path::Path) -> std::io::Result<Vec<(String, u64)>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.ends_with(".blob") {
+            files.push((name, entry.metadata()?.len()));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[tokio::test]
+async fn large_values_live_in_blob_files() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = Database::builder(dir.path()).blob_threshold(1024).open().await?;
+    db.pause_compaction().await?;
+    let large: Vec<u8> = (0..2_000_000u32).map(|i| (i % 251) as u8).collect();
+    db.put_raw(b"large", &large).await?;
+    db.put_raw(b"small", b"inline").await?;
+    db.flush().await?;
+
+    let blobs = blob_files(dir.path())?;
+    assert_eq!(blobs.len(), 1, "{:?}", blobs);
+    assert!(blobs[0].1 >= large.len() as u64);
+    let stats = db.stats().await?;
+    assert!(stats.sstable_bytes < 8 * 1024, "{} bytes of SSTable", stats.sstable_bytes);
+    assert_eq!(db.get_raw(b"large").await?, Some(large.clone()));
+    assert_eq!(db.get_raw(b"small").await?, Some(b"inline".to_vec()));
+
+    // Merging the SSTables copies the reference, not the value
+    db.put_raw(b"other", b"value").await?;
+    db.flush().await?;
+    let sstables = db.stats().await?.sstables;
+    let report = db.compact_now().await?;
+    assert_eq!(report.files_merged, sstables);
+    assert!(report.bytes_written < 8 * 1024);
+    assert_eq!(blob_files(dir.path())?, blobs);
+    assert_eq!(db.get_raw(b"large").await?, Some(large));
+    Ok(())
+}
+
+#[tokio::test]
+async fn the_scheduler_keeps_the_sstable_count_down() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let policy = CompactionPolicy { levels: 2, max_sstables_per_level: 2, ..CompactionPolicy::default() };
+    let db = Database::builder(dir.path()).flush_threshold(1024).compaction_policy(policy).open().await?;
+    for id in 0..500 {
+        db.insert(&item(id)).await?;
+    }
+    assert!(db.metrics().await?.flushes >= 10, "{:?}", db.metrics().await?);
+
+    // The scheduler runs on its own task; wait for the count to settle
+    let mut counts = Vec::new();
+    for _ in 0..500 {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        counts.push(db.stats().await?.sstables);
+        if counts.len() >= 10 && counts[counts.len() - 10..].iter().all(|&count| count == counts[counts.len() - 1]) {
+            break;
+        }
+    }
+    // At most 2 tables in level 0, and level 1's fit in one
+    let settled = *counts.last().unwrap();
+    assert!(settled <= 3, "settled at {} SSTables: {:?}", settled, counts);
+    assert!(db.metrics().await?.compactions > 0);
+    assert_eq!(db.all::<Item>().await?, (0..500).map(item).collect::<Vec<_>>());
+    Ok(())
+}
+
+#[tokio::test]
+async fn a_flush_splits_at_the_target_file_size() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let policy = CompactionPolicy { target_file_size: 4096, ..CompactionPolicy::default() };
+    let db = Database::builder(dir.path()).compaction_policy(policy).open().await?;
+    db.pause_compaction().await?;
+    for id in 0..1000 {
+        db.insert(&item(id)).await?;
+    }
+    db.flush().await?;
+
+    let files = sstable_files(dir.path())?;
+    assert!(files.len() > 5, "{:?}", files);
+    let mut keys = Vec::new();
+    for name in &files {
+        let len = std::fs::metadata(dir.path().join(name))?.len();
+        // A record or a block over the target, at most, plus the index and filter
+        assert!(len < 3 * 4096, "{} is {} bytes", name, len);
+        for entry in db.scan_sstable(name).await? {
+            keys.push(entry?.0);
+        }
+    }
+    // Every record exactly once, with no overlap between the files
+    let records = keys.iter().filter(|key| key.starts_with(b"Item:")).count();
+    keys.sort();
+    keys.dedup();
+    assert_eq!(records, 1000);
+    assert_eq!(keys.iter().filter(|key| key.starts_with(b"Item:")).count(), 1000);
+    assert_eq!(db.all::<Item>().await?, (0..1000).map(item).collect::<Vec<_>>());
+    Ok(())
+}
+
+#[tokio::test]
+async fn scan_sstable_reads_back_exactly_what_was_flushed() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = Database::builder(dir.path()).open().await?;
+    db.pause_compaction().await?;
+    for id in [3, 1, 2] {
+        db.insert(&item(id)).await?;
+    }
+    db.put_raw(b"b", b"second").await?;
+    db.put_raw(b"a", b"first").await?;
+    db.put_raw(b"a", b"rewritten").await?;
+    db.delete_raw(b"c").await?;
+    db.flush().await?;
+
+    let files = sstable_files(dir.path())?;
+    assert_eq!(files.len(), 1, "{:?}", files);
+    let entries = db.scan_sstable(dir.path().join(&files[0])).await?.collect::<Result<Vec<_>, _>>()?;
+    // Raw keys are stored under a `!` prefix, next to the records
+    let raw: Vec<_> = entries.iter().filter(|(key, _)| key.starts_with(b"!")).cloned().collect();
+    assert_eq!(
+        raw,
+        vec![
+            (b"!a".to_vec(), Slot::Value(b"rewritten".to_vec())),
+            (b"!b".to_vec(), Slot::Value(b"second".to_vec())),
+            (b"!c".to_vec(), Slot::Tombstone),
+        ]
+    );
+    let records: Vec<_> = entries.iter().filter(|(key, _)| key.starts_with(b"Item:")).map(|(key, _)| key.clone()).collect();
+    let expected: Vec<_> = (1..=3u64).map(|id| [b"Item:".as_slice(), &id.to_be_bytes()].concat()).collect();
+    assert_eq!(records, expected);
+    assert!(entries.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+    // A damaged block fails the scan rather than reading back wrong
+    corrupt_byte(&dir.path().join(&files[0]), 10)?;
+    let damaged = match db.scan_sstable(&files[0]).await {
+        Ok(scan) => scan.collect::<Result<Vec<_>, _>>().map(|_| ()),
+        Err(e) => Err(e),
+    };
+    assert!(matches!(damaged, Err(DbError::Corruption { .. })), "{:?}", damaged);
+    Ok(())
+}
+
+#[tokio::test]
+async fn a_small_flush_threshold_flushes_sooner() -> Result<(), DbError> {
+    let small_dir = tempfile::tempdir()?;
+    let small = Database::builder(small_dir.path()).flush_threshold(256).open().await?;
+    let default_dir = tempfile::tempdir()?;
+    let default = Database::builder(default_dir.path()).open().await?;
+    for id in 0..50 {
+        small.insert(&item(id)).await?;
+        default.insert(&item(id)).await?;
+    }
+
+    assert!(small.metrics().await?.flushes >= 5, "{:?}", small.metrics().await?);
+    assert_eq!(default.metrics().await?.flushes, 0);
+    assert_eq!(default.stats().await?.sstables, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn flushes_near_the_watermarks_are_never_small() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = Database::builder(dir.path()).flush_watermarks(32 * 1024, 64 * 1024).open().await?;
+    db.pause_compaction().await?;
+    let value = vec![7; 1024];
+
+    // The memtable's size goes up and down around the low mark, as values
+    // are written over and deleted, and slowly gains
+    for round in 0..64u32 {
+        for i in 0..40 {
+            db.put_raw(format!("{}:{}", round, i).as_bytes(), &value).await?;
+        }
+        for i in 0..32 {
+            db.delete_raw(format!("{}:{}", round, i).as_bytes()).await?;
+        }
+    }
+    let sstables = db.stats().await?.sstables;
+
+    // Each flushed memtable held at least the low mark, tombstones and all
+    assert!(sstables > 0 && sstables <= 64 * 40 * 1024 / (32 * 1024), "{} SSTables", sstables);
+    let mut smallest = u64::MAX;
+    for name in sstable_files(dir.path())? {
+        smallest = smallest.min(std::fs::metadata(dir.path().join(name))?.len());
+    }
+    assert!(smallest > 8 * 1024, "an SSTable of {} bytes", smallest);
+    for round in [0, 31, 63] {
+        assert_eq!(db.get_raw(format!("{}:35", round).as_bytes()).await?, Some(value.clone()));
+        assert_eq!(db.get_raw(format!("{}:5", round).as_bytes()).await?, None);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn a_second_read_comes_from_the_block_cache() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = Database::builder(dir.path()).block_cache_size(1024 * 1024).open().await?;
+    for id in 0..100 {
+        db.insert(&item(id)).await?;
+    }
+    db.flush().await?;
+    let before = db.stats().await?;
+
+    let first = db.get::<Item>("42").await?;
+    let after_first = db.stats().await?;
+    let second = db.get::<Item>("42").await?;
+    let after_second = db.stats().await?;
+
+    assert_eq!(first, Some(item(42)));
+    assert_eq!(second, first);
+    assert_eq!(after_first.block_cache_misses, before.block_cache_misses + 1);
+    assert_eq!(after_second.block_cache_misses, after_first.block_cache_misses);
+    assert_eq!(after_second.block_cache_hits, after_first.block_cache_hits + 1);
+
+    // Compaction replaces the file, so its blocks are read afresh
+    db.compact().await?;
+    assert_eq!(db.get::<Item>("42").await?, first);
+    assert_eq!(db.stats().await?.block_cache_misses, after_second.block_cache_misses + 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn a_failed_flush_keeps_the_data_and_leaves_no_sstable() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = Database::open(dir.path().to_str().unwrap()).await?;
+    for id in 0..20 {
+        db.insert(&item(id)).await?;
+    }
+    // The manifest can't be replaced while its temporary name is taken by
+    // a directory, so the flush fails once the SSTable is written
+    let blocker = dir.path().join("MANIFEST.tmp");
+    std::fs::create_dir(&blocker)?;
+    let flush = db.flush().await;
+    assert!(matches!(flush, Err(DbError::FlushFailed { .. })), "{:?}", flush);
+
+    assert!(sstable_files(dir.path())?.is_empty(), "{:?}", sstable_files(dir.path())?);
+    assert_eq!(db.stats().await?.sstables, 0);
+    assert_eq!(db.all::<Item>().await?, (0..20).map(item).collect::<Vec<_>>());
+    assert_eq!(db.get::<Item>("7").await?, Some(item(7)));
+
+    // Once the disk is usable again, the next flush writes out both the
+    // memtable the failure left frozen and the one after it
+    std::fs::remove_dir(&blocker)?;
+    db.insert(&item(20)).await?;
+    db.flush().await?;
+    assert_eq!(sstable_files(dir.path())?.len(), 2);
+    drop(db);
+    let db = Database::open(dir.path().to_str().unwrap()).await?;
+    assert_eq!(db.all::<Item>().await?, (0..=20).map(item).collect::<Vec<_>>());
+    Ok(())
+}
+
+#[tokio::test]
+async fn a_failed_auto_flush_doesnt_fail_the_insert_that_triggered_it() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = Database::builder(dir.path()).flush_threshold(512).open().await?;
+    let mut changes = db.subscribe();
+    // Every flush fails once its SSTable is written, as above
+    let blocker = dir.path().join("MANIFEST.tmp");
+    std::fs::create_dir(&blocker)?;
+
+    // Each insert is committed once it's logged, whatever becomes of the
+    // flushes they set off, so none fails and every one is published
+    for id in 0..30 {
+        db.insert(&item(id)).await?;
+    }
+    for id in 0..30u64 {
+        assert_eq!(changes.try_recv().map(|event| event.key).ok(), Some(id.to_be_bytes().to_vec()));
+    }
+    assert_eq!(db.all::<Item>().await?, (0..30).map(item).collect::<Vec<_>>());
+    // The flush asked for is the one to report the failure
+    let flush = db.flush().await;
+    assert!(matches!(flush, Err(DbError::FlushFailed { .. })), "{:?}", flush);
+
+    std::fs::remove_dir(&blocker)?;
+    db.flush().await?;
+    drop(db);
+    let db = Database::builder(dir.path()).open().await?;
+    assert_eq!(db.all::<Item>().await?, (0..30).map(item).collect::<Vec<_>>());
+    Ok(())
+}
+
+#[tokio::test]
+async fn create_if_missing_false_needs_the_directory() -> Result<(), DbError> {
+    let parent = tempfile::tempdir()?;
+    let missing = Database::builder(parent.path().join("missing")).create_if_missing(false).open().await;
+    assert!(matches!(missing, Err(DbError::StorageError(_))), "{:?}", missing.err());
+    Ok(())
+}
+
+#[cfg(feature = "lz4")]
+#[tokio::test]
+async fn lz4_shrinks_sstables_and_reads_back_the_same() -> Result<(), DbError> {
+    use rust_db::Compression;
+
+    async fn load(dir: &std::path::Path, compression: Compression) -> Result<(Database, u64), DbError> {
+        let db = Database::builder(dir).compression(compression).open().await?;
+        for id in 0..500 {
+            db.insert(&Item { id, name: "compressible ".repeat(20) }).await?;
+        }
+        db.flush().await?;
+        let bytes = db.stats().await?.sstable_bytes;
+        Ok((db, bytes))
+    }
+    let plain_dir = tempfile::tempdir()?;
+    let (plain, plain_bytes) = load(plain_dir.path(), Compression::None).await?;
+    let lz4_dir = tempfile::tempdir()?;
+    let (lz4, lz4_bytes) = load(lz4_dir.path(), Compression::Lz4).await?;
+
+    assert!(lz4_bytes * 4 < plain_bytes, "{} bytes compressed, {} plain", lz4_bytes, plain_bytes);
+    assert_eq!(lz4.all::<Item>().await?, plain.all::<Item>().await?);
+    assert_eq!(lz4.get_by_key::<Item, _>(250u64).await?.map(|item| item.name), Some("compressible ".repeat(20)));
+    Ok(())
+}
+
+// Flip the byte at `offset` in `path`
+fn corrupt_byte(path: &std::path::Path, offset: usize) -> std::io::Result<()> {
+    let mut bytes = std::fs::read(path)?;
+    bytes[offset] ^= 0xff;
+    std::fs::write(path, bytes)
+}
+
+#[tokio::test]
+async fn a_damaged_sstable_reads_as_corruption() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    {
+        let db = Database::open(dir.path().to_str().unwrap()).await?;
+        for id in 0..500 {
+            db.insert(&item(id)).await?;
+        }
+        db.flush().await?;
+    }
+    let sstable = dir.path().join(&sstable_files(dir.path())?[0]);
+    // Inside the first data block, which starts the file. The metadata
+    // sorts after the records, into a later block, so the open still works.
+    corrupt_byte(&sstable, 8)?;
+
+    let db = Database::open(dir.path().to_str().unwrap()).await?;
+    assert_eq!(db.get::<Item>("499").await?, Some(item(499)));
+    let read = db.get::<Item>("0").await;
+    assert!(
+        matches!(&read, Err(DbError::Corruption { file, offset: 0 }) if *file == sstable),
+        "{:?}",
+        read
+    );
+    Ok(())
+}
+
+// The WAL segment in `dir`, which only has the one
+fn wal_segment(dir: &std::path::Path) -> std::io::Result<std::path::PathBuf> {
+    let mut segments = wal_segments(dir)?;
+    assert_eq!(segments.len(), 1, "{:?}", segments);
+    Ok(segments.remove(0))
+}
+
+// The `.log` files in `dir`, in name order
+fn wal_segments(dir: &std::path::Path) -> std::io::Result<Vec<std::path::PathBuf>> {
+    let mut segments = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "log") {
+            segments.push(path);
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+#[tokio::test]
+async fn verify_reports_the_one_damaged_entry() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = Database::builder(dir.path()).durability(rust_db::Durability::Sync).open().await?;
+    for id in 0..100 {
+        db.insert(&item(id)).await?;
+    }
+    db.flush().await?;
+    for id in 100..110 {
+        db.insert(&item(id)).await?;
+    }
+    let clean = db.verify().await?;
+    assert!(clean.is_ok(), "{:?}", clean.problems);
+    assert_eq!(clean.sstables, 1);
+    assert!(clean.sstable_entries >= 100);
+    assert_eq!(clean.wal_records, 10);
+
+    // The value of item 105, in the middle of the live WAL segment
+    let wal = wal_segments(dir.path())?.pop().unwrap();
+    let bytes = std::fs::read(&wal)?;
+    let at = bytes.windows(8).position(|window| window == b"item 105").unwrap();
+    corrupt_byte(&wal, at + 7)?;
+
+    let report = db.verify().await?;
+    assert_eq!(report.problems.len(), 1, "{:?}", report.problems);
+    let problem = &report.problems[0];
+    assert_eq!(problem.file, wal);
+    assert_eq!(problem.key.as_deref(), Some([b"Item:".as_slice(), &105u64.to_be_bytes()].concat().as_slice()));
+    assert_eq!(report.wal_records, 9);
+    assert_eq!((report.sstables, report.sstable_entries), (clean.sstables, clean.sstable_entries));
+    Ok(())
+}
+
+// Offset of the WAL frame whose record holds `needle`. Each frame is a u32
+// length and a u32 checksum, then the record; the segment starts with an
+// 8-byte header.
+fn frame_holding(log: &[u8], needle: &[u8]) -> usize {
+    let mut frame = 8;
+    loop {
+        let len = u32::from_le_bytes(log[frame..frame + 4].try_into().unwrap()) as usize;
+        if log[frame + 8..frame + 8 + len].windows(needle.len()).any(|window| window == needle) {
+            return frame;
+        }
+        frame += 8 + len;
+    }
+}
+
+#[tokio::test]
+async fn a_huge_length_in_the_wal_fails_the_open_cleanly() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let open = || Database::builder(dir.path()).max_record_size(1024 * 1024).open();
+    {
+        let db = open().await?;
+        db.put_raw(b"key", b"value").await?;
+        db.put_raw(b"other", b"value").await?;
+        // Nor is a record over the limit written
+        let written = db.put_raw(b"big", &vec![0; 2 * 1024 * 1024]).await;
+        assert!(matches!(written, Err(DbError::ValueTooLarge { limit: 1048576, .. })), "{:?}", written);
+    }
+    let wal = wal_segment(dir.path())?;
+    let pristine = std::fs::read(&wal)?;
+    let frame = frame_holding(&pristine, b"other");
+
+    // The frame's length claims about 4GB
+    corrupt_byte(&wal, frame + 3)?;
+    match open().await {
+        Err(DbError::ValueTooLarge { size, limit }) => assert_eq!((size >> 24, limit), (0xff, 1024 * 1024)),
+        other => panic!("expected ValueTooLarge, got {:?}", other.map(|_| ())),
+    }
+
+    // A frame whose checksum holds, but whose key claims 2^62 bytes: the
+    // record starts with the op's u32 variant, then the key's u64 length
+    let mut log = pristine.clone();
+    log[frame + 12..frame + 20].copy_from_slice(&(1u64 << 62).to_le_bytes());
+    let len = u32::from_le_bytes(log[frame..frame + 4].try_into().unwrap()) as usize;
+    let checksum = crc32fast::hash(&log[frame + 8..frame + 8 + len]);
+    log[frame + 4..frame + 8].copy_from_slice(&checksum.to_le_bytes());
+    std::fs::write(&wal, log)?;
+    let reopened = open().await;
+    assert!(
+        matches!(&reopened, Err(DbError::Corruption { offset, .. }) if *offset == frame as u64),
+        "{:?}",
+        reopened.map(|_| ())
+    );
+
+    std::fs::write(&wal, &pristine)?;
+    let db = open().await?;
+    assert_eq!(db.get_raw(b"other").await?, Some(b"value".to_vec()));
+    Ok(())
+}
+
+// A copy of the files in `dir` as they are on disk now, as a crash would
+// leave them, without closing the database that has it open
+fn crash_copy(dir: &std::path::Path) -> std::io::Result<tempfile::TempDir> {
+    let copy = tempfile::tempdir()?;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        std::fs::copy(entry.path(), copy.path().join(entry.file_name()))?;
+    }
+    Ok(copy)
+}
+
+#[tokio::test]
+async fn sync_wal_makes_batched_writes_survive_a_crash() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    // Long enough that only `sync_wal` writes the WAL out
+    let durability = Durability::Batched(Duration::from_secs(3600));
+    let db = Database::builder(dir.path()).durability(durability).open().await?;
+    for id in 0..20 {
+        db.insert(&item(id)).await?;
+    }
+
+    // Still buffered in memory, so lost along with the process
+    let lost = crash_copy(dir.path())?;
+    let reopened = Database::builder(lost.path()).open().await?;
+    assert_eq!(reopened.all::<Item>().await?, Vec::new());
+
+    db.sync_wal().await?;
+    let synced = crash_copy(dir.path())?;
+    let reopened = Database::builder(synced.path()).open().await?;
+    assert_eq!(reopened.all::<Item>().await?, (0..20).map(item).collect::<Vec<_>>());
+    // Replayed from the WAL, as nothing was flushed
+    assert_eq!(db.stats().await?.sstables, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn recovery_replays_every_wal_segment_in_order() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let open = || Database::builder(dir.path()).wal_segment_size(4096).wal_retained_segments(1).open();
+    {
+        let db = open().await?;
+        for id in 0..200 {
+            db.insert(&item(id)).await?;
+        }
+        // Later segments overwrite and delete what earlier ones wrote
+        for id in (0..200).step_by(3) {
+            db.insert(&Item { id, name: "rewritten".to_string() }).await?;
+        }
+        db.delete::<Item>("10").await?;
+        assert_eq!(db.stats().await?.sstables, 0);
+    }
+    let segments = wal_segments(dir.path())?;
+    assert!(segments.len() > 5, "{:?}", segments);
+    let name = |path: &std::path::PathBuf| path.file_name().unwrap().to_string_lossy().into_owned();
+    assert_eq!(name(&segments[0]), "wal-000001.log");
+    assert!(segments.iter().all(|path| std::fs::metadata(path).is_ok_and(|meta| meta.len() < 2 * 4096)));
+
+    let db = open().await?;
+    let expected: Vec<Item> = (0..200)
+        .filter(|&id| id != 10)
+        .map(|id| if id % 3 == 0 { Item { id, name: "rewritten".to_string() } } else { item(id) })
+        .collect();
+    assert_eq!(db.all::<Item>().await?, expected);
+
+    // Once flushed, all but the retained one and the current one go
+    db.flush().await?;
+    assert_eq!(wal_segments(dir.path())?.len(), 2);
+    drop(db);
+    assert_eq!(open().await?.all::<Item>().await?, expected);
+    Ok(())
+}
+
+#[tokio::test]
+async fn a_torn_wal_tail_is_dropped_but_damage_before_it_fails() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    {
+        let db = Database::open(dir.path().to_str().unwrap()).await?;
+        for id in 0..3 {
+            db.insert(&item(id)).await?;
+        }
+    }
+    let wal = wal_segment(dir.path())?;
+    let len = std::fs::metadata(&wal)?.len() as usize;
+
+    // A write cut short by a crash: the last record loses its final byte
+    let full = std::fs::read(&wal)?;
+    std::fs::write(&wal, &full[..len - 1])?;
+    {
+        let db = Database::open(dir.path().to_str().unwrap()).await?;
+        assert_eq!(db.all::<Item>().await?, vec![item(0), item(1)]);
+    }
+
+    // A flipped byte in the first record, with good ones after it
+    let mut damaged = full.clone();
+    damaged[20] ^= 0xff;
+    std::fs::write(&wal, damaged)?;
+    let reopened = Database::open(dir.path().to_str().unwrap()).await;
+    assert!(matches!(reopened, Err(DbError::Corruption { .. })), "{:?}", reopened.err());
+    Ok(())
+}
+
+// The entries a cursor finds under `prefix`
+async fn entries_under(db: &Database, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, DbError> {
+    let mut cursor = db.cursor().await?;
+    cursor.seek(prefix);
+    let mut entries = Vec::new();
+    for entry in cursor {
+        let entry = entry?;
+        if !entry.0.starts_with(prefix) {
+            break;
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+#[tokio::test]
+async fn a_write_batch_replays_all_or_nothing() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    // As the cursor has them, under the raw keys' `!`
+    let pair = |key: &str, value: &str| (format!("!{}", key).into_bytes(), value.as_bytes().to_vec());
+    {
+        let db = Database::open(dir.path().to_str().unwrap()).await?;
+        let first = WriteBatch::new().put("config:a", "1").put("config:b", "2").put("config:c", "3");
+        db.apply_batch(first).await?;
+        let second = WriteBatch::new().delete("config:a").put("config:b", "20").put("config:d", "4");
+        db.apply_batch(second).await?;
+        assert_eq!(
+            entries_under(&db, b"!config:").await?,
+            vec![pair("config:b", "20"), pair("config:c", "3"), pair("config:d", "4")]
+        );
+        // The same raw keys `put_raw` writes
+        assert_eq!(db.get_raw(b"config:b").await?, Some(b"20".to_vec()));
+    }
+
+    // Replayed whole
+    let db = Database::open(dir.path().to_str().unwrap()).await?;
+    assert_eq!(
+        entries_under(&db, b"!config:").await?,
+        vec![pair("config:b", "20"), pair("config:c", "3"), pair("config:d", "4")]
+    );
+    drop(db);
+
+    // Cut short by a crash, the second batch is lost as a unit
+    let wal = wal_segment(dir.path())?;
+    let full = std::fs::read(&wal)?;
+    std::fs::write(&wal, &full[..full.len() - 1])?;
+    let db = Database::open(dir.path().to_str().unwrap()).await?;
+    assert_eq!(
+        entries_under(&db, b"!config:").await?,
+        vec![pair("config:a", "1"), pair("config:b", "2"), pair("config:c", "3")]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn a_read_only_handle_reads_but_never_writes() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    {
+        let db = Database::open(dir.path().to_str().unwrap()).await?;
+        db.insert(&item(1)).await?;
+        db.flush().await?;
+        // Only in the WAL, which a read-only open replays without appending
+        db.insert(&item(2)).await?;
+    }
+    // Every file with its length
+    let listing = || -> std::io::Result<Vec<(std::ffi::OsString, u64)>> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(dir.path())? {
+            let entry = entry?;
+            files.push((entry.file_name(), entry.metadata()?.len()));
+        }
+        files.sort();
+        Ok(files)
+    };
+    let before = listing()?;
+
+    let first = Database::open_read_only(dir.path()).await?;
+    let second = Database::open_read_only(dir.path()).await?;
+    assert_eq!(first.get::<Item>("1").await?, Some(item(1)));
+    assert_eq!(second.all::<Item>().await?, vec![item(1), item(2)]);
+
+    assert!(matches!(first.insert(&item(3)).await, Err(DbError::ReadOnly)));
+    assert!(matches!(first.delete::<Item>("1").await, Err(DbError::ReadOnly)));
+    assert!(matches!(first.flush().await, Err(DbError::ReadOnly)));
+    drop((first, second));
+    assert_eq!(listing()?, before);
+    Ok(())
+}
+
+#[tokio::test]
+async fn a_second_writable_open_is_refused() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = Database::open(dir.path().to_str().unwrap()).await?;
+    db.insert(&item(1)).await?;
+
+    let second = Database::open(dir.path().to_str().unwrap()).await;
+    assert!(
+        matches!(&second, Err(DbError::AlreadyOpen { path }) if path == dir.path()),
+        "{:?}",
+        second.err()
+    );
+    // Readers can share the directory with the writer
+    let reader = Database::open_read_only(dir.path()).await?;
+    drop(reader);
+
+    // Dropping the handle releases the lock
+    drop(db);
+    let reopened = Database::open(dir.path().to_str().unwrap()).await?;
+    assert_eq!(reopened.get::<Item>("1").await?, Some(item(1)));
+    Ok(())
+}
+
+// A memtable kept as a sorted `Vec`, to run the engine on something other
+// than the default `BTreeMap`
+#[derive(Debug, Clone, Default)]
+struct SortedVecMemTable(Vec<(Vec<u8>, Slot)>);
+
+impl MemTableImpl for SortedVecMemTable {
+    fn insert(&mut self, key: Vec<u8>, slot: Slot) -> Option<Slot> {
+        match self.0.binary_search_by(|(k, _)| k.as_slice().cmp(&key)) {
+            Ok(i) => Some(std::mem::replace(&mut self.0[i].1, slot)),
+            Err(i) => {
+                self.0.insert(i, (key, slot));
+                None
+            }
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&Slot> {
+        let i = self.0.binary_search_by(|(k, _)| k.as_slice().cmp(key)).ok()?;
+        Some(&self.0[i].1)
+    }
+
+    fn range<'a>(
+        &'a self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> Box<dyn DoubleEndedIterator<Item = (&'a [u8], &'a Slot)> + 'a> {
+        let from = match start {
+            Bound::Included(key) => self.0.partition_point(|(k, _)| k.as_slice() < key),
+            Bound::Excluded(key) => self.0.partition_point(|(k, _)| k.as_slice() <= key),
+            Bound::Unbounded => 0,
+        };
+        let to = match end {
+            Bound::Included(key) => self.0.partition_point(|(k, _)| k.as_slice() <= key),
+            Bound::Excluded(key) => self.0.partition_point(|(k, _)| k.as_slice() < key),
+            Bound::Unbounded => self.0.len(),
+        };
+        Box::new(self.0[from..to.max(from)].iter().map(|(key, slot)| (key.as_slice(), slot)))
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn clone_table(&self) -> Box<dyn MemTableImpl> {
+        Box::new(self.clone())
+    }
+}
+
+// Writes, deletes, flushes, a snapshot and a reopen, returning what each
+// step read back
+async fn memtable_scenario(
+    builder: impl Fn() -> rust_db::DatabaseBuilder,
+) -> Result<Vec<Vec<Item>>, DbError> {
+    let mut seen = Vec::new();
+    let db = builder().open().await?;
+    for id in (0..300).rev() {
+        db.insert(&item(id)).await?;
+    }
+    for id in (0..300).step_by(7) {
+        db.insert(&Item { id, name: "rewritten".to_string() }).await?;
+    }
+    db.delete_range::<Item, u64>(100..150).await?;
+    let snapshot = db.snapshot().await?;
+    for id in (0..300).step_by(5) {
+        db.delete::<Item>(&id.to_string()).await?;
+    }
+    seen.push(snapshot.all::<Item>().await?);
+    drop(snapshot);
+    seen.push(db.all::<Item>().await?);
+    seen.push(db.scan::<Item, u64>(40..=160).await?);
+    seen.push(db.get_many::<Item>(&["3", "5", "7", "120"]).await?.into_iter().flatten().collect());
+    db.close().await?;
+
+    let db = builder().open().await?;
+    seen.push(db.all::<Item>().await?);
+    Ok(seen)
+}
+
+#[tokio::test]
+async fn another_memtable_gives_the_same_results() -> Result<(), DbError> {
+    let default_dir = tempfile::tempdir()?;
+    let vec_dir = tempfile::tempdir()?;
+    let default = memtable_scenario(|| Database::builder(default_dir.path()).flush_threshold(2048)).await?;
+    let sorted_vec = memtable_scenario(|| {
+        Database::builder(vec_dir.path()).flush_threshold(2048).memtable::<SortedVecMemTable>()
+    })
+    .await?;
+
+    assert_eq!(sorted_vec, default);
+    assert!(default.iter().all(|step| !step.is_empty()));
+    Ok(())
+}
+
+#[tokio::test]
+async fn a_sharded_memtable_gives_the_same_results() -> Result<(), DbError> {
+    let single_dir = tempfile::tempdir()?;
+    let sharded_dir = tempfile::tempdir()?;
+    let single = memtable_scenario(|| Database::builder(single_dir.path()).flush_threshold(2048)).await?;
+    let sharded =
+        memtable_scenario(|| Database::builder(sharded_dir.path()).flush_threshold(2048).memtable_shards(8)).await?;
+
+    assert_eq!(sharded, single);
+    Ok(())
+}
+
+// Count a writer in, then wait up to five seconds for `count` of them, and
+// whether they all came
+fn meet(arrived: &AtomicUsize, count: usize) -> bool {
+    arrived.fetch_add(1, Ordering::SeqCst);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while arrived.load(Ordering::SeqCst) < count {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    true
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn typed_writes_to_different_records_run_together() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = Arc::new(Database::builder(dir.path()).memtable_shards(8).open().await?);
+    db.insert(&item(1)).await?;
+    db.insert(&item(2)).await?;
+
+    // Each update waits inside the write for the other to get there too,
+    // which one holding the storage to itself would never let happen
+    let arrived = Arc::new(AtomicUsize::new(0));
+    let updates: Vec<_> = [1u64, 2]
+        .into_iter()
+        .map(|id| {
+            let db = Arc::clone(&db);
+            let arrived = Arc::clone(&arrived);
+            tokio::spawn(async move {
+                let mut met = false;
+                db.update::<Item, _>(&id.to_string(), |item| {
+                    met = meet(&arrived, 2);
+                    item.name = format!("updated {}", id);
+                })
+                .await?;
+                Ok::<_, DbError>(met)
+            })
+        })
+        .collect();
+    for update in updates {
+        assert!(update.await.unwrap()?, "an update ran alone");
+    }
+
+    for id in [1u64, 2] {
+        let updated = db.get_by_key::<Item, _>(id).await?.unwrap();
+        assert_eq!(updated.name, format!("updated {}", id));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "debug-dump")]
+#[tokio::test]
+async fn debug_dump_shows_the_keys_and_sstables() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = Database::builder(dir.path()).open().await?;
+    db.pause_compaction().await?;
+    db.insert(&item(1)).await?;
+    db.insert(&item(2)).await?;
+    db.flush().await?;
+    db.insert(&item(258)).await?;
+    db.delete::<Item>("2").await?;
+    db.flush().await?;
+    db.insert(&item(3)).await?;
+
+    let mut out = Vec::new();
+    db.debug_dump(&mut out).await?;
+    let dump = String::from_utf8(out).unwrap();
+    assert!(dump.contains("sstables: 2\n"), "{}", dump);
+    assert!(dump.contains("  level 0: 2 tables\n"), "{}", dump);
+    assert!(dump.contains("memtable: 1 entries"), "{}", dump);
+    assert!(dump.contains(r#"  "Item:\x00\x00\x00\x00\x00\x00\x00\x03": value, "#), "{}", dump);
+    // The second SSTable holds 2's tombstone and 258 = 0x0102, and the
+    // first the table's metadata keys too
+    let range = r#"bytes, "Item:\x00\x00\x00\x00\x00\x00\x00\x02" to "Item:\x00\x00\x00\x00\x00\x00\x01\x02""#;
+    assert!(dump.lines().any(|line| line.contains(": 2 entries, ") && line.ends_with(range)), "{}", dump);
+    assert!(dump.contains(r#" to "~table:Item""#), "{}", dump);
+    assert!(dump.contains("wal: "), "{}", dump);
+    Ok(())
+}
@@ -0,0 +1,43 @@
+//! `SyncDatabase`, called from plain threads with no Tokio runtime.
+#![cfg(feature = "sync")]
+
+use rust_db::{DbError, Schema, SyncDatabase};
+use serde::{Deserialize, Serialize};
+
+#[derive(Schema, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[table_name = "Task"]
+struct Task {
+    #[primary_key]
+    id: u64,
+    title: String,
+    done: bool,
+}
+
+fn task(id: u64) -> Task {
+    Task { id, title: format!("task {}", id), done: false }
+}
+
+#[test]
+fn inserts_and_gets_without_a_runtime() -> Result<(), DbError> {
+    assert!(tokio::runtime::Handle::try_current().is_err());
+    let dir = tempfile::tempdir()?;
+    {
+        let db = SyncDatabase::open(dir.path())?;
+        for id in 1..=3 {
+            db.insert(&task(id))?;
+        }
+        assert_eq!(db.get::<Task>("2")?, Some(task(2)));
+        assert!(db.update::<Task, _>("2", |task| task.done = true)?);
+        db.delete::<Task>("3")?;
+        // Compaction too runs on this thread, as part of the flush
+        db.flush()?;
+        db.compact()?;
+        let open = db.query::<Task>().filter(|task| !task.done).execute()?;
+        assert_eq!(open, vec![task(1)]);
+        db.close()?;
+    }
+
+    let db = SyncDatabase::open(dir.path())?;
+    assert_eq!(db.all::<Task>()?, vec![task(1), Task { done: true, ..task(2) }]);
+    Ok(())
+}
@@ -0,0 +1,88 @@
+//! The spans the `tracing` feature puts around storage operations.
+#![cfg(feature = "tracing")]
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use rust_db::{Database, DbError, Schema};
+use serde::{Deserialize, Serialize};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+#[derive(Schema, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[table_name = "Item"]
+struct Item {
+    #[primary_key]
+    id: u64,
+    name: String,
+}
+
+/// Each field recorded on a span, formatted
+#[derive(Debug, Clone, Default)]
+struct Fields(HashMap<&'static str, String>);
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0.insert(field.name(), format!("{:?}", value));
+    }
+}
+
+/// Keeps every span closed on any thread, the background flush's included
+#[derive(Default, Clone)]
+struct Capture(Arc<Mutex<Vec<(&'static str, Fields)>>>);
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Capture {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        ctx.span(id).unwrap().extensions_mut().insert(fields);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(fields) = ctx.span(id).unwrap().extensions_mut().get_mut::<Fields>() {
+            values.record(fields);
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let span = ctx.span(&id).unwrap();
+        let fields = span.extensions().get::<Fields>().cloned().unwrap_or_default();
+        self.0.lock().unwrap().push((span.name(), fields));
+    }
+}
+
+#[tokio::test]
+async fn a_flush_records_its_duration_on_its_span() -> Result<(), DbError> {
+    let capture = Capture::default();
+    // Global, as the flush runs on a thread of its own
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(capture.clone())).unwrap();
+
+    let dir = tempfile::tempdir()?;
+    let db = Database::builder(dir.path()).open().await?;
+    for id in 0..10 {
+        db.insert(&Item { id, name: format!("item {}", id) }).await?;
+    }
+    assert!(db.get::<Item>("3").await?.is_some());
+    db.flush().await?;
+
+    let spans = capture.0.lock().unwrap().clone();
+    let named = |name: &str| spans.iter().filter(|span| span.0 == name).map(|span| &span.1 .0).collect::<Vec<_>>();
+    // A flush with nothing frozen to write out returns before it records anything
+    let flushes: Vec<_> = named("flush").into_iter().filter(|fields| fields.contains_key("entries")).collect();
+    assert_eq!(flushes.len(), 1, "{:?}", spans);
+    let flush = flushes[0];
+    assert!(flush["elapsed_us"].parse::<u64>().is_ok(), "{:?}", flush);
+    assert!(flush["entries"].parse::<usize>().unwrap() >= 10, "{:?}", flush);
+    assert_eq!(flush["tables"], "1");
+
+    let inserts = named("insert");
+    assert_eq!(inserts.len(), 10);
+    assert!(inserts.iter().all(|fields| fields.contains_key("elapsed_us") && fields["table"] == "\"Item\""));
+    let get = named("get");
+    assert_eq!((get[0]["found"].as_str(), get[0]["key_bytes"].as_str()), ("true", "13"));
+    Ok(())
+}
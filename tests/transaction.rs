@@ -0,0 +1,56 @@
+//! Transactions and write batches: all of their writes, or none.
+
+use rust_db::{Database, DbError, Schema};
+use serde::{Deserialize, Serialize};
+
+#[derive(Schema, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[table_name = "Account"]
+struct Account {
+    #[primary_key]
+    id: u64,
+    balance: i64,
+}
+
+async fn open(dir: &tempfile::TempDir) -> Result<Database, DbError> {
+    Database::builder(dir.path()).open().await
+}
+
+#[tokio::test]
+async fn rollback_leaves_nothing_behind() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    let db = open(&dir).await?;
+    let mut tx = db.transaction();
+    tx.insert(&Account { id: 1, balance: 100 })?;
+    tx.insert(&Account { id: 2, balance: 50 })?;
+    // Buffered until commit, so nothing shows in the meantime either
+    assert_eq!(db.get::<Account>("1").await?, None);
+    tx.rollback();
+
+    assert_eq!(db.get::<Account>("1").await?, None);
+    assert_eq!(db.get::<Account>("2").await?, None);
+    assert!(db.all::<Account>().await?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn commit_makes_every_write_visible() -> Result<(), DbError> {
+    let dir = tempfile::tempdir()?;
+    {
+        let db = open(&dir).await?;
+        db.insert(&Account { id: 3, balance: 10 }).await?;
+        let mut tx = db.transaction();
+        tx.insert(&Account { id: 1, balance: 100 })?;
+        tx.insert(&Account { id: 2, balance: 50 })?;
+        tx.delete::<Account>("3");
+        tx.commit().await?;
+
+        assert_eq!(db.get::<Account>("1").await?, Some(Account { id: 1, balance: 100 }));
+        assert_eq!(db.get::<Account>("2").await?, Some(Account { id: 2, balance: 50 }));
+        assert_eq!(db.get::<Account>("3").await?, None);
+    }
+
+    // And after the WAL is replayed
+    let db = open(&dir).await?;
+    assert_eq!(db.all::<Account>().await?, vec![Account { id: 1, balance: 100 }, Account { id: 2, balance: 50 }]);
+    Ok(())
+}
@@ -0,0 +1,5 @@
+#[derive(rust_db::Schema, serde::Serialize, serde::Deserialize)]
+#[table_name = "Empty"]
+struct Empty {}
+
+fn main() {}
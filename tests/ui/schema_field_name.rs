@@ -0,0 +1,11 @@
+rust_db::schema! {
+    table_name: "Item",
+    #[derive(Debug)]
+    struct Item {
+        #[primary_key]
+        id: u64,
+        r#type: String,
+    }
+}
+
+fn main() {}
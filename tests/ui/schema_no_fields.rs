@@ -0,0 +1,7 @@
+rust_db::schema! {
+    table_name: "Empty",
+    #[derive(Debug)]
+    struct Empty {}
+}
+
+fn main() {}
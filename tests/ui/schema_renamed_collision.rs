@@ -0,0 +1,13 @@
+rust_db::schema! {
+    table_name: "Account",
+    #[derive(Debug, serde::Serialize)]
+    struct Account {
+        #[primary_key]
+        id: u64,
+        #[serde(rename = "name")]
+        email: String,
+        name: String,
+    }
+}
+
+fn main() {}